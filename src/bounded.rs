@@ -0,0 +1,444 @@
+//! Limiting the number of concurrent block requests.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+use futures::sync::oneshot;
+
+use super::*;
+use crate::http_fetch::NgPreHTTPFetch;
+
+
+/// Number of concurrent requests of batch operations if not specified, which
+/// is the per host connection limit of most browsers.
+pub(crate) const DEFAULT_MAX_CONCURRENT: u32 = 6;
+
+/// A pending acquisition. Waiters with a higher priority are served first,
+/// and among equal priorities those that were submitted first.
+struct Waiter {
+    priority: i32,
+    sequence: u64,
+    /// Receives the permit itself, so that a waiter dropped before it is
+    /// polled releases the permit again.
+    sender: oneshot::Sender<Permit>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+struct SemaphoreState {
+    available: usize,
+    /// Pending acquisitions, as a max-heap by priority.
+    waiters: BinaryHeap<Waiter>,
+    next_sequence: u64,
+}
+
+/// A counting semaphore for futures running on a single thread. Permits are
+/// handed to waiters by priority, and in the order they were requested
+/// otherwise.
+#[derive(Clone)]
+pub(crate) struct Semaphore(Rc<RefCell<SemaphoreState>>);
+
+/// A held permit, which is released when dropped.
+pub(crate) struct Permit(Option<Semaphore>);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore(Rc::new(RefCell::new(SemaphoreState {
+            available: permits,
+            waiters: BinaryHeap::new(),
+            next_sequence: 0,
+        })))
+    }
+
+    pub(crate) fn acquire(&self) -> Box<dyn Future<Item = Permit, Error = Error>> {
+        self.acquire_with_priority(0)
+    }
+
+    /// Acquire a permit ahead of all waiters with a lower `priority`.
+    pub(crate) fn acquire_with_priority(&self, priority: i32) -> Box<dyn Future<Item = Permit, Error = Error>> {
+        let mut state = self.0.borrow_mut();
+        if state.available > 0 && state.waiters.is_empty() {
+            state.available -= 1;
+            return Box::new(future::ok(Permit(Some(self.clone()))));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.waiters.push(Waiter { priority, sequence, sender });
+        Box::new(receiver
+            .map_err(|_| errors::new(errors::ErrorKind::Other, "Semaphore was dropped")))
+    }
+
+    fn release(&self) {
+        let mut state = self.0.borrow_mut();
+        // Hand the permit directly to the next waiter that is still interested.
+        let mut permit = Permit(Some(self.clone()));
+        while let Some(waiter) = state.waiters.pop() {
+            match waiter.sender.send(permit) {
+                Ok(()) => return,
+                Err(returned) => permit = returned,
+            }
+        }
+        // Nobody is waiting, so the permit goes back without releasing it
+        // once more.
+        permit.0 = None;
+        state.available += 1;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.0.take() {
+            semaphore.release();
+        }
+    }
+}
+
+/// Run the future created by `f` once a permit is available, holding the
+/// permit until it completes.
+pub(crate) fn with_permit<F, T, R>(semaphore: &Semaphore, f: F) -> Box<dyn Future<Item = T, Error = Error>>
+        where F: FnOnce() -> R + 'static,
+            R: Future<Item = T, Error = Error> + 'static,
+            T: 'static,
+{
+    with_priority_permit(semaphore, 0, f)
+}
+
+/// Like `with_permit`, waiting for the permit with `priority`.
+pub(crate) fn with_priority_permit<F, T, R>(semaphore: &Semaphore, priority: i32, f: F)
+        -> Box<dyn Future<Item = T, Error = Error>>
+        where F: FnOnce() -> R + 'static,
+            R: Future<Item = T, Error = Error> + 'static,
+            T: 'static,
+{
+    Box::new(semaphore.acquire_with_priority(priority)
+        .and_then(move |permit| f().then(move |result| {
+            drop(permit);
+            result
+        })))
+}
+
+
+/// A reader that allows at most `max_concurrent` block reads of an inner
+/// reader to be in flight at once. Further reads are queued in submission
+/// order.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct BoundedReader {
+    inner: NgPreHTTPFetch,
+    semaphore: Semaphore,
+}
+
+#[wasm_bindgen]
+impl BoundedReader {
+    pub fn new(inner: &NgPreHTTPFetch, max_concurrent: usize) -> BoundedReader {
+        BoundedReader {
+            inner: inner.clone(),
+            semaphore: Semaphore::new(std::cmp::max(max_concurrent, 1)),
+        }
+    }
+}
+
+reader_delegations!(BoundedReader);
+
+impl AbortableReader for BoundedReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        BoundedReader {
+            inner: self.inner.with_signal(signal),
+            semaphore: self.semaphore.clone(),
+        }
+    }
+}
+
+impl NgPreAsyncReader for BoundedReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncReader::read_block::<T>(
+            &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncReader::read_block_subregion::<T>(
+            &inner, &path_name, &data_attrs, grid_position, min, max))
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncReader::block_exists(
+            &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for BoundedReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+
+        with_permit(&self.semaphore, move || NgPreAsyncEtagReader::attributes_etag(&inner, &path_name))
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncEtagReader::block_etag(
+            &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncEtagReader::read_block_with_etag::<T>(
+            &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_permit(&self.semaphore, move || NgPreAsyncEtagReader::read_block_with_encoded_size::<T>(
+            &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+        let known_etag = known_etag.to_owned();
+
+        with_permit(&self.semaphore, move || NgPreAsyncEtagReader::read_block_if_changed::<T>(
+            &inner, &path_name, &data_attrs, grid_position, &known_etag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    use futures::executor::{self, Notify, Spawn};
+
+    use super::*;
+
+    struct NoNotify;
+
+    impl Notify for NoNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    /// Poll each unfinished job once, returning how many have finished.
+    fn poll_all<F: Future>(jobs: &mut [Spawn<F>], finished: &mut [bool]) -> usize {
+        let notify = Arc::new(NoNotify);
+        for (job, finished) in jobs.iter_mut().zip(finished.iter_mut()) {
+            if !*finished {
+                *finished = match job.poll_future_notify(&notify, 0) {
+                    Ok(futures::Async::Ready(_)) | Err(_) => true,
+                    Ok(futures::Async::NotReady) => false,
+                };
+            }
+        }
+
+        finished.iter().filter(|&&finished| finished).count()
+    }
+
+    #[test]
+    fn never_runs_more_jobs_than_permits() {
+        let semaphore = Semaphore::new(2);
+        let in_flight = Rc::new(Cell::new(0));
+        let max_in_flight = Rc::new(Cell::new(0));
+        let started = Rc::new(RefCell::new(Vec::new()));
+
+        let mut senders = Vec::new();
+        let mut jobs = Vec::new();
+        for i in 0..6 {
+            let (sender, receiver) = oneshot::channel::<()>();
+            senders.push(sender);
+            let (in_flight, max_in_flight, started) = (in_flight.clone(), max_in_flight.clone(), started.clone());
+            jobs.push(executor::spawn(with_permit(&semaphore, move || {
+                started.borrow_mut().push(i);
+                in_flight.set(in_flight.get() + 1);
+                max_in_flight.set(std::cmp::max(max_in_flight.get(), in_flight.get()));
+                receiver.then(move |_| {
+                    in_flight.set(in_flight.get() - 1);
+                    Ok::<(), Error>(())
+                })
+            })));
+        }
+        let mut finished = vec![false; jobs.len()];
+
+        assert_eq!(poll_all(&mut jobs, &mut finished), 0);
+        assert_eq!(in_flight.get(), 2);
+        for (done, sender) in senders.into_iter().enumerate() {
+            sender.send(()).unwrap();
+            assert_eq!(poll_all(&mut jobs, &mut finished), done + 1);
+            assert!(in_flight.get() <= 2);
+        }
+
+        assert_eq!(max_in_flight.get(), 2);
+        // Queued jobs start in submission order.
+        assert_eq!(*started.borrow(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn serves_waiters_by_priority() {
+        let semaphore = Semaphore::new(1);
+        let started = Rc::new(RefCell::new(Vec::new()));
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let mut jobs = vec![executor::spawn(with_permit(&semaphore, move ||
+            receiver.map_err(|_| errors::new(errors::ErrorKind::Other, "Canceled"))))];
+        for &priority in &[0, 5, 1] {
+            let started = started.clone();
+            jobs.push(executor::spawn(with_priority_permit(&semaphore, priority, move || {
+                started.borrow_mut().push(priority);
+                future::ok(())
+            })));
+        }
+        let mut finished = vec![false; jobs.len()];
+
+        assert_eq!(poll_all(&mut jobs, &mut finished), 0);
+        sender.send(()).unwrap();
+        // Each finished job hands its permit to the next waiter, which runs
+        // on a later poll.
+        while poll_all(&mut jobs, &mut finished) < jobs.len() {}
+
+        assert_eq!(*started.borrow(), vec![5, 1, 0]);
+    }
+
+    #[test]
+    fn releases_permits_handed_to_dropped_waiters() {
+        let semaphore = Semaphore::new(1);
+        let held = executor::spawn(semaphore.acquire()).wait_future().unwrap();
+
+        // The permit is handed to the queued acquisition, which is dropped
+        // before it is polled.
+        let waiting = semaphore.acquire();
+        drop(held);
+        assert_eq!(semaphore.0.borrow().available, 0);
+        drop(waiting);
+        assert_eq!(semaphore.0.borrow().available, 1);
+
+        let mut next = vec![executor::spawn(semaphore.acquire())];
+        assert_eq!(poll_all(&mut next, &mut [false]), 1);
+    }
+}
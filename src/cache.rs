@@ -0,0 +1,609 @@
+//! In-memory LRU cache of decoded blocks.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::rc::Rc;
+
+use super::*;
+use crate::bounded::{
+    self,
+    Semaphore,
+};
+use crate::http_fetch::{
+    self,
+    NgPreHTTPFetch,
+};
+
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct BlockKey {
+    /// Base path of the reader, so that readers of different datasets can
+    /// share a cache.
+    dataset: String,
+    path_name: String,
+    /// Block size of the scale, as one scale can be read in several chunk
+    /// sizes.
+    block_size: Vec<u32>,
+    grid_position: Vec<i64>,
+}
+
+impl BlockKey {
+    pub(crate) fn new(dataset: &str, path_name: &str, block_size: &[u32], grid_position: &[i64]) -> Self {
+        BlockKey {
+            dataset: dataset.to_owned(),
+            path_name: path_name.to_owned(),
+            block_size: block_size.to_vec(),
+            grid_position: grid_position.to_vec(),
+        }
+    }
+}
+
+struct CacheEntry {
+    /// A boxed `VecDataBlock<T>` for the dataset's data type.
+    block: Box<dyn Any>,
+    etag: Option<String>,
+    size_bytes: usize,
+    last_used: u64,
+}
+
+/// Byte-budgeted LRU map from block keys to decoded blocks.
+///
+/// Entries remember the etag they were fetched with, so that a changed etag
+/// can invalidate them.
+pub(crate) struct BlockCache {
+    capacity_bytes: usize,
+    bytes_used: usize,
+    clock: u64,
+    entries: HashMap<BlockKey, CacheEntry>,
+    /// Keys ordered by last use, oldest first.
+    lru: BTreeMap<u64, BlockKey>,
+    stats: CacheStats,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CacheStats {
+    hits: u64,
+    misses: u64,
+    /// Entries removed to make room for new ones.
+    evictions: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity_bytes: usize) -> Self {
+        BlockCache {
+            capacity_bytes,
+            bytes_used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    pub(crate) fn get<T>(&mut self, key: &BlockKey) -> Option<(VecDataBlock<T>, Option<String>)>
+            where VecDataBlock<T>: Clone + 'static {
+        let now = self.tick();
+        let hit = match self.entries.get_mut(key) {
+            Some(entry) => match entry.block.downcast_ref::<VecDataBlock<T>>() {
+                Some(block) => {
+                    self.lru.remove(&entry.last_used);
+                    entry.last_used = now;
+                    Some((block.clone(), entry.etag.clone()))
+                },
+                None => None,
+            },
+            None => {
+                self.stats.misses += 1;
+                return None;
+            },
+        };
+
+        match hit {
+            Some(hit) => {
+                self.stats.hits += 1;
+                self.lru.insert(now, key.clone());
+                Some(hit)
+            },
+            // The entry was stored with a different data type, so it is stale.
+            None => {
+                self.stats.misses += 1;
+                self.remove(key);
+                None
+            },
+        }
+    }
+
+    pub(crate) fn etag(&self, key: &BlockKey) -> Option<&Option<String>> {
+        self.entries.get(key).map(|entry| &entry.etag)
+    }
+
+    pub(crate) fn insert<T>(&mut self, key: BlockKey, block: VecDataBlock<T>, etag: Option<String>)
+            where VecDataBlock<T>: DataBlock<T> + 'static {
+        let size_bytes = block.get_data().len() * std::mem::size_of::<T>();
+        self.remove(&key);
+        if size_bytes > self.capacity_bytes {
+            return;
+        }
+
+        while self.bytes_used + size_bytes > self.capacity_bytes {
+            let oldest = match self.lru.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let oldest_key = self.lru[&oldest].clone();
+            self.remove(&oldest_key);
+            self.stats.evictions += 1;
+        }
+
+        let now = self.tick();
+        self.bytes_used += size_bytes;
+        self.lru.insert(now, key.clone());
+        self.entries.insert(key, CacheEntry {
+            block: Box::new(block),
+            etag,
+            size_bytes,
+            last_used: now,
+        });
+    }
+
+    pub(crate) fn remove(&mut self, key: &BlockKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.bytes_used -= entry.size_bytes;
+        }
+    }
+
+    /// Statistics and the current size of the cache as a JSON object.
+    pub(crate) fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "hits": self.stats.hits,
+            "misses": self.stats.misses,
+            "evictions": self.stats.evictions,
+            "bytes_used": self.bytes_used,
+            "capacity_bytes": self.capacity_bytes,
+            "entry_count": self.entries.len(),
+        })
+    }
+
+    pub(crate) fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes_used = 0;
+    }
+
+    /// Remove all blocks of one dataset.
+    pub(crate) fn clear_dataset(&mut self, dataset: &str) {
+        let keys: Vec<BlockKey> = self.entries.keys()
+            .filter(|key| key.dataset == dataset)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+
+fn stats_to_js(cache: &BlockCache) -> Result<JsValue, JsValue> {
+    JsValue::from_serde(&cache.stats())
+        .map_err(|e| errors::to_js_error(&errors::new(errors::ErrorKind::Decode, e.to_string())))
+}
+
+
+/// A block cache with a single byte budget that can be shared by the
+/// `CachedReader`s of several datasets.
+#[wasm_bindgen]
+pub struct SharedCache(Rc<RefCell<BlockCache>>);
+
+#[wasm_bindgen]
+impl SharedCache {
+    #[wasm_bindgen(constructor)]
+    pub fn new(capacity_bytes: usize) -> SharedCache {
+        SharedCache(Rc::new(RefCell::new(BlockCache::new(capacity_bytes))))
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// Get `{hits, misses, evictions, bytes_used, capacity_bytes,
+    /// entry_count}` of the cache, across all readers using it.
+    pub fn stats(&self) -> Result<JsValue, JsValue> {
+        stats_to_js(&self.0.borrow())
+    }
+}
+
+
+/// A reader that keeps decoded blocks from an inner reader in an LRU cache
+/// bounded by `capacity_bytes`.
+///
+/// Cached blocks are returned without any request. A call to `block_etag`
+/// returning an etag different from the cached one evicts the stale block.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct CachedReader {
+    inner: NgPreHTTPFetch,
+    cache: Rc<RefCell<BlockCache>>,
+}
+
+#[wasm_bindgen]
+impl CachedReader {
+    pub fn new(inner: &NgPreHTTPFetch, capacity_bytes: usize) -> CachedReader {
+        CachedReader {
+            inner: inner.clone(),
+            cache: Rc::new(RefCell::new(BlockCache::new(capacity_bytes))),
+        }
+    }
+
+    /// Create a reader caching blocks in `shared`, whose budget is shared
+    /// with all other readers using it.
+    pub fn with_shared(inner: &NgPreHTTPFetch, shared: &SharedCache) -> CachedReader {
+        CachedReader {
+            inner: inner.clone(),
+            cache: shared.0.clone(),
+        }
+    }
+
+    /// Remove the blocks of this reader's dataset from the cache.
+    pub fn clear(&self) {
+        self.cache.borrow_mut().clear_dataset(self.inner.base_path());
+    }
+
+    /// Remove the blocks of this reader's dataset from the cache and dispose
+    /// the inner reader, aborting its requests in flight. Every later call
+    /// rejects with a `disposed` error, even for blocks cached by other
+    /// readers sharing the cache. Disposing a reader again does nothing.
+    pub fn dispose(&self) {
+        self.clear();
+        self.inner.dispose();
+    }
+
+    /// Get `{hits, misses, evictions, bytes_used, capacity_bytes,
+    /// entry_count}` of the cache. For a shared cache these cover all readers
+    /// using it.
+    pub fn stats(&self) -> Result<JsValue, JsValue> {
+        stats_to_js(&self.cache.borrow())
+    }
+
+    /// Reset the hit, miss and eviction counters.
+    pub fn reset_stats(&self) {
+        self.cache.borrow_mut().reset_stats();
+    }
+
+    /// Load blocks into the cache without returning them, with at most
+    /// `max_concurrent` requests in flight. Resolves to `undefined` once every
+    /// block is cached or known to be missing; failures of individual blocks
+    /// are ignored. `on_progress(completed, total)` is called after each
+    /// block.
+    pub fn prefetch_blocks(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_positions: JsValue,
+        max_concurrent: Option<u32>,
+        on_progress: Option<js_sys::Function>,
+    ) -> Promise {
+        let grid_positions = match convert_grid_positions(&grid_positions) {
+            Ok(grid_positions) => grid_positions,
+            Err(error) => return errors::reject(error),
+        };
+        let progress = Progress::new(on_progress, grid_positions.len());
+        let semaphore = Semaphore::new(
+            std::cmp::max(max_concurrent.unwrap_or(bounded::DEFAULT_MAX_CONCURRENT), 1) as usize);
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            {
+                let prefetches = grid_positions.into_iter()
+                    .map(|grid_position| {
+                        let reader = self.with_signal(None);
+                        let path_name = path_name.to_owned();
+                        let data_attrs = data_attrs.0.clone();
+                        let progress = progress.clone();
+                        bounded::with_permit(&semaphore, move ||
+                                NgPreAsyncEtagReader::read_block_with_etag::<RsType>(
+                                    &reader, &path_name, &data_attrs, grid_position.into()))
+                            .then(move |_| {
+                                progress.complete();
+                                Ok::<(), Error>(())
+                            })
+                    })
+                    .collect::<Vec<_>>();
+
+                future_to_promise(map_future_error_wasm(future::join_all(prefetches)
+                    .map(|_| JsValue::UNDEFINED)))
+            }
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl CachedReader {
+    /// Read a tile for display like `read_tile`, serving blocks from the
+    /// cache where possible. Requests are aborted by `signal`, and
+    /// `on_progress(completed, total)` is called after each block. Only
+    /// complete blocks are cached, so an aborted tile leaves the cache
+    /// consistent.
+    pub fn render_tile(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        origin: Vec<i64>,
+        size: Vec<u32>,
+        signal: Option<AbortSignal>,
+        on_progress: Option<js_sys::Function>,
+    ) -> Promise {
+        let reader = self.with_signal(signal);
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_tile::<_, RsType>(&reader, path_name, &data_attrs.0, zoom_level, origin, size, on_progress)
+                    .map(|block| JsValue::from(
+                        <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))))
+        }
+    }
+}
+
+reader_delegations!(CachedReader);
+
+impl CachedReader {
+    fn key(&self, path_name: &str, data_attrs: &DatasetAttributes, grid_position: &[i64]) -> BlockKey {
+        BlockKey::new(self.inner.base_path(), path_name, &scale_block_size(data_attrs, path_name), grid_position)
+    }
+}
+
+impl AbortableReader for CachedReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        CachedReader {
+            inner: self.inner.with_signal(signal),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl NgPreAsyncReader for CachedReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+
+        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
+                self, path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, _etag)| block)))
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        if !self.inner.is_disposed() && self.cache.borrow().etag(&self.key(path_name, data_attrs, &grid_position)).is_some() {
+            return Box::new(future::ok(true));
+        }
+
+        NgPreAsyncReader::block_exists(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for CachedReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::attributes_etag(&self.inner, path_name)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let key = self.key(path_name, data_attrs, &grid_position);
+        let cache = self.cache.clone();
+
+        Box::new(NgPreAsyncEtagReader::block_etag(
+                &self.inner, path_name, data_attrs, grid_position)
+            .map(move |etag| {
+                let mut cache = cache.borrow_mut();
+                let stale = cache.etag(&key).map_or(false, |cached| *cached != etag);
+                if stale {
+                    cache.remove(&key);
+                }
+                etag
+            }))
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        if self.inner.is_disposed() {
+            return Box::new(future::err(http_fetch::disposed_error()));
+        }
+        let key = self.key(path_name, data_attrs, &grid_position);
+        let hit = self.cache.borrow_mut().get::<T>(&key);
+        if let Some(hit) = hit {
+            // The callbacks may use the cache, so it must not be borrowed here.
+            self.inner.report_cache_hit(path_name, data_attrs, &grid_position);
+            return Box::new(future::ok(Some(hit)));
+        }
+
+        let cache = self.cache.clone();
+        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
+                &self.inner, path_name, data_attrs, grid_position)
+            .map(move |maybe_block: Option<(VecDataBlock<T>, Option<String>)>| {
+                if let Some((block, etag)) = &maybe_block {
+                    cache.borrow_mut().insert(key, block.clone(), etag.clone());
+                }
+                maybe_block
+            }))
+    }
+
+    // Override the default NgPreAsyncEtagReader impl to report the encoded
+    // size of blocks that were not cached yet.
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        if self.inner.is_disposed() {
+            return Box::new(future::err(http_fetch::disposed_error()));
+        }
+        let key = self.key(path_name, data_attrs, &grid_position);
+        let hit = self.cache.borrow_mut().get::<T>(&key);
+        if let Some((block, etag)) = hit {
+            self.inner.report_cache_hit(path_name, data_attrs, &grid_position);
+            return Box::new(future::ok(Some((block, etag, None))));
+        }
+
+        let cache = self.cache.clone();
+        Box::new(NgPreAsyncEtagReader::read_block_with_encoded_size(
+                &self.inner, path_name, data_attrs, grid_position)
+            .map(move |maybe_block: Option<(VecDataBlock<T>, Option<String>, Option<u32>)>| {
+                if let Some((block, etag, _)) = &maybe_block {
+                    cache.borrow_mut().insert(key, block.clone(), etag.clone());
+                }
+                maybe_block
+            }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: i64) -> BlockKey {
+        BlockKey::new("https://example.com/dataset", "4_4_40", &[4, 1, 1], &[x, 0, 0])
+    }
+
+    fn block(len: usize) -> VecDataBlock<u8> {
+        VecDataBlock::new(vec![len as u32, 1, 1].into(), vec![0, 0, 0].into(), vec![1; len])
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_blocks() {
+        let mut cache = BlockCache::new(8);
+        cache.insert(key(0), block(4), None);
+        cache.insert(key(1), block(4), None);
+        // Using the older block makes the other one the least recently used.
+        assert!(cache.get::<u8>(&key(0)).is_some());
+        cache.insert(key(2), block(4), None);
+
+        assert!(cache.get::<u8>(&key(1)).is_none());
+        assert!(cache.get::<u8>(&key(0)).is_some());
+        assert!(cache.get::<u8>(&key(2)).is_some());
+        assert_eq!(cache.stats()["evictions"], 1);
+    }
+
+    #[test]
+    fn stays_within_its_byte_budget() {
+        let mut cache = BlockCache::new(10);
+        for x in 0..3 {
+            cache.insert(key(x), block(4), None);
+        }
+        assert_eq!(cache.stats()["bytes_used"], 8);
+        assert_eq!(cache.stats()["entry_count"], 2);
+
+        // A block larger than the whole cache is not stored, and does not
+        // evict anything.
+        cache.insert(key(3), block(11), None);
+        assert!(cache.get::<u8>(&key(3)).is_none());
+        assert_eq!(cache.stats()["bytes_used"], 8);
+        assert_eq!(cache.stats()["evictions"], 1);
+    }
+
+    #[test]
+    fn counts_a_reinserted_block_once() {
+        let mut cache = BlockCache::new(16);
+        cache.insert(key(0), block(4), Some("a".to_owned()));
+        cache.insert(key(0), block(6), Some("b".to_owned()));
+
+        assert_eq!(cache.stats()["bytes_used"], 6);
+        assert_eq!(cache.stats()["entry_count"], 1);
+        assert_eq!(cache.etag(&key(0)), Some(&Some("b".to_owned())));
+        assert_eq!(cache.get::<u8>(&key(0)).unwrap().0.get_data().len(), 6);
+
+        cache.remove(&key(0));
+        assert_eq!(cache.stats()["bytes_used"], 0);
+        assert_eq!(cache.etag(&key(0)), None);
+    }
+
+    #[test]
+    fn counts_hits_and_misses_until_reset() {
+        let mut cache = BlockCache::new(16);
+        assert!(cache.get::<u8>(&key(0)).is_none());
+        cache.insert(key(0), block(4), None);
+        assert!(cache.get::<u8>(&key(0)).is_some());
+        // A block of another data type is stale and removed.
+        assert!(cache.get::<u16>(&key(0)).is_none());
+        assert!(cache.get::<u8>(&key(0)).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats["hits"].as_u64(), stats["misses"].as_u64()), (Some(1), Some(3)));
+
+        cache.reset_stats();
+        let stats = cache.stats();
+        assert_eq!((stats["hits"].as_u64(), stats["misses"].as_u64()), (Some(0), Some(0)));
+        assert_eq!(stats["evictions"], 0);
+    }
+}
@@ -0,0 +1,208 @@
+//! Decoder for the precomputed `compressed_segmentation` encoding.
+//!
+//! Each channel of a chunk is split into blocks of
+//! `compressed_segmentation_block_size`. Every block has a 64-bit header
+//! holding the offset of its lookup table, the number of bits per encoded
+//! value and the offset of its packed values. Offsets are in 32-bit words
+//! relative to the start of the channel's data. Lookup table entries are one
+//! word for `uint32` and two words (low, high) for `uint64` data.
+
+use super::*;
+
+
+fn words(bytes: &[u8]) -> Result<Vec<u32>, Error> {
+    if bytes.len() % 4 != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode,
+            "compressed_segmentation data is not a whole number of words"));
+    }
+
+    Ok(bytes.chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect())
+}
+
+fn word(words: &[u32], index: usize) -> Result<u32, Error> {
+    words.get(index).cloned().ok_or_else(|| errors::new(errors::ErrorKind::Decode,
+        "compressed_segmentation offset is out of bounds"))
+}
+
+/// Decode a chunk of the given spatial `size` with `num_channels` channels
+/// into Fortran-ordered labels, channels last. `label_words` is 1 for
+/// `uint32` and 2 for `uint64` labels.
+pub(crate) fn decode(
+    bytes: &[u8],
+    size: &[u32],
+    block_size: &[u32],
+    num_channels: usize,
+    label_words: usize,
+) -> Result<Vec<u64>, Error> {
+    if size.len() != 3 || block_size.len() != 3 || block_size.contains(&0) {
+        return Err(errors::new(errors::ErrorKind::BadAttrs,
+            "compressed_segmentation requires 3D chunks and block sizes"));
+    }
+    let words = words(bytes)?;
+
+    let size: Vec<usize> = size.iter().map(|&s| s as usize).collect();
+    let block_size: Vec<usize> = block_size.iter().map(|&s| s as usize).collect();
+    let grid: Vec<usize> = size.iter().zip(&block_size).map(|(s, b)| (s + b - 1) / b).collect();
+    let channel_len = size[0] * size[1] * size[2];
+
+    let mut labels = vec![0u64; channel_len * num_channels];
+    for channel in 0..num_channels {
+        let base = word(&words, channel)? as usize;
+        let out = &mut labels[channel * channel_len..(channel + 1) * channel_len];
+
+        for bz in 0..grid[2] {
+            for by in 0..grid[1] {
+                for bx in 0..grid[0] {
+                    let block_index = bx + grid[0] * (by + grid[1] * bz);
+                    let header = base + 2 * block_index;
+                    let header_low = word(&words, header)?;
+                    let table_offset = base + (header_low & 0x00ff_ffff) as usize;
+                    let bits = (header_low >> 24) as usize;
+                    let values_offset = base + word(&words, header + 1)? as usize;
+                    if bits > 32 {
+                        return Err(errors::new(errors::ErrorKind::Decode, format!(
+                            "Invalid compressed_segmentation bit width {}", bits)));
+                    }
+                    let mask = if bits == 32 { !0u32 } else { (1u32 << bits) - 1 };
+
+                    let origin = [bx * block_size[0], by * block_size[1], bz * block_size[2]];
+                    for z in 0..block_size[2] {
+                        let oz = origin[2] + z;
+                        if oz >= size[2] {
+                            break;
+                        }
+                        for y in 0..block_size[1] {
+                            let oy = origin[1] + y;
+                            if oy >= size[1] {
+                                break;
+                            }
+                            for x in 0..block_size[0] {
+                                let ox = origin[0] + x;
+                                if ox >= size[0] {
+                                    break;
+                                }
+
+                                let index = if bits == 0 {
+                                    0
+                                } else {
+                                    let bit = (x + block_size[0] * (y + block_size[1] * z)) * bits;
+                                    (word(&words, values_offset + bit / 32)? >> (bit % 32)) & mask
+                                };
+                                let entry = table_offset + label_words * index as usize;
+                                let mut label = u64::from(word(&words, entry)?);
+                                if label_words == 2 {
+                                    label |= u64::from(word(&words, entry + 1)?) << 32;
+                                }
+
+                                out[ox + size[0] * (oy + size[1] * oz)] = label;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect()
+    }
+
+    /// Encode a single channel of Fortran-ordered `labels` with the smallest
+    /// bit width for each block, as precomputed writers do.
+    fn encode(labels: &[u64], size: [usize; 3], block_size: [usize; 3], label_words: usize) -> Vec<u8> {
+        let grid: Vec<usize> = (0..3).map(|d| (size[d] + block_size[d] - 1) / block_size[d]).collect();
+        let num_blocks = grid[0] * grid[1] * grid[2];
+        let block_len = block_size[0] * block_size[1] * block_size[2];
+        let mut channel = vec![0u32; 2 * num_blocks];
+
+        for block_index in 0..num_blocks {
+            let block = [block_index % grid[0], block_index / grid[0] % grid[1], block_index / (grid[0] * grid[1])];
+            // Voxels beyond the chunk have no label and are encoded as index 0.
+            let block_labels: Vec<Option<u64>> = (0..block_len)
+                .map(|i| {
+                    let within = [i % block_size[0], i / block_size[0] % block_size[1], i / (block_size[0] * block_size[1])];
+                    let voxel: Vec<usize> = (0..3).map(|d| block[d] * block_size[d] + within[d]).collect();
+                    if (0..3).all(|d| voxel[d] < size[d]) {
+                        Some(labels[voxel[0] + size[0] * (voxel[1] + size[1] * voxel[2])])
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let mut table: Vec<u64> = block_labels.iter().filter_map(|&label| label).collect();
+            table.sort();
+            table.dedup();
+            let bits = [0, 1, 2, 4, 8, 16, 32].iter().cloned()
+                .find(|&bits| (table.len() as u64) <= 1u64 << bits)
+                .unwrap();
+
+            let values_offset = channel.len();
+            let mut values = vec![0u32; (block_len * bits + 31) / 32];
+            for (i, label) in block_labels.iter().enumerate() {
+                if bits > 0 {
+                    let index = label.map_or(0, |label| table.binary_search(&label).unwrap()) as u32;
+                    values[i * bits / 32] |= index << (i * bits % 32);
+                }
+            }
+            channel.extend(values);
+            let table_offset = channel.len();
+            for &label in &table {
+                channel.push(label as u32);
+                if label_words == 2 {
+                    channel.push((label >> 32) as u32);
+                }
+            }
+            channel[2 * block_index] = table_offset as u32 | (bits as u32) << 24;
+            channel[2 * block_index + 1] = values_offset as u32;
+        }
+
+        // The data of the only channel starts after its offset.
+        let mut words = vec![1];
+        words.extend(channel);
+        to_bytes(&words)
+    }
+
+    #[test]
+    fn decodes_a_known_chunk() {
+        // Two blocks of [2, 1, 1]: one with the table [7, 9] and 1 bit per
+        // value, and one of only label 42 with 0 bits per value.
+        let words = [1, 5 | 1 << 24, 4, 7, 7, 0b10, 7, 9, 42];
+
+        assert_eq!(decode(&to_bytes(&words), &[4, 1, 1], &[2, 1, 1], 1, 1).unwrap(), vec![7, 9, 42, 42]);
+    }
+
+    #[test]
+    fn decodes_chunks_of_several_partial_blocks() {
+        let labels: Vec<u64> = (0..30).map(|i| 100 + (i * 7 % 5)).collect();
+        let bytes = encode(&labels, [5, 3, 2], [2, 2, 2], 1);
+
+        assert_eq!(decode(&bytes, &[5, 3, 2], &[2, 2, 2], 1, 1).unwrap(), labels);
+    }
+
+    #[test]
+    fn rejects_truncated_chunks() {
+        let bytes = encode(&[1, 2, 3, 4], [4, 1, 1], [2, 1, 1], 1);
+
+        assert!(decode(&bytes[..bytes.len() - 4], &[4, 1, 1], &[2, 1, 1], 1, 1).is_err());
+        assert!(decode(&bytes[..bytes.len() - 1], &[4, 1, 1], &[2, 1, 1], 1, 1).is_err());
+    }
+
+    #[test]
+    fn decodes_uint64_labels_beyond_32_bits() {
+        let labels: Vec<u64> = vec![1 << 32 | 5, std::u64::MAX, 3, 1 << 40, 1 << 32 | 5, 3, 0, 1 << 63];
+        let bytes = encode(&labels, [2, 2, 2], [2, 2, 1], 2);
+
+        assert_eq!(decode(&bytes, &[2, 2, 2], &[2, 2, 1], 1, 2).unwrap(), labels);
+        // Read with one word per entry, the same table gives other labels.
+        assert_ne!(decode(&bytes, &[2, 2, 2], &[2, 2, 1], 1, 1).ok(), Some(labels));
+    }
+}
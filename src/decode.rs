@@ -0,0 +1,378 @@
+//! Decoding of fetched chunk bytes into blocks according to the encoding of
+//! their scale.
+
+use super::*;
+
+
+/// Encodings decoded by `decode_block`, which must list every encoding it
+/// matches, with the same `cfg` as its decoder if that is feature-gated.
+pub(crate) const ENCODINGS: &[&str] = &["raw", "compressed_segmentation", "jpeg", "png"];
+
+pub(crate) fn decode_block<T>(
+    bytes: &[u8],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    grid_position: GridCoord,
+    byte_order: raw::ByteOrder,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    let encoding = scale_encoding(data_attrs, zoom_level)?;
+    if data_attrs.storage() == Storage::Float16 && encoding != "raw" {
+        return Err(errors::new(errors::ErrorKind::BadAttrs, format!(
+            "float16 data cannot be stored in {} chunks", encoding)));
+    }
+
+    match encoding.as_str() {
+        "compressed_segmentation" =>
+            decode_compressed_segmentation(bytes, data_attrs, zoom_level, grid_position),
+        "jpeg" => decode_jpeg(bytes, data_attrs, zoom_level, grid_position),
+        "png" => decode_png(bytes, data_attrs, zoom_level, grid_position),
+        "raw" => decode_raw(bytes, data_attrs, zoom_level, grid_position, byte_order),
+        // Leave other encodings to ngpre, which may support them.
+        _ => <ngpre::DefaultBlock as ngpre::DefaultBlockReader<T, &[u8]>>::read_block(
+                    bytes,
+                    data_attrs,
+                    grid_position)
+                .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string())),
+    }
+}
+
+/// Decode a `raw` chunk, whose elements are stored in Fortran order with the
+/// channels as the slowest varying axis. This does not go through ngpre,
+/// which decodes by the compression of the whole dataset rather than by the
+/// encoding of the scale.
+fn decode_raw<T>(
+    bytes: &[u8],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    grid_position: GridCoord,
+    byte_order: raw::ByteOrder,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let size = raw::block_extent(
+        &grid_position,
+        data_attrs.get_block_size(zoom_level),
+        data_attrs.get_dimensions(zoom_level));
+    let num_elements = size.iter().map(|&s| s as usize).product::<usize>()
+        * num_channels(data_attrs) as usize;
+
+    let data: Vec<T> = match data_attrs.storage() {
+        Storage::Native => raw::decode_ordered(bytes, byte_order)?,
+        // Widened data is little-endian.
+        Storage::Float16 => raw::decode_le(&raw::widen_f16(bytes, byte_order)?)?,
+        Storage::Bitpacked => raw::decode_le(&raw::unpack_bits(bytes, num_elements)?)?,
+    };
+    if data.len() != num_elements {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Raw chunk has {} elements, expected {} for block size {:?}", data.len(), num_elements, size)));
+    }
+
+    Ok(VecDataBlock::new(size.into(), grid_position, data))
+}
+
+fn decode_compressed_segmentation<T>(
+    bytes: &[u8],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    grid_position: GridCoord,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let label_words = match data_attrs.get_data_type() {
+        ngpre::DataType::UINT32 => 1,
+        ngpre::DataType::UINT64 => 2,
+        _ => return Err(errors::new(errors::ErrorKind::BadAttrs,
+            "compressed_segmentation requires uint32 or uint64 data")),
+    };
+    // Decoding into a narrower element type would truncate the labels.
+    if T::SIZE != 4 * label_words {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "compressed_segmentation labels of {} data cannot be decoded into {} byte elements",
+            data_attrs.get_data_type(), T::SIZE)));
+    }
+
+    let scale = scale_attributes(data_attrs, zoom_level)?;
+    let block_size: Vec<u32> = scale.get("compressed_segmentation_block_size")
+        .and_then(|block_size| serde_json::from_value(block_size.clone()).ok())
+        .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs,
+            "Scale has no valid compressed_segmentation_block_size"))?;
+
+    let size = raw::block_extent(
+        &grid_position,
+        data_attrs.get_block_size(zoom_level),
+        data_attrs.get_dimensions(zoom_level));
+    let labels = compressed_segmentation::decode(
+        bytes, &size, &block_size, num_channels(data_attrs) as usize, label_words)?;
+
+    Ok(VecDataBlock::new(
+        size.into(),
+        grid_position,
+        labels.into_iter().map(T::from_u64).collect()))
+}
+
+/// Image encodings store a chunk of size `[x, y, z]` as an image of width `x`
+/// and height `y * z` with interleaved channels. Rearrange such pixels into
+/// Fortran order with channels last.
+fn deinterleave_image<P: Copy>(pixels: &[P], num_channels: usize) -> Vec<P> {
+    let num_pixels = pixels.len() / num_channels;
+    let mut data = Vec::with_capacity(pixels.len());
+    for channel in 0..num_channels {
+        data.extend((0..num_pixels).map(|i| pixels[i * num_channels + channel]));
+    }
+
+    data
+}
+
+/// Check a decoded image's shape against the chunk it should contain.
+fn check_image_shape(
+    width: usize,
+    height: usize,
+    channels: usize,
+    size: &[u32],
+    num_channels: usize,
+) -> Result<(), Error> {
+    let expected_height = size[1..].iter().map(|&s| s as usize).product::<usize>();
+    if width != size[0] as usize || height != expected_height || channels != num_channels {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Image of {}x{} with {} channels does not match chunk size {:?} with {} channels",
+            width, height, channels, size, num_channels)));
+    }
+
+    Ok(())
+}
+
+fn decode_jpeg<T>(
+    bytes: &[u8],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    grid_position: GridCoord,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    match data_attrs.get_data_type() {
+        ngpre::DataType::UINT8 => (),
+        _ => return Err(errors::new(errors::ErrorKind::BadAttrs, "jpeg requires uint8 data")),
+    };
+
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    let pixels = decoder.decode()
+        .map_err(|e| errors::new(errors::ErrorKind::Decode, format!("Invalid JPEG: {}", e)))?;
+    let info = decoder.info()
+        .ok_or_else(|| errors::new(errors::ErrorKind::Decode, "Invalid JPEG: missing header"))?;
+    let channels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => 1,
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        jpeg_decoder::PixelFormat::CMYK32 => 4,
+    };
+
+    let size = raw::block_extent(
+        &grid_position,
+        data_attrs.get_block_size(zoom_level),
+        data_attrs.get_dimensions(zoom_level));
+    let num_channels = num_channels(data_attrs) as usize;
+    check_image_shape(info.width as usize, info.height as usize, channels, &size, num_channels)?;
+
+    let data = deinterleave_image(&pixels, num_channels).into_iter()
+        .map(|v| T::from_u64(u64::from(v)))
+        .collect();
+
+    Ok(VecDataBlock::new(size.into(), grid_position, data))
+}
+
+fn decode_png<T>(
+    bytes: &[u8],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    grid_position: GridCoord,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let bytes_per_sample = match data_attrs.get_data_type() {
+        ngpre::DataType::UINT8 => 1,
+        ngpre::DataType::UINT16 => 2,
+        _ => return Err(errors::new(errors::ErrorKind::BadAttrs, "png requires uint8 or uint16 data")),
+    };
+    let invalid = |e: png::DecodingError| errors::new(errors::ErrorKind::Decode, format!("Invalid PNG: {}", e));
+
+    let mut decoder = png::Decoder::new(bytes);
+    // Expand palettes and bit depths below 8 to plain 8 bit samples.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info().map_err(invalid)?;
+    let mut pixels = vec![0u8; info.buffer_size()];
+    reader.next_frame(&mut pixels).map_err(invalid)?;
+
+    let (color_type, bit_depth) = reader.output_color_type();
+    let channels = match color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => return Err(errors::new(errors::ErrorKind::Decode,
+            "Invalid PNG: palette was not expanded")),
+    };
+    let sample_bytes = match bit_depth {
+        png::BitDepth::Sixteen => 2,
+        _ => 1,
+    };
+    if sample_bytes != bytes_per_sample {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "PNG with {} bit samples does not match the data type {}",
+            8 * sample_bytes, data_attrs.get_data_type())));
+    }
+
+    let size = raw::block_extent(
+        &grid_position,
+        data_attrs.get_block_size(zoom_level),
+        data_attrs.get_dimensions(zoom_level));
+    let num_channels = num_channels(data_attrs) as usize;
+    check_image_shape(info.width as usize, info.height as usize, channels, &size, num_channels)?;
+
+    // 16 bit PNG samples are big-endian.
+    let samples: Vec<u64> = pixels.chunks_exact(sample_bytes)
+        .map(|sample| sample.iter().fold(0u64, |value, &byte| value << 8 | u64::from(byte)))
+        .collect();
+    let data = deinterleave_image(&samples, num_channels).into_iter()
+        .map(T::from_u64)
+        .collect();
+
+    Ok(VecDataBlock::new(size.into(), grid_position, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(data_type: &str, encoding: &str) -> DatasetAttributes {
+        parse_dataset_attributes(serde_json::json!({
+            "type": "image",
+            "data_type": data_type,
+            "num_channels": 1,
+            "scales": [{
+                "key": "1_1_1",
+                "size": [4, 1, 1],
+                "chunk_sizes": [[4, 1, 1]],
+                "resolution": [1, 1, 1],
+                "voxel_offset": [0, 0, 0],
+                "encoding": encoding,
+            }],
+        })).unwrap()
+    }
+
+    #[test]
+    fn decodes_raw_float16_as_float32() {
+        let data_attrs = attributes("float16", "raw");
+        assert!(match data_attrs.get_data_type() {
+            ngpre::DataType::FLOAT32 => true,
+            _ => false,
+        });
+
+        // 1.0, -2.0, 2^-24 (the smallest subnormal) and infinity.
+        let halves: [u16; 4] = [0x3c00, 0xc000, 0x0001, 0x7c00];
+        let expected = vec![1.0, -2.0, 2f32.powi(-24), std::f32::INFINITY];
+
+        let little: Vec<u8> = halves.iter().flat_map(|h| h.to_le_bytes().to_vec()).collect();
+        let block = decode_block::<f32>(&little, &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Little)
+            .unwrap();
+        assert_eq!(block.get_size(), &[4, 1, 1][..]);
+        assert_eq!(block.get_data(), &expected[..]);
+
+        let big: Vec<u8> = halves.iter().flat_map(|h| h.to_be_bytes().to_vec()).collect();
+        let block = decode_block::<f32>(&big, &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Big)
+            .unwrap();
+        assert_eq!(block.get_data(), &expected[..]);
+    }
+
+    #[test]
+    fn decodes_bitpacked_masks_as_uint8() {
+        let data_attrs = attributes("bool", "raw");
+        assert!(match data_attrs.get_data_type() {
+            ngpre::DataType::UINT8 => true,
+            _ => false,
+        });
+
+        let block = decode_block::<u8>(&[0b0000_1001], &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Big)
+            .unwrap();
+        assert_eq!(block.get_data(), &[1, 0, 0, 1][..]);
+        assert!(decode_block::<u8>(&[1, 0], &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Little)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_raw_float16_of_wrong_length() {
+        let data_attrs = attributes("float16", "raw");
+
+        assert!(decode_block::<f32>(&[0; 6], &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Little)
+            .is_err());
+    }
+
+    #[test]
+    fn decodes_each_scale_by_its_own_encoding() {
+        let data_attrs = parse_dataset_attributes(serde_json::json!({
+            "type": "segmentation",
+            "data_type": "uint32",
+            "num_channels": 1,
+            "scales": [{
+                "key": "1_1_1",
+                "size": [4, 1, 1],
+                "chunk_sizes": [[4, 1, 1]],
+                "resolution": [1, 1, 1],
+                "voxel_offset": [0, 0, 0],
+                "encoding": "raw",
+            }, {
+                "key": "2_2_2",
+                "size": [4, 1, 1],
+                "chunk_sizes": [[4, 1, 1]],
+                "resolution": [2, 2, 2],
+                "voxel_offset": [0, 0, 0],
+                "encoding": "compressed_segmentation",
+                "compressed_segmentation_block_size": [2, 1, 1],
+            }],
+        })).unwrap();
+        assert_eq!(scale_encoding(&data_attrs, 0).unwrap(), "raw");
+        assert_eq!(scale_encoding(&data_attrs, 1).unwrap(), "compressed_segmentation");
+
+        let raw_bytes = raw::encode_le(&[1u32, 2, 3, 4]);
+        let block = decode_block::<u32>(&raw_bytes, &data_attrs, 0, vec![0, 0, 0].into(), raw::ByteOrder::Little)
+            .unwrap();
+        assert_eq!(block.get_data(), &[1, 2, 3, 4][..]);
+
+        // Two compressed_segmentation blocks, of labels [7, 9] and [42, 42].
+        let words = [1u32, 5 | 1 << 24, 4, 7, 7, 0b10, 7, 9, 42];
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        let block = decode_block::<u32>(&bytes, &data_attrs, 1, vec![0, 0, 0].into(), raw::ByteOrder::Little)
+            .unwrap();
+        assert_eq!(block.get_data(), &[7, 9, 42, 42][..]);
+    }
+
+    #[test]
+    fn decodes_blocks_of_4d_scales() {
+        let data_attrs = parse_dataset_attributes(serde_json::json!({
+            "type": "image",
+            "data_type": "uint8",
+            "num_channels": 1,
+            "scales": [{
+                "key": "1_1_1_1",
+                "size": [4, 2, 2, 3],
+                "chunk_sizes": [[2, 2, 2, 2]],
+                "resolution": [1, 1, 1, 1],
+                "voxel_offset": [0, 0, 0, 0],
+                "encoding": "raw",
+            }],
+        })).unwrap();
+
+        // The last block along the fourth axis is clipped to one slice.
+        let data: Vec<u8> = (0..8).collect();
+        let block = decode_block::<u8>(&data, &data_attrs, 0, vec![1, 0, 0, 1].into(), raw::ByteOrder::Little)
+            .unwrap();
+        assert_eq!(block.get_size(), &[2, 2, 2, 1][..]);
+        assert_eq!(block.get_grid_position(), &[1, 0, 0, 1][..]);
+        assert_eq!(block.get_data(), &data[..]);
+    }
+}
@@ -0,0 +1,270 @@
+//! The `#[wasm_bindgen]` methods of readers, which cannot export the
+//! methods of the `NgPrePromiseReader` and `NgPrePromiseEtagReader` traits
+//! themselves.
+
+/// Delegate the methods of `NgPrePromiseReader` and `NgPrePromiseEtagReader`
+/// to JS for a reader type. Readers that read blocks differently define
+/// their own `read_block` after `reader_delegations!(Reader without
+/// read_block)`.
+macro_rules! reader_delegations {
+    ($reader:ident) => {
+        #[wasm_bindgen]
+        impl $reader {
+            pub fn read_block(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                signal: Option<AbortSignal>,
+                order: Option<String>,
+                chunk_size_index: Option<usize>,
+            ) -> Promise {
+                read_block_in_order(
+                    &self.with_signal(signal), path_name, data_attrs, grid_position, order, chunk_size_index)
+            }
+        }
+
+        reader_delegations!($reader without read_block);
+    };
+    ($reader:ident without read_block) => {
+        /// Delegations to expose NgPrePromiseReader trait to WASM.
+        #[wasm_bindgen]
+        impl $reader {
+            pub fn get_version(&self) -> Promise {
+                NgPrePromiseReader::get_version(self)
+            }
+
+            pub fn get_dataset_attributes(&self, path_name: &str) -> Promise {
+                NgPrePromiseReader::get_dataset_attributes(self, path_name)
+            }
+
+            pub fn exists(&self, path_name: &str) -> Promise {
+                NgPrePromiseReader::exists(self, path_name)
+            }
+
+            pub fn dataset_exists(&self, path_name: &str) -> Promise {
+                NgPrePromiseReader::dataset_exists(self, path_name)
+            }
+
+            pub fn read_blocks(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_positions: JsValue,
+                signals: JsValue,
+                on_progress: Option<js_sys::Function>,
+                transferable: Option<bool>,
+                tolerant: Option<bool>,
+            ) -> Promise {
+                read_blocks_abortable(self, path_name, data_attrs, &grid_positions, signals, on_progress,
+                    transferable.unwrap_or(false), tolerant.unwrap_or(false))
+            }
+
+            pub fn read_blocks_streaming(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_positions: JsValue,
+                on_block: js_sys::Function,
+                max_concurrent: Option<u32>,
+            ) -> Promise {
+                read_blocks_streaming(self, path_name, data_attrs, &grid_positions, on_block, max_concurrent)
+            }
+
+            pub fn iter_blocks(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                zoom_level: usize,
+                min: Vec<i64>,
+                max: Vec<i64>,
+                max_concurrent: Option<u32>,
+            ) -> Result<JsValue, JsValue> {
+                iter_blocks(self, path_name, data_attrs, zoom_level, min, max, max_concurrent)
+            }
+
+            pub fn read_block_into(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                dst: js_sys::Uint8Array,
+                offset: u32,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_into(
+                    self, path_name, data_attrs, grid_position, dst, offset)
+            }
+
+            pub fn read_block_subregion(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                min: Vec<u32>,
+                max: Vec<u32>,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_subregion(
+                    self, path_name, data_attrs, grid_position, min, max)
+            }
+
+            pub fn read_block_at_resolution(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                voxel_resolution: Vec<f64>,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_at_resolution(
+                    self, path_name, data_attrs, grid_position, voxel_resolution)
+            }
+
+            pub fn read_block_channels(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_channels(self, path_name, data_attrs, grid_position)
+            }
+
+            pub fn read_block_normalized(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_normalized(self, path_name, data_attrs, grid_position)
+            }
+
+            pub fn read_block_packed(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                layout: &str,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_packed(self, path_name, data_attrs, grid_position, layout)
+            }
+
+            pub fn read_block_or_fill(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                fill_value: f64,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_or_fill(
+                    self, path_name, data_attrs, grid_position, fill_value)
+            }
+
+            pub fn block_exists(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseReader::block_exists(self, path_name, data_attrs, grid_position)
+            }
+
+            pub fn read_tile(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                zoom_level: usize,
+                origin: Vec<i64>,
+                size: Vec<u32>,
+                pad_to_pot: Option<bool>,
+            ) -> Promise {
+                NgPrePromiseReader::read_tile(self, path_name, data_attrs, zoom_level, origin, size, pad_to_pot)
+            }
+
+            pub fn read_slice(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                zoom_level: usize,
+                axis: u32,
+                index: i64,
+            ) -> Promise {
+                NgPrePromiseReader::read_slice(self, path_name, data_attrs, zoom_level, axis, index)
+            }
+
+            pub fn read_thumbnail(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                max_dim: u32,
+            ) -> Promise {
+                NgPrePromiseReader::read_thumbnail(self, path_name, data_attrs, max_dim)
+            }
+
+            pub fn read_voxel(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                zoom_level: usize,
+                voxel: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseReader::read_voxel(self, path_name, data_attrs, zoom_level, voxel)
+            }
+
+            pub fn read_block_best_effort(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                preferred_zoom: usize,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseReader::read_block_best_effort(
+                    self, path_name, data_attrs, preferred_zoom, grid_position)
+            }
+
+            pub fn list_attributes(&self, path_name: &str) -> Promise {
+                NgPrePromiseReader::list_attributes(self, path_name)
+            }
+
+            pub fn get_info(&self, path_name: &str) -> Promise {
+                NgPrePromiseReader::get_info(self, path_name)
+            }
+
+            pub fn list_datasets(&self, prefix: &str) -> Promise {
+                list_datasets(self, prefix)
+            }
+
+            pub fn attributes_etag(&self, path_name: &str) -> Promise {
+                NgPrePromiseEtagReader::attributes_etag(self, path_name)
+            }
+
+            pub fn block_etag(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseEtagReader::block_etag(
+                    self, path_name, data_attrs, grid_position)
+            }
+
+            pub fn read_block_with_etag(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+            ) -> Promise {
+                NgPrePromiseEtagReader::read_block_with_etag(
+                    self, path_name, data_attrs, grid_position)
+            }
+
+            pub fn read_block_if_changed(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                grid_position: Vec<i64>,
+                known_etag: &str,
+            ) -> Promise {
+                NgPrePromiseEtagReader::read_block_if_changed(
+                    self, path_name, data_attrs, grid_position, known_etag)
+            }
+        }
+    };
+}
@@ -0,0 +1,184 @@
+//! Downsampling of blocks by integer factors per axis.
+
+use std::collections::HashMap;
+
+use super::*;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DownsampleMethod {
+    /// Average of the values, for image data.
+    Mean,
+    /// Most frequent value, for segmentation data.
+    Mode,
+}
+
+impl std::str::FromStr for DownsampleMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "mean" => Ok(DownsampleMethod::Mean),
+            "mode" => Ok(DownsampleMethod::Mode),
+            _ => Err(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Unknown downsampling method: {}", s))),
+        }
+    }
+}
+
+/// Downsample a block by `factors` per axis. Axes whose size is not a
+/// multiple of their factor are truncated. The grid position of the block is
+/// kept.
+pub(crate) fn downsample_block<T>(
+    block: &VecDataBlock<T>,
+    factors: &[u32],
+    method: DownsampleMethod,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let size = block.get_size();
+    if factors.len() != size.len() || factors.contains(&0) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Invalid downsampling factors {:?} for block size {:?}", factors, size)));
+    }
+    let out_size: Vec<u32> = size.iter().zip(factors).map(|(&s, &f)| s / f).collect();
+    if out_size.contains(&0) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Block size {:?} is smaller than the downsampling factors {:?}", size, factors)));
+    }
+
+    let data = block.get_data();
+    let plane_len: usize = size.iter().map(|&s| s as usize).product();
+    if data.len() % plane_len != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} does not match block size {:?}", data.len(), size)));
+    }
+    let out_plane_len: usize = out_size.iter().map(|&s| s as usize).product();
+    let window_len: usize = factors.iter().map(|&f| f as usize).product();
+    let strides = raw::fortran_strides(size);
+
+    let mut downsampled = Vec::with_capacity(out_plane_len * (data.len() / plane_len));
+    let mut window = Vec::with_capacity(window_len);
+    for plane in data.chunks_exact(plane_len) {
+        let mut out_coord = vec![0usize; out_size.len()];
+        for _ in 0..out_plane_len {
+            window.clear();
+            let mut offset = vec![0usize; factors.len()];
+            for _ in 0..window_len {
+                let index: usize = out_coord.iter().zip(&offset).zip(factors.iter().zip(&strides))
+                    .map(|((&c, &o), (&f, &stride))| (c * f as usize + o) * stride)
+                    .sum();
+                window.push(plane[index]);
+                increment(&mut offset, factors);
+            }
+
+            downsampled.push(match method {
+                DownsampleMethod::Mean => mean(&window),
+                DownsampleMethod::Mode => mode(&window),
+            });
+            increment(&mut out_coord, &out_size);
+        }
+    }
+
+    Ok(VecDataBlock::new(out_size.into(), block.get_grid_position().to_vec().into(), downsampled))
+}
+
+/// Advance a Fortran-order odometer over a box of the given size.
+fn increment(coord: &mut [usize], size: &[u32]) {
+    for (c, &s) in coord.iter_mut().zip(size) {
+        *c += 1;
+        if *c < s as usize {
+            return;
+        }
+        *c = 0;
+    }
+}
+
+/// Mean of the values, accumulated in `f64` so that small integer types do
+/// not overflow and rounded for integer types.
+fn mean<T: RawElement>(values: &[T]) -> T {
+    let mean = values.iter().map(|&v| v.to_f64()).sum::<f64>() / values.len() as f64;
+
+    T::from_f64(if T::INTEGER { mean.round() } else { mean })
+}
+
+/// Most frequent of the values, preferring the first one in case of ties.
+/// Values are counted by their bits, so that labels of 64 bit types are not
+/// rounded.
+fn mode<T: RawElement>(values: &[T]) -> T {
+    let mut counts: HashMap<u64, (usize, usize)> = HashMap::with_capacity(values.len());
+    for (i, &value) in values.iter().enumerate() {
+        let mut bytes = [0u8; 8];
+        value.write_le(&mut bytes[..T::SIZE]);
+        counts.entry(u64::from_le_bytes(bytes)).or_insert((0, i)).0 += 1;
+    }
+    let first = counts.values()
+        .max_by_key(|&&(count, first)| (count, std::cmp::Reverse(first)))
+        .map_or(0, |&(_, first)| first);
+
+    values[first]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block<T>(size: Vec<u32>, data: Vec<T>) -> VecDataBlock<T>
+            where VecDataBlock<T>: DataBlock<T> {
+        VecDataBlock::new(size.into(), vec![1, 2, 3].into(), data)
+    }
+
+    #[test]
+    fn truncates_odd_extents_at_the_edge() {
+        // The value of voxel (x, y) is x + 5 y.
+        let data: Vec<u8> = (0..15).collect();
+        let downsampled = downsample_block(&block(vec![5, 3, 1], data), &[2, 2, 1], DownsampleMethod::Mean)
+            .unwrap();
+
+        assert_eq!(downsampled.get_size(), &[2, 1, 1][..]);
+        assert_eq!(downsampled.get_grid_position(), &[1, 2, 3][..]);
+        // Means of [0, 1, 5, 6] and [2, 3, 7, 8].
+        assert_eq!(downsampled.get_data(), &[3, 5][..]);
+    }
+
+    #[test]
+    fn averages_windows_that_are_not_cubes() {
+        // The value of voxel (x, y, z) is x + 4 y + 8 z.
+        let size = vec![4, 2, 2];
+        let factors = [4, 1, 2];
+
+        let data: Vec<f32> = (0..16).map(|v| v as f32).collect();
+        let downsampled = downsample_block(&block(size.clone(), data), &factors, DownsampleMethod::Mean)
+            .unwrap();
+        assert_eq!(downsampled.get_size(), &[1, 2, 1][..]);
+        assert_eq!(downsampled.get_data(), &[5.5, 9.5][..]);
+
+        // Integer means are rounded.
+        let data: Vec<u16> = (0..16).collect();
+        let downsampled = downsample_block(&block(size, data), &factors, DownsampleMethod::Mean)
+            .unwrap();
+        assert_eq!(downsampled.get_data(), &[6, 10][..]);
+    }
+
+    #[test]
+    fn takes_the_most_frequent_label() {
+        let data: Vec<u64> = vec![7, 3, 3, 7, 2, 1 << 60 | 1, 1 << 60 | 1, 1 << 60];
+        let downsampled = downsample_block(&block(vec![8, 1, 1], data), &[4, 1, 1], DownsampleMethod::Mode)
+            .unwrap();
+
+        // Ties go to the label seen first, and labels beyond 2^53 are not
+        // mixed up.
+        assert_eq!(downsampled.get_data(), &[7, 1 << 60 | 1][..]);
+    }
+
+    #[test]
+    fn rejects_factors_not_matching_the_block() {
+        let data: Vec<u8> = vec![0; 8];
+
+        assert!(downsample_block(&block(vec![2, 2, 2], data.clone()), &[2, 2], DownsampleMethod::Mean).is_err());
+        assert!(downsample_block(&block(vec![2, 2, 2], data.clone()), &[2, 0, 2], DownsampleMethod::Mean).is_err());
+        assert!(downsample_block(&block(vec![2, 2, 2], data), &[4, 1, 1], DownsampleMethod::Mean).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Encoding of blocks into chunk bytes according to the encoding of their
+//! scale, for writing.
+
+use super::*;
+
+
+pub(crate) fn encode_block<T>(
+    block: &VecDataBlock<T>,
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+) -> Result<Vec<u8>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let same_type = data_type_match! {
+        data_attrs.get_data_type(),
+        std::any::type_name::<RsType>() == std::any::type_name::<T>()
+    };
+    if !same_type {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Block of {} elements does not match the data type {}",
+            std::any::type_name::<T>(), data_attrs.get_data_type())));
+    }
+
+    let extent = raw::block_extent(
+        block.get_grid_position(),
+        data_attrs.get_block_size(zoom_level),
+        data_attrs.get_dimensions(zoom_level));
+    if block.get_size() != &extent[..] {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Block size {:?} does not match the chunk extent {:?}", block.get_size(), extent)));
+    }
+    let num_elements = extent.iter().map(|&s| s as usize).product::<usize>()
+        * num_channels(data_attrs) as usize;
+    if block.get_data().len() != num_elements {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Block has {} elements, expected {}", block.get_data().len(), num_elements)));
+    }
+
+    if data_attrs.storage() == Storage::Float16 {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument,
+            "Writing float16 chunks is not supported"));
+    }
+
+    match scale_encoding(data_attrs, zoom_level)?.as_str() {
+        "raw" if data_attrs.storage() == Storage::Bitpacked => Ok(raw::pack_bits(block.get_data())),
+        "raw" => Ok(raw::encode_le(block.get_data())),
+        encoding => Err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Writing {} chunks is not supported", encoding))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(data_type: &str) -> DatasetAttributes {
+        parse_dataset_attributes(serde_json::json!({
+            "type": "image",
+            "data_type": data_type,
+            "num_channels": 1,
+            "scales": [{
+                "key": "1_1_1",
+                "size": [2, 2, 1],
+                "chunk_sizes": [[2, 2, 1]],
+                "resolution": [1, 1, 1],
+                "voxel_offset": [0, 0, 0],
+                "encoding": "raw",
+            }],
+        })).unwrap()
+    }
+
+    #[test]
+    fn encodes_raw_blocks_of_the_data_type() {
+        let block = VecDataBlock::new(vec![2, 2, 1].into(), vec![0, 0, 0].into(), vec![1u16, 2, 3, 0x0405]);
+
+        assert_eq!(encode_block(&block, &attributes("uint16"), 0).unwrap(), vec![1, 0, 2, 0, 3, 0, 5, 4]);
+    }
+
+    #[test]
+    fn rejects_blocks_of_another_data_type() {
+        let block = VecDataBlock::new(vec![2, 2, 1].into(), vec![0, 0, 0].into(), vec![1u16, 2, 3, 4]);
+
+        let error = encode_block(&block, &attributes("uint8"), 0).unwrap_err();
+        assert_eq!(errors::kind_of(&error), errors::ErrorKind::InvalidArgument);
+        assert!(encode_block(&block, &attributes("int16"), 0).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Structured errors surfaced to JavaScript.
+//!
+//! Internally all readers use `std::io::Error`. Errors created here carry a
+//! `ReaderError` payload with an `ErrorKind`, which is preserved through the
+//! futures chain and turned into a JS `Error` with an extra `kind` property
+//! when a promise is rejected.
+
+use std::fmt;
+use std::io;
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Network,
+    Cors,
+    Decode,
+    NotFound,
+    BadAttrs,
+    InvalidArgument,
+    Aborted,
+    Timeout,
+    NoScale,
+    EncodingMismatch,
+    ChecksumMismatch,
+    Disposed,
+    Other,
+}
+
+const ALL_KINDS: [ErrorKind; 13] = [
+    ErrorKind::Network,
+    ErrorKind::Cors,
+    ErrorKind::Decode,
+    ErrorKind::NotFound,
+    ErrorKind::BadAttrs,
+    ErrorKind::InvalidArgument,
+    ErrorKind::Aborted,
+    ErrorKind::Timeout,
+    ErrorKind::NoScale,
+    ErrorKind::EncodingMismatch,
+    ErrorKind::ChecksumMismatch,
+    ErrorKind::Disposed,
+    ErrorKind::Other,
+];
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Network => "network",
+            ErrorKind::Cors => "cors",
+            ErrorKind::Decode => "decode",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::BadAttrs => "bad_attrs",
+            ErrorKind::InvalidArgument => "invalid_argument",
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::NoScale => "no_scale",
+            ErrorKind::EncodingMismatch => "encoding_mismatch",
+            ErrorKind::ChecksumMismatch => "checksum_mismatch",
+            ErrorKind::Disposed => "disposed",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// The kind named `name` by `as_str`, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        ALL_KINDS.iter().cloned().find(|kind| kind.as_str() == name)
+    }
+
+    fn io_kind(self) -> io::ErrorKind {
+        match self {
+            ErrorKind::NotFound => io::ErrorKind::NotFound,
+            ErrorKind::Timeout => io::ErrorKind::TimedOut,
+            ErrorKind::Decode |
+            ErrorKind::EncodingMismatch |
+            ErrorKind::ChecksumMismatch => io::ErrorKind::InvalidData,
+            ErrorKind::BadAttrs |
+            ErrorKind::InvalidArgument => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ReaderError {
+    kind: ErrorKind,
+    message: String,
+    /// Additional properties set on the JS error object.
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ReaderError {
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn details(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.details
+    }
+}
+
+impl fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ReaderError {}
+
+
+/// Create an `io::Error` carrying a structured error kind.
+pub fn new(kind: ErrorKind, message: impl Into<String>) -> io::Error {
+    with_details(kind, message, serde_json::Map::new())
+}
+
+/// Create an `io::Error` carrying a structured error kind and additional
+/// properties for the JS error object.
+pub fn with_details(
+    kind: ErrorKind,
+    message: impl Into<String>,
+    details: serde_json::Map<String, serde_json::Value>,
+) -> io::Error {
+    io::Error::new(kind.io_kind(), ReaderError {
+        kind,
+        message: message.into(),
+        details,
+    })
+}
+
+fn reader_error(error: &io::Error) -> Option<&ReaderError> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<ReaderError>())
+}
+
+/// Determine the structured kind of an error, falling back to a mapping of
+/// the `io::ErrorKind` for errors not created by this module (e.g. those
+/// returned by `ngpre`).
+pub fn kind_of(error: &io::Error) -> ErrorKind {
+    match reader_error(error) {
+        Some(reader_error) => reader_error.kind,
+        None => match error.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::InvalidData |
+            io::ErrorKind::UnexpectedEof => ErrorKind::Decode,
+            io::ErrorKind::InvalidInput => ErrorKind::BadAttrs,
+            _ => ErrorKind::Other,
+        },
+    }
+}
+
+/// The message of an error, without its kind.
+pub fn message_of(error: &io::Error) -> String {
+    match reader_error(error) {
+        Some(reader_error) => reader_error.message.clone(),
+        None => error.to_string(),
+    }
+}
+
+/// A copy of an error with the same kind, message and details, for errors
+/// shared by several futures.
+pub fn copy_of(error: &io::Error) -> io::Error {
+    match reader_error(error) {
+        Some(reader_error) => with_details(reader_error.kind, reader_error.message.clone(),
+            reader_error.details.clone()),
+        None => new(kind_of(error), error.to_string()),
+    }
+}
+
+/// Convert an error into a JS `Error` object with `kind` and `message`
+/// properties, plus any details of the error.
+pub fn to_js_error(error: &io::Error) -> JsValue {
+    let js_error = js_sys::Error::new(&message_of(error));
+    // Setting a property on a fresh, extensible object cannot fail.
+    let _ = js_sys::Reflect::set(
+        &js_error,
+        &JsValue::from_str("kind"),
+        &JsValue::from_str(kind_of(error).as_str()));
+
+    if let Some(reader_error) = reader_error(error) {
+        for (key, value) in &reader_error.details {
+            let _ = js_sys::Reflect::set(
+                &js_error,
+                &JsValue::from_str(key),
+                &JsValue::from_serde(value).unwrap_or(JsValue::NULL));
+        }
+    }
+
+    JsValue::from(js_error)
+}
+
+/// A promise that is already rejected with the given error.
+pub fn reject(error: io::Error) -> js_sys::Promise {
+    js_sys::Promise::reject(&to_js_error(&error))
+}
+
+/// Convert a rejection value from a JS API (e.g. `fetch`) into an error.
+pub fn from_js_error(error: JsValue) -> io::Error {
+    match error.dyn_ref::<js_sys::Error>() {
+        // `fetch` rejects with a `TypeError` when the request could not be
+        // made at all.
+        Some(js_error) if js_error.name() == "TypeError" =>
+            new(ErrorKind::Network, String::from(js_error.message())),
+        // Requests and body reads cancelled through an `AbortSignal` reject
+        // with an `AbortError` `DOMException`.
+        Some(js_error) if js_error.name() == "AbortError" =>
+            new(ErrorKind::Aborted, String::from(js_error.message())),
+        Some(js_error) =>
+            new(ErrorKind::Other, String::from(js_error.message())),
+        None => new(ErrorKind::Other, format!("{:?}", error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_names_of_all_kinds() {
+        for &kind in &ALL_KINDS {
+            assert_eq!(ErrorKind::from_name(kind.as_str()), Some(kind));
+        }
+        assert_eq!(ErrorKind::from_name("unknown"), None);
+    }
+}
@@ -1,22 +1,50 @@
-use std::fmt::Write;
+use std::any::Any;
+use std::io::Read;
 use std::str::FromStr;
 use std::cmp;
+use std::cell::{
+    Cell,
+    RefCell,
+};
+use std::collections::HashMap;
+use std::rc::Rc;
 
-use js_sys::ArrayBuffer;
+use futures::future::Shared;
+use futures::sync::oneshot;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
+    AbortController,
+    Headers,
     Request,
+    RequestCache,
+    RequestCredentials,
     RequestInit,
     RequestMode,
     Response,
 };
 
 use super::*;
+use crate::recording::Transport;
+use crate::sharded::{
+    self,
+    CachedIndex,
+    IndexCache,
+    ShardingSpec,
+};
 
 
 const ATTRIBUTES_FILE: &str = "info";
 
+/// Largest span of a shard fetched with one request by coalescing chunk
+/// ranges, so that a few distant chunks do not download the shard between
+/// them.
+pub const MAX_COALESCED_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Future for the body (and etag) of a file or byte range, or `None` if the
+/// file does not exist.
+type BytesFuture = Box<dyn Future<Item = Option<(Vec<u8>, Option<String>)>, Error = Error>>;
+
 enum GlobalProxy {
     Window(web_sys::Window),
     WorkerGlobalScope(web_sys::WorkerGlobalScope),
@@ -29,11 +57,27 @@ impl GlobalProxy {
             GlobalProxy::WorkerGlobalScope(scope) => scope.fetch_with_request(request),
         }
     }
+
+    fn set_timeout(&self, handler: &js_sys::Function, timeout_ms: i32) -> Result<i32, JsValue> {
+        match self {
+            GlobalProxy::Window(window) =>
+                window.set_timeout_with_callback_and_timeout_and_arguments_0(handler, timeout_ms),
+            GlobalProxy::WorkerGlobalScope(scope) =>
+                scope.set_timeout_with_callback_and_timeout_and_arguments_0(handler, timeout_ms),
+        }
+    }
+
+    fn clear_timeout(&self, handle: i32) {
+        match self {
+            GlobalProxy::Window(window) => window.clear_timeout_with_handle(handle),
+            GlobalProxy::WorkerGlobalScope(scope) => scope.clear_timeout_with_handle(handle),
+        }
+    }
 }
 
 fn self_() -> Result<GlobalProxy, JsValue> {
     let global = js_sys::global();
-    if js_sys::eval("typeof WorkerGlobalScope !== 'undefined'")?.as_bool().unwrap() {
+    if js_sys::eval("typeof WorkerGlobalScope !== 'undefined'")?.as_bool().unwrap_or(false) {
         Ok(global.dyn_into::<web_sys::WorkerGlobalScope>().map(GlobalProxy::WorkerGlobalScope)?)
     }
     else {
@@ -41,315 +85,2794 @@ fn self_() -> Result<GlobalProxy, JsValue> {
     }
 }
 
+/// The origin (`scheme://host:port`) of an absolute HTTP(S) or object URL,
+/// or `None` for other URLs, such as relative or `file:` URLs.
+fn url_origin(url: &str) -> Option<&str> {
+    // Object URLs belong to the origin of the page that created them, which
+    // follows the `blob:` scheme.
+    let url = url.trim_start_matches("blob:");
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return None;
+    }
+    let host_start = url.find("://")? + 3;
+    let host_end = url[host_start..].find(|c| c == '/' || c == '?' || c == '#')
+        .map_or(url.len(), |end| host_start + end);
 
-#[wasm_bindgen]
-#[derive(Clone)]
-pub struct NgPreHTTPFetch {
-    base_path: String,
+    Some(&url[..host_end])
 }
 
-impl NgPreHTTPFetch {
-    fn fetch(&self, path_name: &str) -> JsFuture {
-        let mut request_options = RequestInit::new();
-        request_options.method("GET");
-        request_options.mode(RequestMode::Cors);
+/// `<object>.<property>` of the global object, such as `navigator.onLine`,
+/// which exists in both windows and workers.
+fn global_property(object: &str, property: &str) -> Option<JsValue> {
+    let object = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str(object)).ok()?;
+    js_sys::Reflect::get(&object, &JsValue::from_str(property)).ok()
+}
+
+/// `fetch` rejects with the same `TypeError` whether the server could not be
+/// reached or the response was blocked by CORS. A failed request to another
+/// origin while the browser considers itself online is probed with a
+/// `no-cors` request, which succeeds with an opaque response if the server
+/// is reachable, in which case the failure is reported as a CORS error.
+/// Otherwise the error is kept. Always fails.
+fn cors_or_network(error: Error, url: &str) -> Box<dyn Future<Item = Response, Error = Error>> {
+    if errors::kind_of(&error) != errors::ErrorKind::Network {
+        return Box::new(future::err(error));
+    }
+    let online = global_property("navigator", "onLine")
+        .and_then(|online| online.as_bool())
+        .unwrap_or(true);
+    let page_origin = global_property("location", "origin")
+        .and_then(|origin| origin.as_string());
+    let cross_origin = match (url_origin(url), page_origin) {
+        (Some(origin), Some(page_origin)) => !origin.eq_ignore_ascii_case(&page_origin),
+        _ => false,
+    };
+    if !online || !cross_origin {
+        return Box::new(future::err(error));
+    }
 
-        let req = Request::new_with_str_and_init(
-            &format!("{}/{}", &self.base_path, path_name),
-            &request_options).unwrap();
+    let mut probe_options = RequestInit::new();
+    probe_options.method("HEAD");
+    probe_options.mode(RequestMode::NoCors);
+    let probe = Request::new_with_str_and_init(url, &probe_options)
+        .and_then(|req| Ok(self_()?.fetch_with_request(&req)));
+    let probe = match probe {
+        Ok(probe) => probe,
+        Err(_) => return Box::new(future::err(error)),
+    };
+
+    let url = url.to_owned();
+    Box::new(JsFuture::from(probe).then(move |probed| -> Result<Response, Error> {
+        match probed {
+            Ok(_) => Err(errors::new(errors::ErrorKind::Cors, format!(
+                "Request to {} failed because the server does not allow cross-origin \
+                requests. Check its Access-Control-Allow-Origin header ({})",
+                url, errors::message_of(&error)))),
+            Err(_) => Err(error),
+        }
+    }))
+}
+
+/// Resolve after `timeout_ms` milliseconds.
+fn sleep(timeout_ms: u32) -> Box<dyn Future<Item = (), Error = Error>> {
+    let promise = Promise::new(&mut |resolve, _reject| {
+        let scheduled = self_().and_then(|global| global.set_timeout(&resolve, timeout_ms as i32));
+        if scheduled.is_err() {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    });
+
+    Box::new(map_future_error_rust(JsFuture::from(promise)).map(|_| ()))
+}
+
+/// A listener forwarding the abort of a signal to the controller of a
+/// request, which is removed from the signal when this is dropped.
+struct ForwardedAbort {
+    signal: AbortSignal,
+    on_abort: Closure<dyn FnMut()>,
+}
 
-        let req_promise = self_().unwrap().fetch_with_request(&req);
+impl Drop for ForwardedAbort {
+    fn drop(&mut self) {
+        let _ = self.signal.remove_event_listener_with_callback("abort", self.on_abort.as_ref().unchecked_ref());
+    }
+}
 
-        JsFuture::from(req_promise)
+/// Merge byte ranges, sorted by their start, into spans of consecutive
+/// ranges at most `gap` bytes apart and at most `max_span` bytes long, as
+/// `(start, end, number of ranges)`. A range longer than `max_span` gets a
+/// span of its own.
+fn coalesced_spans(ranges: &[(u64, u64)], gap: u64, max_span: u64) -> Vec<(u64, u64, usize)> {
+    let mut spans: Vec<(u64, u64, usize)> = Vec::new();
+    for &(start, end) in ranges {
+        match spans.last_mut() {
+            Some((span_start, span_end, count)) if start <= span_end.saturating_add(gap)
+                    && cmp::max(*span_end, end) - *span_start <= max_span => {
+                *span_end = cmp::max(*span_end, end);
+                *count += 1;
+            },
+            _ => spans.push((start, end, 1)),
+        }
     }
 
-    fn fetch_json(&self, path_name: &str) -> impl Future<Item = JsValue, Error = JsValue> {
-        self.fetch(path_name).and_then(|resp_value| {
-            assert!(resp_value.is_instance_of::<Response>());
-            let resp: Response = resp_value.dyn_into()?;
+    spans
+}
 
-            resp.json()
-        }).and_then(|json_value: Promise| {
-            JsFuture::from(json_value)
-        })
+/// Abort `controller` when `signal` is aborted, until the returned listener
+/// is dropped.
+fn forward_abort(signal: &AbortSignal, controller: &AbortController) -> Option<ForwardedAbort> {
+    if signal.aborted() {
+        controller.abort();
+        return None;
     }
 
-    fn get_attributes(&self, path_name: &str) -> impl Future<Item = serde_json::Value, Error = Error> {
-        let path = self.get_dataset_attributes_path(path_name);
-        let to_return = self
-            .fetch_json(&path)
-            .map(|json| json.into_serde().unwrap());
+    let controller = controller.clone();
+    let on_abort: Closure<dyn FnMut()> = Closure::once(move || controller.abort());
+    // Adding a listener to an `AbortSignal` cannot fail.
+    let _ = signal.add_event_listener_with_callback("abort", on_abort.as_ref().unchecked_ref());
 
-        map_future_error_rust(to_return)
+    Some(ForwardedAbort { signal: signal.clone(), on_abort })
+}
+
+/// A future failing with an `aborted` error once `signal` is aborted, and
+/// never resolving otherwise.
+fn aborted(signal: &AbortSignal) -> Box<dyn Future<Item = (), Error = Error>> {
+    let error = || errors::new(errors::ErrorKind::Aborted, "The read was aborted");
+    if signal.aborted() {
+        return Box::new(future::err(error()));
     }
 
-    fn relative_block_path(&self, path_name: &str, grid_position: &[i64], block_size: &[u32], voxel_offset: &[i32], dimensions: &[u64]) -> String {
-        let mut block_path = path_name.to_owned();
-        let mut n = 0;
-        write!(block_path, "/").unwrap();
-        for coord in grid_position {
-            if n > 0 {
-                write!(block_path, "_").unwrap();
-            }
-            // This assumes 0 <= coord <= grid_size[n].
-            let begin_offset = voxel_offset[n] as i64 + coord * block_size[n] as i64;
-            let end_offset = voxel_offset[n] as i64 + cmp::min(
-                     (coord + 1) * block_size[n] as i64, dimensions[n] as i64);
+    let (sender, receiver) = oneshot::channel();
+    let on_abort = Closure::once_into_js(move || { let _ = sender.send(()); });
+    // Adding a listener to an `AbortSignal` cannot fail.
+    let _ = signal.add_event_listener_with_callback("abort", on_abort.unchecked_ref());
 
-            write!(block_path, "{}-{}", begin_offset, end_offset).unwrap();
-            n = n + 1;
-        }
+    Box::new(receiver.then(move |result| match result {
+        Ok(()) => future::Either::A(future::err(error())),
+        Err(_) => future::Either::B(future::empty()),
+    }))
+}
+
+/// `future`, failing with an `aborted` error as soon as `signal` is aborted.
+/// This does not cancel `future`, which may be shared with other reads.
+fn abortable<T: 'static>(future: Box<dyn Future<Item = T, Error = Error>>, signal: Option<&AbortSignal>)
+        -> Box<dyn Future<Item = T, Error = Error>> {
+    let signal = match signal {
+        Some(signal) => signal,
+        None => return future,
+    };
+
+    Box::new(future.select2(aborted(signal)).then(|result| match result {
+        Ok(future::Either::A((item, _))) => Ok(item),
+        Ok(future::Either::B(((), _))) => unreachable!("`aborted` never resolves"),
+        Err(future::Either::A((error, _))) |
+        Err(future::Either::B((error, _))) => Err(error),
+    }))
+}
 
-        block_path
+/// A timer aborting the controller of a request once its timeout expires,
+/// which is cleared when this is dropped.
+struct Deadline {
+    handle: i32,
+    expired: Rc<Cell<bool>>,
+    _on_expiry: Closure<dyn FnMut()>,
+}
+
+impl Deadline {
+    fn expired(&self) -> bool {
+        self.expired.get()
     }
+}
 
-    fn get_dataset_attributes_path(&self, path_name: &str) -> String {
-        if path_name.is_empty() {
-            ATTRIBUTES_FILE.to_owned()
-        } else {
-            // There is only one top-level attribute file
-            format!("{}", ATTRIBUTES_FILE)
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        if let Ok(global) = self_() {
+            global.clear_timeout(self.handle);
         }
     }
 }
 
-#[wasm_bindgen]
-impl NgPreHTTPFetch {
-    pub fn open(base_path: &str) -> Promise {
-        let reader = NgPreHTTPFetch {
-            base_path: base_path.into(),
-        };
+/// Abort `controller` after `timeout_ms` milliseconds, unless the returned
+/// deadline is dropped first.
+fn deadline(controller: &AbortController, timeout_ms: u32) -> Result<Deadline, Error> {
+    let expired = Rc::new(Cell::new(false));
+    let on_expiry: Closure<dyn FnMut()> = {
+        let controller = controller.clone();
+        let expired = expired.clone();
+        Closure::once(move || {
+            expired.set(true);
+            controller.abort();
+        })
+    };
+    let handle = self_()
+        .and_then(|global| global.set_timeout(on_expiry.as_ref().unchecked_ref(), timeout_ms as i32))
+        .map_err(convert_jsvalue_error)?;
 
-        let to_return = NgPreAsyncReader::get_version(&reader).and_then(|version| {
+    Ok(Deadline { handle, expired, _on_expiry: on_expiry })
+}
 
-            if !ngpre::is_version_compatible(&ngpre::VERSION, &version) {
-                return future::err(Error::new(ErrorKind::Other, "TODO: Incompatible version"))
-            }
+fn timeout_error(timeout_ms: u32) -> Error {
+    errors::new(errors::ErrorKind::Timeout, format!("Request timed out after {} ms", timeout_ms))
+}
 
-            future::ok(JsValue::from(reader))
-        });
+/// Delay before retry number `retries + 1`: exponential in the number of
+/// retries, with half of it randomized to spread out retries of many
+/// clients.
+fn backoff_delay(base_delay_ms: u32, retries: u32) -> u32 {
+    let delay = base_delay_ms as f64 * 2f64.powi(cmp::min(retries, 16) as i32);
+    (delay * (0.5 + 0.5 * js_sys::Math::random())) as u32
+}
 
-        future_to_promise(map_future_error_wasm(to_return))
-    }
+/// The error for a request that still failed after `retries` retries, with a
+/// `retries_exhausted` property.
+fn retries_exhausted(result: Result<Response, Error>, retries: u32) -> Error {
+    let (kind, message) = match result {
+        Ok(resp) => (errors::ErrorKind::Network,
+            format!("HTTP {} {} for {}", resp.status(), resp.status_text(), resp.url())),
+        Err(error) => (errors::kind_of(&error), errors::message_of(&error)),
+    };
+    let mut details = serde_json::Map::new();
+    details.insert("retries_exhausted".to_owned(), serde_json::json!(retries));
+
+    errors::with_details(kind, format!("{} (after {} retries)", message, retries), details)
 }
 
-/// Delegations to expose NgPrePromiseReader trait to WASM.
-#[wasm_bindgen]
-impl NgPreHTTPFetch {
-    pub fn get_version(&self) -> Promise {
-        NgPrePromiseReader::get_version(self)
+/// Reject responses that are not successful, reporting 404 as `not_found`.
+fn check_status(resp: &Response) -> Result<(), Error> {
+    if resp.ok() {
+        Ok(())
+    } else if resp.status() == 404 {
+        Err(errors::new(errors::ErrorKind::NotFound, format!("Not found: {}", resp.url())))
+    } else {
+        Err(errors::new(errors::ErrorKind::Network,
+            format!("HTTP {} {} for {}", resp.status(), resp.status_text(), resp.url())))
     }
+}
 
-    pub fn get_dataset_attributes(&self, path_name: &str) -> Promise {
-        NgPrePromiseReader::get_dataset_attributes(self, path_name)
+fn response_bytes(resp: &Response) -> Box<dyn Future<Item = Vec<u8>, Error = Error>> {
+    match resp.array_buffer() {
+        Ok(arrbuff_promise) => Box::new(map_future_error_rust(JsFuture::from(arrbuff_promise))
+            .map(|arrbuff_value| js_sys::Uint8Array::new(&arrbuff_value).to_vec())),
+        Err(error) => Box::new(future::err(convert_jsvalue_error(error))),
     }
+}
 
-    pub fn exists(&self, path_name: &str) -> Promise {
-        NgPrePromiseReader::exists(self, path_name)
+/// Check that the `Content-Type` of a chunk agrees with the encoding of its
+/// scale. Responses without a `Content-Type` and encodings without a known
+/// media type are accepted.
+fn check_content_type(content_type: Option<String>, encoding: &str) -> Result<(), Error> {
+    let content_type = match content_type {
+        Some(content_type) => content_type,
+        None => return Ok(()),
+    };
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let expected: &[&str] = match encoding {
+        "jpeg" => &["image/jpeg", "image/jpg"],
+        "png" => &["image/png"],
+        "raw" | "compressed_segmentation" => &["application/octet-stream", "binary/octet-stream"],
+        _ => return Ok(()),
+    };
+    if expected.contains(&media_type.as_str()) {
+        return Ok(());
     }
 
-    pub fn dataset_exists(&self, path_name: &str) -> Promise {
-        NgPrePromiseReader::dataset_exists(self, path_name)
-    }
+    let mut details = serde_json::Map::new();
+    details.insert("content_type".to_owned(), serde_json::json!(content_type));
+    details.insert("encoding".to_owned(), serde_json::json!(encoding));
+    Err(errors::with_details(errors::ErrorKind::EncodingMismatch, format!(
+        "Content-Type {} does not match the {} encoding", content_type, encoding), details))
+}
 
-    pub fn read_block(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise {
-        NgPrePromiseReader::read_block(self, path_name, data_attrs, grid_position)
+/// Undo an HTTP `Content-Encoding` of a response body, unless the browser has
+/// already done so. Gzip data is recognized by its magic number rather than
+/// by the `Content-Encoding`, which is hidden from cross-origin requests
+/// unless exposed and missing for files merely stored compressed; bodies that
+/// only start like gzip are kept as they are. Brotli has no magic number, but
+/// browsers keep reporting the encoded `Content-Length` when they decode it,
+/// so a brotli body of exactly that length has not been decoded yet.
+fn decode_content_encoding(bytes: Vec<u8>, encoding: Option<String>, content_length: Option<usize>)
+        -> Result<Vec<u8>, Error> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(sharded::gunzip(&bytes).unwrap_or(bytes));
     }
+    let is_brotli = encoding.map_or(false, |encoding| encoding.trim().eq_ignore_ascii_case("br"));
+    if !is_brotli || content_length != Some(bytes.len()) {
+        return Ok(bytes);
+    }
+
+    let mut decoded = Vec::new();
+    brotli_decompressor::Decompressor::new(&bytes[..], 4096).read_to_end(&mut decoded)
+        .map_err(|e| errors::new(errors::ErrorKind::Decode, format!("Invalid brotli data: {}", e)))?;
+    Ok(decoded)
+}
+
 
-    pub fn list_attributes(&self, path_name: &str) -> Promise {
-        NgPrePromiseReader::list_attributes(self, path_name)
+/// Parse the bytes of an info file, decompressing them first if they are
+/// gzip data (e.g. an `info.gz`, or an `info` stored compressed without a
+/// `Content-Encoding`).
+fn parse_info(bytes: &[u8]) -> Result<serde_json::Value, Error> {
+    let decoded;
+    let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+        decoded = sharded::gunzip(bytes)?;
+        &decoded[..]
+    } else {
+        bytes
+    };
+
+    serde_json::from_slice(bytes)
+        .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, format!("Invalid JSON: {}", e)))
+}
+
+/// Join two URL or path components with exactly one `/`, so that neither a
+/// trailing slash on `base` nor a leading one on `path` doubles it. An empty
+/// component leaves the other unchanged.
+pub(crate) fn join_path(base: &str, path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return base.to_owned();
+    }
+    let base = base.trim_end_matches('/');
+    if base.is_empty() {
+        return path.to_owned();
     }
 
-    pub fn block_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise {
-        NgPrePromiseEtagReader::block_etag(
-            self, path_name, data_attrs, grid_position)
+    format!("{}/{}", base, path)
+}
+
+/// Entry names of a directory listing generated by the server: either a JSON
+/// array of names or of objects with a `name` (like nginx's JSON autoindex),
+/// or otherwise the relative links of an HTML index page.
+fn parse_directory_listing(body: &str) -> Vec<String> {
+    let names: Vec<String> = match serde_json::from_str::<Vec<serde_json::Value>>(body) {
+        Ok(entries) => entries.iter()
+            .filter_map(|entry| entry.as_str()
+                .or_else(|| entry.get("name").and_then(|name| name.as_str()))
+                .map(str::to_owned))
+            .collect(),
+        Err(_) => body.split("href=").skip(1)
+            .filter_map(|link| {
+                let quote = link.chars().next().filter(|&c| c == '"' || c == '\'')?;
+                link[1..].split(quote).next().map(str::to_owned)
+            })
+            .collect(),
+    };
+
+    let mut entries: Vec<String> = Vec::with_capacity(names.len());
+    for name in names {
+        let name = name.trim_end_matches('/');
+        let is_entry = !name.is_empty() && !name.starts_with('.') &&
+            !name.contains(|c| c == '/' || c == '?' || c == '#' || c == ':');
+        if is_entry && !entries.iter().any(|entry| entry == name) {
+            entries.push(name.to_owned());
+        }
     }
 
-    pub fn read_block_with_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise {
-        NgPrePromiseEtagReader::read_block_with_etag(
-            self, path_name, data_attrs, grid_position)
+    entries
+}
+
+/// Whether `path` is a URL with a scheme, such as `https:`, `blob:` or
+/// `file:`, rather than a path relative to the base path.
+fn has_scheme(path: &str) -> bool {
+    // A single letter before the colon is a Windows drive, not a scheme.
+    match path.find(':') {
+        Some(end) => end > 1 && path[..end].chars().enumerate().all(|(i, c)|
+            c.is_ascii_alphabetic() || (i > 0 && (c.is_ascii_digit() || c == '+' || c == '-' || c == '.'))),
+        None => false,
     }
 }
 
-impl NgPreAsyncReader for NgPreHTTPFetch {
-    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
-        let to_return = self.get_attributes("")
-            .and_then(|_attr| {
-                Ok(ngpre::Version::from_str(&"2.3.0").unwrap())
-            });
+/// A `404` response for files missing from a reader's URL map.
+fn missing_response() -> Result<Response, Error> {
+    let mut init = web_sys::ResponseInit::new();
+    init.status(404);
+    Response::new_with_opt_u8_array_and_init(None, &init).map_err(errors::from_js_error)
+}
 
-        Box::new(to_return)
-    }
+/// Whether a reader has been disposed, and the controllers of its requests
+/// in flight, which `dispose` aborts.
+#[derive(Default)]
+struct Lifecycle {
+    disposed: bool,
+    next_request: u64,
+    in_flight: HashMap<u64, AbortController>,
+}
 
-    fn get_dataset_attributes(&self, path_name: &str) ->
-            Box<dyn Future<Item = ngpre::DatasetAttributes, Error = Error>> {
+/// A byte range of a shard waiting to be fetched together with nearby ranges.
+struct PendingRange {
+    start: u64,
+    end: u64,
+    sender: oneshot::Sender<Result<Option<(Vec<u8>, Option<String>)>, Error>>,
+}
 
-        let path = self.get_dataset_attributes_path(path_name);
-        let to_return = self
-            .fetch_json(&path)
-            .map(|json| { json.into_serde().unwrap() });
+/// A chunk read shared by concurrent reads of the chunk, which is aborted
+/// once none of them is waiting for it any more. The result is type-erased,
+/// since reads of several data types share one map.
+struct SharedRead {
+    result: Shared<Box<dyn Future<Item = Rc<dyn Any>, Error = Error>>>,
+    controller: AbortController,
+    /// Number of reads still waiting for the result.
+    waiters: Rc<Cell<usize>>,
+}
 
-        Box::new(map_future_error_rust(to_return))
+/// Counts a read waiting for a shared read while it lives, whether it
+/// resolves, is aborted or is just dropped. The last waiter to go removes
+/// the shared read and aborts it, unless it has already completed.
+struct SharedReadWaiter {
+    key: String,
+    waiters: Rc<Cell<usize>>,
+    in_flight_chunks: Rc<RefCell<HashMap<String, SharedRead>>>,
+    controller: AbortController,
+}
+
+impl Drop for SharedReadWaiter {
+    fn drop(&mut self) {
+        self.waiters.set(self.waiters.get() - 1);
+        if self.waiters.get() > 0 {
+            return;
+        }
+        // A completed read has removed itself already.
+        let unfinished = {
+            let mut in_flight_chunks = self.in_flight_chunks.borrow_mut();
+            if in_flight_chunks.get(&self.key).map_or(false, |read| Rc::ptr_eq(&read.waiters, &self.waiters)) {
+                in_flight_chunks.remove(&self.key)
+            } else {
+                None
+            }
+        };
+        // Aborting notifies listeners synchronously, so the reads must not
+        // be borrowed here.
+        if unfinished.is_some() {
+            drop(unfinished);
+            self.controller.abort();
+        }
     }
+}
 
-    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
-        let to_return = self.fetch(path_name).and_then(|resp_value| {
-            assert!(resp_value.is_instance_of::<Response>());
-            let resp: Response = resp_value.dyn_into().unwrap();
+pub(crate) fn disposed_error() -> Error {
+    errors::new(errors::ErrorKind::Disposed, "Reader has been disposed")
+}
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct NgPreHTTPFetch {
+    base_path: String,
+    signal: Option<AbortSignal>,
+    /// Headers added to every request.
+    headers: Vec<(String, String)>,
+    /// Function returning a `Promise<string>` of a bearer token, which is
+    /// called for every request.
+    token_provider: Option<js_sys::Function>,
+    credentials: Option<RequestCredentials>,
+    /// Cache mode of requests, which is the browser's default without one.
+    cache_mode: Option<RequestCache>,
+    /// Function `(path_name, grid_position, zoom_level) => url` overriding
+    /// the URLs of unsharded chunks.
+    chunk_url: Option<js_sys::Function>,
+    /// Whether to check the `Content-Type` of chunks against the encoding of
+    /// their scale.
+    strict_content_type: bool,
+    /// Byte order of raw chunks.
+    byte_order: raw::ByteOrder,
+    /// Whether to try `info.gz` when an info file is missing.
+    info_gz_fallback: bool,
+    /// Whether to verify unsharded chunks against checksums of the response.
+    verify_checksums: bool,
+    /// Whether to fetch a chunk once more, bypassing caches, when it fails
+    /// to decode.
+    refetch_on_decode_error: bool,
+    max_retries: u32,
+    base_delay_ms: u32,
+    /// Time after which a request is aborted, or 0 for no timeout.
+    timeout_ms: u32,
+    transport: Transport,
+    /// Shard and minishard indices, shared by all copies of this reader.
+    shard_indices: Rc<RefCell<IndexCache>>,
+    /// Shared by all copies of this reader, so that disposing one disposes
+    /// all of them.
+    lifecycle: Rc<RefCell<Lifecycle>>,
+    /// Largest gap between chunk byte ranges of a shard that are fetched in
+    /// one request, or `None` to fetch every chunk on its own.
+    coalesce_gap_bytes: Option<u64>,
+    /// Chunk byte ranges waiting to be coalesced, by shard path.
+    pending_ranges: Rc<RefCell<HashMap<String, Vec<PendingRange>>>>,
+    /// Whether concurrent reads of a chunk share one fetch.
+    deduplicate_reads: bool,
+    /// Chunk reads in flight, by chunk path or, for sharded chunks, by scale
+    /// and chunk ID, and by the kind of their result, shared by all copies
+    /// of this reader.
+    in_flight_chunks: Rc<RefCell<HashMap<String, SharedRead>>>,
+    /// URLs of files by their path, such as object URLs of local files, for
+    /// datasets not served from a directory of URLs.
+    url_map: Option<Rc<HashMap<String, String>>>,
+    /// Function called with `{method, url}` when a request starts.
+    on_fetch_start: Option<js_sys::Function>,
+    /// Function called with `{method, url, duration_ms, bytes, status,
+    /// cache_hit, error}` when a request ends.
+    on_fetch_end: Option<js_sys::Function>,
+}
 
-            future::ok(resp.ok())
+impl NgPreHTTPFetch {
+    /// Send a request, retrying on server errors (5xx) and network failures
+    /// with exponential backoff up to `max_retries` times.
+    fn fetch_with_body(&self, method: &str, path_name: &str, headers: &[(&str, &str)], body: Option<&JsValue>)
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        if self.is_disposed() {
+            return Box::new(future::err(disposed_error()));
+        }
+        let reader = self.clone();
+        let method = method.to_owned();
+        let url = match self.mapped_url(path_name) {
+            Some(url) => url,
+            None => return Box::new(future::result(missing_response())),
+        };
+        let headers: Vec<(String, String)> = self.headers.iter().cloned()
+            .chain(headers.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())))
+            .collect();
+        if let Some(replayed) = self.transport.replay(&method, &url, &headers) {
+            return self.instrumented(&method, &url, Box::new(future::result(replayed)));
+        }
+        let body = body.cloned();
+        let max_retries = self.max_retries;
+        let base_delay_ms = self.base_delay_ms;
+        let (recorded_method, recorded_url, recorded_headers) = (method.clone(), url.clone(), headers.clone());
+
+        let response = future::loop_fn(0, move |retries| {
+            let url = url.clone();
+            reader.send_with_token(&method, &url, headers.clone(), body.clone())
+                .then(move |result| -> Box<dyn Future<Item = future::Loop<Response, u32>, Error = Error>> {
+                    let retryable = match &result {
+                        Ok(resp) => resp.status() >= 500,
+                        Err(error) => match errors::kind_of(error) {
+                            errors::ErrorKind::Network |
+                            errors::ErrorKind::Timeout => true,
+                            _ => false,
+                        },
+                    };
+                    if retryable && retries < max_retries {
+                        return Box::new(sleep(backoff_delay(base_delay_ms, retries))
+                            .map(move |()| future::Loop::Continue(retries + 1)));
+                    }
+
+                    let error = match result {
+                        Ok(resp) if !retryable || max_retries == 0 =>
+                            return Box::new(future::ok(future::Loop::Break(resp))),
+                        Ok(resp) => return Box::new(future::err(retries_exhausted(Ok(resp), retries))),
+                        Err(error) if !retryable || max_retries == 0 => error,
+                        Err(error) => retries_exhausted(Err(error), retries),
+                    };
+                    // Only a failure that retrying did not resolve is told
+                    // apart from a CORS error.
+                    Box::new(cors_or_network(error, &url).map(future::Loop::Break))
+                })
         });
 
-        Box::new(map_future_error_rust(to_return))
+        let response = self.transport.record(&recorded_method, &recorded_url, &recorded_headers, response);
+        self.instrumented(&recorded_method, &recorded_url, response)
     }
 
-    // Override the default NgPreAsyncReader impl to not require the GET on the
-    // dataset directory path to be 200.
-    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
-        let path = self.get_dataset_attributes_path(path_name);
-        NgPreAsyncReader::exists(self, &path)
+    /// Report a request to the fetch callbacks, if there are any.
+    fn instrumented(&self, method: &str, url: &str, response: Box<dyn Future<Item = Response, Error = Error>>)
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        if self.on_fetch_start.is_none() && self.on_fetch_end.is_none() {
+            return response;
+        }
+        let (method, url) = (JsValue::from_str(method), JsValue::from_str(url));
+        if let Some(on_fetch_start) = &self.on_fetch_start {
+            // Errors of the callbacks do not affect the request.
+            let _ = on_fetch_start.call1(&JsValue::NULL,
+                &js_object(&[("method", method.clone()), ("url", url.clone())]));
+        }
+        let on_fetch_end = match &self.on_fetch_end {
+            Some(on_fetch_end) => on_fetch_end.clone(),
+            None => return response,
+        };
+        let started = js_sys::Date::now();
+
+        Box::new(response.then(move |result| {
+            let (status, bytes, error) = match &result {
+                Ok(resp) => {
+                    let bytes = resp.headers().get("Content-Length").unwrap_or(None)
+                        .and_then(|length| length.parse::<f64>().ok())
+                        .map_or(JsValue::NULL, JsValue::from_f64);
+                    (JsValue::from(resp.status()), bytes, JsValue::NULL)
+                },
+                Err(error) => (JsValue::NULL, JsValue::NULL, JsValue::from_str(errors::kind_of(error).as_str())),
+            };
+            let cache_hit = result.as_ref().map_or(false, |resp| resp.status() == 304);
+            let _ = on_fetch_end.call1(&JsValue::NULL, &js_object(&[
+                ("method", method),
+                ("url", url),
+                ("duration_ms", JsValue::from_f64(js_sys::Date::now() - started)),
+                ("bytes", bytes),
+                ("status", status),
+                ("cache_hit", JsValue::from_bool(cache_hit)),
+                ("error", error),
+            ]));
+            result
+        }))
     }
 
-    fn read_block<T>(
-        &self,
-        path_name: &str,
-        data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
-        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
-            T: ReflectedType,
-    {
-
-        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
-                self, path_name, data_attrs, grid_position)
-            .map(|maybe_block| maybe_block.map(|(block, _etag)| block)))
+    /// Report a block served from a cache in front of this reader to the
+    /// fetch callbacks, as a request with `cache_hit` set.
+    pub(crate) fn report_cache_hit(&self, path_name: &str, data_attrs: &DatasetAttributes, grid_position: &[i64]) {
+        if self.on_fetch_start.is_none() && self.on_fetch_end.is_none() {
+            return;
+        }
+        let url = match self.chunk_url_of(path_name, data_attrs, grid_position) {
+            Ok(url) => JsValue::from_str(&url),
+            Err(_) => return,
+        };
+        let method = JsValue::from_str("GET");
+        if let Some(on_fetch_start) = &self.on_fetch_start {
+            let _ = on_fetch_start.call1(&JsValue::NULL,
+                &js_object(&[("method", method.clone()), ("url", url.clone())]));
+        }
+        if let Some(on_fetch_end) = &self.on_fetch_end {
+            let _ = on_fetch_end.call1(&JsValue::NULL, &js_object(&[
+                ("method", method),
+                ("url", url),
+                ("duration_ms", JsValue::from_f64(0.0)),
+                ("bytes", JsValue::NULL),
+                ("status", JsValue::NULL),
+                ("cache_hit", JsValue::from_bool(true)),
+                ("error", JsValue::NULL),
+            ]));
+        }
     }
 
-    fn list(&self, _path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
-        // TODO: Not implemented because remote paths are not listable.
-        unimplemented!()
-    }
+    fn send_with_token(&self, method: &str, url: &str, mut headers: Vec<(String, String)>, body: Option<JsValue>)
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        let reader = self.clone();
+        let method = method.to_owned();
+        let url = url.to_owned();
 
-    fn list_attributes(
-        &self,
-        path_name: &str,
-    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        Box::new(self.token().and_then(move |token| {
+            if let Some(token) = token {
+                headers.push(("Authorization".to_owned(), format!("Bearer {}", token)));
+            }
 
-        Box::new(self.get_attributes(path_name))
+            reader.send(&method, &url, &headers, body.as_ref())
+        }))
     }
-}
 
-impl NgPreAsyncEtagReader for NgPreHTTPFetch {
-    fn block_etag(
-        &self,
-        path_name: &str,
-        _data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+    fn send(&self, method: &str, url: &str, headers: &[(String, String)], body: Option<&JsValue>)
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        // Each request gets its own controller, so that it can be aborted on
+        // expiry of the timeout or by `dispose` without aborting the reader's
+        // signal.
+        let controller = match AbortController::new() {
+            Ok(controller) => controller,
+            Err(error) => return Box::new(future::err(convert_jsvalue_error(error))),
+        };
+        let forwarded = self.signal.as_ref().and_then(|signal| forward_abort(signal, &controller));
+        // The timeout covers the download of the body as well as the
+        // response headers.
+        let deadline = if self.timeout_ms > 0 {
+            match deadline(&controller, self.timeout_ms) {
+                Ok(deadline) => Some(deadline),
+                Err(error) => return Box::new(future::err(error)),
+            }
+        } else {
+            None
+        };
+        let request = {
+            let mut lifecycle = self.lifecycle.borrow_mut();
+            if lifecycle.disposed {
+                return Box::new(future::err(disposed_error()));
+            }
+            lifecycle.next_request += 1;
+            let request = lifecycle.next_request;
+            lifecycle.in_flight.insert(request, controller.clone());
+            request
+        };
+
         let mut request_options = RequestInit::new();
-        request_options.method("HEAD");
+        request_options.method(method);
         request_options.mode(RequestMode::Cors);
+        request_options.signal(Some(&controller.signal()));
+        request_options.body(body);
+        if let Some(credentials) = self.credentials {
+            request_options.credentials(credentials);
+        }
+        if let Some(cache_mode) = self.cache_mode {
+            request_options.cache(cache_mode);
+        }
+
+        let req_promise = Headers::new()
+            .and_then(|request_headers| {
+                for (name, value) in headers {
+                    request_headers.set(name, value)?;
+                }
+                request_options.headers(&request_headers);
+
+                Request::new_with_str_and_init(url, &request_options)
+            })
+            .and_then(|req| Ok(self_()?.fetch_with_request(&req)));
+
+        let to_return = match req_promise {
+            Ok(req_promise) => future::Either::A(JsFuture::from(req_promise)
+                .and_then(|resp_value| resp_value.dyn_into::<Response>())),
+            Err(error) => future::Either::B(future::err(error)),
+        };
+        let response = map_future_error_rust(to_return);
+
+        let lifecycle = self.lifecycle.clone();
+        let timeout_ms = self.timeout_ms;
+        Box::new(response.then(move |result| {
+            let timed_out = deadline.as_ref().map_or(false, Deadline::expired);
+            let settled = {
+                let lifecycle = lifecycle.clone();
+                move || {
+                    lifecycle.borrow_mut().in_flight.remove(&request);
+                    drop(forwarded);
+                    drop(deadline);
+                }
+            };
+            let resp = match result {
+                Ok(resp) => resp,
+                Err(error) => {
+                    settled();
+                    return if lifecycle.borrow().disposed {
+                        Err(disposed_error())
+                    } else if timed_out {
+                        Err(timeout_error(timeout_ms))
+                    } else {
+                        Err(error)
+                    };
+                },
+            };
+
+            // The request stays abortable by `dispose` and the reader's
+            // signal until its body has been downloaded, which is awaited on
+            // a copy of the response.
+            match resp.clone().and_then(|copy| copy.array_buffer()) {
+                Ok(body) => {
+                    wasm_bindgen_futures::spawn_local(JsFuture::from(body).then(move |_| {
+                        settled();
+                        Ok::<(), ()>(())
+                    }));
+                },
+                Err(_) => settled(),
+            }
+            Ok(resp)
+        }))
+    }
 
-        // TODO: Could be nicer
-        let zoom_level = _data_attrs.get_scales().iter().position(|s| s.key == path_name).unwrap();
+    /// The body of a response, failing with a `disposed` error if the reader
+    /// was disposed while it was downloaded, so that it is not decoded, and
+    /// with a `timeout` error if the request timed out meanwhile.
+    fn body_bytes(&self, resp: &Response) -> Box<dyn Future<Item = Vec<u8>, Error = Error>> {
+        let lifecycle = self.lifecycle.clone();
+        let signal = self.signal.clone();
+        let timeout_ms = self.timeout_ms;
+
+        Box::new(response_bytes(resp).then(move |result| {
+            if lifecycle.borrow().disposed {
+                return Err(disposed_error());
+            }
+            match result {
+                // Besides `dispose`, only the signal of the reader and the
+                // deadline of the request abort its download.
+                Err(ref error) if timeout_ms > 0
+                        && errors::kind_of(error) == errors::ErrorKind::Aborted
+                        && !signal.as_ref().map_or(false, AbortSignal::aborted) =>
+                    Err(timeout_error(timeout_ms)),
+                result => result,
+            }
+        }))
+    }
 
-        let block_path = self.relative_block_path(path_name, &grid_position,
-                 _data_attrs.get_block_size(zoom_level), _data_attrs.get_voxel_offset(zoom_level),
-                 _data_attrs.get_dimensions(zoom_level));
+    /// Get a bearer token from the token provider, if there is one.
+    fn token(&self) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let provider = match &self.token_provider {
+            Some(provider) => provider,
+            None => return Box::new(future::ok(None)),
+        };
 
-        let req = Request::new_with_str_and_init(
-            &format!("{}/{}", &self.base_path, block_path),
-            &request_options).unwrap();
+        let token = match provider.call0(&JsValue::NULL) {
+            Ok(token) => token,
+            Err(error) => return Box::new(future::err(convert_jsvalue_error(error))),
+        };
 
-        let req_promise = self_().unwrap().fetch_with_request(&req);
+        Box::new(map_future_error_rust(JsFuture::from(Promise::resolve(&token)))
+            .and_then(|token| token.as_string()
+                .map(Some)
+                .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+                    "Token provider did not resolve to a string"))))
+    }
 
-        let f = JsFuture::from(req_promise)
-            .map(|resp_value| {
-                assert!(resp_value.is_instance_of::<Response>());
-                let resp: Response = resp_value.dyn_into().unwrap();
+    fn fetch_with_headers(&self, method: &str, path_name: &str, headers: &[(&str, &str)])
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        self.fetch_with_body(method, path_name, headers, None)
+    }
 
-                if resp.ok() {
-                    resp.headers().get("ETag").unwrap_or(None)
-                } else {
-                    None
-                }
-            });
+    fn fetch_with_method(&self, method: &str, path_name: &str)
+            -> Box<dyn Future<Item = Response, Error = Error>> {
+        self.fetch_with_headers(method, path_name, &[])
+    }
 
-        Box::new(map_future_error_rust(f))
+    fn fetch(&self, path_name: &str) -> Box<dyn Future<Item = Response, Error = Error>> {
+        self.fetch_with_method("GET", path_name)
     }
 
-    fn read_block_with_etag<T>(
+    fn fetch_bytes(&self, path_name: &str) -> BytesFuture {
+        Box::new(self.fetch_bytes_if_changed(path_name, None, None, false)
+            .map(|conditional| match conditional {
+                Conditional::Changed(maybe_bytes) => maybe_bytes,
+                Conditional::Unchanged => None,
+            }))
+    }
+
+    /// Fetch a file unless its etag is still `known_etag`, using
+    /// `If-None-Match`. With an `expected_encoding`, the `Content-Type` of the
+    /// response has to match that chunk encoding, and with `verify_checksum`
+    /// the body has to match any CRC32C of the response headers.
+    fn fetch_bytes_if_changed(
         &self,
         path_name: &str,
-        data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
-            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
-                T: ReflectedType,
-    {
+        known_etag: Option<&str>,
+        expected_encoding: Option<&str>,
+        verify_checksum: bool,
+    ) -> Box<dyn Future<Item = Conditional<Option<(Vec<u8>, Option<String>)>>, Error = Error>> {
+        let expected_encoding = expected_encoding.map(str::to_owned);
+        let path = path_name.to_owned();
+        let headers: Vec<(&str, &str)> = known_etag.iter()
+            .map(|&etag| ("If-None-Match", etag))
+            .collect();
+
+        let reader = self.clone();
+
+        Box::new(self.fetch_with_headers("GET", path_name, &headers).and_then(move |resp| {
+            if resp.status() == 304 {
+                return future::Either::B(future::ok(Conditional::Unchanged));
+            }
+            if resp.status() == 404 {
+                return future::Either::B(future::ok(Conditional::Changed(None)));
+            }
+            if let Err(error) = check_status(&resp) {
+                return future::Either::B(future::err(error));
+            }
 
-        let da2 = data_attrs.clone();
+            let headers = resp.headers();
+            if let Some(expected_encoding) = &expected_encoding {
+                let content_type: Option<String> = headers.get("Content-Type").unwrap_or(None);
+                if let Err(error) = check_content_type(content_type, expected_encoding) {
+                    return future::Either::B(future::err(error));
+                }
+            }
+            let etag: Option<String> = headers.get("ETag").unwrap_or(None);
+            let encoding: Option<String> = headers.get("Content-Encoding").unwrap_or(None);
+            let content_length: Option<usize> = headers.get("Content-Length").unwrap_or(None)
+                .and_then(|length| length.parse().ok());
+            // The checksum is of the stored bytes, so it cannot be verified
+            // once the body has been decoded.
+            let stored_encoding: Option<String> = headers.get("x-goog-stored-content-encoding").unwrap_or(None);
+            let goog_hash: Option<String> = if verify_checksum && encoding.is_none() &&
+                    stored_encoding.as_ref().map_or(true, |stored| stored == "identity") {
+                headers.get("x-goog-hash").unwrap_or(None)
+            } else {
+                None
+            };
+            future::Either::A(reader.body_bytes(&resp).and_then(move |bytes| -> Result<_, Error> {
+                checksum::verify(&bytes, goog_hash.as_ref().map(String::as_str), &path)?;
+                decode_content_encoding(bytes, encoding, content_length)
+                    .map(|bytes| Conditional::Changed(Some((bytes, etag))))
+            }))
+        }))
+    }
 
-        // TODO: Could be nicer
-        let zoom_level = data_attrs.get_scales().iter().position(|s| s.key == path_name).unwrap();
-        let voxel_offset = data_attrs.get_voxel_offset(zoom_level);
-        let block_size = data_attrs.get_block_size(zoom_level);
-        let dimensions = data_attrs.get_dimensions(zoom_level);
+    /// Fetch the byte range `[start, end)` of a file.
+    fn fetch_range(&self, path_name: &str, start: u64, end: u64) -> BytesFuture {
+        if start >= end {
+            return Box::new(future::ok(Some((Vec::new(), None))));
+        }
 
-        let block_path = self.relative_block_path(path_name, &grid_position,
-                block_size, voxel_offset, dimensions);
+        let reader = self.clone();
+        let range = format!("bytes={}-{}", start, end - 1);
+        // Some browsers fail range requests of object and file URLs, with a
+        // `TypeError` or `416 Range Not Satisfiable`, so those are retried
+        // once for the whole file, which is then cropped.
+        let is_local = self.mapped_url(path_name)
+            .map_or(false, |url| url.starts_with("blob:") || url.starts_with("file:"));
+        let whole_file = {
+            let reader = self.clone();
+            let path = path_name.to_owned();
+            move || reader.fetch(&path)
+        };
+        let response = self.fetch_with_headers("GET", path_name, &[("Range", range.as_str())])
+            .then(move |result| -> Box<dyn Future<Item = Response, Error = Error>> {
+                match result {
+                    Ok(ref resp) if is_local && resp.status() == 416 => whole_file(),
+                    Err(ref error) if is_local && errors::kind_of(error) == errors::ErrorKind::Network =>
+                        whole_file(),
+                    result => Box::new(future::result(result)),
+                }
+            });
 
-        // Make sure we are in bounds with requested blocks. This method accepts signed inputed, to
-        // allow catching overflows when data from JavaScript is passed in.
-        let mut offset_grid_position = GridCoord::new();
-        let mut n = 0;
-        for coord in grid_position {
-            if coord < 0 || coord * block_size[n] as i64 > dimensions[n] as i64  {
-                return Box::new(future::ok(None));
+        Box::new(response.and_then(move |resp| {
+            if resp.status() == 404 {
+                return future::Either::B(future::ok(None));
             }
-            offset_grid_position.push(coord as u64);
-            n = n + 1;
-        }
-
-        let f = self.fetch(&block_path).and_then(|resp_value| {
-            assert!(resp_value.is_instance_of::<Response>());
-            let resp: Response = resp_value.dyn_into().unwrap();
-
-            if resp.ok() {
-                let etag: Option<String> = resp.headers().get("ETag").unwrap_or(None);
-                let to_return = JsFuture::from(resp.array_buffer().unwrap())
-                    .map(move |arrbuff_value| {
-                        assert!(arrbuff_value.is_instance_of::<ArrayBuffer>());
-                        let typebuff: js_sys::Uint8Array = js_sys::Uint8Array::new(&arrbuff_value);
-                        let buff = typebuff.to_vec();
-
-                        Some((<ngpre::DefaultBlock as ngpre::DefaultBlockReader<T, &[u8]>>::read_block(
-                            &buff,
-                            &da2,
-                            offset_grid_position).unwrap(),
-                            etag))
-                    });
-                future::Either::A(to_return)
-            } else  {
-                future::Either::B(future::ok(None))
+            if let Err(error) = check_status(&resp) {
+                return future::Either::B(future::err(error));
             }
-        });
 
-        Box::new(map_future_error_rust(f))
+            let partial = resp.status() == 206;
+            let headers = resp.headers();
+            let etag: Option<String> = headers.get("ETag").unwrap_or(None);
+            let encoding: Option<String> = headers.get("Content-Encoding").unwrap_or(None);
+            let content_length: Option<usize> = headers.get("Content-Length").unwrap_or(None)
+                .and_then(|length| length.parse().ok());
+            future::Either::A(reader.body_bytes(&resp).and_then(move |bytes| {
+                // Servers ignoring the range may send the whole file encoded.
+                // Partial bodies are left alone, since the chunks of a shard
+                // may themselves be gzip data.
+                let bytes = if partial {
+                    bytes
+                } else {
+                    decode_content_encoding(bytes, encoding, content_length)?
+                };
+                let (start, end) = (start as usize, end as usize);
+                if partial && bytes.len() == end - start {
+                    Ok(Some((bytes, etag)))
+                } else if !partial && bytes.len() >= end {
+                    // The server ignored the range, so crop the full body.
+                    Ok(Some((bytes[start..end].to_vec(), etag)))
+                } else {
+                    Err(errors::new(errors::ErrorKind::Network, format!(
+                        "Incomplete response for byte range {}-{}", start, end)))
+                }
+            }))
+        }))
     }
-}
+
+    /// The whole index of a shard, or `None` if the shard does not exist,
+    /// from the shard index cache if possible.
+    ///
+    /// The index is fetched without the signal of the reader, since other
+    /// reads may wait for it; aborting the signal only fails this read.
+    fn shard_index(&self, shard_path: &str, spec: &ShardingSpec) -> Box<dyn Future<Item = Rc<CachedIndex>, Error = Error>> {
+        let reader = self.without_signal();
+        let fetched_path = shard_path.to_owned();
+        let index_size = spec.shard_index_size();
+
+        abortable(sharded::cached_index(&self.shard_indices, shard_path.to_owned(), move ||
+            Box::new(reader.fetch_range(&fetched_path, 0, index_size)
+                .map(|maybe_index| Rc::new(CachedIndex::Shard(maybe_index.map(|(index, _etag)| index)))))),
+            self.signal.as_ref())
+    }
+
+    /// The decoded index of a minishard, from the shard index cache if
+    /// possible. The minishard index is empty if the minishard has no chunks.
+    /// As for `shard_index`, aborting the signal of the reader only fails
+    /// this read.
+    fn minishard_index(&self, shard_path: &str, spec: &ShardingSpec, minishard: u64)
+            -> Box<dyn Future<Item = Rc<CachedIndex>, Error = Error>> {
+        let reader = self.without_signal();
+        let fetched_path = shard_path.to_owned();
+        let spec = spec.clone();
+        let key = format!("{}#{}", shard_path, minishard);
+
+        abortable(sharded::cached_index(&self.shard_indices, key, move ||
+            Box::new(reader.shard_index(&fetched_path, &spec).and_then(move |shard_index| {
+                let (entry_start, entry_end) = spec.shard_index_range(minishard);
+                let index_range = match &*shard_index {
+                    CachedIndex::Shard(Some(index)) => index.get(entry_start as usize..entry_end as usize)
+                        .ok_or_else(|| errors::new(errors::ErrorKind::Decode, format!(
+                            "Shard index of {} is truncated", fetched_path)))
+                        .and_then(|entry| spec.minishard_index_range(entry)),
+                    // A missing shard contains no chunks.
+                    _ => Ok(None),
+                };
+                let (index_start, index_end) = match index_range {
+                    Ok(Some(index_range)) => index_range,
+                    Ok(None) => return future::Either::B(future::ok(Rc::new(CachedIndex::Minishard(Vec::new())))),
+                    Err(error) => return future::Either::B(future::err(error)),
+                };
+
+                future::Either::A(reader.fetch_range(&fetched_path, index_start, index_end)
+                    .and_then(move |maybe_index| Ok(Rc::new(CachedIndex::Minishard(match maybe_index {
+                        Some((index, _etag)) => spec.decode_minishard_index(index)?,
+                        None => Vec::new(),
+                    })))))
+            }))),
+            self.signal.as_ref())
+    }
+
+    /// Locate a chunk in a sharded scale by looking it up in the shard index
+    /// and minishard index, which are cached. Resolves to the shard path and
+    /// the chunk's byte range within it, or `None` if the chunk does not
+    /// exist.
+    fn locate_sharded_chunk(&self, path_name: &str, spec: &ShardingSpec, chunk_id: u64)
+            -> Box<dyn Future<Item = Option<(String, (u64, u64))>, Error = Error>> {
+        let location = spec.locate(chunk_id);
+        let shard_path = join_path(path_name, &spec.shard_file_name(location.shard));
+
+        Box::new(self.minishard_index(&shard_path, spec, location.minishard)
+            .map(move |minishard_index| {
+                let chunk_range = match &*minishard_index {
+                    CachedIndex::Minishard(index) => index.iter()
+                        .find(|&&(id, _)| id == chunk_id)
+                        .map(|&(_, range)| range),
+                    CachedIndex::Shard(_) => None,
+                };
+
+                chunk_range.map(|chunk_range| (shard_path, chunk_range))
+            }))
+    }
+
+    /// Fetch the bytes of a chunk in a sharded scale.
+    fn fetch_sharded_chunk(&self, path_name: &str, spec: ShardingSpec, chunk_id: u64) -> BytesFuture {
+        let reader = self.clone();
+        let data_encoding = spec.data_encoding;
+
+        Box::new(self.locate_sharded_chunk(path_name, &spec, chunk_id)
+            .and_then(move |maybe_chunk| -> BytesFuture {
+                let (shard_path, (chunk_start, chunk_end)) = match maybe_chunk {
+                    Some(chunk) => chunk,
+                    None => return Box::new(future::ok(None)),
+                };
+
+                Box::new(reader.fetch_shard_range(&shard_path, chunk_start, chunk_end)
+                    .and_then(move |maybe_chunk| match maybe_chunk {
+                        Some((bytes, etag)) => data_encoding.decode(bytes)
+                            .map(|bytes| Some((bytes, etag))),
+                        None => Ok(None),
+                    }))
+            }))
+    }
+
+    /// Fetch the byte range `[start, end)` of a shard. With
+    /// `coalesce_gap_bytes` set, the range is fetched on the next task
+    /// together with the other ranges of the shard requested until then that
+    /// are at most that many bytes apart.
+    fn fetch_shard_range(&self, shard_path: &str, start: u64, end: u64) -> BytesFuture {
+        let gap = match self.coalesce_gap_bytes {
+            Some(gap) => gap,
+            None => return self.fetch_range(shard_path, start, end),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        let first = {
+            let mut pending = self.pending_ranges.borrow_mut();
+            let ranges = pending.entry(shard_path.to_owned()).or_insert_with(Vec::new);
+            ranges.push(PendingRange { start, end, sender });
+            ranges.len() == 1
+        };
+        if first {
+            let reader = self.clone();
+            let shard_path = shard_path.to_owned();
+            // Running the future through a promise starts it.
+            let _ = future_to_promise(sleep(0).then(move |_| {
+                reader.flush_shard_ranges(&shard_path, gap);
+                Ok(JsValue::UNDEFINED)
+            }));
+        }
+
+        // The ranges are fetched for all reads waiting for them, so aborting
+        // the signal of the reader only fails this read.
+        abortable(Box::new(receiver
+                .map_err(|_| errors::new(errors::ErrorKind::Other, "Coalesced range request was dropped"))
+                .and_then(future::result)),
+            self.signal.as_ref())
+    }
+
+    /// Fetch the pending ranges of a shard, merging ranges at most `gap`
+    /// bytes apart into one request whose response is split between them.
+    /// Ranges of reads that have been dropped, such as aborted reads, are not
+    /// fetched.
+    fn flush_shard_ranges(&self, shard_path: &str, gap: u64) {
+        let mut ranges = self.pending_ranges.borrow_mut().remove(shard_path).unwrap_or_default();
+        ranges.retain(|range| !range.sender.is_canceled());
+        ranges.sort_by_key(|range| range.start);
+
+        let spans: Vec<(u64, u64)> = ranges.iter().map(|range| (range.start, range.end)).collect();
+        let mut ranges = ranges.into_iter();
+        let groups = coalesced_spans(&spans, gap, MAX_COALESCED_BYTES).into_iter()
+            .map(|(group_start, group_end, count)|
+                (group_start, group_end, ranges.by_ref().take(count).collect::<Vec<_>>()));
+
+        let reader = self.without_signal();
+        for (group_start, group_end, group) in groups {
+            let shard_path = shard_path.to_owned();
+            let _ = future_to_promise(reader.fetch_range(&shard_path, group_start, group_end).then(move |result| {
+                for range in group {
+                    let part = match &result {
+                        Ok(Some((bytes, etag))) => bytes
+                            .get((range.start - group_start) as usize..(range.end - group_start) as usize)
+                            .map(|part| Some((part.to_vec(), etag.clone())))
+                            .ok_or_else(|| errors::new(errors::ErrorKind::Decode, format!(
+                                "Range response of {} is truncated", shard_path))),
+                        Ok(None) => Ok(None),
+                        Err(error) => Err(errors::new(errors::kind_of(error), errors::message_of(error))),
+                    };
+                    // The read may have been dropped in the meantime.
+                    let _ = range.sender.send(part);
+                }
+                Ok(JsValue::UNDEFINED)
+            }));
+        }
+    }
+
+    /// Check whether a file exists with a HEAD request, falling back to a
+    /// single byte range GET for servers that do not support HEAD.
+    fn file_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let reader = self.clone();
+        let path_name = path_name.to_owned();
+
+        Box::new(self.fetch_with_method("HEAD", &path_name)
+            .and_then(move |resp| -> Box<dyn Future<Item = bool, Error = Error>> {
+                match resp.status() {
+                    404 => Box::new(future::ok(false)),
+                    405 | 501 => Box::new(reader
+                        .fetch_with_headers("GET", &path_name, &[("Range", "bytes=0-0")])
+                        .and_then(|resp| match resp.status() {
+                            404 => Ok(false),
+                            _ => check_status(&resp).map(|()| true),
+                        })),
+                    _ => Box::new(future::result(check_status(&resp).map(|()| true))),
+                }
+            }))
+    }
+
+    fn fetch_json(&self, path_name: &str) -> impl Future<Item = JsValue, Error = Error> {
+        self.fetch(path_name).and_then(|resp| {
+            check_status(&resp)?;
+
+            resp.json().map_err(convert_jsvalue_error)
+        }).and_then(|json_value: Promise| {
+            JsFuture::from(json_value).map_err(|error| errors::new(
+                errors::ErrorKind::BadAttrs,
+                format!("Invalid JSON: {:?}", error)))
+        })
+    }
+
+    fn get_attributes(&self, path_name: &str) -> impl Future<Item = serde_json::Value, Error = Error> {
+        let path = self.get_dataset_attributes_path(path_name);
+        self.fetch_info(&path)
+    }
+
+    /// Fetch and parse an info file, which may be gzip-compressed. If the
+    /// file is missing and `info_gz_fallback` is set, `<info_path>.gz` is
+    /// tried as well.
+    fn fetch_info(&self, info_path: &str) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        let reader = self.clone();
+        let info_path = info_path.to_owned();
+
+        Box::new(self.fetch_bytes(&info_path).and_then(move |maybe_bytes| match maybe_bytes {
+            Some((bytes, _etag)) => future::Either::B(future::result(parse_info(&bytes))),
+            None if reader.info_gz_fallback => {
+                let gz_path = format!("{}.gz", info_path);
+                future::Either::A(reader.fetch_bytes(&gz_path).and_then(move |maybe_bytes| match maybe_bytes {
+                    Some((bytes, _etag)) => parse_info(&bytes),
+                    None => Err(errors::new(errors::ErrorKind::NotFound,
+                        format!("Not found: {} or {}", info_path, gz_path))),
+                }))
+            },
+            None => future::Either::B(future::err(errors::new(errors::ErrorKind::NotFound,
+                format!("Not found: {}", info_path)))),
+        }))
+    }
+
+    /// The URL of a path relative to the base path.
+    fn url(&self, path_name: &str) -> String {
+        // Chunk URLs given by `chunk_url` may be absolute.
+        if has_scheme(path_name) {
+            path_name.to_owned()
+        } else {
+            join_path(&self.base_path, path_name)
+        }
+    }
+
+    /// The URL of a file, looked up in the URL map if there is one, or
+    /// `None` if the map has no such file.
+    fn mapped_url(&self, path_name: &str) -> Option<String> {
+        let url = self.url(path_name);
+        match &self.url_map {
+            Some(url_map) if !has_scheme(&url) =>
+                url_map.get(url.trim_start_matches('/')).cloned(),
+            _ => Some(url),
+        }
+    }
+
+    /// The path of the file holding a chunk: its shard on sharded scales,
+    /// else the chunk itself. `None` if the chunk is out of bounds.
+    fn chunk_file_path(&self, path_name: &str, data_attrs: &DatasetAttributes, grid_position: &[i64])
+            -> Result<Option<String>, Error> {
+        let zoom_level = zoom_level(data_attrs, path_name)?;
+        check_grid_ndim(data_attrs, zoom_level, grid_position)?;
+        let offset_grid_position = match checked_grid_position(grid_position,
+                data_attrs.get_block_size(zoom_level), data_attrs.get_dimensions(zoom_level)) {
+            Some(offset_grid_position) => offset_grid_position,
+            None => return Ok(None),
+        };
+
+        match ShardingSpec::for_scale(data_attrs, zoom_level)? {
+            Some(spec) => {
+                let chunk_id = sharded::compressed_morton_code(
+                    &offset_grid_position, &sharded::grid_shape(data_attrs, zoom_level));
+                Ok(Some(join_path(path_name, &spec.shard_file_name(spec.locate(chunk_id).shard))))
+            },
+            None => self.block_path(path_name, data_attrs, zoom_level, grid_position).map(Some),
+        }
+    }
+
+    /// The URL that reads of a chunk fetch, as given by `get_chunk_url`.
+    pub(crate) fn chunk_url_of(&self, path_name: &str, data_attrs: &DatasetAttributes, grid_position: &[i64])
+            -> Result<String, Error> {
+        let chunk_path = self.chunk_file_path(path_name, data_attrs, grid_position)?
+            .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Grid position {:?} is out of bounds", grid_position)))?;
+
+        self.mapped_url(&chunk_path)
+            .ok_or_else(|| errors::new(errors::ErrorKind::NotFound,
+                format!("Not found in URL map: {}", chunk_path)))
+    }
+
+    pub(crate) fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    pub(crate) fn byte_order(&self) -> raw::ByteOrder {
+        self.byte_order
+    }
+
+    pub(crate) fn refetch_on_decode_error(&self) -> bool {
+        self.refetch_on_decode_error
+    }
+
+    pub(crate) fn is_disposed(&self) -> bool {
+        self.lifecycle.borrow().disposed
+    }
+
+    /// A copy of this reader whose requests are not aborted by its signal,
+    /// for fetches shared by several reads.
+    fn without_signal(&self) -> Self {
+        let mut reader = self.clone();
+        reader.signal = None;
+        reader
+    }
+
+    /// A reader with the default configuration that has not checked its
+    /// version.
+    pub(crate) fn unopened(base_path: &str) -> Self {
+        NgPreHTTPFetchBuilder::new(base_path).reader
+    }
+
+    pub(crate) fn with_on_fetch_start(mut self, on_fetch_start: js_sys::Function) -> Self {
+        self.on_fetch_start = Some(on_fetch_start);
+        self
+    }
+
+    pub(crate) fn with_on_fetch_end(mut self, on_fetch_end: js_sys::Function) -> Self {
+        self.on_fetch_end = Some(on_fetch_end);
+        self
+    }
+
+    /// A copy of this reader whose requests go to `transport`. It has its
+    /// own shard index cache, so that no index requests are skipped, and its
+    /// own pending ranges, so that none are fetched by the other transport.
+    pub(crate) fn with_transport(&self, transport: Transport) -> Self {
+        let mut reader = self.clone();
+        reader.transport = transport;
+        reader.shard_indices = Rc::new(RefCell::new(
+            IndexCache::new(self.shard_indices.borrow().capacity_bytes())));
+        reader.pending_ranges = Rc::new(RefCell::new(HashMap::new()));
+        reader.in_flight_chunks = Rc::new(RefCell::new(HashMap::new()));
+        reader
+    }
+
+    fn relative_block_path(&self, path_name: &str, grid_position: &[i64], block_size: &[u32], voxel_offset: &[i32], dimensions: &[u64]) -> String {
+        join_path(path_name, &chunk_key(grid_position, block_size, voxel_offset, dimensions))
+    }
+
+    /// Path of an unsharded chunk, as given by the `chunk_url` function if
+    /// there is one.
+    fn block_path(&self, path_name: &str, data_attrs: &DatasetAttributes, zoom_level: usize, grid_position: &[i64])
+            -> Result<String, Error> {
+        let chunk_url = match &self.chunk_url {
+            Some(chunk_url) => chunk_url,
+            None => return Ok(self.relative_block_path(path_name, grid_position,
+                data_attrs.get_block_size(zoom_level), data_attrs.get_voxel_offset(zoom_level),
+                data_attrs.get_dimensions(zoom_level))),
+        };
+
+        let js_grid_position = grid_position.iter()
+            .map(|&coord| JsValue::from_f64(coord as f64))
+            .collect::<js_sys::Array>();
+        let url = chunk_url.call3(&JsValue::NULL, &JsValue::from_str(path_name),
+                &js_grid_position, &JsValue::from(zoom_level as u32))
+            .map_err(convert_jsvalue_error)?;
+
+        url.as_string().ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+            format!("chunk_url returned {:?} instead of a string", url)))
+    }
+
+    fn get_dataset_attributes_path(&self, path_name: &str) -> String {
+        if path_name.is_empty() {
+            ATTRIBUTES_FILE.to_owned()
+        } else {
+            // There is only one top-level attribute file
+            format!("{}", ATTRIBUTES_FILE)
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl NgPreHTTPFetch {
+    pub fn open(base_path: &str) -> Promise {
+        NgPreHTTPFetchBuilder::new(base_path).open()
+    }
+
+    /// Abort all requests in flight, clear the shard index cache and make
+    /// every later call reject with a `disposed` error. This applies to all
+    /// readers wrapping this one or created from it, such as with
+    /// `with_signal`. Disposing a reader again does nothing.
+    pub fn dispose(&self) {
+        let in_flight = {
+            let mut lifecycle = self.lifecycle.borrow_mut();
+            if lifecycle.disposed {
+                return;
+            }
+            lifecycle.disposed = true;
+            std::mem::replace(&mut lifecycle.in_flight, HashMap::new())
+        };
+
+        // Aborting notifies listeners synchronously, so the lifecycle must
+        // not be borrowed here.
+        for controller in in_flight.values() {
+            controller.abort();
+        }
+        self.shard_indices.borrow_mut().clear();
+        self.in_flight_chunks.borrow_mut().clear();
+    }
+}
+
+/// Configures the requests of an `NgPreHTTPFetch` before opening it.
+#[wasm_bindgen]
+pub struct NgPreHTTPFetchBuilder {
+    reader: NgPreHTTPFetch,
+}
+
+#[wasm_bindgen]
+impl NgPreHTTPFetchBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(base_path: &str) -> NgPreHTTPFetchBuilder {
+        NgPreHTTPFetchBuilder {
+            reader: NgPreHTTPFetch {
+                base_path: base_path.into(),
+                signal: None,
+                headers: Vec::new(),
+                token_provider: None,
+                credentials: None,
+                cache_mode: None,
+                chunk_url: None,
+                url_map: None,
+                on_fetch_start: None,
+                on_fetch_end: None,
+                strict_content_type: false,
+                byte_order: raw::ByteOrder::Little,
+                info_gz_fallback: false,
+                verify_checksums: false,
+                refetch_on_decode_error: false,
+                max_retries: 0,
+                base_delay_ms: 100,
+                timeout_ms: 0,
+                transport: Transport::Network,
+                shard_indices: Rc::new(RefCell::new(
+                    IndexCache::new(sharded::DEFAULT_SHARD_INDEX_CACHE_BYTES))),
+                lifecycle: Rc::new(RefCell::new(Lifecycle::default())),
+                coalesce_gap_bytes: None,
+                pending_ranges: Rc::new(RefCell::new(HashMap::new())),
+                deduplicate_reads: true,
+                in_flight_chunks: Rc::new(RefCell::new(HashMap::new())),
+            },
+        }
+    }
+
+    /// Add a header to all requests, including info, chunk and shard
+    /// requests.
+    pub fn header(mut self, name: &str, value: &str) -> NgPreHTTPFetchBuilder {
+        self.reader.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Set a function returning a `Promise<string>` of a token, which is
+    /// called before every request and sent as `Authorization: Bearer`.
+    pub fn token_provider(mut self, provider: js_sys::Function) -> NgPreHTTPFetchBuilder {
+        self.reader.token_provider = Some(provider);
+        self
+    }
+
+    /// Set the credentials mode of requests: `"omit"`, `"same-origin"` or
+    /// `"include"`.
+    pub fn credentials(mut self, mode: &str) -> Result<NgPreHTTPFetchBuilder, JsValue> {
+        let credentials = match mode {
+            "omit" => RequestCredentials::Omit,
+            "same-origin" => RequestCredentials::SameOrigin,
+            "include" => RequestCredentials::Include,
+            _ => return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Unknown credentials mode: {}", mode)))),
+        };
+        self.reader.credentials = Some(credentials);
+        Ok(self)
+    }
+
+    /// Try `info.gz` when an info file is not found. Info files are
+    /// decompressed whenever they are gzip data, with or without this.
+    pub fn info_gz_fallback(mut self, fallback: bool) -> NgPreHTTPFetchBuilder {
+        self.reader.info_gz_fallback = fallback;
+        self
+    }
+
+    /// Verify the CRC32C of unsharded chunks where the server reports one,
+    /// rejecting with a `checksum_mismatch` error on failure. Only Google
+    /// Cloud Storage's `x-goog-hash` header is supported, which cross-origin
+    /// servers must list in `Access-Control-Expose-Headers`; chunks without a
+    /// checksum are not verified.
+    pub fn verify_checksums(mut self, verify: bool) -> NgPreHTTPFetchBuilder {
+        self.reader.verify_checksums = verify;
+        self
+    }
+
+    /// Fetch a chunk that fails to decode once more, bypassing the browser
+    /// cache with the request cache mode `reload`, before rejecting with a
+    /// `decode` error, for CDNs serving truncated chunks now and then.
+    /// Defaults to false.
+    pub fn refetch_on_decode_error(mut self, refetch: bool) -> NgPreHTTPFetchBuilder {
+        self.reader.refetch_on_decode_error = refetch;
+        self
+    }
+
+    /// Let concurrent reads of the same chunk share one fetched and decoded
+    /// block, which is only aborted once no read sharing it is waiting. Conditional
+    /// reads (with a known etag) are never shared. Defaults to true.
+    pub fn deduplicate_reads(mut self, deduplicate: bool) -> NgPreHTTPFetchBuilder {
+        self.reader.deduplicate_reads = deduplicate;
+        self
+    }
+
+    /// Set the byte order of `raw` chunks, for reading and writing:
+    /// `"little"` (the precomputed default) or `"big"`. Other encodings are
+    /// not affected.
+    pub fn endianness(mut self, endianness: &str) -> Result<NgPreHTTPFetchBuilder, JsValue> {
+        self.reader.byte_order = endianness.parse()
+            .map_err(|e| errors::to_js_error(&e))?;
+        Ok(self)
+    }
+
+    /// Call `on_fetch_start({method, url})` whenever a request starts,
+    /// before any retries. The callback must be cheap, as it runs
+    /// synchronously.
+    pub fn on_fetch_start(mut self, on_fetch_start: js_sys::Function) -> NgPreHTTPFetchBuilder {
+        self.reader.on_fetch_start = Some(on_fetch_start);
+        self
+    }
+
+    /// Call `on_fetch_end({method, url, duration_ms, bytes, status,
+    /// cache_hit, error})` whenever a request ends, including its retries.
+    /// `bytes` is the `Content-Length` of the response if known, `error` the
+    /// kind of error of a failed request, and `cache_hit` is set for `304 Not
+    /// Modified` responses and for blocks served by a `CachedReader`.
+    pub fn on_fetch_end(mut self, on_fetch_end: js_sys::Function) -> NgPreHTTPFetchBuilder {
+        self.reader.on_fetch_end = Some(on_fetch_end);
+        self
+    }
+
+    /// Read files from the URLs of `url_map`, a `Map` from their paths
+    /// (joined to the base path, which may be empty) to URLs, such as object
+    /// URLs of the files of a local directory. Files missing from the map
+    /// are not found.
+    ///
+    /// This takes the place of a base URL like `blob:...` for local files:
+    /// each object URL is an opaque ID of a single file, so paths cannot be
+    /// joined to one to reach the other files of a dataset.
+    pub fn url_map(mut self, url_map: &js_sys::Map) -> Result<NgPreHTTPFetchBuilder, JsValue> {
+        let mut urls = HashMap::new();
+        let mut invalid = None;
+        url_map.for_each(&mut |url, path| match (path.as_string(), url.as_string()) {
+            (Some(path), Some(url)) => { urls.insert(path.trim_start_matches('/').to_owned(), url); },
+            _ => invalid = Some(path),
+        });
+        if let Some(path) = invalid {
+            return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                format!("URL map entry {:?} is not a pair of strings", path))));
+        }
+
+        self.reader.url_map = Some(Rc::new(urls));
+        Ok(self)
+    }
+
+    /// Set a function `(path_name, grid_position, zoom_level) => url`
+    /// computing the URL of unsharded chunks, for stores not using the
+    /// `x0-x1_y0-y1_z0-z1` chunk names. The URL may be absolute or relative
+    /// to the base path. Shard files are still located by their standard
+    /// names.
+    pub fn chunk_url(mut self, chunk_url: js_sys::Function) -> NgPreHTTPFetchBuilder {
+        self.reader.chunk_url = Some(chunk_url);
+        self
+    }
+
+    /// Retry requests failing with a server error (5xx) or a network error up
+    /// to `max_retries` times. Defaults to 0.
+    pub fn max_retries(mut self, max_retries: u32) -> NgPreHTTPFetchBuilder {
+        self.reader.max_retries = max_retries;
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles for every further
+    /// retry. Defaults to 100 ms.
+    pub fn base_delay_ms(mut self, base_delay_ms: u32) -> NgPreHTTPFetchBuilder {
+        self.reader.base_delay_ms = base_delay_ms;
+        self
+    }
+
+    /// Reject chunks whose `Content-Type` disagrees with the encoding of
+    /// their scale (e.g. `image/jpeg` for `jpeg`, `application/octet-stream`
+    /// for `raw`) with an `encoding_mismatch` error. Defaults to false.
+    pub fn strict_content_type(mut self, strict: bool) -> NgPreHTTPFetchBuilder {
+        self.reader.strict_content_type = strict;
+        self
+    }
+
+    /// Abort each request, such as for a single chunk or shard index, that
+    /// has not received its whole response body within `timeout_ms`
+    /// milliseconds, rejecting with a `timeout` error. Defaults to 0, which
+    /// means no timeout.
+    pub fn timeout_ms(mut self, timeout_ms: u32) -> NgPreHTTPFetchBuilder {
+        self.reader.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Fetch chunks of a shard whose byte ranges are at most `gap_bytes`
+    /// apart with one range request, when they are read at the same time
+    /// (such as by `read_blocks`). Each read then waits for the next task
+    /// before its request is sent. By default every chunk is fetched on its
+    /// own.
+    pub fn coalesce_gap_bytes(mut self, gap_bytes: u32) -> NgPreHTTPFetchBuilder {
+        self.reader.coalesce_gap_bytes = Some(u64::from(gap_bytes));
+        self
+    }
+
+    /// Keep up to `bytes` of shard and minishard indices of sharded scales,
+    /// least recently used first out, so that reads from a shard whose
+    /// indices are cached only fetch the chunk. Defaults to 16 MiB.
+    pub fn shard_index_cache_bytes(mut self, bytes: usize) -> NgPreHTTPFetchBuilder {
+        self.reader.shard_indices = Rc::new(RefCell::new(IndexCache::new(bytes)));
+        self
+    }
+
+    pub fn open(self) -> Promise {
+        let reader = self.reader;
+
+        let to_return = NgPreAsyncReader::get_version(&reader).and_then(|version| {
+
+            if !ngpre::is_version_compatible(&ngpre::VERSION, &version) {
+                return future::err(Error::new(ErrorKind::Other, "TODO: Incompatible version"))
+            }
+
+            future::ok(JsValue::from(reader))
+        });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+}
+
+reader_delegations!(NgPreHTTPFetch);
+
+macro_rules! block_writer_delegations {
+    ($($method:ident, $d_name:ident;)*) => {
+        /// Delegations to expose NgPrePromiseWriter trait to WASM, one method
+        /// per block type.
+        #[wasm_bindgen]
+        impl NgPreHTTPFetch {
+            $(
+            pub fn $method(
+                &self,
+                path_name: &str,
+                data_attrs: &wrapped::DatasetAttributes,
+                block: $d_name,
+            ) -> Promise {
+                NgPrePromiseWriter::write_block(self, path_name, data_attrs, block.0)
+            }
+            )*
+        }
+    }
+}
+
+block_writer_delegations! {
+    write_block_uint8, VecDataBlockUINT8;
+    write_block_uint16, VecDataBlockUINT16;
+    write_block_uint32, VecDataBlockUINT32;
+    write_block_uint64, VecDataBlockUINT64;
+    write_block_int8, VecDataBlockINT8;
+    write_block_int16, VecDataBlockINT16;
+    write_block_int32, VecDataBlockINT32;
+    write_block_int64, VecDataBlockINT64;
+    write_block_float32, VecDataBlockFLOAT32;
+    write_block_float64, VecDataBlockFLOAT64;
+}
+
+impl AbortableReader for NgPreHTTPFetch {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        let mut reader = self.clone();
+        if signal.is_some() {
+            reader.signal = signal;
+        }
+        reader
+    }
+}
+
+impl NgPreAsyncReader for NgPreHTTPFetch {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        let to_return = self.get_attributes("")
+            .and_then(|_attr| {
+                Ok(ngpre::Version::from_str(&"2.3.0").unwrap())
+            });
+
+        Box::new(to_return)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+
+        let to_return = self.get_attributes(path_name)
+            .and_then(parse_dataset_attributes);
+
+        Box::new(to_return)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let to_return = self.fetch(path_name).map(|resp| resp.ok());
+
+        Box::new(to_return)
+    }
+
+    // Override the default NgPreAsyncReader impl to not require the GET on the
+    // dataset directory path to be 200.
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        Box::new(NgPreAsyncReader::get_dataset_attributes(self, path_name)
+            .then(|result| has_dataset_attributes(result.map(|_| ()))))
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+
+        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
+                self, path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, _etag)| block)))
+    }
+
+    // Override the default NgPreAsyncReader impl to only fetch the needed byte
+    // range of raw, unsharded, single channel blocks when it is contiguous.
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        let zoom_level = match zoom_level(data_attrs, path_name) {
+            Ok(zoom_level) => zoom_level,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        if let Err(error) = check_grid_ndim(data_attrs, zoom_level, &grid_position) {
+            return Box::new(future::err(error));
+        }
+        let block_size = data_attrs.get_block_size(zoom_level);
+        let dimensions = data_attrs.get_dimensions(zoom_level);
+
+        let offset_grid_position = match checked_grid_position(&grid_position, block_size, dimensions) {
+            Some(offset_grid_position) => offset_grid_position,
+            None => return Box::new(future::ok(None)),
+        };
+        let extent = raw::block_extent(&offset_grid_position, block_size, dimensions);
+        if let Err(error) = raw::check_box(&extent, &min, &max) {
+            return Box::new(future::err(error));
+        }
+
+        let is_raw = scale_encoding(data_attrs, zoom_level).map_or(false, |e| e == "raw");
+        let is_sharded = ShardingSpec::for_scale(data_attrs, zoom_level).map_or(true, |s| s.is_some());
+        // Elements stored in another type than they are read as cannot be
+        // fetched by their offset in the block.
+        let is_native = data_attrs.storage() == Storage::Native;
+        let range = if is_raw && is_native && !is_sharded && num_channels(data_attrs) == 1 {
+            raw::contiguous_range(&extent, &min, &max)
+        } else {
+            None
+        };
+
+        match range {
+            Some((start, end)) => {
+                let block_path = match self.block_path(path_name, data_attrs, zoom_level, &grid_position) {
+                    Ok(block_path) => block_path,
+                    Err(error) => return Box::new(future::err(error)),
+                };
+                let size: Vec<u32> = min.iter().zip(&max).map(|(&lo, &hi)| hi - lo).collect();
+                let byte_order = self.byte_order;
+
+                Box::new(self.fetch_range(&block_path, (start * T::SIZE) as u64, (end * T::SIZE) as u64)
+                    .and_then(move |maybe_bytes| match maybe_bytes {
+                        Some((bytes, _etag)) => raw::decode_ordered::<T>(&bytes, byte_order)
+                            .map(|data| Some(VecDataBlock::new(size.into(), offset_grid_position, data))),
+                        None => Ok(None),
+                    }))
+            },
+            None => read_block_cropped(self, path_name, data_attrs, grid_position, min, max),
+        }
+    }
+
+    // Override the default NgPreAsyncReader impl to not download the block.
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let zoom_level = match zoom_level(data_attrs, path_name) {
+            Ok(zoom_level) => zoom_level,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        if let Err(error) = check_grid_ndim(data_attrs, zoom_level, &grid_position) {
+            return Box::new(future::err(error));
+        }
+        let block_size = data_attrs.get_block_size(zoom_level);
+        let dimensions = data_attrs.get_dimensions(zoom_level);
+
+        let offset_grid_position = match checked_grid_position(&grid_position, block_size, dimensions) {
+            Some(offset_grid_position) => offset_grid_position,
+            None => return Box::new(future::ok(false)),
+        };
+
+        match ShardingSpec::for_scale(data_attrs, zoom_level) {
+            Ok(Some(spec)) => {
+                let chunk_id = sharded::compressed_morton_code(
+                    &offset_grid_position, &sharded::grid_shape(data_attrs, zoom_level));
+                Box::new(self.locate_sharded_chunk(path_name, &spec, chunk_id)
+                    .map(|maybe_chunk| maybe_chunk.is_some()))
+            },
+            Ok(None) => match self.block_path(path_name, data_attrs, zoom_level, &grid_position) {
+                Ok(block_path) => self.file_exists(&block_path),
+                Err(error) => Box::new(future::err(error)),
+            },
+            Err(error) => Box::new(future::err(error)),
+        }
+    }
+
+    // Remote paths are only listable if the server generates directory
+    // listings, see `parse_directory_listing`.
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        let path = format!("{}/", path_name.trim_end_matches('/'));
+        let to_return = self.fetch(&path).and_then(|resp| {
+            if resp.status() == 404 {
+                return future::Either::B(future::ok(Vec::new()));
+            }
+            let text = check_status(&resp)
+                .and_then(|_| resp.text().map_err(convert_jsvalue_error));
+
+            future::Either::A(future::result(text)
+                .and_then(|text| map_future_error_rust(JsFuture::from(text)))
+                .map(|text| parse_directory_listing(&text.as_string().unwrap_or_default())))
+        });
+
+        Box::new(to_return)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+
+        Box::new(self.get_attributes(path_name))
+    }
+}
+
+impl NgPreAsyncEtagReader for NgPreHTTPFetch {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let reader = self.clone();
+        let info_path = self.get_dataset_attributes_path(path_name);
+
+        let f = self.fetch_with_method("HEAD", &info_path)
+            .and_then(move |resp| -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+                if resp.ok() {
+                    Box::new(future::ok(resp.headers().get("ETag").unwrap_or(None)))
+                } else if reader.info_gz_fallback {
+                    Box::new(reader.fetch_with_method("HEAD", &format!("{}.gz", info_path))
+                        .map(|resp| if resp.ok() {
+                            resp.headers().get("ETag").unwrap_or(None)
+                        } else {
+                            None
+                        }))
+                } else {
+                    Box::new(future::ok(None))
+                }
+            });
+
+        Box::new(f)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        // Etags of sharded chunks are those of the whole shard.
+        let block_path = match self.chunk_file_path(path_name, data_attrs, &grid_position) {
+            Ok(Some(block_path)) => block_path,
+            Ok(None) => return Box::new(future::ok(None)),
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        let f = self.fetch_with_method("HEAD", &block_path)
+            .map(|resp| {
+                if resp.ok() {
+                    resp.headers().get("ETag").unwrap_or(None)
+                } else {
+                    None
+                }
+            });
+
+        Box::new(f)
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        Box::new(self.read_block_conditional::<T>(path_name, data_attrs, grid_position, None)
+            .map(|conditional| match conditional {
+                Conditional::Changed(maybe_block) => maybe_block,
+                Conditional::Unchanged => None,
+            }))
+    }
+
+    // Override the default NgPreAsyncEtagReader impl to let the server skip
+    // sending unchanged blocks.
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        self.read_block_conditional::<T>(path_name, data_attrs, grid_position, Some(known_etag))
+    }
+
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        Box::new(self.read_chunk_decoded::<T>(path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, etag, encoded_size)| (block, etag, Some(encoded_size)))))
+    }
+}
+
+impl NgPreHTTPFetch {
+    /// Read a block, unless it still has `known_etag`.
+    fn read_block_conditional<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: Option<&str>,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let known_etag = match known_etag {
+            Some(known_etag) => known_etag,
+            None => return Box::new(self.read_chunk_decoded::<T>(path_name, data_attrs, grid_position)
+                .map(|maybe_block| Conditional::Changed(maybe_block.map(|(block, etag, _)| (block, etag))))),
+        };
+        let reader = self.clone();
+        let (path_name, da2, grid_position2) = (path_name.to_owned(), data_attrs.clone(), grid_position.clone());
+
+        let f = self.fetch_chunk_if_changed(path_name.as_str(), data_attrs, grid_position, Some(known_etag))
+            .and_then(move |conditional| match conditional {
+                Conditional::Changed(Some(chunk)) => future::Either::A(
+                    reader.decode_chunk::<T>(&path_name, &da2, grid_position2, chunk)
+                        .map(|maybe_block| Conditional::Changed(maybe_block.map(|(block, etag, _)| (block, etag))))),
+                Conditional::Changed(None) => future::Either::B(future::ok(Conditional::Changed(None))),
+                Conditional::Unchanged => future::Either::B(future::ok(Conditional::Unchanged)),
+            });
+
+        Box::new(f)
+    }
+
+    /// Read and decode a chunk, with its etag and encoded size, sharing the
+    /// decoded block with concurrent reads of the chunk.
+    fn read_chunk_decoded<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, u32)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let (path_name2, da2) = (path_name.to_owned(), data_attrs.clone());
+        self.read_chunk_shared(path_name, data_attrs, grid_position.clone(), std::any::type_name::<T>(),
+            move |reader| {
+                let (reader2, path_name3, da3) = (reader.clone(), path_name2.clone(), da2.clone());
+                Box::new(reader.fetch_chunk_if_changed(&path_name2, &da2, grid_position.clone(), None)
+                    .and_then(move |conditional| match conditional {
+                        Conditional::Changed(Some(chunk)) => future::Either::A(
+                            reader2.decode_chunk::<T>(&path_name3, &da3, grid_position, chunk)),
+                        _ => future::Either::B(future::ok(None)),
+                    }))
+            })
+    }
+
+    /// Decode a fetched chunk, with its etag and encoded size. With
+    /// `refetch_on_decode_error`, a chunk failing to decode is fetched once
+    /// more bypassing caches, so that only a chunk failing twice is rejected
+    /// as corrupt.
+    fn decode_chunk<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        chunk: FetchedChunk,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, u32)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        if self.is_disposed() {
+            return Box::new(future::err(disposed_error()));
+        }
+        let byte_order = self.byte_order;
+        let decode = move |chunk: FetchedChunk, data_attrs: &DatasetAttributes| {
+            let encoded_size = chunk.bytes.len() as u32;
+            decode::decode_block::<T>(&chunk.bytes, data_attrs, chunk.zoom_level, chunk.grid_position, byte_order)
+                .map(|block| (block, chunk.etag, encoded_size))
+        };
+
+        match decode(chunk, data_attrs) {
+            Ok(decoded) => Box::new(future::ok(Some(decoded))),
+            Err(ref error) if self.refetch_on_decode_error && errors::kind_of(error) == errors::ErrorKind::Decode => {
+                let da2 = data_attrs.clone();
+                Box::new(self.bypassing_caches().fetch_chunk_if_changed(path_name, data_attrs, grid_position, None)
+                    .and_then(move |conditional| match conditional {
+                        Conditional::Changed(Some(chunk)) => decode(chunk, &da2).map(Some),
+                        // The chunk was removed in the meantime.
+                        _ => Ok(None),
+                    }))
+            },
+            Err(error) => Box::new(future::err(error)),
+        }
+    }
+
+    /// A copy of this reader whose requests bypass HTTP caches and are not
+    /// coalesced with those of other reads. The cache mode `reload` fetches
+    /// from the server without adding headers, which would need a CORS
+    /// preflight.
+    pub(crate) fn bypassing_caches(&self) -> Self {
+        let mut reader = self.clone();
+        reader.cache_mode = Some(RequestCache::Reload);
+        reader.coalesce_gap_bytes = None;
+        reader.pending_ranges = Rc::new(RefCell::new(HashMap::new()));
+        reader.deduplicate_reads = false;
+        reader
+    }
+}
+
+/// The undecoded bytes of a chunk, with the zoom level and the grid position
+/// (relative to the voxel offset) to decode them at.
+#[derive(Clone)]
+pub(crate) struct FetchedChunk {
+    pub bytes: Vec<u8>,
+    pub etag: Option<String>,
+    pub zoom_level: usize,
+    pub grid_position: GridCoord,
+}
+
+/// Where a chunk is fetched from: its zoom level, its grid position relative
+/// to the voxel offset, its shard and chunk ID if the scale is sharded, and
+/// the key its reads are shared by.
+struct ChunkLocation {
+    zoom_level: usize,
+    grid_position: GridCoord,
+    sharded_chunk: Option<(ShardingSpec, u64)>,
+    fetch_key: String,
+}
+
+impl NgPreHTTPFetch {
+    /// Locate a chunk, or `None` if it lies outside the scale.
+    fn locate_chunk(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: &UnboundedGridCoord,
+    ) -> Result<Option<ChunkLocation>, Error> {
+        let zoom_level = zoom_level(data_attrs, path_name)?;
+        check_grid_ndim(data_attrs, zoom_level, grid_position)?;
+        let block_size = data_attrs.get_block_size(zoom_level);
+        let dimensions = data_attrs.get_dimensions(zoom_level);
+
+        let offset_grid_position = match checked_grid_position(grid_position, block_size, dimensions) {
+            Some(offset_grid_position) => offset_grid_position,
+            None => return Ok(None),
+        };
+
+        // The `chunk_url` function is only called for unsharded chunks, so
+        // concurrent reads of sharded chunks are shared by their chunk ID.
+        let (sharded_chunk, fetch_key) = match ShardingSpec::for_scale(data_attrs, zoom_level)? {
+            Some(spec) => {
+                let chunk_id = sharded::compressed_morton_code(
+                    &offset_grid_position, &sharded::grid_shape(data_attrs, zoom_level));
+                (Some((spec, chunk_id)), format!("{}#chunk={}", path_name, chunk_id))
+            },
+            None => (None, self.block_path(path_name, data_attrs, zoom_level, grid_position)?),
+        };
+
+        Ok(Some(ChunkLocation {
+            zoom_level,
+            grid_position: offset_grid_position,
+            sharded_chunk,
+            fetch_key,
+        }))
+    }
+
+    /// Fetch the bytes of a chunk, sharded or not, unless its etag is still
+    /// `known_etag`.
+    pub(crate) fn fetch_chunk_if_changed(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: Option<&str>,
+    ) -> Box<dyn Future<Item = Conditional<Option<FetchedChunk>>, Error = Error>> {
+        let ChunkLocation { zoom_level, grid_position: offset_grid_position, sharded_chunk, fetch_key } =
+                match self.locate_chunk(path_name, data_attrs, &grid_position) {
+            Ok(Some(location)) => location,
+            Ok(None) => return Box::new(future::ok(Conditional::Changed(None))),
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        let bytes: Box<dyn Future<Item = Conditional<Option<(Vec<u8>, Option<String>)>>, Error = Error>> =
+                match sharded_chunk {
+            Some((spec, chunk_id)) => {
+                // Etags of sharded chunks are those of the whole shard, so
+                // they are only compared after fetching.
+                let known_etag = known_etag.map(str::to_owned);
+                Box::new(self.fetch_sharded_chunk(path_name, spec, chunk_id)
+                    .map(move |maybe_bytes| match &known_etag {
+                        Some(known_etag) => Conditional::unless_etag(maybe_bytes, known_etag),
+                        None => Conditional::Changed(maybe_bytes),
+                    }))
+            },
+            None => {
+                let expected_encoding = if self.strict_content_type {
+                    match scale_encoding(data_attrs, zoom_level) {
+                        Ok(encoding) => Some(encoding),
+                        Err(error) => return Box::new(future::err(error)),
+                    }
+                } else {
+                    None
+                };
+                self.fetch_bytes_if_changed(&fetch_key, known_etag, expected_encoding.as_ref().map(String::as_str),
+                    self.verify_checksums)
+            },
+        };
+
+        let f = bytes.map(move |conditional| match conditional {
+            Conditional::Changed(Some((bytes, etag))) => Conditional::Changed(Some(FetchedChunk {
+                bytes,
+                etag,
+                zoom_level,
+                grid_position: offset_grid_position,
+            })),
+            Conditional::Changed(None) => Conditional::Changed(None),
+            Conditional::Unchanged => Conditional::Unchanged,
+        });
+
+        Box::new(f)
+    }
+}
+
+impl NgPreHTTPFetch {
+    /// Read a chunk with `read`, sharing its result with concurrent reads
+    /// of the chunk whose result is of the same `kind`. `read` is given a
+    /// copy of this reader with the signal of the shared read. Aborting or
+    /// dropping a read only aborts the shared read if no other read is
+    /// waiting for it. Chunks outside the scale are `None` without reading.
+    pub(crate) fn read_chunk_shared<I, F>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        kind: &str,
+        read: F,
+    ) -> Box<dyn Future<Item = Option<I>, Error = Error>>
+            where I: Clone + 'static,
+                F: FnOnce(&NgPreHTTPFetch) -> Box<dyn Future<Item = Option<I>, Error = Error>>,
+    {
+        if !self.deduplicate_reads {
+            return read(self);
+        }
+        let key = match self.locate_chunk(path_name, data_attrs, &grid_position) {
+            Ok(Some(location)) => format!("{}#{}", location.fetch_key, kind),
+            Ok(None) => return Box::new(future::ok(None)),
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        let existing = self.in_flight_chunks.borrow().get(&key)
+            .map(|read| (read.result.clone(), read.controller.clone(), read.waiters.clone()));
+        let (result, controller, waiters) = match existing {
+            Some(existing) => existing,
+            None => {
+                let controller = match AbortController::new() {
+                    Ok(controller) => controller,
+                    Err(error) => return Box::new(future::err(convert_jsvalue_error(error))),
+                };
+                let mut reader = self.clone();
+                reader.signal = Some(controller.signal());
+                reader.deduplicate_reads = false;
+
+                let waiters = Rc::new(Cell::new(0));
+                let (in_flight_chunks, done_key, done_waiters) =
+                    (self.in_flight_chunks.clone(), key.clone(), waiters.clone());
+                let result: Box<dyn Future<Item = Rc<dyn Any>, Error = Error>> = Box::new(read(&reader)
+                    .map(|result| Rc::new(result) as Rc<dyn Any>)
+                    .then(move |result| {
+                        let mut in_flight_chunks = in_flight_chunks.borrow_mut();
+                        if in_flight_chunks.get(&done_key).map_or(false, |read| Rc::ptr_eq(&read.waiters, &done_waiters)) {
+                            in_flight_chunks.remove(&done_key);
+                        }
+                        result
+                    }));
+                let read = SharedRead {
+                    result: result.shared(),
+                    controller,
+                    waiters,
+                };
+                let existing = (read.result.clone(), read.controller.clone(), read.waiters.clone());
+                self.in_flight_chunks.borrow_mut().insert(key.clone(), read);
+                existing
+            },
+        };
+        waiters.set(waiters.get() + 1);
+        let waiter = SharedReadWaiter {
+            key,
+            waiters,
+            in_flight_chunks: self.in_flight_chunks.clone(),
+            controller,
+        };
+
+        let result = result
+            .map_err(|error| errors::copy_of(&error))
+            .and_then(|result| result.downcast_ref::<Option<I>>().cloned().ok_or_else(|| errors::new(
+                errors::ErrorKind::Other, "Shared chunk read has a result of another kind")));
+        let result: Box<dyn Future<Item = Option<I>, Error = Error>> = match &self.signal {
+            Some(signal) => Box::new(result.select2(aborted(signal)).then(|result| match result {
+                Ok(future::Either::A((result, _))) => Ok(result),
+                Err(future::Either::A((error, _))) => Err(error),
+                Ok(future::Either::B(((), _))) => unreachable!("`aborted` never resolves"),
+                Err(future::Either::B((error, _))) => Err(error),
+            })),
+            None => Box::new(result),
+        };
+
+        Box::new(result.then(move |result| {
+            drop(waiter);
+            result
+        }))
+    }
+}
+
+impl NgPreAsyncWriter for NgPreHTTPFetch {
+    fn write_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        block: VecDataBlock<T>,
+    ) -> Box<dyn Future<Item = (), Error = Error>>
+            where VecDataBlock<T>: DataBlock<T>,
+                T: ReflectedType + RawElement,
+    {
+        let zoom_level = match zoom_level(data_attrs, path_name) {
+            Ok(zoom_level) => zoom_level,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        match ShardingSpec::for_scale(data_attrs, zoom_level) {
+            Ok(None) => (),
+            Ok(Some(_)) => return Box::new(future::err(errors::new(
+                errors::ErrorKind::InvalidArgument, "Writing to sharded scales is not supported"))),
+            Err(error) => return Box::new(future::err(error)),
+        }
+        let block_size = data_attrs.get_block_size(zoom_level);
+        let dimensions = data_attrs.get_dimensions(zoom_level);
+
+        let grid_position: Vec<i64> = block.get_grid_position().iter().map(|&c| c as i64).collect();
+        if let Err(error) = check_grid_ndim(data_attrs, zoom_level, &grid_position) {
+            return Box::new(future::err(error));
+        }
+        if checked_grid_position(&grid_position, block_size, dimensions).is_none() {
+            return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Grid position {:?} is out of bounds", grid_position))));
+        }
+
+        let mut bytes = match encode::encode_block(&block, data_attrs, zoom_level) {
+            Ok(bytes) => bytes,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        if self.byte_order == raw::ByteOrder::Big {
+            match scale_encoding(data_attrs, zoom_level) {
+                Ok(ref encoding) if encoding == "raw" =>
+                    raw::swap_byte_order(&mut bytes, std::mem::size_of::<T>()),
+                Ok(_) => (),
+                Err(error) => return Box::new(future::err(error)),
+            }
+        }
+        let block_path = match self.block_path(path_name, data_attrs, zoom_level, &grid_position) {
+            Ok(block_path) => block_path,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let body = JsValue::from(js_sys::Uint8Array::from(&bytes[..]));
+
+        Box::new(self.fetch_with_body("PUT", &block_path,
+                &[("Content-Type", "application/octet-stream")], Some(&body))
+            .and_then(|resp| check_status(&resp)))
+    }
+}
+
+/// Segment meshes, whose directory is given by the `mesh` attribute of the
+/// dataset info.
+#[wasm_bindgen]
+impl NgPreHTTPFetch {
+    /// Resolve a segment to the locations of its mesh fragments, as
+    /// `{format, fragments: [{key, offset, size}]}` for legacy meshes or
+    /// `{format, chunk_shape, grid_origin, lod_scales, vertex_offsets,
+    /// vertex_quantization_bits, transform, lods: [{fragments: [{key,
+    /// offset, size, position}]}]}` for multi-resolution meshes. Resolves to
+    /// `null` for a segment without a mesh.
+    pub fn get_mesh_manifest(&self, path_name: &str, segment_id: &js_sys::BigInt) -> Promise {
+        let segment_id = match bigint_to_u64(segment_id) {
+            Ok(segment_id) => segment_id,
+            Err(error) => return errors::reject(error),
+        };
+        let reader = self.clone();
+
+        let to_return = self.get_subdirectory_info(path_name, "mesh")
+            .and_then(move |(mesh_dir, info)| reader.fetch_mesh_manifest(&mesh_dir, &info, segment_id))
+            .and_then(|maybe_manifest| match maybe_manifest {
+                Some(manifest) => JsValue::from_serde(&manifest)
+                    .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string())),
+                None => Ok(JsValue::NULL),
+            });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// Fetch a mesh fragment located by `get_mesh_manifest`, i.e. `size`
+    /// bytes at `offset` of the file `key`, or the whole file without a
+    /// size. Legacy fragments are decoded to `{positions: Float32Array,
+    /// indices: Uint32Array}` with three coordinates per vertex and three
+    /// indices per triangle. Resolves to `null` for a missing fragment and
+    /// rejects with `decode` for corrupt geometry.
+    ///
+    /// Only legacy meshes are read. For multi-resolution meshes this rejects
+    /// with `invalid_argument` without fetching anything.
+    pub fn get_mesh_fragment(
+        &self,
+        path_name: &str,
+        fragment_key: &str,
+        fragment_offset: f64,
+        fragment_size: Option<f64>,
+    ) -> Promise {
+        let reader = self.clone();
+        let fragment_key = fragment_key.to_owned();
+
+        let to_return = self.get_subdirectory_info(path_name, "mesh")
+            .and_then(|(_mesh_dir, info)| mesh::MeshFormat::of_info(&info))
+            .and_then(move |format| match format {
+                mesh::MeshFormat::Legacy => Ok(match fragment_size {
+                    Some(size) => reader.fetch_range(&fragment_key, fragment_offset as u64,
+                        (fragment_offset + size) as u64),
+                    None => reader.fetch_bytes(&fragment_key),
+                }),
+                mesh::MeshFormat::MultilodDraco => Err(errors::new(errors::ErrorKind::InvalidArgument,
+                    "Only fragments of legacy meshes can be read, not of neuroglancer_multilod_draco meshes")),
+            })
+            .and_then(|bytes| bytes)
+            .and_then(|maybe_bytes| -> Result<JsValue, Error> {
+                let bytes = match maybe_bytes {
+                    Some((bytes, _etag)) => bytes,
+                    None => return Ok(JsValue::NULL),
+                };
+                let (positions, indices) = mesh::decode_legacy_fragment(&bytes)?;
+
+                Ok(js_object(&[
+                    ("positions", js_sys::Float32Array::from(&positions[..]).into()),
+                    ("indices", js_sys::Uint32Array::from(&indices[..]).into()),
+                ]))
+            });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+}
+
+/// Skeletons, whose directory is given by the `skeletons` attribute of the
+/// dataset info.
+#[wasm_bindgen]
+impl NgPreHTTPFetch {
+    /// Fetch the skeleton of a segment as `{vertices: Float32Array, edges:
+    /// Uint32Array, ...attributes}`, with three coordinates per vertex, two
+    /// vertex indices per edge and an array for each of the
+    /// `vertex_attributes` of the skeleton info. Resolves to `null` for a
+    /// segment without a skeleton.
+    pub fn get_skeleton(&self, path_name: &str, segment_id: &js_sys::BigInt) -> Promise {
+        let segment_id = match bigint_to_u64(segment_id) {
+            Ok(segment_id) => segment_id,
+            Err(error) => return errors::reject(error),
+        };
+        let reader = self.clone();
+
+        let to_return = self.get_subdirectory_info(path_name, "skeletons")
+            .and_then(move |(skeleton_dir, info)| {
+                let attributes = skeleton::vertex_attributes(&info)?;
+                let bytes = match ShardingSpec::of_attributes(&info)? {
+                    Some(spec) => reader.fetch_sharded_chunk(&skeleton_dir, spec, segment_id),
+                    None => reader.fetch_bytes(&join_path(&skeleton_dir, &segment_id.to_string())),
+                };
+
+                Ok(bytes.and_then(move |maybe_bytes| match maybe_bytes {
+                    Some((bytes, _etag)) => skeleton::decode_skeleton(&bytes, &attributes),
+                    None => Ok(JsValue::NULL),
+                }))
+            })
+            .flatten();
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// Get the attributes of a dataset nested under a parent path, such as
+    /// an image layer in a subdirectory of another source, from the info at
+    /// `parent_path/subpath` (relative to the base path). Rejects with kind
+    /// `not_found` if there is no info there.
+    pub fn get_dataset_attributes_at(&self, parent_path: &str, subpath: &str) -> Promise {
+        let info_path = join_path(&join_path(parent_path, subpath), ATTRIBUTES_FILE);
+        let to_return = self.fetch_info(&info_path)
+            .and_then(parse_dataset_attributes)
+            .map(|da| JsValue::from(wrapped::DatasetAttributes(da)));
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// List the IDs of segments with a mesh (`kind` `"mesh"`, the default)
+    /// or skeleton (`"skeletons"`), as an array of `BigInt`s, stopping after
+    /// `limit` IDs. For sharded directories every shard index and minishard
+    /// index is read one after the other, which takes one request per shard
+    /// plus one per non-empty minishard. Unsharded directories are listed,
+    /// which requires a server generating directory indices.
+    pub fn list_segment_ids(&self, path_name: &str, limit: Option<u32>, kind: Option<String>) -> Promise {
+        let key = kind.unwrap_or_else(|| "mesh".to_owned());
+        if key != "mesh" && key != "skeletons" {
+            return errors::reject(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Unknown segment data kind: {}", key)));
+        }
+        let limit = limit.map_or(usize::max_value(), |limit| limit as usize);
+        let reader = self.clone();
+
+        let to_return = self.get_subdirectory_info(path_name, &key)
+            .and_then(move |(dir, info)| -> Box<dyn Future<Item = Vec<u64>, Error = Error>> {
+                match ShardingSpec::of_attributes(&info) {
+                    Ok(Some(spec)) => reader.list_sharded_ids(&dir, spec, limit),
+                    Ok(None) => Box::new(NgPreAsyncReader::list(&reader, &dir)
+                        .map(move |names| {
+                            let mut ids: Vec<u64> = names.iter()
+                                .filter_map(|name| name.trim_end_matches(":0").trim_end_matches(".index").parse().ok())
+                                .collect();
+                            ids.sort();
+                            ids.dedup();
+                            ids.truncate(limit);
+                            ids
+                        })),
+                    Err(error) => Box::new(future::err(error)),
+                }
+            })
+            .map(|ids| JsValue::from(ids.into_iter().map(RawElement::to_js).collect::<js_sys::Array>()));
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// The URL that `read_block` fetches for a chunk, without fetching it:
+    /// the shard file for sharded scales, and the result of `chunk_url` if
+    /// set. The URL is relative if the base path is. With a URL map, this is
+    /// the mapped URL, and files missing from the map reject with
+    /// `not_found`.
+    pub fn get_chunk_url(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Result<String, JsValue> {
+        self.chunk_url_of(path_name, &data_attrs.0, &grid_position)
+            .map_err(|e| errors::to_js_error(&e))
+    }
+
+    /// Load the shard index and all minishard indices of the shard holding
+    /// the chunk at `grid_position` into the shard index cache, so that later
+    /// reads from that shard only fetch chunk data. Resolves to the number of
+    /// chunks in the shard; rejects with `invalid_argument` for unsharded
+    /// scales.
+    pub fn load_shard_index(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+        let data_attrs = &data_attrs.0;
+        let located = zoom_level(data_attrs, path_name).and_then(|zoom_level| {
+            check_grid_ndim(data_attrs, zoom_level, &grid_position)?;
+            let offset_grid_position = checked_grid_position(&grid_position,
+                    data_attrs.get_block_size(zoom_level), data_attrs.get_dimensions(zoom_level))
+                .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+                    format!("Grid position {:?} is out of bounds", grid_position)))?;
+            let spec = ShardingSpec::for_scale(data_attrs, zoom_level)?
+                .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+                    format!("Scale {} is not sharded", path_name)))?;
+            let chunk_id = sharded::compressed_morton_code(
+                &offset_grid_position, &sharded::grid_shape(data_attrs, zoom_level));
+            let shard_path = join_path(path_name, &spec.shard_file_name(spec.locate(chunk_id).shard));
+            Ok((spec, shard_path))
+        });
+        let (spec, shard_path) = match located {
+            Ok(located) => located,
+            Err(error) => return errors::reject(error),
+        };
+
+        let minishard_indices = (0..1u64 << spec.minishard_bits)
+            .map(|minishard| self.minishard_index(&shard_path, &spec, minishard))
+            .collect::<Vec<_>>();
+        let to_return = future::join_all(minishard_indices).map(|minishard_indices| {
+            let num_chunks = minishard_indices.iter()
+                .map(|minishard_index| match &**minishard_index {
+                    CachedIndex::Minishard(index) => index.len(),
+                    CachedIndex::Shard(_) => 0,
+                })
+                .sum::<usize>();
+            JsValue::from(num_chunks as f64)
+        });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// Read a chunk of the scale at `zoom_level` (default 0) from `url`,
+    /// such as a presigned link, instead of its standard path. The URL may be
+    /// absolute or relative to the base path, and the reader's headers and
+    /// authentication apply. Resolves to `null` if the URL is not found.
+    pub fn read_block_from_url(
+        &self,
+        url: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        zoom_level: Option<usize>,
+    ) -> Promise {
+        let zoom_level = zoom_level.unwrap_or(0);
+        if zoom_level >= data_attrs.0.get_scales().len() {
+            return errors::reject(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Dataset has no scale {}", zoom_level)));
+        }
+        if let Err(error) = check_grid_ndim(&data_attrs.0, zoom_level, &grid_position) {
+            return errors::reject(error);
+        }
+        let offset_grid_position = match checked_grid_position(&grid_position,
+                data_attrs.0.get_block_size(zoom_level), data_attrs.0.get_dimensions(zoom_level)) {
+            Some(offset_grid_position) => offset_grid_position,
+            None => return errors::reject(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Grid position {:?} is out of bounds", grid_position))),
+        };
+        let expected_encoding = if self.strict_content_type {
+            match scale_encoding(&data_attrs.0, zoom_level) {
+                Ok(encoding) => Some(encoding),
+                Err(error) => return errors::reject(error),
+            }
+        } else {
+            None
+        };
+        let bytes = self.fetch_bytes_if_changed(url, None, expected_encoding.as_ref().map(String::as_str),
+            self.verify_checksums);
+        let byte_order = self.byte_order;
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            {
+                let da2 = data_attrs.0.clone();
+                let to_return = bytes.and_then(move |conditional| match conditional {
+                    Conditional::Changed(Some((buff, etag))) =>
+                        decode::decode_block::<RsType>(&buff, &da2, zoom_level, offset_grid_position, byte_order)
+                            .map(|block| JsValue::from(
+                                <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from((block, etag)))),
+                    _ => Ok(JsValue::NULL),
+                });
+
+                future_to_promise(map_future_error_wasm(to_return))
+            }
+        }
+    }
+}
+
+impl NgPreHTTPFetch {
+    /// Chunk IDs in the shards of a sharded directory, in shard and minishard
+    /// order, reading shards until `limit` IDs have been found.
+    fn list_sharded_ids(&self, dir: &str, spec: ShardingSpec, limit: usize)
+            -> Box<dyn Future<Item = Vec<u64>, Error = Error>> {
+        let reader = self.clone();
+        let dir = dir.to_owned();
+        let num_shards = 1u64 << spec.shard_bits;
+
+        Box::new(future::loop_fn((0u64, Vec::new()), move |(shard, ids)| {
+            if shard >= num_shards || ids.len() >= limit {
+                let mut ids: Vec<u64> = ids;
+                ids.truncate(limit);
+                return future::Either::B(future::ok(future::Loop::Break(ids)));
+            }
+
+            future::Either::A(reader.list_shard_ids(&dir, &spec, shard, ids, limit)
+                .map(move |ids| future::Loop::Continue((shard + 1, ids))))
+        }))
+    }
+
+    /// Append the chunk IDs of one shard to `ids`, reading its minishard
+    /// indices one after the other until there are `limit` IDs.
+    fn list_shard_ids(&self, dir: &str, spec: &ShardingSpec, shard: u64, ids: Vec<u64>, limit: usize)
+            -> Box<dyn Future<Item = Vec<u64>, Error = Error>> {
+        let reader = self.clone();
+        let spec = spec.clone();
+        let shard_path = join_path(dir, &spec.shard_file_name(shard));
+
+        Box::new(self.fetch_range(&shard_path, 0, spec.shard_index_size())
+            .and_then(move |maybe_index| -> Box<dyn Future<Item = Vec<u64>, Error = Error>> {
+                // A missing shard contains no chunks.
+                let shard_index = match maybe_index {
+                    Some((shard_index, _etag)) => shard_index,
+                    None => return Box::new(future::ok(ids)),
+                };
+                let index_ranges = match shard_index.chunks(16)
+                        .map(|entry| spec.minishard_index_range(entry))
+                        .collect::<Result<Vec<_>, Error>>() {
+                    Ok(index_ranges) => index_ranges.into_iter().flatten().collect::<Vec<_>>(),
+                    Err(error) => return Box::new(future::err(error)),
+                };
+
+                Box::new(future::loop_fn((index_ranges.into_iter(), ids), move |(mut index_ranges, mut ids)| {
+                    let (start, end) = match index_ranges.next() {
+                        Some(index_range) if ids.len() < limit => index_range,
+                        _ => return future::Either::B(future::ok(future::Loop::Break(ids))),
+                    };
+                    let spec = spec.clone();
+
+                    future::Either::A(reader.fetch_range(&shard_path, start, end)
+                        .and_then(move |maybe_index| {
+                            if let Some((minishard_index, _etag)) = maybe_index {
+                                ids.extend(spec.decode_minishard_index(minishard_index)?
+                                    .into_iter()
+                                    .map(|(id, _)| id));
+                            }
+                            Ok(future::Loop::Continue((index_ranges, ids)))
+                        }))
+                }))
+            }))
+    }
+
+    /// The directory named by the attribute `key` (e.g. `mesh`) of a dataset
+    /// info, with the info of that directory, which is `null` if it has none.
+    fn get_subdirectory_info(&self, path_name: &str, key: &str)
+            -> Box<dyn Future<Item = (String, serde_json::Value), Error = Error>> {
+        let reader = self.clone();
+        let path_name = path_name.to_owned();
+        let key = key.to_owned();
+
+        Box::new(self.get_attributes(&path_name).and_then(move |attrs| {
+            let dir = match attrs.get(&key).and_then(|dir| dir.as_str()) {
+                Some(dir) => join_path(&path_name, dir),
+                None => return future::Either::B(future::err(errors::new(errors::ErrorKind::BadAttrs,
+                    format!("Dataset has no {} directory", key)))),
+            };
+            let info_path = join_path(&dir, ATTRIBUTES_FILE);
+
+            future::Either::A(reader.fetch_info(&info_path).then(move |result| match result {
+                Ok(info) => Ok((dir, info)),
+                Err(ref error) if errors::kind_of(error) == errors::ErrorKind::NotFound =>
+                    Ok((dir, serde_json::Value::Null)),
+                Err(error) => Err(error),
+            }))
+        }))
+    }
+
+    fn fetch_mesh_manifest(&self, mesh_dir: &str, info: &serde_json::Value, segment_id: u64)
+            -> Box<dyn Future<Item = Option<serde_json::Value>, Error = Error>> {
+        let format = match mesh::MeshFormat::of_info(info) {
+            Ok(format) => format,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let spec = match ShardingSpec::of_attributes(info) {
+            Ok(spec) => spec,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let mesh_dir = mesh_dir.to_owned();
+        let info = info.clone();
+
+        match (format, spec) {
+            (mesh::MeshFormat::Legacy, None) => Box::new(
+                self.fetch_json(&join_path(&mesh_dir, &format!("{}:0", segment_id)))
+                    .then(move |result| -> Result<Option<serde_json::Value>, Error> { match result {
+                    Ok(json) => {
+                        let manifest: serde_json::Value = json.into_serde()
+                            .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string()))?;
+                        let fragments = mesh::legacy_fragments(&mesh_dir, &manifest)?;
+                        Ok(Some(serde_json::json!({
+                            "format": format.as_str(),
+                            "fragments": fragments,
+                        })))
+                    },
+                    Err(ref error) if errors::kind_of(error) == errors::ErrorKind::NotFound => Ok(None),
+                    Err(error) => Err(error),
+                }})),
+            // Sharded legacy meshes store the whole mesh as the chunk.
+            (mesh::MeshFormat::Legacy, Some(spec)) => Box::new(
+                self.locate_sharded_chunk(&mesh_dir, &spec, segment_id).map(move |maybe_chunk|
+                    maybe_chunk.map(|(shard_path, (start, end))| serde_json::json!({
+                        "format": format.as_str(),
+                        "fragments": [mesh::fragment_location(&shard_path, start, Some(end - start))],
+                    })))),
+            (mesh::MeshFormat::MultilodDraco, None) => Box::new(
+                self.fetch_bytes(&join_path(&mesh_dir, &format!("{}.index", segment_id))).and_then(move |maybe_bytes| {
+                    match maybe_bytes {
+                        Some((bytes, _etag)) => mesh::parse_multilod_manifest(&bytes).map(|manifest| Some(
+                            manifest.to_json(&info, &join_path(&mesh_dir, &segment_id.to_string()), 0))),
+                        None => Ok(None),
+                    }
+                })),
+            // The manifest is the chunk of the segment, and its fragments are
+            // stored right before it in the shard.
+            (mesh::MeshFormat::MultilodDraco, Some(spec)) => {
+                let reader = self.clone();
+                let data_encoding = spec.data_encoding;
+                Box::new(self.locate_sharded_chunk(&mesh_dir, &spec, segment_id)
+                    .and_then(move |maybe_chunk| -> Box<dyn Future<Item = Option<serde_json::Value>, Error = Error>> {
+                        let (shard_path, (start, end)) = match maybe_chunk {
+                            Some(chunk) => chunk,
+                            None => return Box::new(future::ok(None)),
+                        };
+
+                        Box::new(reader.fetch_range(&shard_path, start, end)
+                            .and_then(move |maybe_bytes| -> Result<Option<serde_json::Value>, Error> {
+                                let bytes = match maybe_bytes {
+                                    Some((bytes, _etag)) => data_encoding.decode(bytes)?,
+                                    None => return Ok(None),
+                                };
+                                let manifest = mesh::parse_multilod_manifest(&bytes)?;
+                                let data_start = start.checked_sub(manifest.data_size())
+                                    .ok_or_else(|| errors::new(errors::ErrorKind::Decode,
+                                        "Mesh fragments exceed the start of the shard"))?;
+
+                                Ok(Some(manifest.to_json(&info, &shard_path, data_start)))
+                            }))
+                    }))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_contiguous_ranges_into_one_request() {
+        let ranges = [(0, 100), (100, 250), (250, 300), (300, 400)];
+
+        assert_eq!(coalesced_spans(&ranges, 0, MAX_COALESCED_BYTES), vec![(0, 400, 4)]);
+    }
+
+    #[test]
+    fn coalesces_ranges_within_the_gap() {
+        let ranges = [(0, 100), (110, 200), (1000, 1100)];
+
+        assert_eq!(coalesced_spans(&ranges, 10, MAX_COALESCED_BYTES), vec![(0, 200, 2), (1000, 1100, 1)]);
+        assert_eq!(coalesced_spans(&ranges, 0, MAX_COALESCED_BYTES).len(), 3);
+    }
+
+    #[test]
+    fn caps_the_span_of_coalesced_ranges() {
+        let ranges = [(0, 100), (100, 200), (200, 300), (300, 1000)];
+
+        assert_eq!(coalesced_spans(&ranges, 0, 250), vec![(0, 200, 2), (200, 300, 1), (300, 1000, 1)]);
+    }
+
+    #[test]
+    fn joins_paths_with_one_slash() {
+        assert_eq!(join_path("https://example.com/data", "scale"), "https://example.com/data/scale");
+        assert_eq!(join_path("https://example.com/data/", "scale"), "https://example.com/data/scale");
+        assert_eq!(join_path("https://example.com/data", "/scale"), "https://example.com/data/scale");
+        assert_eq!(join_path("https://example.com/data//", "//scale"), "https://example.com/data/scale");
+    }
+
+    #[test]
+    fn joins_empty_components() {
+        assert_eq!(join_path("https://example.com/data/", ""), "https://example.com/data/");
+        assert_eq!(join_path("", "/scale"), "scale");
+    }
+
+    #[test]
+    fn joins_base_dataset_scale_and_chunk_key() {
+        let dataset = join_path("https://example.com/", "/datasets/brain/");
+        let scale = join_path(&dataset, "/4_4_40/");
+
+        assert_eq!(join_path(&scale, "0-64_0-64_0-8"), "https://example.com/datasets/brain/4_4_40/0-64_0-64_0-8");
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_gzip_bodies_by_their_magic_number() {
+        let chunk: Vec<u8> = (0..=255).collect();
+
+        assert_eq!(decode_content_encoding(gzip(&chunk), Some("gzip".to_owned()), None).unwrap(), chunk);
+        // The `Content-Encoding` of cross-origin responses may be hidden.
+        assert_eq!(decode_content_encoding(gzip(&chunk), None, None).unwrap(), chunk);
+    }
+
+    #[test]
+    fn keeps_bodies_the_browser_already_decoded() {
+        let chunk: Vec<u8> = (0..=255).collect();
+        let encoded_length = gzip(&chunk).len();
+
+        assert_eq!(decode_content_encoding(chunk.clone(), Some("gzip".to_owned()), Some(encoded_length)).unwrap(), chunk);
+        assert_eq!(decode_content_encoding(chunk.clone(), Some("br".to_owned()), Some(encoded_length)).unwrap(), chunk);
+    }
+
+    #[test]
+    fn keeps_bodies_that_only_look_like_gzip() {
+        let chunk = vec![0x1f, 0x8b, 0, 1, 2, 3];
+
+        assert_eq!(decode_content_encoding(chunk.clone(), None, None).unwrap(), chunk);
+    }
+
+    #[test]
+    fn parses_gzip_compressed_info() {
+        let info = br#"{"type": "image", "data_type": "uint8", "num_channels": 1, "scales": []}"#;
+
+        assert_eq!(parse_info(&gzip(info)).unwrap(), serde_json::from_slice::<serde_json::Value>(info).unwrap());
+        assert_eq!(parse_info(info).unwrap()["data_type"], "uint8");
+        assert_eq!(errors::kind_of(&parse_info(b"{").unwrap_err()), errors::ErrorKind::BadAttrs);
+    }
+}
+
@@ -6,6 +6,17 @@ use wasm_bindgen;
 use wasm_bindgen_futures;
 use web_sys;
 
+mod checksum;
+mod compressed_segmentation;
+mod decode;
+mod downsample;
+mod encode;
+mod mesh;
+mod raw;
+mod render;
+mod sharded;
+mod skeleton;
+mod transform;
 mod utils;
 
 use std::io::{
@@ -16,13 +27,30 @@ use std::io::{
 use js_sys::Promise;
 use futures::{future, Future};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::future_to_promise;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{
+    future_to_promise,
+    JsFuture,
+};
+use web_sys::AbortSignal;
 
 use ngpre::prelude::*;
 use ngpre::{data_type_match, data_type_rstype_replace};
 
+use raw::RawElement;
+
 
+#[macro_use]
+mod delegations;
+
+pub mod bounded;
+pub mod cache;
+pub mod errors;
 pub mod http_fetch;
+pub mod persistent;
+pub mod priority;
+pub mod recording;
+pub mod worker;
 
 
 pub trait NgPrePromiseReader {
@@ -42,10 +70,193 @@ pub trait NgPrePromiseReader {
         grid_position: Vec<i64>,
     ) -> Promise;
 
+    /// Read many blocks at once. Resolves to an array in the same order as
+    /// `grid_positions`, with `null` for missing blocks.
+    fn read_blocks(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_positions: Vec<Vec<i64>>,
+    ) -> Promise;
+
+    /// Read a block and copy its data into `dst` starting at element `offset`
+    /// (in elements of the dataset's data type, stored little-endian).
+    /// Resolves to the number of elements written, or `null` for a missing
+    /// block.
+    fn read_block_into(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        dst: js_sys::Uint8Array,
+        offset: u32,
+    ) -> Promise;
+
+    /// Read the part of a block within the box `[min, max)`, in voxels
+    /// relative to the block origin. The resolved block's `get_size()` is
+    /// the cropped extent.
+    fn read_block_subregion(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Promise;
+
+    /// Read a block from the scale whose resolution best matches
+    /// `voxel_resolution`, resolving to `{block, zoom_level}`.
+    ///
+    /// A scale matches if each axis of its resolution is within
+    /// `RESOLUTION_TOLERANCE` (relative) of the requested one. The scale is
+    /// chosen from `data_attrs`, so `path_name` is only the dataset path.
+    fn read_block_at_resolution(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        voxel_resolution: Vec<f64>,
+    ) -> Promise;
+
+    /// Read a block and split it into one single-channel block per channel.
+    /// Precomputed stores channels as the slowest varying axis, so each of
+    /// the returned planes is in the block's spatial (Fortran) order.
+    /// Resolves to `null` for a missing block.
+    fn read_block_channels(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    /// Read a block as a `VecDataBlockFLOAT32` normalized by the range of
+    /// the dataset's data type: unsigned integers are divided by their
+    /// maximum into `[0, 1]`, signed integers are divided by their maximum
+    /// and clamped to `[-1, 1]` (so both the minimum and `-max` map to -1),
+    /// and floats are passed through unchanged. Resolves to `null` for a
+    /// missing block.
+    fn read_block_normalized(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    /// Read a block like `read_block`, but resolve missing blocks to a
+    /// block filled with `fill_value` (converted to the dataset's data type).
+    /// The filled block has the extent a stored block would have, i.e. the
+    /// block size clipped to the dataset dimensions.
+    fn read_block_or_fill(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        fill_value: f64,
+    ) -> Promise;
+
+    /// Read a block with its data reordered into `layout`, which lists the
+    /// axes `x`, `y`, `z` and the channel axis `c` from fastest to slowest
+    /// varying. `"xyzc"` is the stored order and `"cxyz"` interleaves the
+    /// channels, as for a `texImage3D` upload. The block's size stays that of
+    /// its spatial axes. Resolves to `null` for a missing block.
+    fn read_block_packed(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        layout: &str,
+    ) -> Promise;
+
+    /// Check whether a block exists without downloading it.
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    /// Read the voxel box of `size` at `origin`, which includes the voxel
+    /// offset like `get_bounds`, stitched together from all blocks
+    /// overlapping it. Voxels outside the dataset or in missing blocks are
+    /// zero. The grid position of the result is that of the block containing
+    /// `origin`.
+    ///
+    /// With `pad_to_pot`, the block is zero-padded to the next power of two
+    /// along each axis, and the promise resolves to `{block, valid_size}`,
+    /// where `valid_size` is the requested `size` at the block's origin.
+    fn read_tile(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        origin: Vec<i64>,
+        size: Vec<u32>,
+        pad_to_pot: Option<bool>,
+    ) -> Promise;
+
+    /// Read the plane at `index` (including the voxel offset) along `axis`
+    /// of a 3D scale as a 2D block, whose size is the size of the scale
+    /// along the other two axes, stitched together from all blocks
+    /// intersecting the plane. Voxels outside the dataset or in missing
+    /// blocks are zero.
+    fn read_slice(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        axis: u32,
+        index: i64,
+    ) -> Promise;
+
+    /// Render a thumbnail of the dataset from its coarsest scale: the
+    /// central plane along `z`, downsampled to fit within `max_dim` pixels
+    /// and windowed from its minimum to its maximum value. Resolves to an
+    /// `ImageData`, and rejects if the plane spans more than
+    /// `MAX_THUMBNAIL_CHUNKS` chunks. The scales are taken from
+    /// `data_attrs`, so `path_name` is only the dataset path.
+    fn read_thumbnail(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        max_dim: u32,
+    ) -> Promise;
+
+    /// Read the value of the voxel at `voxel`, which includes the voxel
+    /// offset like `get_bounds`: a number (a `BigInt` for 64 bit integer
+    /// types), or an array with a value per channel for multi-channel
+    /// datasets. Resolves to `null` for voxels outside the dataset or in
+    /// missing blocks.
+    fn read_voxel(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        voxel: Vec<i64>,
+    ) -> Promise;
+
+    /// Read the block at `grid_position` of scale `preferred_zoom`, falling
+    /// back to the blocks containing its center at each coarser scale (higher
+    /// zoom level) in turn while blocks are missing. Resolves to `{block,
+    /// zoom_level}` for the first block found, or `null` if no scale has one.
+    /// The scales are taken from `data_attrs`, so `path_name` is only the
+    /// dataset path.
+    fn read_block_best_effort(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        preferred_zoom: usize,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    /// Get the raw attributes of the `info` file. Prefer `get_info`, which
+    /// has getters for the common keys.
     fn list_attributes(&self, path_name: &str) -> Promise;
+
+    /// Get the attributes of the `info` file as a `DatasetInfo`.
+    fn get_info(&self, path_name: &str) -> Promise;
 }
 
-impl<T> NgPrePromiseReader for T where T: NgPreAsyncReader {
+impl<T> NgPrePromiseReader for T where T: NgPreAsyncReader + Clone + 'static {
     fn get_version(&self) -> Promise {
         let to_return = self.get_version()
             .map(|v| JsValue::from(wrapped::Version(v)));
@@ -90,135 +301,2593 @@ impl<T> NgPrePromiseReader for T where T: NgPreAsyncReader {
         }
     }
 
+    fn read_blocks(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_positions: Vec<Vec<i64>>,
+    ) -> Promise {
+        join_blocks(
+            grid_positions.into_iter().map(|grid_position| (self, grid_position)),
+            path_name,
+            data_attrs,
+            None,
+            false,
+            false)
+    }
+
+    fn read_block_into(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        dst: js_sys::Uint8Array,
+        offset: u32,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .and_then(move |maybe_block| match maybe_block {
+                        Some(block) => copy_into_bytes(block.get_data(), &dst, offset)
+                            .map(JsValue::from),
+                        None => Ok(JsValue::NULL),
+                    })))
+        }
+    }
+
+    fn read_block_subregion(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block_subregion::<RsType>(
+                        path_name, &data_attrs.0, grid_position.into(), min, max)
+                    .map(|maybe_block| JsValue::from(
+                        maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))))
+        }
+    }
+
+    fn read_block_at_resolution(
+        &self,
+        _path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        voxel_resolution: Vec<f64>,
+    ) -> Promise {
+
+        let zoom_level = match matching_scale(&data_attrs.0, &voxel_resolution) {
+            Ok(zoom_level) => zoom_level,
+            Err(error) => return errors::reject(error),
+        };
+        let scale_key = data_attrs.0.get_scales()[zoom_level].key.clone();
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(&scale_key, &data_attrs.0, grid_position.into())
+                    .map(move |maybe_block| js_object(&[
+                        ("block", JsValue::from(
+                            maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from))),
+                        ("zoom_level", JsValue::from(zoom_level as u32)),
+                    ]))))
+        }
+    }
+
+    fn read_block_channels(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .and_then(|maybe_block| match maybe_block {
+                        Some(block) => split_channels(&block).map(|channels| JsValue::from(channels.into_iter()
+                            .map(|channel| JsValue::from(
+                                <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(channel)))
+                            .collect::<js_sys::Array>())),
+                        None => Ok(JsValue::NULL),
+                    })))
+        }
+    }
+
+    fn read_block_normalized(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .map(|maybe_block| match maybe_block {
+                        Some(block) => JsValue::from(
+                            <f32 as VecBlockMonomorphizerReflection>::MONOMORPH::from(normalize_block(&block))),
+                        None => JsValue::NULL,
+                    })))
+        }
+    }
+
+    fn read_block_packed(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        layout: &str,
+    ) -> Promise {
+        let ndim = zoom_level(&data_attrs.0, path_name)
+            .map(|zoom_level| data_attrs.0.get_ndim(zoom_level));
+        let order = match ndim.and_then(|ndim| parse_layout(layout, ndim)) {
+            Ok(order) => order,
+            Err(error) => return errors::reject(error),
+        };
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .and_then(move |maybe_block| match maybe_block {
+                        Some(block) => pack_block(block, &order).map(|block| JsValue::from(
+                            <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block))),
+                        None => Ok(JsValue::NULL),
+                    })))
+        }
+    }
+
+    fn read_block_or_fill(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        fill_value: f64,
+    ) -> Promise {
+
+        let zoom_level = match zoom_level(&data_attrs.0, path_name) {
+            Ok(zoom_level) => zoom_level,
+            Err(error) => return errors::reject(error),
+        };
+        if let Err(error) = check_grid_ndim(&data_attrs.0, zoom_level, &grid_position) {
+            return errors::reject(error);
+        }
+        let block_size = data_attrs.0.get_block_size(zoom_level);
+        let dimensions = data_attrs.0.get_dimensions(zoom_level);
+        // Positions out of bounds still resolve to `null`.
+        let fill = checked_grid_position(&grid_position, block_size, dimensions)
+            .map(|offset_grid_position| {
+                let size = raw::block_extent(&offset_grid_position, block_size, dimensions);
+                let num_elements = size.iter().map(|&s| s as usize).product::<usize>()
+                    * num_channels(&data_attrs.0) as usize;
+                (size, offset_grid_position, num_elements)
+            });
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .map(move |maybe_block| JsValue::from(maybe_block
+                        .or_else(|| fill.map(|(size, offset_grid_position, num_elements)| VecDataBlock::new(
+                            size.into(),
+                            offset_grid_position,
+                            vec![RsType::from_f64(fill_value); num_elements])))
+                        .map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))))
+        }
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+        let to_return = self.block_exists(path_name, &data_attrs.0, grid_position.into())
+            .map(JsValue::from);
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    fn read_tile(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        origin: Vec<i64>,
+        size: Vec<u32>,
+        pad_to_pot: Option<bool>,
+    ) -> Promise {
+        let channels = num_channels(&data_attrs.0) as usize;
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_tile::<_, RsType>(self, path_name, &data_attrs.0, zoom_level, origin, size.clone(), None)
+                    .map(move |block| if pad_to_pot.unwrap_or(false) {
+                        js_object(&[
+                            ("block", JsValue::from(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(
+                                pad_to_power_of_two(block, channels)))),
+                            ("valid_size", JsValue::from_serde(&size).unwrap()),
+                        ])
+                    } else {
+                        JsValue::from(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block))
+                    })))
+        }
+    }
+
+    fn read_slice(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        axis: u32,
+        index: i64,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_slice::<_, RsType>(self, path_name, &data_attrs.0, zoom_level, axis, index)
+                    .map(|block| JsValue::from(
+                        <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))))
+        }
+    }
+
+    fn read_thumbnail(
+        &self,
+        _path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        max_dim: u32,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_thumbnail::<_, RsType>(self, &data_attrs.0, max_dim)
+                    .and_then(|(mut pixels, width, height)| web_sys::ImageData::new_with_u8_clamped_array_and_sh(
+                            wasm_bindgen::Clamped(&mut pixels[..]), width, height)
+                        .map(JsValue::from)
+                        .map_err(errors::from_js_error))))
+        }
+    }
+
+    fn read_voxel(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        zoom_level: usize,
+        voxel: Vec<i64>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_voxel::<_, RsType>(self, path_name, &data_attrs.0, zoom_level, voxel)))
+        }
+    }
+
+    fn read_block_best_effort(
+        &self,
+        _path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        preferred_zoom: usize,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                read_block_best_effort::<_, RsType>(self, &data_attrs.0, preferred_zoom, grid_position)
+                    .map(|found| match found {
+                        Some((block, zoom_level)) => js_object(&[
+                            ("block", JsValue::from(
+                                <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block))),
+                            ("zoom_level", JsValue::from(zoom_level as u32)),
+                        ]),
+                        None => JsValue::NULL,
+                    })))
+        }
+    }
+
     fn list_attributes(
         &self,
         path_name: &str,
     ) -> Promise {
 
-        // TODO: Superfluous conversion from JSON to JsValue to serde to JsValue.
-        let to_return = self.list_attributes(path_name)
-            .map(|v| JsValue::from_serde(&v).unwrap());
+        // TODO: Superfluous conversion from JSON to JsValue to serde to JsValue.
+        let to_return = self.list_attributes(path_name)
+            .and_then(|v| JsValue::from_serde(&v)
+                .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string())));
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    fn get_info(&self, path_name: &str) -> Promise {
+        let to_return = self.list_attributes(path_name)
+            .map(|attrs| JsValue::from(wrapped::DatasetInfo(attrs)));
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+}
+
+
+pub trait NgPrePromiseEtagReader {
+    /// Resolves to the etag of the dataset's attributes, or `null`, to tell
+    /// whether attributes cached by the caller are outdated.
+    fn attributes_etag(&self, path_name: &str) -> Promise;
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    fn read_block_with_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise;
+
+    /// Read a block unless its etag is still `known_etag`, in which case
+    /// resolve to `{unchanged: true}`. Otherwise resolves like
+    /// `read_block_with_etag`.
+    fn read_block_if_changed(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        known_etag: &str,
+    ) -> Promise;
+}
+
+impl<T> NgPrePromiseEtagReader for T where T: NgPreAsyncEtagReader {
+    fn attributes_etag(&self, path_name: &str) -> Promise {
+        let to_return = self.attributes_etag(path_name)
+            .map(JsValue::from);
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+        let to_return = self.block_etag(path_name, &data_attrs.0, grid_position.into())
+            .map(JsValue::from);
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    fn read_block_with_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block_with_etag::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                    .map(|maybe_block| JsValue::from(
+                        maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))))
+        }
+    }
+
+    fn read_block_if_changed(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        known_etag: &str,
+    ) -> Promise {
+
+        data_type_match! {
+            data_attrs.0.get_data_type(),
+            future_to_promise(map_future_error_wasm(
+                self.read_block_if_changed::<RsType>(
+                        path_name, &data_attrs.0, grid_position.into(), known_etag)
+                    .map(|conditional| match conditional {
+                        Conditional::Unchanged => js_object(&[("unchanged", JsValue::TRUE)]),
+                        Conditional::Changed(maybe_block) => JsValue::from(
+                            maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)),
+                    })))
+        }
+    }
+}
+
+
+/// Read blocks for each pair of reader and grid position concurrently,
+/// resolving to an array of blocks (or `null`s) in iteration order.
+///
+/// With `tolerant`, failed reads do not reject the batch, which instead
+/// resolves to `{blocks, errors}` where failed blocks are `null` and `errors`
+/// has an `{index, kind, message}` entry per failure.
+fn join_blocks<'a, R, I>(
+    reads: I,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    on_progress: Option<js_sys::Function>,
+    transferable: bool,
+    tolerant: bool,
+) -> Promise
+        where R: NgPreAsyncReader + 'a,
+            I: IntoIterator<Item = (&'a R, Vec<i64>)>,
+{
+    let reads: Vec<_> = reads.into_iter().collect();
+    let progress = Progress::new(on_progress, reads.len());
+    let positions = if transferable {
+        Some(reads.iter().map(|(_, grid_position)| grid_position.clone()).collect::<Vec<_>>())
+    } else {
+        None
+    };
+    let dtype = data_attrs.0.get_data_type().to_string();
+
+    data_type_match! {
+        data_attrs.0.get_data_type(),
+        {
+            let blocks = reads.into_iter()
+                .map(|(reader, grid_position)| {
+                    let progress = progress.clone();
+                    reader.read_block::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                        .then(move |result| {
+                            progress.complete();
+                            result
+                        })
+                });
+            let to_js = move |maybe_blocks: Vec<Option<VecDataBlock<RsType>>>| match positions {
+                Some(positions) => transferable_blocks(positions, maybe_blocks, &dtype),
+                None => JsValue::from(maybe_blocks.into_iter()
+                    .map(|maybe_block| JsValue::from(
+                        maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))
+                    .collect::<js_sys::Array>()),
+            };
+
+            if tolerant {
+                let results = blocks.map(|block| block.then(|result| -> Result<_, Error> { Ok(result) }));
+
+                future_to_promise(map_future_error_wasm(
+                    future::join_all(results)
+                        .map(move |results| {
+                            let failures = js_sys::Array::new();
+                            let maybe_blocks = results.into_iter()
+                                .enumerate()
+                                .map(|(index, result)| result.unwrap_or_else(|error| {
+                                    failures.push(&js_object(&[
+                                        ("index", JsValue::from(index as u32)),
+                                        ("kind", JsValue::from_str(errors::kind_of(&error).as_str())),
+                                        ("message", JsValue::from_str(&errors::message_of(&error))),
+                                    ]));
+                                    None
+                                }))
+                                .collect();
+                            tolerant_blocks(to_js(maybe_blocks), failures, transferable)
+                        })))
+            } else {
+                future_to_promise(map_future_error_wasm(future::join_all(blocks).map(to_js)))
+            }
+        }
+    }
+}
+
+/// Add the `errors` of a tolerant batch to its result: as a property of the
+/// `transferable` object, or next to the array of blocks as
+/// `{blocks, errors}`.
+fn tolerant_blocks(blocks: JsValue, failures: js_sys::Array, transferable: bool) -> JsValue {
+    if transferable {
+        // Setting a property on a fresh, extensible object cannot fail.
+        let _ = js_sys::Reflect::set(&blocks, &JsValue::from_str("errors"), &failures);
+        blocks
+    } else {
+        js_object(&[("blocks", blocks), ("errors", failures.into())])
+    }
+}
+
+/// Batch result as `{positions, buffers, sizes, dtype}`, with the data of
+/// each block in its own `ArrayBuffer` (or `null` for missing blocks) for the
+/// transfer list of `postMessage`.
+fn transferable_blocks<T>(
+    positions: Vec<Vec<i64>>,
+    maybe_blocks: Vec<Option<VecDataBlock<T>>>,
+    dtype: &str,
+) -> JsValue
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let buffers = js_sys::Array::new();
+    let sizes = js_sys::Array::new();
+    for maybe_block in maybe_blocks {
+        match maybe_block {
+            Some(block) => {
+                sizes.push(&JsValue::from_serde(block.get_size()).unwrap());
+                let data = block.into_data();
+                let bytes = js_sys::Uint8Array::new_with_length((data.len() * std::mem::size_of::<T>()) as u32);
+                copy_into_bytes(&data, &bytes, 0).unwrap();
+                buffers.push(&bytes.buffer());
+            },
+            None => {
+                sizes.push(&JsValue::NULL);
+                buffers.push(&JsValue::NULL);
+            },
+        }
+    }
+
+    js_object(&[
+        ("positions", JsValue::from_serde(&positions).unwrap()),
+        ("buffers", buffers.into()),
+        ("sizes", sizes.into()),
+        ("dtype", JsValue::from_str(dtype)),
+    ])
+}
+
+
+/// Reports completed reads of a batch to an `on_progress(completed, total)`
+/// callback.
+#[derive(Clone)]
+struct Progress {
+    on_progress: Option<js_sys::Function>,
+    completed: std::rc::Rc<std::cell::Cell<usize>>,
+    total: usize,
+}
+
+impl Progress {
+    fn new(on_progress: Option<js_sys::Function>, total: usize) -> Self {
+        Progress {
+            on_progress,
+            completed: std::rc::Rc::new(std::cell::Cell::new(0)),
+            total,
+        }
+    }
+
+    fn complete(&self) {
+        let on_progress = match &self.on_progress {
+            Some(on_progress) => on_progress,
+            None => return,
+        };
+        let completed = self.completed.get() + 1;
+        if completed > self.total {
+            return;
+        }
+        self.completed.set(completed);
+        // Exceptions thrown by the callback must not fail the batch.
+        let _ = on_progress.call2(&JsValue::NULL,
+            &JsValue::from(completed as u32), &JsValue::from(self.total as u32));
+    }
+}
+
+
+pub trait NgPrePromiseWriter {
+    /// Encode a block with the encoding of its scale and store it. Resolves
+    /// to `undefined` once the block is written.
+    fn write_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        block: VecDataBlock<T>,
+    ) -> Promise
+            where VecDataBlock<T>: DataBlock<T>,
+                T: ReflectedType + RawElement;
+}
+
+impl<W> NgPrePromiseWriter for W where W: NgPreAsyncWriter {
+    fn write_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        block: VecDataBlock<T>,
+    ) -> Promise
+            where VecDataBlock<T>: DataBlock<T>,
+                T: ReflectedType + RawElement,
+    {
+        let to_return = self.write_block(path_name, &data_attrs.0, block)
+            .map(|()| JsValue::UNDEFINED);
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+}
+
+
+/// Readers whose requests can be bound to an `AbortSignal`.
+pub(crate) trait AbortableReader: Sized {
+    /// A copy of this reader whose requests are aborted when `signal` fires.
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self;
+}
+
+/// Batch read where `signals` is either a single `AbortSignal` shared by all
+/// blocks, an array with one `AbortSignal` (or `null`) per grid position, or
+/// `undefined`.
+///
+/// With `transferable`, resolves to `{positions, buffers, sizes, dtype}`
+/// rather than an array of blocks (see `transferable_blocks`), and with
+/// `tolerant` failed reads are reported rather than rejecting the batch (see
+/// `join_blocks`).
+fn read_blocks_abortable<R>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    grid_positions: &JsValue,
+    signals: JsValue,
+    on_progress: Option<js_sys::Function>,
+    transferable: bool,
+    tolerant: bool,
+) -> Promise
+        where R: NgPreAsyncReader + AbortableReader,
+{
+    let grid_positions = match convert_grid_positions(grid_positions) {
+        Ok(grid_positions) => grid_positions,
+        Err(error) => return errors::reject(error),
+    };
+
+    if js_sys::Array::is_array(&signals) {
+        let signals = js_sys::Array::from(&signals);
+        if signals.length() as usize != grid_positions.len() {
+            return errors::reject(errors::new(errors::ErrorKind::InvalidArgument,
+                "Expected one signal per grid position"));
+        }
+
+        let readers: Vec<R> = (0..signals.length())
+            .map(|i| reader.with_signal(signals.get(i).dyn_into::<AbortSignal>().ok()))
+            .collect();
+
+        join_blocks(readers.iter().zip(grid_positions), path_name, data_attrs, on_progress,
+            transferable, tolerant)
+    } else {
+        let reader = reader.with_signal(signals.dyn_into::<AbortSignal>().ok());
+
+        join_blocks(
+            grid_positions.into_iter().map(|grid_position| (&reader, grid_position)),
+            path_name,
+            data_attrs,
+            on_progress,
+            transferable,
+            tolerant)
+    }
+}
+
+
+/// Read blocks with at most `max_concurrent` requests in flight, calling
+/// `on_block(index, block_or_null, error)` once per grid position as soon as
+/// its read completes. `error` is `undefined` unless the read failed, in
+/// which case `block_or_null` is `null`. Resolves to `undefined` once all
+/// reads have completed.
+fn read_blocks_streaming<R>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    grid_positions: &JsValue,
+    on_block: js_sys::Function,
+    max_concurrent: Option<u32>,
+) -> Promise
+        where R: NgPreAsyncReader + AbortableReader + 'static,
+{
+    let grid_positions = match convert_grid_positions(grid_positions) {
+        Ok(grid_positions) => grid_positions,
+        Err(error) => return errors::reject(error),
+    };
+    let semaphore = bounded::Semaphore::new(
+        std::cmp::max(max_concurrent.unwrap_or(bounded::DEFAULT_MAX_CONCURRENT), 1) as usize);
+
+    data_type_match! {
+        data_attrs.0.get_data_type(),
+        {
+            let reads = grid_positions.into_iter()
+                .enumerate()
+                .map(|(index, grid_position)| {
+                    let reader = reader.with_signal(None);
+                    let path_name = path_name.to_owned();
+                    let data_attrs = data_attrs.0.clone();
+                    let on_block = on_block.clone();
+                    bounded::with_permit(&semaphore, move ||
+                            reader.read_block::<RsType>(&path_name, &data_attrs, grid_position.into()))
+                        .then(move |result| {
+                            let (block, error) = match result {
+                                Ok(maybe_block) => (JsValue::from(
+                                    maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)),
+                                    JsValue::UNDEFINED),
+                                Err(error) => (JsValue::NULL, errors::to_js_error(&error)),
+                            };
+                            // Exceptions thrown by the callback must not stop other reads.
+                            let _ = on_block.call3(&JsValue::NULL, &JsValue::from(index as u32), &block, &error);
+                            Ok::<(), Error>(())
+                        })
+                })
+                .collect::<Vec<_>>();
+
+            future_to_promise(map_future_error_wasm(future::join_all(reads)
+                .map(|_| JsValue::UNDEFINED)))
+        }
+    }
+}
+
+
+/// List the datasets directly under `prefix`, i.e. the entries that have an
+/// `info` file, with a summary of their attributes. At most
+/// `DEFAULT_MAX_CONCURRENT` datasets are probed at once. Resolves to an empty
+/// array if the prefix does not exist.
+fn list_datasets<R>(reader: &R, prefix: &str) -> Promise
+        where R: NgPreAsyncReader + AbortableReader + 'static,
+{
+    let prefix = prefix.trim_end_matches('/').to_owned();
+    let probe_reader = reader.with_signal(None);
+
+    let to_return = reader.list(&prefix)
+        .and_then(move |entries| {
+            let semaphore = bounded::Semaphore::new(bounded::DEFAULT_MAX_CONCURRENT as usize);
+            let probes = entries.into_iter()
+                .map(|entry| {
+                    let path = if prefix.is_empty() { entry } else { format!("{}/{}", prefix, entry) };
+                    let reader = probe_reader.with_signal(None);
+                    bounded::with_permit(&semaphore, move || reader.dataset_exists(&path)
+                        .and_then(move |exists| if exists {
+                            future::Either::A(reader.get_dataset_attributes(&path)
+                                .map(move |data_attrs| Some(dataset_summary(&path, &data_attrs))))
+                        } else {
+                            future::Either::B(future::ok(None))
+                        }))
+                })
+                .collect::<Vec<_>>();
+
+            future::join_all(probes)
+        })
+        .and_then(|summaries| {
+            let summaries: Vec<serde_json::Value> = summaries.into_iter().flatten().collect();
+            JsValue::from_serde(&summaries)
+                .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string()))
+        });
+
+    future_to_promise(map_future_error_wasm(to_return))
+}
+
+/// The path, data type, layer type and full resolution dimensions of a
+/// dataset.
+fn dataset_summary(path_name: &str, data_attrs: &DatasetAttributes) -> serde_json::Value {
+    serde_json::json!({
+        "path": path_name,
+        "data_type": data_attrs.get_data_type().to_string(),
+        "layer_type": layer_type(data_attrs),
+        "dimensions": data_attrs.get_dimensions(0),
+    })
+}
+
+
+/// Grid positions of all blocks of a scale overlapping the voxel box
+/// `[min, max)`, which includes the voxel offset like `get_bounds`. The last
+/// axis varies fastest (row-major order).
+fn grid_positions_in_box(
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    min: &[i64],
+    max: &[i64],
+) -> Result<Vec<Vec<i64>>, Error> {
+    let ndim = data_attrs.get_ndim(zoom_level);
+    if min.len() != ndim || max.len() != ndim {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Box {:?}-{:?} does not have {} dimensions", min, max, ndim)));
+    }
+    let grid_shape = sharded::grid_shape(data_attrs, zoom_level);
+    let ranges: Vec<(i64, i64)> = (0..ndim)
+        .map(|d| {
+            let offset = data_attrs.get_voxel_offset(zoom_level)[d] as i64;
+            let block_size = data_attrs.get_block_size(zoom_level)[d] as i64;
+            let lo = ((min[d] - offset) as f64 / block_size as f64).floor() as i64;
+            let hi = ((max[d] - offset) as f64 / block_size as f64).ceil() as i64;
+            (std::cmp::max(lo, 0), std::cmp::min(hi, grid_shape[d] as i64))
+        })
+        .collect();
+    if ranges.iter().any(|&(lo, hi)| lo >= hi) {
+        return Ok(Vec::new());
+    }
+
+    let mut grid_positions = Vec::new();
+    let mut grid_position: Vec<i64> = ranges.iter().map(|&(lo, _)| lo).collect();
+    'outer: loop {
+        grid_positions.push(grid_position.clone());
+        for d in (0..ndim).rev() {
+            grid_position[d] += 1;
+            if grid_position[d] < ranges[d].1 {
+                continue 'outer;
+            }
+            grid_position[d] = ranges[d].0;
+        }
+        break;
+    }
+
+    Ok(grid_positions)
+}
+
+struct BlockIteratorState {
+    pending: std::collections::VecDeque<Vec<i64>>,
+    in_flight: std::collections::VecDeque<Promise>,
+}
+
+/// A JS async iterator over `{grid_position, block}` of all blocks of scale
+/// `zoom_level` overlapping the voxel box `[min, max)`, in the order of
+/// `grid_positions_in_box`. Blocks are read when the consumer pulls, with up
+/// to `max_concurrent` reads ahead.
+fn iter_blocks<R>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    zoom_level: usize,
+    min: Vec<i64>,
+    max: Vec<i64>,
+    max_concurrent: Option<u32>,
+) -> Result<JsValue, JsValue>
+        where R: NgPreAsyncReader + AbortableReader + Clone + 'static,
+{
+    let grid_positions = grid_positions_in_box(&data_attrs.0, zoom_level, &min, &max)
+        .map_err(|e| errors::to_js_error(&e))?;
+    let max_concurrent = std::cmp::max(
+        max_concurrent.unwrap_or(bounded::DEFAULT_MAX_CONCURRENT), 1) as usize;
+
+    let reader = reader.with_signal(None);
+    let path_name = path_name.to_owned();
+    let data_attrs = wrapped::DatasetAttributes(data_attrs.0.clone());
+    let state = std::rc::Rc::new(std::cell::RefCell::new(BlockIteratorState {
+        pending: grid_positions.into(),
+        in_flight: std::collections::VecDeque::new(),
+    }));
+
+    let next = Closure::wrap(Box::new(move || -> Promise {
+        let mut state = state.borrow_mut();
+        while state.in_flight.len() < max_concurrent {
+            let grid_position = match state.pending.pop_front() {
+                Some(grid_position) => grid_position,
+                None => break,
+            };
+            let js_grid_position = JsValue::from_serde(&grid_position).unwrap();
+            let read = NgPrePromiseReader::read_block(&reader, &path_name, &data_attrs, grid_position);
+            state.in_flight.push_back(future_to_promise(JsFuture::from(read)
+                .map(move |block| js_object(&[("grid_position", js_grid_position), ("block", block)]))));
+        }
+
+        match state.in_flight.pop_front() {
+            Some(read) => future_to_promise(JsFuture::from(read)
+                .map(|value| js_object(&[("value", value), ("done", JsValue::FALSE)]))),
+            None => Promise::resolve(&js_object(&[("value", JsValue::UNDEFINED), ("done", JsValue::TRUE)])),
+        }
+    }) as Box<dyn FnMut() -> Promise>);
+
+    let iterator = js_sys::Object::new();
+    js_sys::Reflect::set(&iterator, &JsValue::from_str("next"), &next.into_js_value())?;
+    js_sys::Reflect::set(
+        &iterator,
+        &js_sys::Symbol::async_iterator(),
+        &js_sys::Function::new_no_args("return this"))?;
+
+    Ok(JsValue::from(iterator))
+}
+
+
+/// This trait exists to preserve type information between calls (rather than
+/// erasing it with `Promise`) and for easier potential future compatibility
+/// with an NgPre core async trait.
+pub trait NgPreAsyncReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>>;
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+        Box<dyn Future<Item = DatasetAttributes, Error = Error>>;
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>>;
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        Box::new(self.exists(path_name).join(
+            self.get_dataset_attributes(path_name)
+                .then(|result| has_dataset_attributes(result.map(|_| ())))
+        ).map(|(exists, has_attr)| exists && has_attr))
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement;
+
+    /// Read the part of a block within the box `[min, max)`, in voxels
+    /// relative to the block origin. By default the whole block is read and
+    /// cropped.
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        read_block_cropped(self, path_name, data_attrs, grid_position, min, max)
+    }
+
+    /// Check whether a block exists. By default the block is read.
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        data_type_match! {
+            data_attrs.get_data_type(),
+            Box::new(self.read_block::<RsType>(path_name, data_attrs, grid_position)
+                .map(|maybe_block| maybe_block.is_some()))
+        }
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>>;
+
+    fn list_attributes(&self, path_name: &str) -> Box<dyn Future<Item = serde_json::Value, Error = Error>>;
+}
+
+/// Whether an attempt to get dataset attributes found a valid `info`: a
+/// missing or malformed `info` means there is no dataset, while other errors
+/// (e.g. network errors) are passed on.
+fn has_dataset_attributes(result: Result<(), Error>) -> Result<bool, Error> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(error) => match errors::kind_of(&error) {
+            errors::ErrorKind::NotFound |
+            errors::ErrorKind::BadAttrs |
+            errors::ErrorKind::Decode => Ok(false),
+            _ => Err(error),
+        },
+    }
+}
+
+fn read_block_cropped<R, T>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &DatasetAttributes,
+    grid_position: UnboundedGridCoord,
+    min: Vec<u32>,
+    max: Vec<u32>,
+) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where R: NgPreAsyncReader + ?Sized,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    Box::new(reader.read_block::<T>(path_name, data_attrs, grid_position)
+        .and_then(move |maybe_block| match maybe_block {
+            Some(block) => crop_block(&block, &min, &max).map(Some),
+            None => Ok(None),
+        }))
+}
+
+/// Split a block into one block per channel plane.
+fn split_channels<T>(block: &VecDataBlock<T>) -> Result<Vec<VecDataBlock<T>>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType,
+{
+    let plane_len: usize = block.get_size().iter().map(|&s| s as usize).product();
+    let data = block.get_data();
+    if plane_len == 0 || data.len() % plane_len != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} does not match block size {:?}", data.len(), block.get_size())));
+    }
+
+    Ok(data.chunks_exact(plane_len)
+        .map(|plane| VecDataBlock::new(
+            block.get_size().to_vec().into(),
+            block.get_grid_position().to_vec().into(),
+            plane.to_vec()))
+        .collect())
+}
+
+/// Convert a block to `f32` with `RawElement::to_normalized_f32`.
+fn normalize_block<T>(block: &VecDataBlock<T>) -> VecDataBlock<f32>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    VecDataBlock::new(
+        block.get_size().to_vec().into(),
+        block.get_grid_position().to_vec().into(),
+        block.get_data().iter().map(|&value| value.to_normalized_f32()).collect())
+}
+
+/// Read a block like `NgPrePromiseReader::read_block`, with its etag and
+/// encoded size if the reader knows them.
+fn read_block_with_encoded_size<R>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    grid_position: Vec<i64>,
+) -> Promise
+        where R: NgPreAsyncEtagReader,
+{
+
+    data_type_match! {
+        data_attrs.0.get_data_type(),
+        future_to_promise(map_future_error_wasm(
+            reader.read_block_with_encoded_size::<RsType>(path_name, &data_attrs.0, grid_position.into())
+                .map(|maybe_block| JsValue::from(
+                    maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))))
+    }
+}
+
+/// Read a block with its data in `order`: `"F"` (the default) keeps the
+/// stored column-major order, where `x` varies fastest, while `"C"` reverses
+/// the spatial axes into row-major order, so that `get_data()` is indexed as
+/// `[c][x][y][z]`. Channels stay the slowest varying axis either way.
+///
+/// `chunk_size_index` selects one of the scale's `chunk_sizes` (the first by
+/// default), with `grid_position` in units of that chunk size.
+pub(crate) fn read_block_in_order<R>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    grid_position: Vec<i64>,
+    order: Option<String>,
+    chunk_size_index: Option<usize>,
+) -> Promise
+        where R: NgPrePromiseReader + NgPreAsyncEtagReader,
+{
+    let selected;
+    let data_attrs = match chunk_size_index {
+        None | Some(0) => data_attrs,
+        Some(index) => match with_chunk_size(&data_attrs.0, path_name, index) {
+            Ok(attrs) => {
+                selected = wrapped::DatasetAttributes(attrs);
+                &selected
+            },
+            Err(error) => return errors::reject(error),
+        },
+    };
+
+    match order.as_ref().map(String::as_str) {
+        None | Some("F") => read_block_with_encoded_size(reader, path_name, data_attrs, grid_position),
+        Some("C") => match zoom_level(&data_attrs.0, path_name) {
+            Ok(zoom_level) => {
+                let ndim = data_attrs.0.get_ndim(zoom_level);
+                let layout: String = "xyzw"[..std::cmp::min(ndim, 4)].chars().rev()
+                    .chain(std::iter::once('c'))
+                    .collect();
+                NgPrePromiseReader::read_block_packed(reader, path_name, data_attrs, grid_position, &layout)
+            },
+            Err(error) => errors::reject(error),
+        },
+        Some(order) => errors::reject(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Unknown order {:?}, expected \"F\" or \"C\"", order))),
+    }
+}
+
+/// Parse a packed layout such as `"cxyz"`, which lists the spatial axes and
+/// the channel axis `c` from fastest to slowest varying, into the order of
+/// the block's axes (with the channel axis last) for `raw::transpose`.
+fn parse_layout(layout: &str, ndim: usize) -> Result<Vec<usize>, Error> {
+    let axes = &"xyzw"[..std::cmp::min(ndim, 4)];
+    let order: Vec<usize> = layout.chars()
+        .filter_map(|axis| if axis == 'c' { Some(ndim) } else { axes.find(axis) })
+        .collect();
+    let mut sorted = order.clone();
+    sorted.sort();
+    if layout.chars().count() != ndim + 1 || axes.len() != ndim || sorted != (0..=ndim).collect::<Vec<_>>() {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Invalid layout {:?}, expected a permutation of {:?}", layout, format!("{}c", axes))));
+    }
+
+    Ok(order)
+}
+
+/// Reorder the data of a block, including its channels, into `order`.
+fn pack_block<T>(block: VecDataBlock<T>, order: &[usize]) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType,
+{
+    let size = block.get_size().to_vec();
+    let grid_position = block.get_grid_position().to_vec();
+    let plane_len: usize = size.iter().map(|&s| s as usize).product();
+    let data = block.into_data();
+    if plane_len == 0 || data.len() % plane_len != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} does not match block size {:?}", data.len(), size)));
+    }
+    let mut full_size = size.clone();
+    full_size.push((data.len() / plane_len) as u32);
+
+    let packed = raw::transpose(&data, &full_size, order);
+
+    Ok(VecDataBlock::new(size.into(), grid_position.into(), packed))
+}
+
+/// Copy the box `[min, max)` of a block into a new block of the box's size.
+fn crop_block<T>(block: &VecDataBlock<T>, min: &[u32], max: &[u32]) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType,
+{
+    let data = raw::crop(block.get_data(), block.get_size(), min, max)?;
+    let size: Vec<u32> = min.iter().zip(max).map(|(&lo, &hi)| hi - lo).collect();
+
+    Ok(VecDataBlock::new(size.into(), block.get_grid_position().to_vec().into(), data))
+}
+
+/// The grid position at scale `to` of the block containing the center of the
+/// block at `grid_position` of scale `from`. With block size `b`, voxel
+/// offset `o` and resolution `r` of each scale, the center lies at
+/// `(o_from + (g_from + 1/2) * b_from) * r_from` in physical units, so
+///
+/// `g_to = floor(((o_from + (g_from + 1/2) * b_from) * r_from / r_to - o_to) / b_to)`
+///
+/// per axis. Using the center rather than the origin keeps rounding at block
+/// boundaries from picking a neighbouring block.
+fn grid_position_at_scale(
+    data_attrs: &DatasetAttributes,
+    from: usize,
+    to: usize,
+    grid_position: &[i64],
+) -> Result<Vec<i64>, Error> {
+    let (from_resolution, to_resolution) = (scale_resolution(data_attrs, from)?, scale_resolution(data_attrs, to)?);
+    let (from_block_size, to_block_size) = (data_attrs.get_block_size(from), data_attrs.get_block_size(to));
+    let (from_offset, to_offset) = (data_attrs.get_voxel_offset(from), data_attrs.get_voxel_offset(to));
+    if from_resolution.len() != grid_position.len() || to_resolution.len() != grid_position.len() {
+        return Err(errors::new(errors::ErrorKind::BadAttrs, format!(
+            "Resolutions of scales {} and {} do not match grid position {:?}", from, to, grid_position)));
+    }
+
+    Ok((0..grid_position.len())
+        .map(|d| {
+            let center = from_offset[d] as f64 + (grid_position[d] as f64 + 0.5) * from_block_size[d] as f64;
+            let voxel = center * from_resolution[d] / to_resolution[d] - to_offset[d] as f64;
+            (voxel / to_block_size[d] as f64).floor() as i64
+        })
+        .collect())
+}
+
+/// Read the block at `grid_position` of scale `preferred_zoom`, or else the
+/// first block found at coarser scales, with the zoom level it was found at.
+/// Reads are made one after the other, each only if the previous block was
+/// missing.
+fn read_block_best_effort<R, T>(
+    reader: &R,
+    data_attrs: &DatasetAttributes,
+    preferred_zoom: usize,
+    grid_position: Vec<i64>,
+) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, usize)>, Error = Error>>
+        where R: NgPreAsyncReader + Clone + 'static,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    let num_scales = data_attrs.get_scales().len();
+    if preferred_zoom >= num_scales {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Dataset has no scale {}", preferred_zoom))));
+    }
+
+    let mut found: Box<dyn Future<Item = Option<(VecDataBlock<T>, usize)>, Error = Error>> =
+        Box::new(future::ok(None));
+    for zoom_level in preferred_zoom..num_scales {
+        let scale_grid_position = match grid_position_at_scale(data_attrs, preferred_zoom, zoom_level, &grid_position) {
+            Ok(scale_grid_position) => scale_grid_position,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        // The read is only made once all finer reads found nothing, since
+        // even making it may send a request or queue for a permit.
+        let (reader, data_attrs) = (reader.clone(), data_attrs.clone());
+        found = Box::new(found.and_then(move |found| match found {
+            Some(found) => future::Either::A(future::ok(Some(found))),
+            None => future::Either::B(future::lazy(move || reader.read_block::<T>(
+                    &data_attrs.get_scales()[zoom_level].key, &data_attrs, scale_grid_position.into()))
+                .map(move |maybe_block| maybe_block.map(|block| (block, zoom_level)))),
+        }));
+    }
+
+    found
+}
+
+/// Read the block containing `voxel` and pick out its value, or its values
+/// per channel.
+fn read_voxel<R, T>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    voxel: Vec<i64>,
+) -> Box<dyn Future<Item = JsValue, Error = Error>>
+        where R: NgPreAsyncReader + ?Sized,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    if zoom_level >= data_attrs.get_scales().len() {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Dataset has no scale {}", zoom_level))));
+    }
+    let ndim = data_attrs.get_ndim(zoom_level);
+    if voxel.len() != ndim {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Voxel {:?} does not have {} dimensions", voxel, ndim))));
+    }
+    let block_size = data_attrs.get_block_size(zoom_level);
+    let relative: Vec<i64> = voxel.iter().zip(data_attrs.get_voxel_offset(zoom_level))
+        .map(|(&v, &o)| v - o as i64)
+        .collect();
+    let in_bounds = relative.iter().zip(data_attrs.get_dimensions(zoom_level))
+        .all(|(&r, &d)| r >= 0 && (r as u64) < d);
+    if !in_bounds {
+        return Box::new(future::ok(JsValue::NULL));
+    }
+    let grid_position: Vec<i64> = relative.iter().zip(block_size)
+        .map(|(&r, &s)| r / s as i64)
+        .collect();
+    let within: Vec<usize> = relative.iter().zip(block_size)
+        .map(|(&r, &s)| (r % s as i64) as usize)
+        .collect();
+    let channels = num_channels(data_attrs) as usize;
+
+    Box::new(reader.read_block::<T>(path_name, data_attrs, grid_position.into())
+        .and_then(move |maybe_block| {
+            let block = match maybe_block {
+                Some(block) => block,
+                None => return Ok(JsValue::NULL),
+            };
+            let index: usize = within.iter().zip(raw::fortran_strides(block.get_size()))
+                .map(|(&w, stride)| w * stride)
+                .sum();
+            let plane_len: usize = block.get_size().iter().map(|&s| s as usize).product();
+            let data = block.get_data();
+            let values: Vec<JsValue> = (0..channels)
+                .map(|c| data.get(c * plane_len + index).map(|&value| value.to_js()))
+                .collect::<Option<_>>()
+                .ok_or_else(|| errors::new(errors::ErrorKind::Decode, format!(
+                    "Block {:?} has too few elements for {} channels", block.get_grid_position(), channels)))?;
+
+            Ok(if channels == 1 {
+                values.into_iter().next().unwrap()
+            } else {
+                JsValue::from(values.into_iter().collect::<js_sys::Array>())
+            })
+        }))
+}
+
+/// Read the voxel box of `size` at `origin` by copying the overlapping parts
+/// of all blocks into a zero-filled block, reporting each read block to
+/// `on_progress`.
+fn read_tile<R, T>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    origin: Vec<i64>,
+    size: Vec<u32>,
+    on_progress: Option<js_sys::Function>,
+) -> Box<dyn Future<Item = VecDataBlock<T>, Error = Error>>
+        where R: NgPreAsyncReader + ?Sized,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    if size.len() != origin.len() || size.contains(&0) {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Invalid tile size {:?} at {:?}", size, origin))));
+    }
+    let tile_max: Vec<i64> = origin.iter().zip(&size).map(|(&o, &s)| o + s as i64).collect();
+    let grid_positions = match grid_positions_in_box(data_attrs, zoom_level, &origin, &tile_max) {
+        Ok(grid_positions) => grid_positions,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    let voxel_offset: Vec<i64> = data_attrs.get_voxel_offset(zoom_level).iter()
+        .map(|&o| o as i64).collect();
+    let block_size: Vec<i64> = data_attrs.get_block_size(zoom_level).iter()
+        .map(|&b| b as i64).collect();
+    let channels = num_channels(data_attrs) as usize;
+
+    let progress = Progress::new(on_progress, grid_positions.len());
+
+    let reads = grid_positions.into_iter()
+        .map(|grid_position| {
+            let progress = progress.clone();
+            reader.read_block::<T>(path_name, data_attrs, grid_position.clone().into())
+                .map(move |maybe_block| {
+                    progress.complete();
+                    maybe_block.map(|block| (grid_position, block))
+                })
+        })
+        .collect::<Vec<_>>();
+
+    Box::new(future::join_all(reads).and_then(move |blocks| {
+        let plane_len: usize = size.iter().map(|&s| s as usize).product();
+        let mut data = vec![T::default(); plane_len * channels];
+
+        for (grid_position, block) in blocks.into_iter().flatten() {
+            let block_dims = block.get_size();
+            let block_plane_len: usize = block_dims.iter().map(|&s| s as usize).product();
+            if block.get_data().len() != block_plane_len * channels {
+                return Err(errors::new(errors::ErrorKind::Decode, format!(
+                    "Data length {} of block {:?} does not match its size {:?}",
+                    block.get_data().len(), grid_position, block_dims)));
+            }
+
+            let mut src_min = Vec::with_capacity(size.len());
+            let mut dst_min = Vec::with_capacity(size.len());
+            let mut extent = Vec::with_capacity(size.len());
+            for d in 0..size.len() {
+                let begin = voxel_offset[d] + grid_position[d] * block_size[d];
+                let lo = std::cmp::max(origin[d], begin);
+                let hi = std::cmp::min(tile_max[d], begin + block_dims[d] as i64);
+                src_min.push((lo - begin) as u32);
+                dst_min.push((lo - origin[d]) as u32);
+                extent.push(std::cmp::max(hi - lo, 0) as u32);
+            }
+            if extent.contains(&0) {
+                continue;
+            }
+
+            for channel in 0..channels {
+                raw::copy_box(
+                    &block.get_data()[channel * block_plane_len..(channel + 1) * block_plane_len],
+                    block_dims, &src_min,
+                    &mut data[channel * plane_len..(channel + 1) * plane_len],
+                    &size, &dst_min,
+                    &extent);
+            }
+        }
+
+        let tile_grid_position: Vec<u64> = (0..size.len())
+            .map(|d| std::cmp::max((origin[d] - voxel_offset[d]).div_euclid(block_size[d]), 0) as u64)
+            .collect();
+
+        Ok(VecDataBlock::new(size.into(), tile_grid_position.into(), data))
+    }))
+}
+
+/// Copy blocks of a scale into one block spanning all of them, at their
+/// offsets by grid position, with zeros wherever no block lies. The grid
+/// position of the result is the smallest of the blocks along each axis.
+fn merge_vec_blocks<T>(
+    blocks: &[&VecDataBlock<T>],
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    if zoom_level >= data_attrs.get_scales().len() {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Dataset has no scale {}", zoom_level)));
+    }
+    let block_size = data_attrs.get_block_size(zoom_level);
+    let ndim = block_size.len();
+    if blocks.is_empty() {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, "No blocks to merge"));
+    }
+    if let Some(block) = blocks.iter().find(|block|
+            block.get_size().len() != ndim || block.get_grid_position().len() != ndim) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Block {:?} does not have the {} dimensions of scale {}",
+            block.get_grid_position(), ndim, zoom_level)));
+    }
+    let mut grid_positions = std::collections::HashSet::new();
+    if let Some(block) = blocks.iter().find(|block| !grid_positions.insert(block.get_grid_position())) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot merge several blocks at grid position {:?}", block.get_grid_position())));
+    }
+    let channels = num_channels(data_attrs) as usize;
+
+    // Voxel ranges relative to the voxel offset.
+    let begin = |block: &VecDataBlock<T>, d: usize| block.get_grid_position()[d] * block_size[d] as u64;
+    let min: Vec<u64> = (0..ndim)
+        .map(|d| blocks.iter().map(|&block| begin(block, d)).min().unwrap())
+        .collect();
+    let size: Vec<u32> = (0..ndim)
+        .map(|d| {
+            let extent = blocks.iter().map(|&block| begin(block, d) + block.get_size()[d] as u64).max().unwrap()
+                - min[d];
+            if extent > u64::from(std::u32::MAX) {
+                return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+                    "Merged block would span {} voxels along axis {}", extent, d)));
+            }
+            Ok(extent as u32)
+        })
+        .collect::<Result<_, Error>>()?;
+    let too_large = || errors::new(errors::ErrorKind::InvalidArgument,
+        format!("Merged block of size {:?} is too large", size));
+    let plane_len = size.iter().try_fold(1usize, |len, &s| len.checked_mul(s as usize))
+        .ok_or_else(too_large)?;
+    let mut data = vec![T::default(); plane_len.checked_mul(channels).ok_or_else(too_large)?];
+
+    for &block in blocks {
+        let block_dims = block.get_size();
+        let block_plane_len: usize = block_dims.iter().map(|&s| s as usize).product();
+        if block.get_data().len() != block_plane_len * channels {
+            return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+                "Data length {} of block {:?} does not match its size {:?}",
+                block.get_data().len(), block.get_grid_position(), block_dims)));
+        }
+        let dst_min: Vec<u32> = (0..ndim).map(|d| (begin(block, d) - min[d]) as u32).collect();
+        let src_min = vec![0; ndim];
+
+        for channel in 0..channels {
+            raw::copy_box(
+                &block.get_data()[channel * block_plane_len..(channel + 1) * block_plane_len],
+                block_dims, &src_min,
+                &mut data[channel * plane_len..(channel + 1) * plane_len],
+                &size, &dst_min,
+                block_dims);
+        }
+    }
+
+    let grid_position: Vec<u64> = (0..ndim).map(|d| min[d] / block_size[d] as u64).collect();
+
+    Ok(VecDataBlock::new(size.into(), grid_position.into(), data))
+}
+
+/// Zero-pad a block (with `channels` channels) to the next power of two
+/// along each axis, keeping its data at the origin, as needed for some GPU
+/// textures.
+fn pad_to_power_of_two<T: RawElement>(block: VecDataBlock<T>, channels: usize) -> VecDataBlock<T>
+        where VecDataBlock<T>: DataBlock<T>,
+{
+    let size = block.get_size().to_vec();
+    let padded: Vec<u32> = size.iter().map(|&s| s.next_power_of_two()).collect();
+    if padded == size {
+        return block;
+    }
+    let grid_position = block.get_grid_position().to_vec();
+    let plane_len: usize = size.iter().map(|&s| s as usize).product();
+    let padded_plane_len: usize = padded.iter().map(|&s| s as usize).product();
+    let origin = vec![0; size.len()];
+
+    let data = block.into_data();
+    let mut padded_data = vec![T::default(); padded_plane_len * channels];
+    for channel in 0..channels {
+        raw::copy_box(
+            &data[channel * plane_len..(channel + 1) * plane_len], &size, &origin,
+            &mut padded_data[channel * padded_plane_len..(channel + 1) * padded_plane_len], &padded, &origin,
+            &size);
+    }
+
+    VecDataBlock::new(padded.into(), grid_position.into(), padded_data)
+}
+
+/// Read the plane at `index` along `axis` as a tile one voxel thick, and drop
+/// that axis from the tile, which leaves its data in the same order.
+fn read_slice<R, T>(
+    reader: &R,
+    path_name: &str,
+    data_attrs: &DatasetAttributes,
+    zoom_level: usize,
+    axis: u32,
+    index: i64,
+) -> Box<dyn Future<Item = VecDataBlock<T>, Error = Error>>
+        where R: NgPreAsyncReader + ?Sized,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+{
+    if zoom_level >= data_attrs.get_scales().len() {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Dataset has no scale {}", zoom_level))));
+    }
+    let ndim = data_attrs.get_ndim(zoom_level);
+    if ndim != 3 || axis >= 3 {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot slice a {}D scale along axis {}", ndim, axis))));
+    }
+    let axis = axis as usize;
+    let mut origin: Vec<i64> = data_attrs.get_voxel_offset(zoom_level).iter()
+        .map(|&o| o as i64).collect();
+    let mut size: Vec<u32> = data_attrs.get_dimensions(zoom_level).iter()
+        .map(|&d| d as u32).collect();
+    origin[axis] = index;
+    size[axis] = 1;
+
+    Box::new(read_tile::<R, T>(reader, path_name, data_attrs, zoom_level, origin, size, None)
+        .map(move |tile| {
+            let mut size = tile.get_size().to_vec();
+            let mut grid_position = tile.get_grid_position().to_vec();
+            size.remove(axis);
+            grid_position.remove(axis);
+
+            VecDataBlock::new(size.into(), grid_position.into(), tile.into_data())
+        }))
+}
+
+/// Most chunks `read_thumbnail` reads from the coarsest scale.
+pub const MAX_THUMBNAIL_CHUNKS: u64 = 64;
+
+/// Render the central `z` plane of the coarsest scale (the one with the
+/// fewest voxels) into grayscale RGBA pixels of at most `max_dim` pixels
+/// along either axis, with their width and height.
+fn read_thumbnail<R, T>(
+    reader: &R,
+    data_attrs: &DatasetAttributes,
+    max_dim: u32,
+) -> Box<dyn Future<Item = (Vec<u8>, u32, u32), Error = Error>>
+        where R: NgPreAsyncReader + ?Sized,
+            VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement + PartialOrd,
+{
+    if max_dim == 0 {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument,
+            "Thumbnails must be at least 1 pixel in size")));
+    }
+    let zoom_level = match (0..data_attrs.get_scales().len())
+            .min_by_key(|&zoom_level| data_attrs.get_dimensions(zoom_level).iter().product::<u64>()) {
+        Some(zoom_level) => zoom_level,
+        None => return Box::new(future::err(errors::new(errors::ErrorKind::BadAttrs, "Dataset has no scales"))),
+    };
+    if data_attrs.get_ndim(zoom_level) != 3 {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot render a thumbnail of a {}D scale", data_attrs.get_ndim(zoom_level)))));
+    }
+    let grid_shape = sharded::grid_shape(data_attrs, zoom_level);
+    if grid_shape[0] * grid_shape[1] > MAX_THUMBNAIL_CHUNKS {
+        return Box::new(future::err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "The coarsest scale spans {} chunks per plane, more than the {} read for thumbnails",
+            grid_shape[0] * grid_shape[1], MAX_THUMBNAIL_CHUNKS))));
+    }
+    let z = data_attrs.get_voxel_offset(zoom_level)[2] as i64
+        + data_attrs.get_dimensions(zoom_level)[2] as i64 / 2;
+    let method = if layer_type(data_attrs) == "segmentation" {
+        downsample::DownsampleMethod::Mode
+    } else {
+        downsample::DownsampleMethod::Mean
+    };
+
+    Box::new(read_slice::<R, T>(reader, &data_attrs.get_scales()[zoom_level].key, data_attrs, zoom_level, 2, z)
+        .and_then(move |slice| {
+            let size = slice.get_size().to_vec();
+            let factor = (std::cmp::max(size[0], size[1]) + max_dim - 1) / max_dim;
+            let factors = [std::cmp::min(factor, size[0]), std::cmp::min(factor, size[1])];
+            let slice = if factor > 1 {
+                downsample::downsample_block(&slice, &factors, method)?
+            } else {
+                slice
+            };
+            let size = slice.get_size();
+            let first_channel = &slice.get_data()[..(size[0] * size[1]) as usize];
+            let (window_min, window_max) = render::min_max(first_channel)
+                .map_or((0.0, 1.0), |(min, max)| (min.to_f64(), max.to_f64()));
+
+            render::slice_to_rgba(slice.get_data(), &[size[0], size[1], 1], 2, 0, window_min, window_max)
+        }))
+}
+
+
+pub trait NgPreAsyncEtagReader {
+    /// The etag of the dataset's attributes (`info`) file, or `None` if the
+    /// server reports none.
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>>;
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>>;
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement;
+
+    /// Read a block unless its etag is still `known_etag`. By default the
+    /// block is read and its etag compared. Blocks without an etag are
+    /// always considered changed.
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let known_etag = known_etag.to_owned();
+        Box::new(self.read_block_with_etag::<T>(path_name, data_attrs, grid_position)
+            .map(move |maybe_block| Conditional::unless_etag(maybe_block, &known_etag)))
+    }
+
+    /// Read a block with its etag and the number of encoded bytes it was
+    /// decoded from (after any HTTP `Content-Encoding` is undone). By default
+    /// the encoded size is `None`, for readers that do not know it.
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        Box::new(self.read_block_with_etag::<T>(path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, etag)| (block, etag, None))))
+    }
+}
+
+/// The result of a read conditional on an etag.
+pub enum Conditional<V> {
+    /// The data still has the known etag.
+    Unchanged,
+    Changed(V),
+}
+
+impl<D> Conditional<Option<(D, Option<String>)>> {
+    /// Treat data with the known etag as unchanged.
+    pub(crate) fn unless_etag(maybe_data: Option<(D, Option<String>)>, known_etag: &str) -> Self {
+        match maybe_data {
+            Some((_, Some(ref etag))) if etag == known_etag => Conditional::Unchanged,
+            maybe_data => Conditional::Changed(maybe_data),
+        }
+    }
+}
+
+
+pub trait NgPreAsyncWriter {
+    fn write_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        block: VecDataBlock<T>,
+    ) -> Box<dyn Future<Item = (), Error = Error>>
+            where VecDataBlock<T>: DataBlock<T>,
+                T: ReflectedType + RawElement;
+}
+
+
+fn map_future_error_rust<F: Future<Item = T, Error = JsValue>, T>(future: F)
+        -> impl Future<Item = T, Error = Error> {
+    future.map_err(convert_jsvalue_error)
+}
+
+fn map_future_error_wasm<F: Future<Item = T, Error = Error>, T>(future: F)
+        -> impl Future<Item = T, Error = JsValue> {
+    future.map_err(|error| errors::to_js_error(&error))
+}
+
+fn convert_jsvalue_error(error: JsValue) -> Error {
+    errors::from_js_error(error)
+}
+
+/// Make sure we are in bounds with requested blocks. This accepts signed input, to allow
+/// catching overflows when data from JavaScript is passed in. Grid positions
+/// with the wrong number of coordinates are never in bounds.
+fn checked_grid_position(grid_position: &[i64], block_size: &[u32], dimensions: &[u64]) -> Option<GridCoord> {
+    if grid_position.len() != block_size.len() || grid_position.len() != dimensions.len() {
+        return None;
+    }
+    let mut offset_grid_position = GridCoord::new();
+    let mut n = 0;
+    for &coord in grid_position {
+        if coord < 0 || coord * block_size[n] as i64 > dimensions[n] as i64  {
+            return None;
+        }
+        offset_grid_position.push(coord as u64);
+        n = n + 1;
+    }
+
+    Some(offset_grid_position)
+}
+
+/// Reject grid positions that do not have one coordinate per dimension of
+/// the scale at `zoom_level`, which may have any number of dimensions.
+fn check_grid_ndim(data_attrs: &DatasetAttributes, zoom_level: usize, grid_position: &[i64]) -> Result<(), Error> {
+    let ndim = data_attrs.get_dimensions(zoom_level).len();
+    if grid_position.len() != ndim {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Grid position {:?} has {} coordinates, expected {} for zoom level {}",
+            grid_position, grid_position.len(), ndim, zoom_level)));
+    }
+
+    Ok(())
+}
+
+/// Find the zoom level of the scale with the given key.
+fn zoom_level(data_attrs: &DatasetAttributes, path_name: &str) -> Result<usize, Error> {
+    data_attrs.get_scales().iter().position(|s| s.key == path_name)
+        .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs,
+            format!("No scale with key {}", path_name)))
+}
+
+/// The block size of the scale with the given key, or none for an unknown
+/// scale, which tells apart blocks read with different chunk sizes.
+pub(crate) fn scale_block_size(data_attrs: &DatasetAttributes, path_name: &str) -> Vec<u32> {
+    zoom_level(data_attrs, path_name)
+        .map(|zoom_level| data_attrs.get_block_size(zoom_level).to_vec())
+        .unwrap_or_default()
+}
+
+/// The key of an unsharded chunk within its scale directory, such as
+/// `0-64_64-128_0-30`: the voxel range `begin-end` of the chunk along each
+/// axis, including the voxel offset and clamped to the dimensions.
+pub(crate) fn chunk_key(grid_position: &[i64], block_size: &[u32], voxel_offset: &[i32], dimensions: &[u64]) -> String {
+    use std::fmt::Write;
+
+    let mut chunk_key = String::new();
+    let mut n = 0;
+    for coord in grid_position {
+        if n > 0 {
+            write!(chunk_key, "_").unwrap();
+        }
+        // This assumes 0 <= coord <= grid_size[n].
+        let begin_offset = voxel_offset[n] as i64 + coord * block_size[n] as i64;
+        let end_offset = voxel_offset[n] as i64 + std::cmp::min(
+            (coord + 1) * block_size[n] as i64, dimensions[n] as i64);
+
+        write!(chunk_key, "{}-{}", begin_offset, end_offset).unwrap();
+        n = n + 1;
+    }
+
+    chunk_key
+}
+
+/// The grid position of the chunk with key `key` in the scale at
+/// `zoom_level`, the inverse of `chunk_key`. Keys whose ranges are not
+/// exactly those of a chunk of the scale are rejected.
+fn chunk_key_grid_position(data_attrs: &DatasetAttributes, zoom_level: usize, key: &str) -> Result<Vec<u64>, Error> {
+    if zoom_level >= data_attrs.get_scales().len() {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Dataset has no scale {}", zoom_level)));
+    }
+    let block_size = data_attrs.get_block_size(zoom_level);
+    let voxel_offset = data_attrs.get_voxel_offset(zoom_level);
+    let dimensions = data_attrs.get_dimensions(zoom_level);
+    let invalid = || errors::new(errors::ErrorKind::InvalidArgument,
+        format!("{} is not a chunk key of zoom level {}", key, zoom_level));
+
+    let ranges = key.split('_').collect::<Vec<_>>();
+    if ranges.len() != dimensions.len() {
+        return Err(invalid());
+    }
+
+    let mut grid_position = Vec::with_capacity(ranges.len());
+    for (n, range) in ranges.into_iter().enumerate() {
+        // The begin may be negative, so split at the first `-` after it.
+        let split = range.get(1..).and_then(|rest| rest.find('-')).ok_or_else(invalid)? + 1;
+        let begin = range[..split].parse::<i64>().map_err(|_| invalid())?;
+        let offset_begin = begin - voxel_offset[n] as i64;
+        if offset_begin < 0 || block_size[n] == 0 || offset_begin % block_size[n] as i64 != 0 {
+            return Err(invalid());
+        }
+        let coord = offset_begin / block_size[n] as i64;
+        if coord * block_size[n] as i64 >= dimensions[n] as i64 {
+            return Err(invalid());
+        }
+        grid_position.push(coord);
+    }
+
+    if chunk_key(&grid_position, block_size, voxel_offset, dimensions) != key {
+        return Err(invalid());
+    }
+
+    Ok(grid_position.into_iter().map(|coord| coord as u64).collect())
+}
+
+/// Keys of a scale that legacy single resolution `info` files have at the
+/// top level.
+const LEGACY_SCALE_KEYS: &[&str] = &[
+    "key", "size", "chunk_sizes", "voxel_offset", "resolution", "encoding",
+    "compressed_segmentation_block_size", "sharding",
+];
+
+/// How the elements of a dataset are stored in `raw` chunks, if that differs
+/// from the data type its blocks are read as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Storage {
+    /// As the data type of the blocks.
+    Native,
+    /// `float16` values, read as `float32`.
+    Float16,
+    /// `bool` masks of one bit per voxel, read as `uint8` blocks of 0 and 1.
+    Bitpacked,
+}
+
+/// The attributes of a dataset as parsed from its `info`: the
+/// `ngpre::DatasetAttributes` its blocks are read with, which it derefs to,
+/// and what of the info ngpre does not model. This shadows the ngpre type of
+/// the prelude, so attributes are only made by `parse_dataset_attributes`.
+///
+/// It serializes back to the info it was parsed from, so that it survives
+/// being sent to workers or stored.
+#[derive(Clone)]
+pub struct DatasetAttributes {
+    attrs: ngpre::DatasetAttributes,
+    storage: Storage,
+    /// The top-level `transform` of the info.
+    transform: Option<serde_json::Value>,
+}
+
+impl DatasetAttributes {
+    pub(crate) fn storage(&self) -> Storage {
+        self.storage
+    }
+}
+
+impl std::ops::Deref for DatasetAttributes {
+    type Target = ngpre::DatasetAttributes;
+
+    fn deref(&self) -> &ngpre::DatasetAttributes {
+        &self.attrs
+    }
+}
+
+impl serde::Serialize for DatasetAttributes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut info = serde_json::to_value(&self.attrs).map_err(serde::ser::Error::custom)?;
+        match self.storage {
+            Storage::Native => (),
+            Storage::Float16 => info["data_type"] = serde_json::json!("float16"),
+            Storage::Bitpacked => info["data_type"] = serde_json::json!("bool"),
+        }
+        if let Some(transform) = &self.transform {
+            info["transform"] = transform.clone();
+        }
+
+        serde::Serialize::serialize(&info, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DatasetAttributes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let info = <serde_json::Value as serde::Deserialize>::deserialize(deserializer)?;
+        parse_dataset_attributes(info).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parse an `info` file, accepting both the multi-scale layout and the legacy
+/// layout without `scales`, which is normalized to a single scale with zoom
+/// level 0 and key `"."` (unless it specifies a key).
+fn parse_dataset_attributes(mut info: serde_json::Value) -> Result<DatasetAttributes, Error> {
+    normalize_legacy_info(&mut info);
+
+    // `ngpre::DataType` has no half-precision or boolean type, so
+    // half-precision data is read as `float32` and bitpacked masks as
+    // `uint8` blocks of 0 and 1.
+    let storage = match info.get("data_type").and_then(|t| t.as_str()) {
+        Some("float16") => {
+            info["data_type"] = serde_json::json!("float32");
+            Storage::Float16
+        },
+        Some("bool") => {
+            info["data_type"] = serde_json::json!("uint8");
+            Storage::Bitpacked
+        },
+        _ => Storage::Native,
+    };
+    let transform = info.get("transform").filter(|transform| !transform.is_null()).cloned();
+
+    let attrs = serde_json::from_value(info)
+        .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, format!("Invalid info: {}", e)))?;
+
+    Ok(DatasetAttributes { attrs, storage, transform })
+}
+
+/// Move the scale keys of a legacy `info` without `scales` into a single
+/// scale. Returns whether the info had the legacy layout.
+fn normalize_legacy_info(info: &mut serde_json::Value) -> bool {
+    if let serde_json::Value::Object(attrs) = info {
+        if !attrs.contains_key("scales") {
+            let mut scale = serde_json::Map::new();
+            for &key in LEGACY_SCALE_KEYS {
+                if let Some(value) = attrs.remove(key) {
+                    scale.insert(key.to_owned(), value);
+                }
+            }
+            // A single chunk size may be given on its own.
+            if let Some(chunk_size) = attrs.remove("chunk_size") {
+                scale.entry("chunk_sizes").or_insert_with(|| serde_json::json!([chunk_size]));
+            }
+            scale.entry("key").or_insert_with(|| serde_json::json!("."));
+            attrs.insert("scales".to_owned(), serde_json::json!([scale]));
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Data types of precomputed datasets that can be read.
+const DATA_TYPES: &[&str] = &[
+    "uint8", "uint16", "uint32", "uint64", "int8", "int16", "int32", "int64", "float16", "float32",
+    "float64", "bool",
+];
+
+/// Check an `info` document for problems, as `(errors, warnings)`. Errors
+/// prevent reading the dataset, warnings point at fields that are ignored or
+/// may not behave as expected.
+fn check_info(json: &str) -> (Vec<String>, Vec<String>) {
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut info: serde_json::Value = match serde_json::from_str(json) {
+        Ok(info) => info,
+        Err(e) => return (vec![format!("Invalid JSON: {}", e)], warnings),
+    };
+    if !info.is_object() {
+        return (vec!["The info is not a JSON object".to_owned()], warnings);
+    }
+    if normalize_legacy_info(&mut info) {
+        warnings.push("The info has no scales array and is read as a single scale".to_owned());
+    }
+
+    match info.get("data_type").map(|t| t.as_str()) {
+        None => problems.push("Missing data_type".to_owned()),
+        Some(Some(data_type)) if DATA_TYPES.contains(&data_type) => (),
+        Some(data_type) => problems.push(format!("Unknown data_type {}", data_type.unwrap_or("(not a string)"))),
+    }
+    match info.get("type").and_then(|t| t.as_str()) {
+        Some("image") | Some("segmentation") => (),
+        Some(layer_type) => warnings.push(format!("Unknown layer type {}", layer_type)),
+        None => warnings.push("Missing type, read as image".to_owned()),
+    }
+    match info.get("num_channels") {
+        None => warnings.push("Missing num_channels, read as 1".to_owned()),
+        Some(num_channels) if num_channels.as_u64().map_or(false, |n| n > 0) => (),
+        Some(_) => problems.push("num_channels is not a positive integer".to_owned()),
+    }
+
+    let scales = match info.get("scales").and_then(|scales| scales.as_array()) {
+        Some(scales) if !scales.is_empty() => scales,
+        Some(_) => {
+            problems.push("scales is empty".to_owned());
+            return (problems, warnings);
+        },
+        None => {
+            problems.push("scales is not an array".to_owned());
+            return (problems, warnings);
+        },
+    };
+
+    let mut keys = Vec::new();
+    for (zoom_level, scale) in scales.iter().enumerate() {
+        let name = match scale.get("key").and_then(|key| key.as_str()) {
+            Some(key) => {
+                if keys.contains(&key) {
+                    problems.push(format!("Duplicate scale key {}", key));
+                }
+                keys.push(key);
+                format!("Scale {} ({})", zoom_level, key)
+            },
+            None => {
+                problems.push(format!("Scale {} has no key", zoom_level));
+                format!("Scale {}", zoom_level)
+            },
+        };
+        let numbers = |field: &str, integer: bool| scale.get(field)
+            .and_then(|values| values.as_array())
+            .and_then(|values| values.iter()
+                .map(|v| if integer { v.as_u64().map(|v| v as f64) } else { v.as_f64() })
+                .collect::<Option<Vec<f64>>>());
+
+        let size = numbers("size", true);
+        let ndim = match &size {
+            Some(size) => size.len(),
+            None => {
+                problems.push(format!("{} has no valid size", name));
+                0
+            },
+        };
+        match scale.get("chunk_sizes").and_then(|c| c.as_array()) {
+            Some(chunk_sizes) if !chunk_sizes.is_empty() => {
+                let valid = chunk_sizes[0].as_array().map_or(false, |chunk_size|
+                    chunk_size.iter().all(|c| c.as_u64().map_or(false, |c| c > 0))
+                        && (ndim == 0 || chunk_size.len() == ndim));
+                if !valid {
+                    problems.push(format!("{} has invalid chunk_sizes", name));
+                }
+                if chunk_sizes.len() > 1 {
+                    warnings.push(format!("{} has several chunk_sizes, only the first is read by default", name));
+                }
+            },
+            _ => problems.push(format!("{} has no chunk_sizes", name)),
+        }
+        match numbers("resolution", false) {
+            Some(resolution) if ndim == 0 || resolution.len() == ndim => {
+                if resolution.iter().any(|&r| r <= 0.0) {
+                    warnings.push(format!("{} has a resolution that is not positive", name));
+                }
+            },
+            Some(_) => problems.push(format!("{} has a resolution of the wrong length", name)),
+            None => problems.push(format!("{} has no valid resolution", name)),
+        }
+        match scale.get("voxel_offset") {
+            None => (),
+            Some(voxel_offset) => {
+                let valid = voxel_offset.as_array().map_or(false, |offset|
+                    offset.iter().all(|o| o.is_i64()) && (ndim == 0 || offset.len() == ndim));
+                if !valid {
+                    problems.push(format!("{} has an invalid voxel_offset", name));
+                }
+            },
+        }
+        match scale.get("encoding").and_then(|e| e.as_str()) {
+            Some("compressed_segmentation") => {
+                if scale.get("compressed_segmentation_block_size").is_none() {
+                    problems.push(format!("{} has no compressed_segmentation_block_size", name));
+                }
+            },
+            Some(encoding) if decode::ENCODINGS.contains(&encoding) => (),
+            Some(encoding) => warnings.push(format!("{} has encoding {}, which may not be decodable", name, encoding)),
+            None => problems.push(format!("{} has no encoding", name)),
+        }
+        if scale.get("sharding").is_some() {
+            if let Err(e) = sharded::ShardingSpec::of_attributes(scale) {
+                problems.push(format!("{} has invalid sharding: {}", name, errors::message_of(&e)));
+            }
+        }
+    }
+
+    // Anything else the reader would reject.
+    if problems.is_empty() {
+        if let Err(e) = parse_dataset_attributes(info) {
+            problems.push(errors::message_of(&e));
+        }
+    }
+
+    (problems, warnings)
+}
+
+/// Map voxel coordinates of a scale (including the voxel offset) to physical
+/// coordinates: scaled by the resolution, then by the dataset's `transform`,
+/// if the info has one.
+fn voxel_to_physical(data_attrs: &DatasetAttributes, zoom_level: usize, voxel: &[f64]) -> Result<Vec<f64>, Error> {
+    let resolution = checked_resolution(data_attrs, zoom_level, voxel.len())?;
+    let scaled: Vec<f64> = voxel.iter().zip(&resolution).map(|(&v, &r)| v * r).collect();
+
+    Ok(transform::apply(&dataset_transform(data_attrs, voxel.len())?, &scaled))
+}
+
+/// The inverse of `voxel_to_physical`.
+fn physical_to_voxel(data_attrs: &DatasetAttributes, zoom_level: usize, physical: &[f64]) -> Result<Vec<f64>, Error> {
+    let resolution = checked_resolution(data_attrs, zoom_level, physical.len())?;
+    let inverse = transform::invert(&dataset_transform(data_attrs, physical.len())?)?;
+
+    Ok(transform::apply(&inverse, physical).iter().zip(&resolution).map(|(&p, &r)| p / r).collect())
+}
+
+fn checked_resolution(data_attrs: &DatasetAttributes, zoom_level: usize, ndim: usize) -> Result<Vec<f64>, Error> {
+    if zoom_level >= data_attrs.get_scales().len() || ndim != data_attrs.get_ndim(zoom_level) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Expected {} coordinates at zoom level {}", data_attrs.get_ndim(0), zoom_level)));
+    }
+    let resolution = scale_resolution(data_attrs, zoom_level)?;
+    if resolution.len() != ndim || resolution.iter().any(|&r| r == 0.0) {
+        return Err(errors::new(errors::ErrorKind::BadAttrs, format!(
+            "Invalid resolution {:?}", resolution)));
+    }
+
+    Ok(resolution)
+}
+
+/// The top-level `transform` of the info, or the identity if there is none.
+fn dataset_transform(data_attrs: &DatasetAttributes, ndim: usize) -> Result<transform::Affine, Error> {
+    match &data_attrs.transform {
+        None => Ok(transform::identity(ndim)),
+        Some(value) => transform::parse_affine(value, ndim),
+    }
+}
+
+/// The JSON attributes of a single scale, including keys that have no
+/// accessor on `ngpre::DatasetAttributes`.
+fn scale_attributes(data_attrs: &DatasetAttributes, zoom_level: usize) -> Result<serde_json::Value, Error> {
+    let attrs = serde_json::to_value(data_attrs)
+        .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, e.to_string()))?;
+
+    attrs.get("scales")
+        .and_then(|scales| scales.get(zoom_level))
+        .cloned()
+        .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs,
+            format!("No scale at zoom level {}", zoom_level)))
+}
+
+/// Attributes whose scale `path_name` uses the chunk size at `index` of its
+/// `chunk_sizes` list, so that blocks of that chunk layout are read.
+fn with_chunk_size(data_attrs: &DatasetAttributes, path_name: &str, index: usize) -> Result<DatasetAttributes, Error> {
+    let zoom_level = zoom_level(data_attrs, path_name)?;
+    let mut attrs = serde_json::to_value(data_attrs)
+        .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, e.to_string()))?;
+    let chunk_sizes = attrs.pointer_mut(&format!("/scales/{}/chunk_sizes", zoom_level))
+        .and_then(serde_json::Value::as_array_mut)
+        .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs,
+            format!("Scale {} has no chunk_sizes", path_name)))?;
+    let chunk_size = chunk_sizes.get(index).cloned()
+        .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Scale {} has {} chunk sizes, no chunk size {}", path_name, chunk_sizes.len(), index)))?;
+    *chunk_sizes = vec![chunk_size];
+
+    parse_dataset_attributes(attrs)
+}
+
+/// Fields of two JSON objects whose values differ, as `{field: {old, new}}`,
+/// with `null` for a field missing on one side.
+fn changed_fields(
+    old: &serde_json::Map<String, serde_json::Value>,
+    new: &serde_json::Map<String, serde_json::Value>,
+    skip: &[&str],
+) -> serde_json::Map<String, serde_json::Value> {
+    let null = serde_json::Value::Null;
+    let mut fields = old.keys().chain(new.keys().filter(|key| !old.contains_key(*key)))
+        .filter(|key| !skip.contains(&key.as_str()))
+        .collect::<Vec<_>>();
+    fields.sort();
+
+    fields.into_iter()
+        .filter_map(|field| {
+            let (old_value, new_value) = (old.get(field).unwrap_or(&null), new.get(field).unwrap_or(&null));
+            if old_value == new_value {
+                None
+            } else {
+                Some((field.clone(), serde_json::json!({"old": old_value, "new": new_value})))
+            }
+        })
+        .collect()
+}
+
+/// Differences between two dataset attributes, as `{equal, fields, scales:
+/// {added, removed, changed}}`. `fields` holds the changed top-level fields
+/// (such as `data_type`), and scales are matched by key: `added` and
+/// `removed` list keys, and `changed` maps keys to their changed fields.
+fn attributes_diff(old: &DatasetAttributes, new: &DatasetAttributes) -> Result<serde_json::Value, Error> {
+    let to_object = |data_attrs: &DatasetAttributes| match serde_json::to_value(data_attrs) {
+        Ok(serde_json::Value::Object(attrs)) => Ok(attrs),
+        Ok(_) => Err(errors::new(errors::ErrorKind::BadAttrs, "Attributes are not an object")),
+        Err(e) => Err(errors::new(errors::ErrorKind::BadAttrs, e.to_string())),
+    };
+    let (old, new) = (to_object(old)?, to_object(new)?);
+    let scales = |attrs: &serde_json::Map<String, serde_json::Value>| attrs.get("scales")
+        .and_then(serde_json::Value::as_array)
+        .map(|scales| scales.iter()
+            .filter_map(|scale| scale.as_object())
+            .map(|scale| (scale.get("key").and_then(serde_json::Value::as_str).unwrap_or("").to_owned(), scale.clone()))
+            .collect::<Vec<_>>())
+        .unwrap_or_default();
+    let (old_scales, new_scales) = (scales(&old), scales(&new));
+    let find = |scales: &[(String, serde_json::Map<String, serde_json::Value>)], key: &str|
+        scales.iter().find(|(k, _)| k == key).map(|(_, scale)| scale.clone());
+
+    let fields = changed_fields(&old, &new, &["scales"]);
+    let added = new_scales.iter()
+        .filter(|(key, _)| find(&old_scales, key).is_none())
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let removed = old_scales.iter()
+        .filter(|(key, _)| find(&new_scales, key).is_none())
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let changed = old_scales.iter()
+        .filter_map(|(key, old_scale)| find(&new_scales, key)
+            .map(|new_scale| (key.clone(), changed_fields(old_scale, &new_scale, &[]))))
+        .filter(|(_, fields)| !fields.is_empty())
+        .map(|(key, fields)| (key, serde_json::Value::Object(fields)))
+        .collect::<serde_json::Map<_, _>>();
+    let equal = fields.is_empty() && added.is_empty() && removed.is_empty() && changed.is_empty();
+
+    Ok(serde_json::json!({
+        "equal": equal,
+        "fields": fields,
+        "scales": {
+            "added": added,
+            "removed": removed,
+            "changed": changed,
+        },
+    }))
+}
+
+fn scale_resolution(data_attrs: &DatasetAttributes, zoom_level: usize) -> Result<Vec<f64>, Error> {
+    scale_attributes(data_attrs, zoom_level)?
+        .get("resolution")
+        .and_then(|resolution| serde_json::from_value(resolution.clone()).ok())
+        .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs, "Scale has no valid resolution"))
+}
+
+/// Maximum relative difference per axis for a scale's resolution to match a
+/// requested resolution.
+pub const RESOLUTION_TOLERANCE: f64 = 0.01;
+
+/// Find the scale whose resolution best matches `resolution`, rejecting with
+/// `no_scale` (and the available resolutions) if none is within tolerance.
+fn matching_scale(data_attrs: &DatasetAttributes, resolution: &[f64]) -> Result<usize, Error> {
+    let resolutions = (0..data_attrs.get_scales().len())
+        .map(|zoom_level| scale_resolution(data_attrs, zoom_level))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let best = resolutions.iter()
+        .enumerate()
+        .filter(|(_, scale_resolution)| scale_resolution.len() == resolution.len())
+        .map(|(zoom_level, scale_resolution)| {
+            let difference = scale_resolution.iter().zip(resolution)
+                .map(|(&s, &r)| ((s - r) / r).abs())
+                .fold(0.0, f64::max);
+            (zoom_level, difference)
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    match best {
+        Some((zoom_level, difference)) if difference <= RESOLUTION_TOLERANCE => Ok(zoom_level),
+        _ => {
+            let mut details = serde_json::Map::new();
+            details.insert("available_resolutions".to_owned(), serde_json::json!(resolutions));
+            Err(errors::with_details(errors::ErrorKind::NoScale,
+                format!("No scale matches resolution {:?}, available: {:?}", resolution, resolutions),
+                details))
+        },
+    }
+}
+
+/// Find the scale whose resolution is closest to `resolution` by Euclidean
+/// distance, without a tolerance. Of equally close scales, the finer one
+/// (with the smaller voxel volume) is chosen.
+fn nearest_scale(data_attrs: &DatasetAttributes, resolution: &[f64]) -> Result<usize, Error> {
+    let resolutions = (0..data_attrs.get_scales().len())
+        .map(|zoom_level| scale_resolution(data_attrs, zoom_level))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    resolutions.iter()
+        .enumerate()
+        .filter(|(_, scale_resolution)| scale_resolution.len() == resolution.len())
+        .map(|(zoom_level, scale_resolution)| {
+            let distance = scale_resolution.iter().zip(resolution)
+                .map(|(&s, &r)| (s - r) * (s - r))
+                .sum::<f64>();
+            let volume = scale_resolution.iter().product::<f64>();
+            (zoom_level, distance, volume)
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal)))
+        .map(|(zoom_level, _, _)| zoom_level)
+        .ok_or_else(|| {
+            let mut details = serde_json::Map::new();
+            details.insert("available_resolutions".to_owned(), serde_json::json!(resolutions));
+            errors::with_details(errors::ErrorKind::NoScale,
+                format!("No scale has {} dimensions, available: {:?}", resolution.len(), resolutions),
+                details)
+        })
+}
+
+/// The encoding of a scale, e.g. `raw`, `jpeg` or `compressed_segmentation`.
+fn scale_encoding(data_attrs: &DatasetAttributes, zoom_level: usize) -> Result<String, Error> {
+    let scale = scale_attributes(data_attrs, zoom_level)?;
+
+    Ok(match scale.get("encoding").and_then(|encoding| encoding.as_str()) {
+        Some(encoding) => encoding.to_owned(),
+        None => data_attrs.get_compression().to_string().to_lowercase(),
+    })
+}
+
+fn num_channels(data_attrs: &DatasetAttributes) -> u32 {
+    serde_json::to_value(data_attrs).ok()
+        .and_then(|attrs| attrs.get("num_channels").and_then(|n| n.as_u64()))
+        .unwrap_or(1) as u32
+}
 
-        future_to_promise(map_future_error_wasm(to_return))
+/// The layer type of the dataset (`"image"` or `"segmentation"`), which is
+/// `"image"` if the info does not specify one.
+fn layer_type(data_attrs: &DatasetAttributes) -> String {
+    serde_json::to_value(data_attrs).ok()
+        .and_then(|attrs| attrs.get("type").and_then(|t| t.as_str()).map(str::to_owned))
+        .unwrap_or_else(|| "image".to_owned())
+}
+
+/// Copy elements into the byte view `dst` starting at element `offset`.
+fn copy_into_bytes<T: Copy>(data: &[T], dst: &js_sys::Uint8Array, offset: u32) -> Result<u32, Error> {
+    let element_size = std::mem::size_of::<T>();
+    let byte_offset = offset as usize * element_size;
+    let byte_len = data.len() * element_size;
+    if byte_offset + byte_len > dst.byte_length() as usize {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Destination has {} bytes, but {} are required",
+            dst.byte_length(), byte_offset + byte_len)));
     }
+
+    // WASM memory is little-endian, so this is the little-endian byte layout.
+    let bytes = unsafe {
+        std::slice::from_raw_parts(data.as_ptr() as *const u8, byte_len)
+    };
+    // The view is only valid until the next allocation, so use it at once.
+    let view = unsafe { js_sys::Uint8Array::view(bytes) };
+    dst.set(&view, byte_offset as u32);
+
+    Ok(data.len() as u32)
 }
 
+/// Build a plain JS object from property names and values.
+fn js_object(entries: &[(&str, JsValue)]) -> JsValue {
+    let object = js_sys::Object::new();
+    for (key, value) in entries {
+        // Setting a property on a fresh, extensible object cannot fail.
+        let _ = js_sys::Reflect::set(&object, &JsValue::from_str(key), value);
+    }
 
-pub trait NgPrePromiseEtagReader {
-    fn block_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise;
+    JsValue::from(object)
+}
 
-    fn read_block_with_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise;
+/// Convert a JS `BigInt`, such as a segment ID, to a `u64`.
+fn bigint_to_u64(value: &js_sys::BigInt) -> Result<u64, Error> {
+    value.to_string(10).ok()
+        .and_then(|digits| String::from(digits).parse().ok())
+        .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+            "Expected an unsigned 64 bit integer"))
 }
 
-impl<T> NgPrePromiseEtagReader for T where T: NgPreAsyncEtagReader {
-    fn block_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise {
-        let to_return = self.block_etag(path_name, &data_attrs.0, grid_position.into())
-            .map(JsValue::from);
+/// Convert a JS array of grid positions (arrays of numbers) for batch reads.
+fn convert_grid_positions(grid_positions: &JsValue) -> Result<Vec<Vec<i64>>, Error> {
+    grid_positions.into_serde()
+        .map_err(|e| errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Expected an array of grid positions: {}", e)))
+}
 
-        future_to_promise(map_future_error_wasm(to_return))
-    }
 
-    fn read_block_with_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &wrapped::DatasetAttributes,
-        grid_position: Vec<i64>,
-    ) -> Promise {
+/// Decode the bytes of a chunk of the scale `path_name`, as fetched by
+/// `read_block`, into a block.
+#[wasm_bindgen]
+pub fn decode_block(
+    path_name: &str,
+    data_attrs: &wrapped::DatasetAttributes,
+    grid_position: Vec<i64>,
+    bytes: js_sys::Uint8Array,
+) -> Result<JsValue, JsValue> {
+    let data_attrs = &data_attrs.0;
+    let decoded = zoom_level(data_attrs, path_name).and_then(|zoom_level| {
+        check_grid_ndim(data_attrs, zoom_level, &grid_position)?;
+        let offset_grid_position = checked_grid_position(&grid_position,
+                data_attrs.get_block_size(zoom_level), data_attrs.get_dimensions(zoom_level))
+            .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Grid position {:?} is out of bounds", grid_position)))?;
+        let bytes = bytes.to_vec();
 
         data_type_match! {
-            data_attrs.0.get_data_type(),
-            future_to_promise(map_future_error_wasm(
-                self.read_block_with_etag::<RsType>(path_name, &data_attrs.0, grid_position.into())
-                    .map(|maybe_block| JsValue::from(
-                        maybe_block.map(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from)))))
+            data_attrs.get_data_type(),
+            decode::decode_block::<RsType>(&bytes, data_attrs, zoom_level, offset_grid_position,
+                    raw::ByteOrder::Little)
+                .map(|block| JsValue::from(
+                    <RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))
         }
-    }
+    });
+
+    decoded.map_err(|e| errors::to_js_error(&e))
 }
 
 
-/// This trait exists to preserve type information between calls (rather than
-/// erasing it with `Promise`) and for easier potential future compatibility
-/// with an NgPre core async trait.
-pub trait NgPreAsyncReader {
-    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>>;
+/// Check a precomputed `info` document without opening a dataset, with the
+/// same parsing as readers. Returns `{valid, errors, warnings}`, where
+/// `errors` lists the problems that prevent reading the dataset and
+/// `warnings` lists fields that are ignored or may not work as expected.
+#[wasm_bindgen]
+pub fn validate_info(json: &str) -> JsValue {
+    let (errors, warnings) = check_info(json);
 
-    fn get_dataset_attributes(&self, path_name: &str) ->
-        Box<dyn Future<Item = ngpre::DatasetAttributes, Error = Error>>;
+    JsValue::from_serde(&serde_json::json!({
+        "valid": errors.is_empty(),
+        "errors": errors,
+        "warnings": warnings,
+    })).unwrap()
+}
 
-    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>>;
+/// List the chunk encodings (such as `"jpeg"`) that `read_block` can decode
+/// in this build, as an array of strings. Other encodings are left to ngpre
+/// and may fail to decode.
+#[wasm_bindgen]
+pub fn supported_compressions() -> JsValue {
+    JsValue::from_serde(decode::ENCODINGS).unwrap()
+}
 
-    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
-        Box::new(self.exists(path_name).join(
-            self.get_dataset_attributes(path_name)
-                .map(|_| true)
-                .or_else(|_| futures::future::ok(false))
-        ).map(|(exists, has_attr)| exists && has_attr))
-    }
+/// Compute the compressed Morton code (a `BigInt` in JS) of a grid position
+/// in a grid of the given shape, as used for chunk IDs of sharded scales.
+#[wasm_bindgen]
+pub fn compressed_morton_code(grid_position: Vec<u64>, grid_shape: Vec<u64>) -> Result<u64, JsValue> {
+    sharded::check_grid_shape(&grid_shape)
+        .and_then(|()| {
+            let in_grid = grid_position.len() == grid_shape.len() &&
+                grid_position.iter().zip(&grid_shape).all(|(&p, &s)| p < s);
+            if !in_grid {
+                return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+                    "Grid position {:?} is not within grid shape {:?}", grid_position, grid_shape)));
+            }
 
-    fn read_block<T>(
-        &self,
-        path_name: &str,
-        data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
-            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
-                T: ReflectedType;
+            Ok(sharded::compressed_morton_code(&grid_position, &grid_shape))
+        })
+        .map_err(|e| errors::to_js_error(&e))
+}
 
-    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>>;
+/// Compute the grid position of a compressed Morton code in a grid of the
+/// given shape, the inverse of `compressed_morton_code`.
+#[wasm_bindgen]
+pub fn decode_morton_code(code: u64, grid_shape: Vec<u64>) -> Result<Vec<u64>, JsValue> {
+    sharded::check_grid_shape(&grid_shape)
+        .map(|()| sharded::decode_morton_code(code, &grid_shape))
+        .map_err(|e| errors::to_js_error(&e))
+}
 
-    fn list_attributes(&self, path_name: &str) -> Box<dyn Future<Item = serde_json::Value, Error = Error>>;
+
+/// Compression ratio assumed by `estimate_region_bytes` for encodings other
+/// than `raw`.
+pub const DEFAULT_COMPRESSION_RATIO: f64 = 4.0;
+
+/// Estimate the number of bytes to fetch for the voxel box `[min, max)` of a
+/// scale, without making any requests. For `raw` chunks this is the exact
+/// decoded size of all overlapping chunks (before any HTTP compression); for
+/// other encodings it is that size divided by `compression_ratio` (default
+/// `DEFAULT_COMPRESSION_RATIO`), so only approximate.
+#[wasm_bindgen]
+pub fn estimate_region_bytes(
+    data_attrs: &wrapped::DatasetAttributes,
+    zoom_level: usize,
+    min: Vec<i64>,
+    max: Vec<i64>,
+    compression_ratio: Option<f64>,
+) -> Result<f64, JsValue> {
+    let data_attrs = &data_attrs.0;
+    let estimate = grid_positions_in_box(data_attrs, zoom_level, &min, &max).and_then(|grid_positions| {
+        let compression_ratio = compression_ratio.unwrap_or(DEFAULT_COMPRESSION_RATIO);
+        if !(compression_ratio > 0.0) {
+            return Err(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Invalid compression ratio {}", compression_ratio)));
+        }
+        let element_size = data_type_match! {
+            data_attrs.get_data_type(),
+            std::mem::size_of::<RsType>()
+        };
+
+        let num_elements: f64 = grid_positions.iter()
+            .map(|grid_position| {
+                let grid_position: Vec<u64> = grid_position.iter().map(|&c| c as u64).collect();
+                raw::block_extent(&grid_position, data_attrs.get_block_size(zoom_level), data_attrs.get_dimensions(zoom_level))
+                    .iter()
+                    .map(|&e| f64::from(e))
+                    .product::<f64>()
+            })
+            .sum();
+        let decoded_bytes = num_elements * f64::from(num_channels(data_attrs)) * element_size as f64;
+
+        Ok(match scale_encoding(data_attrs, zoom_level)?.as_str() {
+            "raw" => decoded_bytes,
+            _ => decoded_bytes / compression_ratio,
+        })
+    });
+
+    estimate.map_err(|e| errors::to_js_error(&e))
 }
 
 
-pub trait NgPreAsyncEtagReader {
-    fn block_etag(
-        &self,
-        path_name: &str,
-        data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<String>, Error = Error>>;
+/// The JS typed array holding the data of blocks of a data type.
+fn typed_array_name(data_type: &ngpre::DataType) -> &'static str {
+    match data_type {
+        ngpre::DataType::UINT8 => "Uint8Array",
+        ngpre::DataType::UINT16 => "Uint16Array",
+        ngpre::DataType::UINT32 => "Uint32Array",
+        ngpre::DataType::UINT64 => "BigUint64Array",
+        ngpre::DataType::INT8 => "Int8Array",
+        ngpre::DataType::INT16 => "Int16Array",
+        ngpre::DataType::INT32 => "Int32Array",
+        ngpre::DataType::INT64 => "BigInt64Array",
+        ngpre::DataType::FLOAT32 => "Float32Array",
+        ngpre::DataType::FLOAT64 => "Float64Array",
+    }
+}
 
-    fn read_block_with_etag<T>(
-        &self,
-        path_name: &str,
-        data_attrs: &DatasetAttributes,
-        grid_position: UnboundedGridCoord,
-    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
-            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
-                T: ReflectedType;
+/// The bytes viewed by a JS typed array, in the little-endian byte order of
+/// wasm.
+fn typed_array_bytes(array: &JsValue) -> Result<Vec<u8>, JsValue> {
+    let get = |key: &str| js_sys::Reflect::get(array, &JsValue::from_str(key));
+    let buffer = get("buffer")?.dyn_into::<js_sys::ArrayBuffer>()?;
+    let byte_offset = get("byteOffset")?.as_f64().unwrap_or(0.0) as u32;
+    let byte_length = get("byteLength")?.as_f64().unwrap_or(0.0) as u32;
+
+    Ok(js_sys::Uint8Array::new_with_byte_offset_and_length(&buffer, byte_offset, byte_length).to_vec())
 }
 
+/// Call the method `method` of a JS object without arguments.
+fn call_getter(object: &JsValue, method: &str) -> Result<JsValue, JsValue> {
+    js_sys::Reflect::get(object, &JsValue::from_str(method))
+        .and_then(|method| method.dyn_into::<js_sys::Function>().map_err(JsValue::from))
+        .and_then(|method| method.call0(object))
+}
 
-fn map_future_error_rust<F: Future<Item = T, Error = JsValue>, T>(future: F)
-        -> impl Future<Item = T, Error = Error> {
-    future.map_err(convert_jsvalue_error)
+/// The name of the constructor of a JS value, such as `Uint8Array`.
+fn constructor_name(value: &JsValue) -> Option<String> {
+    js_sys::Reflect::get(value, &JsValue::from_str("constructor"))
+        .and_then(|constructor| js_sys::Reflect::get(&constructor, &JsValue::from_str("name")))
+        .ok()
+        .and_then(|name| name.as_string())
 }
 
-fn map_future_error_wasm<F: Future<Item = T, Error = Error>, T>(future: F)
-        -> impl Future<Item = T, Error = JsValue> {
-    future.map_err(|error| {
-        let js_error = js_sys::Error::new(&format!("{:?}", error));
-        JsValue::from(js_error)
-    })
+/// The data types of blocks, one for each monomorphized block type.
+static BLOCK_DATA_TYPES: [ngpre::DataType; 10] = [
+    ngpre::DataType::UINT8, ngpre::DataType::UINT16, ngpre::DataType::UINT32, ngpre::DataType::UINT64,
+    ngpre::DataType::INT8, ngpre::DataType::INT16, ngpre::DataType::INT32, ngpre::DataType::INT64,
+    ngpre::DataType::FLOAT32, ngpre::DataType::FLOAT64,
+];
+
+/// The data type of a block object from JS, from the typed array of its data.
+fn block_data_type(block: &JsValue) -> Result<&'static ngpre::DataType, Error> {
+    let typed_array = call_getter(block, "get_data").ok().as_ref().and_then(constructor_name);
+    BLOCK_DATA_TYPES.iter()
+        .find(|&data_type| typed_array.as_ref().map(String::as_str) == Some(typed_array_name(data_type)))
+        .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument,
+            format!("Expected a block, got {:?}", block)))
 }
 
-fn convert_jsvalue_error(error: JsValue) -> Error {
-    Error::new(std::io::ErrorKind::Other, format!("{:?}", error))
+/// Copy a block object from JS, such as a `VecDataBlockUINT8`, through its
+/// getters, since exported structs cannot be taken out of a JS array. Rejects
+/// blocks whose data is not a `typed_array`, i.e. of another data type.
+fn block_from_js<T>(block: &JsValue, typed_array: &str) -> Result<VecDataBlock<T>, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: RawElement,
+{
+    let not_a_block = |_| errors::new(errors::ErrorKind::InvalidArgument,
+        format!("Expected a block with a {} of data, got {:?}", typed_array, block));
+
+    let data = call_getter(block, "get_data").map_err(not_a_block)?;
+    if constructor_name(&data).as_ref().map(String::as_str) != Some(typed_array) {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot merge blocks of other data types than the dataset's, whose data is a {}", typed_array)));
+    }
+    let data = typed_array_bytes(&data).map_err(not_a_block)?;
+    let size = call_getter(block, "get_size").and_then(|size| typed_array_bytes(&size)).map_err(not_a_block)?;
+    let grid_position = call_getter(block, "get_grid_position")
+        .and_then(|grid_position| typed_array_bytes(&grid_position))
+        .map_err(not_a_block)?;
+
+    Ok(VecDataBlock::new(
+        raw::decode_le::<u32>(&size)?.into(),
+        raw::decode_le::<u64>(&grid_position)?.into(),
+        raw::decode_le::<T>(&data)?))
+}
+
+/// Stitch an array of blocks already in memory, such as from a custom cache,
+/// into one block spanning all of them, placed by their grid positions, with
+/// zeros where no block lies. Rejects with `invalid_argument` if a block does
+/// not have the data type of the dataset or two blocks have the same grid
+/// position. See `BlockMerger` to add blocks one at a time.
+#[wasm_bindgen]
+pub fn merge_blocks(
+    blocks: js_sys::Array,
+    data_attrs: &wrapped::DatasetAttributes,
+    zoom_level: usize,
+) -> Result<JsValue, JsValue> {
+    let data_attrs = &data_attrs.0;
+    let typed_array = typed_array_name(data_attrs.get_data_type());
+    let merged = data_type_match! {
+        data_attrs.get_data_type(),
+        (0..blocks.length())
+            .map(|i| block_from_js::<RsType>(&blocks.get(i), typed_array))
+            .collect::<Result<Vec<_>, Error>>()
+            .and_then(|blocks| merge_vec_blocks(&blocks.iter().collect::<Vec<_>>(), data_attrs, zoom_level))
+            .map(|block| JsValue::from(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))
+    };
+
+    merged.map_err(|e| errors::to_js_error(&e))
+}
+
+/// Downsample a block of any data type by `factors` per axis with `method`
+/// `"mean"` (for images) or `"mode"` (for segmentation), like the block's
+/// `downsample` method, e.g. to synthesize a missing scale from the next
+/// finer one. Sizes not divisible by their factor are truncated.
+#[wasm_bindgen]
+pub fn downsample_block(block: &JsValue, factors: Vec<u32>, method: &str) -> Result<JsValue, JsValue> {
+    let downsampled = method.parse::<downsample::DownsampleMethod>()
+        .and_then(|method| block_data_type(block).map(|data_type| (method, data_type)))
+        .and_then(|(method, data_type)| data_type_match! {
+            data_type,
+            block_from_js::<RsType>(block, typed_array_name(data_type))
+                .and_then(|block| downsample::downsample_block(&block, &factors, method))
+                .map(|block| JsValue::from(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))
+        });
+
+    downsampled.map_err(|e| errors::to_js_error(&e))
 }
 
 
@@ -235,9 +2904,47 @@ pub mod wrapped {
         }
     }
 
+    /// The attributes of an `info` file, with getters for the common keys.
+    #[wasm_bindgen]
+    pub struct DatasetInfo(pub(crate) serde_json::Value);
+
+    #[wasm_bindgen]
+    impl DatasetInfo {
+        pub fn get_data_type(&self) -> Option<String> {
+            self.0.get("data_type").and_then(|t| t.as_str()).map(str::to_owned)
+        }
+
+        /// Get the number of channels, which is 1 if the info does not
+        /// specify it.
+        pub fn get_num_channels(&self) -> u32 {
+            self.0.get("num_channels").and_then(|n| n.as_u64()).unwrap_or(1) as u32
+        }
+
+        /// Get the layer type, which is `"image"` if the info does not specify
+        /// it.
+        pub fn get_layer_type(&self) -> String {
+            self.0.get("type").and_then(|t| t.as_str()).unwrap_or("image").to_owned()
+        }
+
+        pub fn num_scales(&self) -> usize {
+            self.0.get("scales").and_then(|scales| scales.as_array()).map_or(0, Vec::len)
+        }
+
+        /// Get the value of any key, or `undefined` if it is not set.
+        pub fn get(&self, key: &str) -> JsValue {
+            self.0.get(key)
+                .and_then(|value| JsValue::from_serde(value).ok())
+                .unwrap_or(JsValue::UNDEFINED)
+        }
+
+        pub fn to_json(&self) -> JsValue {
+            JsValue::from_serde(&self.0).unwrap()
+        }
+    }
+
     #[wasm_bindgen]
     #[derive(serde::Deserialize, serde::Serialize)]
-    pub struct DatasetAttributes(pub(crate) ngpre::DatasetAttributes);
+    pub struct DatasetAttributes(pub(crate) super::DatasetAttributes);
 
     #[wasm_bindgen]
     impl DatasetAttributes {
@@ -253,34 +2960,225 @@ pub mod wrapped {
             self.0.get_voxel_offset(zoom_level).to_owned()
         }
 
+        /// Get the number of chunks along each axis, rounding up for
+        /// dimensions that are not multiples of the block size. The last
+        /// chunk along such an axis has the valid extent
+        /// `dimension - (grid_shape - 1) * block_size`.
+        pub fn get_grid_shape(&self, zoom_level: usize) -> Vec<u64> {
+            sharded::grid_shape(&self.0, zoom_level)
+        }
+
+        /// The key of the chunk at `grid_position` in the scale at
+        /// `zoom_level`, as used for the file name of unsharded chunks, such
+        /// as `0-64_64-128_0-30` for 3D.
+        pub fn grid_position_to_key(&self, zoom_level: usize, grid_position: Vec<i64>) -> Result<String, JsValue> {
+            if zoom_level >= self.0.get_scales().len() {
+                return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                    format!("Dataset has no scale {}", zoom_level))));
+            }
+            let block_size = self.0.get_block_size(zoom_level);
+            let dimensions = self.0.get_dimensions(zoom_level);
+            super::check_grid_ndim(&self.0, zoom_level, &grid_position)
+                .map_err(|e| errors::to_js_error(&e))?;
+            if super::checked_grid_position(&grid_position, block_size, dimensions).is_none() {
+                return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                    format!("Grid position {:?} is out of bounds", grid_position))));
+            }
+
+            Ok(super::chunk_key(&grid_position, block_size, self.0.get_voxel_offset(zoom_level), dimensions))
+        }
+
+        /// The grid position of the chunk with key `key` in the scale at
+        /// `zoom_level`, the inverse of `grid_position_to_key`.
+        pub fn key_to_grid_position(&self, zoom_level: usize, key: &str) -> Result<Vec<u64>, JsValue> {
+            super::chunk_key_grid_position(&self.0, zoom_level, key)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        /// The chunk sizes a scale is stored in, as listed by its
+        /// `chunk_sizes`. Pass the index of one to `read_block` as
+        /// `chunk_size_index` to read blocks of that layout.
+        pub fn get_chunk_size_options(&self, zoom_level: usize) -> JsValue {
+            super::scale_attributes(&self.0, zoom_level).ok()
+                .and_then(|scale| scale.get("chunk_sizes").cloned())
+                .map(|chunk_sizes| JsValue::from_serde(&chunk_sizes).unwrap())
+                .unwrap_or(JsValue::NULL)
+        }
+
+        /// Get the voxel bounds `{min, max}` of a scale, with `max` exclusive
+        /// and both including the voxel offset.
+        pub fn get_bounds(&self, zoom_level: usize) -> JsValue {
+            let min: Vec<i64> = self.0.get_voxel_offset(zoom_level).iter().map(|&o| o as i64).collect();
+            let max: Vec<i64> = min.iter().zip(self.0.get_dimensions(zoom_level))
+                .map(|(&o, &d)| o + d as i64)
+                .collect();
+
+            JsValue::from_serde(&serde_json::json!({"min": min, "max": max})).unwrap()
+        }
+
+        /// Map voxel coordinates of a scale, including the voxel offset, to
+        /// physical coordinates (nanometers for precomputed), in the axis
+        /// order of the info. This applies the scale resolution and then any
+        /// top-level affine `transform` of the info.
+        pub fn voxel_to_physical(&self, zoom_level: usize, voxel: Vec<f64>) -> Result<Vec<f64>, JsValue> {
+            super::voxel_to_physical(&self.0, zoom_level, &voxel)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        /// Map physical coordinates to (fractional) voxel coordinates of a
+        /// scale, the inverse of `voxel_to_physical`.
+        pub fn physical_to_voxel(&self, zoom_level: usize, physical: Vec<f64>) -> Result<Vec<f64>, JsValue> {
+            super::physical_to_voxel(&self.0, zoom_level, &physical)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        /// Whether the dataset has a scale at `zoom_level`. Other methods
+        /// taking a zoom level require it to exist.
+        pub fn has_scale(&self, zoom_level: usize) -> bool {
+            zoom_level < self.0.get_scales().len()
+        }
+
+        /// The zoom level of the scale whose resolution is closest to
+        /// `resolution` by Euclidean distance. Ties are resolved in favour of
+        /// the finer scale. Unlike `read_block_at_resolution`, any scale of
+        /// the same dimensionality matches.
+        pub fn nearest_scale(&self, resolution: Vec<f64>) -> Result<usize, JsValue> {
+            super::nearest_scale(&self.0, &resolution)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        /// Grid positions of all blocks of a scale overlapping the voxel box
+        /// `[min, max)`, which includes the voxel offset like `get_bounds`,
+        /// as an array for `read_blocks`. The box is clamped to the dataset
+        /// bounds, so a box outside the volume has no grid positions.
+        pub fn grid_positions_for_box(&self, zoom_level: usize, min: Vec<i64>, max: Vec<i64>) -> Result<JsValue, JsValue> {
+            let grid_positions = super::grid_positions_in_box(&self.0, zoom_level, &min, &max)
+                .map_err(|e| errors::to_js_error(&e))?;
+
+            Ok(JsValue::from_serde(&grid_positions).unwrap())
+        }
+
         pub fn get_data_type(&self) -> String {
             self.0.get_data_type().to_string()
         }
 
-        pub fn get_compression(&self) -> String {
-            self.0.get_compression().to_string()
+        /// Get the number of bytes of an element of the data type.
+        pub fn get_data_type_size(&self) -> u32 {
+            data_type_match! {
+                self.0.get_data_type(),
+                std::mem::size_of::<RsType>() as u32
+            }
+        }
+
+        /// Get the compression of the scale at `zoom_level`, which may differ
+        /// between scales, e.g. `"raw"` for the full resolution and `"jpeg"`
+        /// for coarser scales. Without a zoom level, this is the compression
+        /// of the dataset as reported by ngpre.
+        pub fn get_compression(&self, zoom_level: Option<usize>) -> Result<String, JsValue> {
+            match zoom_level {
+                Some(zoom_level) => scale_encoding(&self.0, zoom_level)
+                    .map_err(|e| errors::to_js_error(&e)),
+                None => Ok(self.0.get_compression().to_string()),
+            }
+        }
+
+        /// Get the number of channels, which is 1 if the info does not
+        /// specify it.
+        pub fn get_num_channels(&self) -> u32 {
+            num_channels(&self.0)
+        }
+
+        /// Get the layer type, e.g. `"image"` or `"segmentation"`. Defaults to
+        /// `"image"` if the info has no `type`.
+        pub fn get_layer_type(&self) -> String {
+            layer_type(&self.0)
         }
 
         pub fn get_ndim(&self, zoom_level: usize) -> usize {
             self.0.get_ndim(zoom_level)
         }
 
-        /// Get the total number of elements possible given the dimensions.
+        /// Get the total number of elements possible given the dimensions,
+        /// including all channels.
         pub fn get_num_elements(&self, zoom_level: usize) -> usize {
-            self.0.get_num_elements(zoom_level)
+            self.0.get_num_elements(zoom_level) * num_channels(&self.0) as usize
         }
 
-        /// Get the total number of elements possible in a block.
+        /// Get the total number of elements possible in a block, including
+        /// all channels.
         pub fn get_block_num_elements(&self, zoom_level: usize) -> usize {
+            self.get_block_num_elements_per_channel(zoom_level) * num_channels(&self.0) as usize
+        }
+
+        /// Get the number of elements possible in one channel of a block,
+        /// i.e. the product of the block size.
+        pub fn get_block_num_elements_per_channel(&self, zoom_level: usize) -> usize {
             self.0.get_block_num_elements(zoom_level)
         }
 
+        pub fn num_scales(&self) -> usize {
+            self.0.get_scales().len()
+        }
+
+        /// Get an array describing every scale, with `zoom_level`,
+        /// `dimensions`, `block_size`, `voxel_offset`, `resolution` and
+        /// `compression`.
+        pub fn get_scales(&self) -> Result<JsValue, JsValue> {
+            let scales = (0..self.num_scales())
+                .map(|zoom_level| Ok(serde_json::json!({
+                    "zoom_level": zoom_level,
+                    "dimensions": self.0.get_dimensions(zoom_level),
+                    "block_size": self.0.get_block_size(zoom_level),
+                    "voxel_offset": self.0.get_voxel_offset(zoom_level),
+                    "resolution": scale_resolution(&self.0, zoom_level).ok(),
+                    "compression": scale_encoding(&self.0, zoom_level)?,
+                })))
+                .collect::<Result<Vec<_>, Error>>()
+                .map_err(|e| errors::to_js_error(&e))?;
+
+            JsValue::from_serde(&scales)
+                .map_err(|e| errors::to_js_error(&errors::new(errors::ErrorKind::Other, e.to_string())))
+        }
+
         pub fn to_json(&self) -> JsValue {
             JsValue::from_serde(self).unwrap()
         }
 
-        pub fn from_json(js: &JsValue) -> Self {
-            JsValue::into_serde(js).unwrap()
+        /// Describe how `other` differs from these attributes, as `{equal,
+        /// fields, scales: {added, removed, changed}}`. Changed values are
+        /// given as `{old, new}`, for top-level fields (such as `data_type`)
+        /// in `fields` and for fields of scales in `changed`, keyed by scale
+        /// key. `added` and `removed` list the keys of scales only in
+        /// `other` or only in these attributes.
+        pub fn diff(&self, other: &DatasetAttributes) -> Result<JsValue, JsValue> {
+            let diff = super::attributes_diff(&self.0, &other.0)
+                .map_err(|e| errors::to_js_error(&e))?;
+
+            Ok(JsValue::from_serde(&diff).unwrap())
+        }
+
+        /// Parse attributes from their JSON object, as given by `to_json`.
+        /// Throws an error of kind `bad_attrs` if they are invalid.
+        pub fn from_json(js: &JsValue) -> Result<DatasetAttributes, JsValue> {
+            JsValue::into_serde(js)
+                .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, format!("Invalid info: {}", e)))
+                .and_then(parse_dataset_attributes)
+                .map(DatasetAttributes)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        /// Parse the contents of a precomputed `info` file. Throws an error
+        /// of kind `bad_attrs` with the parse error message if it is invalid.
+        pub fn from_info_string(s: &str) -> Result<DatasetAttributes, JsValue> {
+            serde_json::from_str(s)
+                .map_err(|e| errors::new(errors::ErrorKind::BadAttrs, format!("Invalid info: {}", e)))
+                .and_then(parse_dataset_attributes)
+                .map(DatasetAttributes)
+                .map_err(|e| errors::to_js_error(&e))
+        }
+
+        pub fn to_info_string(&self) -> String {
+            serde_json::to_string(&self.0).unwrap()
         }
     }
 }
@@ -292,7 +3190,7 @@ trait VecBlockMonomorphizerReflection {
 macro_rules! data_block_monomorphizer {
     ($d_name:ident, $d_type:ty) => {
         #[wasm_bindgen]
-        pub struct $d_name(VecDataBlock<$d_type>, Option<String>);
+        pub struct $d_name(VecDataBlock<$d_type>, Option<String>, Option<u32>);
 
         impl VecBlockMonomorphizerReflection for $d_type {
             type MONOMORPH = $d_name;
@@ -300,18 +3198,45 @@ macro_rules! data_block_monomorphizer {
 
         impl From<VecDataBlock<$d_type>> for $d_name {
             fn from(block: VecDataBlock<$d_type>) -> Self {
-                $d_name(block, None)
+                $d_name(block, None, None)
             }
         }
 
         impl From<(VecDataBlock<$d_type>, Option<String>)> for $d_name {
             fn from((block, etag): (VecDataBlock<$d_type>, Option<String>)) -> Self {
-                $d_name(block, etag)
+                $d_name(block, etag, None)
+            }
+        }
+
+        impl From<(VecDataBlock<$d_type>, Option<String>, Option<u32>)> for $d_name {
+            fn from((block, etag, encoded_size): (VecDataBlock<$d_type>, Option<String>, Option<u32>)) -> Self {
+                $d_name(block, etag, encoded_size)
             }
         }
 
         #[wasm_bindgen]
         impl $d_name {
+            /// Create a block from its parts. `data` must hold
+            /// `product(size) * num_channels` elements, where `num_channels`
+            /// defaults to 1.
+            #[wasm_bindgen(constructor)]
+            pub fn new(
+                grid_position: Vec<u64>,
+                size: Vec<u32>,
+                data: Vec<$d_type>,
+                num_channels: Option<u32>,
+            ) -> Result<$d_name, JsValue> {
+                let num_elements = size.iter().map(|&s| s as usize).product::<usize>()
+                    * num_channels.unwrap_or(1) as usize;
+                if data.len() != num_elements {
+                    return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                        format!("Block of size {:?} needs {} elements, got {}",
+                            size, num_elements, data.len()))));
+                }
+
+                Ok($d_name(VecDataBlock::new(size.into(), grid_position.into(), data), None, None))
+            }
+
             pub fn get_size(&self) -> Vec<u32> {
                 self.0.get_size().to_owned()
             }
@@ -328,13 +3253,113 @@ macro_rules! data_block_monomorphizer {
                 self.0.into_data()
             }
 
+            /// Consume the block and return a copy of its data in a new
+            /// `ArrayBuffer` outside of the wasm memory, which can be
+            /// transferred with `postMessage`. The block's own data is freed.
+            /// Elements are stored in little-endian byte order, so the buffer
+            /// can be wrapped in the typed array of the block's data type.
+            pub fn into_arraybuffer(self) -> js_sys::ArrayBuffer {
+                let data = self.0.into_data();
+                let bytes = js_sys::Uint8Array::new_with_length(
+                    (data.len() * std::mem::size_of::<$d_type>()) as u32);
+                copy_into_bytes(&data, &bytes, 0).unwrap();
+
+                bytes.buffer()
+            }
+
             pub fn get_num_elements(&self) -> u32 {
                 self.0.get_num_elements()
             }
 
+            /// Get the size of the part of this block that lies within the
+            /// volume of scale `zoom_level`. It is smaller than the chunk size
+            /// for blocks at the upper boundary of the volume, and anything
+            /// beyond it in blocks stored at full size is padding.
+            pub fn valid_extent(
+                &self,
+                data_attrs: &wrapped::DatasetAttributes,
+                zoom_level: usize,
+            ) -> Result<Vec<u32>, JsValue> {
+                let data_attrs = &data_attrs.0;
+                if zoom_level >= data_attrs.get_scales().len() {
+                    return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                        format!("Dataset has no scale {}", zoom_level))));
+                }
+                let extent = raw::block_extent(self.0.get_grid_position(),
+                    data_attrs.get_block_size(zoom_level), data_attrs.get_dimensions(zoom_level));
+                if extent.len() != self.0.get_size().len() {
+                    return Err(errors::to_js_error(&errors::new(errors::ErrorKind::InvalidArgument,
+                        format!("Block of size {:?} does not match the {} dimensions of scale {}",
+                            self.0.get_size(), extent.len(), zoom_level))));
+                }
+
+                Ok(extent.iter().zip(self.0.get_size())
+                    .map(|(&valid, &size)| std::cmp::min(valid, size))
+                    .collect())
+            }
+
+            /// Render the slice at `index` along `axis` of the (first channel of
+            /// the) 3D block as grayscale, mapping `[window_min, window_max]`
+            /// linearly to `[0, 255]`.
+            pub fn block_slice_to_imagedata(
+                &self,
+                axis: u32,
+                index: u32,
+                window_min: f64,
+                window_max: f64,
+            ) -> Result<web_sys::ImageData, JsValue> {
+                let (mut pixels, width, height) = render::slice_to_rgba(
+                        self.0.get_data(), self.0.get_size(), axis, index, window_min, window_max)
+                    .map_err(|e| errors::to_js_error(&e))?;
+
+                web_sys::ImageData::new_with_u8_clamped_array_and_sh(
+                    wasm_bindgen::Clamped(&mut pixels[..]), width, height)
+            }
+
+            /// Count the block's values (of all channels) in `bins` equal
+            /// bins spanning `[range_min, range_max]`. Values outside the
+            /// range are counted in the first or last bin.
+            pub fn block_histogram(&self, bins: u32, range_min: f64, range_max: f64) -> Result<Vec<u32>, JsValue> {
+                render::histogram(self.0.get_data(), bins, range_min, range_max)
+                    .map_err(|e| errors::to_js_error(&e))
+            }
+
+            /// Get the range `{min, max}` of the block's values over all
+            /// channels, as numbers (`BigInt`s for 64 bit integer types), or
+            /// `null` for a block without values (other than NaN).
+            pub fn block_min_max(&self) -> JsValue {
+                match render::min_max(self.0.get_data()) {
+                    Some((min, max)) => js_object(&[("min", min.to_js()), ("max", max.to_js())]),
+                    None => JsValue::NULL,
+                }
+            }
+
+            /// Downsample the block by `factors` per axis with `method`
+            /// `"mean"` (for images) or `"mode"` (for segmentation). Sizes not
+            /// divisible by their factor are truncated.
+            pub fn downsample(&self, factors: Vec<u32>, method: &str) -> Result<$d_name, JsValue> {
+                method.parse::<downsample::DownsampleMethod>()
+                    .and_then(|method| downsample::downsample_block(&self.0, &factors, method))
+                    .map($d_name::from)
+                    .map_err(|e| errors::to_js_error(&e))
+            }
+
+            /// Get the number of bytes of an element.
+            pub fn get_element_size(&self) -> u32 {
+                std::mem::size_of::<$d_type>() as u32
+            }
+
             pub fn get_etag(&self) -> Option<String> {
                 self.1.to_owned()
             }
+
+            /// Get the number of bytes the block was decoded from, as
+            /// fetched by `read_block` (after any HTTP `Content-Encoding` is
+            /// undone), or `undefined` if unknown, such as for blocks
+            /// created in JS or served from a cache.
+            pub fn get_encoded_size(&self) -> Option<u32> {
+                self.2
+            }
         }
     }
 }
@@ -349,3 +3374,281 @@ data_block_monomorphizer!(VecDataBlockINT32, i32);
 data_block_monomorphizer!(VecDataBlockINT64, i64);
 data_block_monomorphizer!(VecDataBlockFLOAT32, f32);
 data_block_monomorphizer!(VecDataBlockFLOAT64, f64);
+
+
+/// Collects blocks of one scale to stitch them into a single block, for
+/// blocks that are already in memory. Blocks are added with the method for
+/// their type, such as `add_uint8`, since a JS array cannot hold them.
+#[wasm_bindgen]
+pub struct BlockMerger {
+    data_attrs: DatasetAttributes,
+    zoom_level: usize,
+    /// Boxed `VecDataBlock<T>`s of the types they were added as.
+    blocks: Vec<Box<dyn std::any::Any>>,
+}
+
+#[wasm_bindgen]
+impl BlockMerger {
+    #[wasm_bindgen(constructor)]
+    pub fn new(data_attrs: &wrapped::DatasetAttributes, zoom_level: usize) -> BlockMerger {
+        BlockMerger {
+            data_attrs: data_attrs.0.clone(),
+            zoom_level,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Merge the added blocks into one block spanning all of them, placed
+    /// by their grid positions, with zeros where no block was added. Rejects
+    /// with `invalid_argument` if a block does not have the data type of the
+    /// dataset or two blocks have the same grid position.
+    pub fn finish(&self) -> Result<JsValue, JsValue> {
+        let data_attrs = &self.data_attrs;
+        let merged = data_type_match! {
+            data_attrs.get_data_type(),
+            self.blocks.iter()
+                .map(|block| block.downcast_ref::<VecDataBlock<RsType>>()
+                    .ok_or_else(|| errors::new(errors::ErrorKind::InvalidArgument, format!(
+                        "Cannot merge blocks of other data types than the dataset's {}",
+                        serde_json::to_value(data_attrs.get_data_type()).unwrap_or(serde_json::Value::Null)))))
+                .collect::<Result<Vec<_>, Error>>()
+                .and_then(|blocks| merge_vec_blocks(&blocks, data_attrs, self.zoom_level))
+                .map(|block| JsValue::from(<RsType as VecBlockMonomorphizerReflection>::MONOMORPH::from(block)))
+        };
+
+        merged.map_err(|e| errors::to_js_error(&e))
+    }
+}
+
+macro_rules! block_merger_adders {
+    ($($method:ident, $d_name:ident;)*) => {
+        /// Add a block to merge, one method per block type.
+        #[wasm_bindgen]
+        impl BlockMerger {
+            $(
+            pub fn $method(&mut self, block: &$d_name) {
+                self.blocks.push(Box::new(block.0.clone()));
+            }
+            )*
+        }
+    }
+}
+
+block_merger_adders! {
+    add_uint8, VecDataBlockUINT8;
+    add_uint16, VecDataBlockUINT16;
+    add_uint32, VecDataBlockUINT32;
+    add_uint64, VecDataBlockUINT64;
+    add_int8, VecDataBlockINT8;
+    add_int16, VecDataBlockINT16;
+    add_int32, VecDataBlockINT32;
+    add_int64, VecDataBlockINT64;
+    add_float32, VecDataBlockFLOAT32;
+    add_float64, VecDataBlockFLOAT64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> serde_json::Value {
+        serde_json::json!({
+            "type": "image",
+            "data_type": "uint8",
+            "num_channels": 1,
+            "scales": [{
+                "key": "4_4_40",
+                "size": [100, 100, 10],
+                "chunk_sizes": [[64, 64, 8]],
+                "resolution": [4, 4, 40],
+                "voxel_offset": [0, 0, 0],
+                "encoding": "raw",
+            }],
+        })
+    }
+
+    #[test]
+    fn applies_the_transform_of_the_info() {
+        let mut info = info();
+        info["transform"] = serde_json::json!([[2, 0, 0, 10], [0, 1, 0, 0], [0, 0, 1, -5]]);
+        let data_attrs = parse_dataset_attributes(info).unwrap();
+
+        assert_eq!(voxel_to_physical(&data_attrs, 0, &[1.0, 2.0, 3.0]).unwrap(), vec![18.0, 8.0, 115.0]);
+        assert_eq!(physical_to_voxel(&data_attrs, 0, &[18.0, 8.0, 115.0]).unwrap(), vec![1.0, 2.0, 3.0]);
+
+        // The transform is kept through serialization, such as for workers.
+        let reparsed: DatasetAttributes = serde_json::from_value(serde_json::to_value(&data_attrs).unwrap()).unwrap();
+        assert_eq!(voxel_to_physical(&reparsed, 0, &[1.0, 2.0, 3.0]).unwrap(), vec![18.0, 8.0, 115.0]);
+    }
+
+    #[test]
+    fn scales_by_the_resolution_without_a_transform() {
+        let data_attrs = parse_dataset_attributes(info()).unwrap();
+
+        assert_eq!(voxel_to_physical(&data_attrs, 0, &[1.0, 2.0, 3.0]).unwrap(), vec![4.0, 8.0, 120.0]);
+    }
+
+    #[test]
+    fn merges_blocks_at_their_grid_positions() {
+        let data_attrs = parse_dataset_attributes(info()).unwrap();
+        let first = VecDataBlock::<u8>::new(vec![64, 64, 8].into(), vec![0, 0, 1].into(), vec![1; 64 * 64 * 8]);
+        let second = VecDataBlock::<u8>::new(vec![36, 64, 8].into(), vec![1, 0, 1].into(), vec![2; 36 * 64 * 8]);
+
+        let merged = merge_vec_blocks(&[&second, &first], &data_attrs, 0).unwrap();
+
+        assert_eq!(merged.get_size(), &[100, 64, 8][..]);
+        assert_eq!(merged.get_grid_position(), &[0, 0, 1][..]);
+        assert_eq!(merged.get_data()[63], 1);
+        assert_eq!(merged.get_data()[64], 2);
+        assert_eq!(merged.get_data()[99], 2);
+    }
+
+    #[test]
+    fn zero_fills_missing_blocks() {
+        let data_attrs = parse_dataset_attributes(info()).unwrap();
+        let first = VecDataBlock::<u8>::new(vec![64, 64, 8].into(), vec![0, 0, 0].into(), vec![1; 64 * 64 * 8]);
+        let last = VecDataBlock::<u8>::new(vec![36, 36, 8].into(), vec![1, 1, 0].into(), vec![2; 36 * 36 * 8]);
+
+        let merged = merge_vec_blocks(&[&first, &last], &data_attrs, 0).unwrap();
+
+        assert_eq!(merged.get_size(), &[100, 100, 8][..]);
+        // The first voxel of the missing block at grid position [1, 0, 0].
+        assert_eq!(merged.get_data()[64], 0);
+        assert_eq!(merged.get_data()[99 * 100 + 99], 2);
+    }
+
+    #[test]
+    fn rejects_blocks_at_the_same_grid_position() {
+        let data_attrs = parse_dataset_attributes(info()).unwrap();
+        let block = VecDataBlock::<u8>::new(vec![64, 64, 8].into(), vec![0, 0, 0].into(), vec![1; 64 * 64 * 8]);
+
+        let error = merge_vec_blocks(&[&block, &block], &data_attrs, 0).unwrap_err();
+        assert_eq!(errors::kind_of(&error), errors::ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn rejects_merged_blocks_too_large_for_their_size() {
+        let data_attrs = parse_dataset_attributes(info()).unwrap();
+        let first = VecDataBlock::<u8>::new(vec![1, 1, 1].into(), vec![0, 0, 0].into(), vec![1]);
+        let far = VecDataBlock::<u8>::new(vec![1, 1, 1].into(), vec![1 << 27, 0, 0].into(), vec![2]);
+
+        let error = merge_vec_blocks(&[&first, &far], &data_attrs, 0).unwrap_err();
+        assert_eq!(errors::kind_of(&error), errors::ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn reads_legacy_info_as_a_single_scale() {
+        let data_attrs = parse_dataset_attributes(serde_json::json!({
+            "type": "image",
+            "data_type": "uint16",
+            "num_channels": 1,
+            "size": [100, 50, 10],
+            "chunk_size": [64, 64, 8],
+            "resolution": [4, 4, 40],
+            "voxel_offset": [1, 2, 3],
+            "encoding": "raw",
+        })).unwrap();
+
+        assert_eq!(data_attrs.get_scales().len(), 1);
+        assert_eq!(zoom_level(&data_attrs, ".").unwrap(), 0);
+        assert_eq!(data_attrs.get_dimensions(0), &[100, 50, 10][..]);
+        assert_eq!(data_attrs.get_block_size(0), &[64, 64, 8][..]);
+        assert_eq!(data_attrs.get_voxel_offset(0), &[1, 2, 3][..]);
+        assert_eq!(scale_encoding(&data_attrs, 0).unwrap(), "raw");
+    }
+
+    #[test]
+    fn tells_missing_and_malformed_info_from_other_errors() {
+        let missing = errors::new(errors::ErrorKind::NotFound, "Not found: info");
+        assert!(!has_dataset_attributes(Err(missing)).unwrap());
+
+        let malformed = parse_dataset_attributes(serde_json::json!({"type": "image"})).map(|_| ());
+        assert!(!has_dataset_attributes(malformed).unwrap());
+
+        let valid = parse_dataset_attributes(info()).map(|_| ());
+        assert!(has_dataset_attributes(valid).unwrap());
+
+        let offline = errors::new(errors::ErrorKind::Network, "Failed to fetch");
+        assert!(has_dataset_attributes(Err(offline)).is_err());
+    }
+
+    #[test]
+    fn counts_the_elements_of_all_channels() {
+        let mut info = info();
+        info["num_channels"] = serde_json::json!(3);
+        let data_attrs = wrapped::DatasetAttributes(parse_dataset_attributes(info).unwrap());
+
+        assert_eq!(data_attrs.get_num_elements(0), 100 * 100 * 10 * 3);
+        assert_eq!(data_attrs.get_block_num_elements(0), 64 * 64 * 8 * 3);
+        assert_eq!(data_attrs.get_block_num_elements_per_channel(0), 64 * 64 * 8);
+    }
+
+    #[test]
+    fn packs_non_cubic_blocks_in_c_order() {
+        let order = parse_layout("zyxc", 3).unwrap();
+        assert_eq!(order, vec![2, 1, 0, 3]);
+
+        let data: Vec<u8> = (0..24).collect();
+        let block = pack_block(VecDataBlock::new(vec![2, 3, 4].into(), vec![0, 0, 0].into(), data), &order).unwrap();
+
+        // The size stays that of the spatial axes, in x, y, z order.
+        assert_eq!(block.get_size(), &[2, 3, 4][..]);
+        for z in 0..4 {
+            for y in 0..3 {
+                for x in 0..2 {
+                    assert_eq!(block.get_data()[z + 4 * (y + 3 * x)] as usize, x + 2 * (y + 3 * z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gives_the_valid_extent_of_edge_blocks() {
+        let data_attrs = wrapped::DatasetAttributes(parse_dataset_attributes(info()).unwrap());
+        // Stored at full size, but only [36, 64, 2] of it lies in the volume.
+        let edge = VecDataBlockUINT8::from(
+            VecDataBlock::new(vec![64, 64, 8].into(), vec![1, 0, 1].into(), vec![0; 64 * 64 * 8]));
+        // Already clipped when it was read.
+        let clipped = VecDataBlockUINT8::from(
+            VecDataBlock::new(vec![36, 36, 2].into(), vec![1, 1, 1].into(), vec![0; 36 * 36 * 2]));
+
+        assert_eq!(edge.valid_extent(&data_attrs, 0).unwrap(), vec![36, 64, 2]);
+        assert_eq!(clipped.valid_extent(&data_attrs, 0).unwrap(), vec![36, 36, 2]);
+    }
+
+    fn info_4d() -> serde_json::Value {
+        serde_json::json!({
+            "type": "image",
+            "data_type": "uint8",
+            "num_channels": 1,
+            "scales": [{
+                "key": "1_1_1_1",
+                "size": [4, 2, 2, 3],
+                "chunk_sizes": [[2, 2, 2, 2]],
+                "resolution": [1, 1, 1, 1],
+                "voxel_offset": [0, 0, 0, 0],
+                "encoding": "raw",
+            }],
+        })
+    }
+
+    #[test]
+    fn round_trips_chunk_keys_of_4d_scales() {
+        let data_attrs = parse_dataset_attributes(info_4d()).unwrap();
+        let key = chunk_key(&[1, 0, 0, 1], data_attrs.get_block_size(0), data_attrs.get_voxel_offset(0),
+            data_attrs.get_dimensions(0));
+
+        assert_eq!(key, "2-4_0-2_0-2_2-3");
+        assert_eq!(chunk_key_grid_position(&data_attrs, 0, &key).unwrap(), vec![1, 0, 0, 1]);
+        assert!(chunk_key_grid_position(&data_attrs, 0, "2-4_0-2_0-2").is_err());
+    }
+
+    #[test]
+    fn checks_grid_positions_against_the_scale_dimensions() {
+        let data_attrs = parse_dataset_attributes(info_4d()).unwrap();
+
+        assert!(check_grid_ndim(&data_attrs, 0, &[1, 0, 0, 1]).is_ok());
+        assert!(check_grid_ndim(&data_attrs, 0, &[1, 0, 0]).is_err());
+        assert!(check_grid_ndim(&data_attrs, 0, &[1, 0, 0, 1, 0]).is_err());
+    }
+}
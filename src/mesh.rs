@@ -0,0 +1,287 @@
+//! Segment meshes of precomputed segmentation datasets, in either the legacy
+//! format with a JSON manifest per segment or the multi-resolution
+//! `neuroglancer_multilod_draco` format.
+
+use super::*;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MeshFormat {
+    Legacy,
+    MultilodDraco,
+}
+
+impl MeshFormat {
+    /// The format declared by a mesh info, which is the legacy format for
+    /// mesh directories without an info.
+    pub(crate) fn of_info(info: &serde_json::Value) -> Result<Self, Error> {
+        match info.get("@type").and_then(|t| t.as_str()) {
+            None | Some("neuroglancer_legacy_mesh") => Ok(MeshFormat::Legacy),
+            Some("neuroglancer_multilod_draco") => Ok(MeshFormat::MultilodDraco),
+            Some(other) => Err(errors::new(errors::ErrorKind::BadAttrs,
+                format!("Unsupported mesh type: {}", other))),
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            MeshFormat::Legacy => "legacy",
+            MeshFormat::MultilodDraco => "multilod_draco",
+        }
+    }
+}
+
+
+/// The location of a fragment: `size` bytes at `offset` in the file `key`,
+/// or the whole file if `size` is `None`.
+pub(crate) fn fragment_location(key: &str, offset: u64, size: Option<u64>) -> serde_json::Value {
+    serde_json::json!({
+        "key": key,
+        "offset": offset,
+        "size": size,
+    })
+}
+
+/// Fragments listed in a legacy `<segment_id>:0` manifest, which are files in
+/// the mesh directory.
+pub(crate) fn legacy_fragments(mesh_dir: &str, manifest: &serde_json::Value) -> Result<Vec<serde_json::Value>, Error> {
+    let names: Vec<String> = manifest.get("fragments")
+        .and_then(|fragments| serde_json::from_value(fragments.clone()).ok())
+        .ok_or_else(|| errors::new(errors::ErrorKind::Decode, "Mesh manifest has no valid fragments"))?;
+
+    Ok(names.iter()
+        .map(|name| fragment_location(&http_fetch::join_path(mesh_dir, name), 0, None))
+        .collect())
+}
+
+
+/// Decode a legacy mesh fragment, which is the vertex count followed by the
+/// vertex positions and the triangle vertex indices, into the positions and
+/// indices. Fragments from gzip-encoded shards are decompressed first.
+pub(crate) fn decode_legacy_fragment(bytes: &[u8]) -> Result<(Vec<f32>, Vec<u32>), Error> {
+    let decoded;
+    let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+        decoded = sharded::gunzip(bytes)?;
+        &decoded[..]
+    } else {
+        bytes
+    };
+
+    let mut cursor = LeCursor { bytes, position: 0 };
+    let num_vertices = cursor.u32s(1)?[0] as usize;
+    let positions = cursor.f32s(num_vertices.saturating_mul(3))?;
+    let remaining = bytes.len() - cursor.position;
+    if remaining % 12 != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, "Mesh fragment has incomplete triangles"));
+    }
+    let indices = cursor.u32s(remaining / 4)?;
+    if indices.iter().any(|&index| index as usize >= num_vertices) {
+        return Err(errors::new(errors::ErrorKind::Decode, "Mesh fragment has invalid vertex indices"));
+    }
+
+    Ok((positions, indices))
+}
+
+/// A parsed multi-resolution manifest (`<segment_id>.index`).
+pub(crate) struct MultilodManifest {
+    chunk_shape: Vec<f32>,
+    grid_origin: Vec<f32>,
+    lod_scales: Vec<f32>,
+    vertex_offsets: Vec<f32>,
+    /// For each level of detail, the fragment positions (all x, then all y,
+    /// then all z) and the byte size of each fragment.
+    lods: Vec<(Vec<u32>, Vec<u32>)>,
+}
+
+struct LeCursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> LeCursor<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        let end = count.checked_mul(4).and_then(|len| len.checked_add(self.position))
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| errors::new(errors::ErrorKind::Decode, "Truncated mesh data"))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+
+        Ok(taken)
+    }
+
+    fn u32s(&mut self, count: usize) -> Result<Vec<u32>, Error> {
+        raw::decode_le(self.take(count)?)
+    }
+
+    fn f32s(&mut self, count: usize) -> Result<Vec<f32>, Error> {
+        raw::decode_le(self.take(count)?)
+    }
+}
+
+pub(crate) fn parse_multilod_manifest(bytes: &[u8]) -> Result<MultilodManifest, Error> {
+    let mut cursor = LeCursor { bytes, position: 0 };
+    let chunk_shape = cursor.f32s(3)?;
+    let grid_origin = cursor.f32s(3)?;
+    let num_lods = cursor.u32s(1)?[0] as usize;
+    let lod_scales = cursor.f32s(num_lods)?;
+    let vertex_offsets = cursor.f32s(3 * num_lods)?;
+    let num_fragments = cursor.u32s(num_lods)?;
+
+    let mut lods = Vec::with_capacity(num_lods);
+    for &n in &num_fragments {
+        let positions = cursor.u32s(3 * n as usize)?;
+        let sizes = cursor.u32s(n as usize)?;
+        lods.push((positions, sizes));
+    }
+    if cursor.position != bytes.len() {
+        return Err(errors::new(errors::ErrorKind::Decode, "Mesh manifest has trailing data"));
+    }
+
+    Ok(MultilodManifest { chunk_shape, grid_origin, lod_scales, vertex_offsets, lods })
+}
+
+impl MultilodManifest {
+    /// Total byte size of all fragments.
+    pub(crate) fn data_size(&self) -> u64 {
+        self.lods.iter()
+            .flat_map(|(_, sizes)| sizes.iter())
+            .map(|&size| u64::from(size))
+            .sum()
+    }
+
+    /// The manifest as JSON, with the fragments stored consecutively in the
+    /// file `key` starting at `data_start`. Mesh info attributes needed to
+    /// decode fragments are included.
+    pub(crate) fn to_json(&self, info: &serde_json::Value, key: &str, data_start: u64) -> serde_json::Value {
+        let mut offset = data_start;
+        let lods: Vec<serde_json::Value> = self.lods.iter()
+            .map(|(positions, sizes)| {
+                let n = sizes.len();
+                let fragments: Vec<serde_json::Value> = sizes.iter().enumerate()
+                    .map(|(i, &size)| {
+                        let mut fragment = fragment_location(key, offset, Some(u64::from(size)));
+                        fragment["position"] = serde_json::json!([positions[i], positions[n + i], positions[2 * n + i]]);
+                        offset += u64::from(size);
+                        fragment
+                    })
+                    .collect();
+                serde_json::json!({"fragments": fragments})
+            })
+            .collect();
+
+        serde_json::json!({
+            "format": MeshFormat::MultilodDraco.as_str(),
+            "chunk_shape": self.chunk_shape,
+            "grid_origin": self.grid_origin,
+            "lod_scales": self.lod_scales,
+            "vertex_offsets": self.vertex_offsets.chunks(3).collect::<Vec<_>>(),
+            "vertex_quantization_bits": info.get("vertex_quantization_bits"),
+            "transform": info.get("transform"),
+            "lods": lods,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A legacy fragment of one triangle.
+    fn triangle() -> Vec<u8> {
+        let mut bytes = raw::encode_le(&[3u32]);
+        bytes.extend(raw::encode_le(&[0f32, 0., 0., 1., 0., 0., 0., 1., 0.]));
+        bytes.extend(raw::encode_le(&[0u32, 1, 2]));
+        bytes
+    }
+
+    #[test]
+    fn decodes_legacy_fragments() {
+        let (positions, indices) = decode_legacy_fragment(&triangle()).unwrap();
+
+        assert_eq!(positions, vec![0., 0., 0., 1., 0., 0., 0., 1., 0.]);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn decodes_gzipped_legacy_fragments() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&triangle()).unwrap();
+
+        assert_eq!(decode_legacy_fragment(&encoder.finish().unwrap()).unwrap().1, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_corrupt_legacy_fragments() {
+        let bytes = triangle();
+        let kind = |bytes: &[u8]| errors::kind_of(&decode_legacy_fragment(bytes).unwrap_err());
+
+        // Truncated positions.
+        assert_eq!(kind(&bytes[..20]), errors::ErrorKind::Decode);
+        // An incomplete triangle.
+        assert_eq!(kind(&bytes[..bytes.len() - 4]), errors::ErrorKind::Decode);
+        // An index beyond the vertices.
+        let mut invalid = bytes.clone();
+        invalid.truncate(bytes.len() - 4);
+        invalid.extend(raw::encode_le(&[3u32]));
+        assert_eq!(kind(&invalid), errors::ErrorKind::Decode);
+    }
+
+    /// A manifest of two levels of detail, with two and one fragments.
+    fn manifest() -> Vec<u8> {
+        let mut bytes = raw::encode_le(&[8f32, 8., 8., 1., 2., 3.]);
+        bytes.extend(raw::encode_le(&[2u32]));
+        bytes.extend(raw::encode_le(&[1f32, 2., 0., 0., 0., 0.5, 0.5, 0.5]));
+        bytes.extend(raw::encode_le(&[2u32, 1]));
+        // Fragments at [0, 1, 2] and [3, 4, 5], of 10 and 20 bytes.
+        bytes.extend(raw::encode_le(&[0u32, 3, 1, 4, 2, 5, 10, 20]));
+        bytes.extend(raw::encode_le(&[0u32, 0, 0, 30]));
+        bytes
+    }
+
+    #[test]
+    fn lists_legacy_fragments_in_the_mesh_directory() {
+        let manifest = serde_json::json!({"fragments": ["1:0:0-64_0-64_0-64", "1:0:64-128_0-64_0-64"]});
+
+        for mesh_dir in &["mesh", "mesh/"] {
+            let fragments = legacy_fragments(mesh_dir, &manifest).unwrap();
+            assert_eq!(fragments, vec![
+                fragment_location("mesh/1:0:0-64_0-64_0-64", 0, None),
+                fragment_location("mesh/1:0:64-128_0-64_0-64", 0, None),
+            ]);
+        }
+        assert!(legacy_fragments("mesh", &serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn parses_multilod_manifests() {
+        let manifest = parse_multilod_manifest(&manifest()).unwrap();
+        assert_eq!(manifest.data_size(), 60);
+
+        let json = manifest.to_json(&serde_json::json!({"vertex_quantization_bits": 10}), "1.shard", 100);
+        assert_eq!(json["chunk_shape"], serde_json::json!([8., 8., 8.]));
+        assert_eq!(json["grid_origin"], serde_json::json!([1., 2., 3.]));
+        assert_eq!(json["lod_scales"], serde_json::json!([1., 2.]));
+        assert_eq!(json["vertex_offsets"], serde_json::json!([[0., 0., 0.], [0.5, 0.5, 0.5]]));
+        assert_eq!(json["vertex_quantization_bits"], 10);
+        assert_eq!(json["lods"][0]["fragments"], serde_json::json!([
+            {"key": "1.shard", "offset": 100, "size": 10, "position": [0, 1, 2]},
+            {"key": "1.shard", "offset": 110, "size": 20, "position": [3, 4, 5]},
+        ]));
+        assert_eq!(json["lods"][1]["fragments"], serde_json::json!([
+            {"key": "1.shard", "offset": 130, "size": 30, "position": [0, 0, 0]},
+        ]));
+    }
+
+    #[test]
+    fn rejects_truncated_or_overlong_manifests() {
+        let bytes = manifest();
+        assert!(parse_multilod_manifest(&bytes[..bytes.len() - 4]).is_err());
+
+        let mut overlong = bytes.clone();
+        overlong.extend(raw::encode_le(&[0u32]));
+        assert!(parse_multilod_manifest(&overlong).is_err());
+    }
+}
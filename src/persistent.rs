@@ -0,0 +1,538 @@
+//! Persistent cache of decoded blocks in IndexedDB, which survives page
+//! reloads.
+//!
+//! Each block is one record of the `blocks` object store, keyed by the
+//! dataset, scale path and grid position and holding the block's data as
+//! little-endian bytes together with its etag. Records are evicted least
+//! recently used first, through an index on their access time.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbCursorWithValue,
+    IdbDatabase,
+    IdbFactory,
+    IdbObjectStore,
+    IdbObjectStoreParameters,
+    IdbOpenDbRequest,
+    IdbRequest,
+    IdbTransactionMode,
+};
+
+use super::*;
+use crate::http_fetch::{
+    self,
+    NgPreHTTPFetch,
+};
+
+
+const STORE_NAME: &str = "blocks";
+const LAST_USED_INDEX: &str = "last_used";
+const DB_VERSION: u32 = 1;
+
+/// Resolve to the result of an IndexedDB request.
+fn request_result(request: &IdbRequest) -> Box<dyn Future<Item = JsValue, Error = Error>> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        let on_success = Closure::once_into_js(move || {
+            let _ = resolve.call1(&JsValue::NULL, &succeeded.result().unwrap_or(JsValue::UNDEFINED));
+        });
+        let on_error = Closure::once_into_js(move || {
+            let _ = reject.call1(&JsValue::NULL, &js_sys::Error::new("IndexedDB request failed"));
+        });
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+
+    Box::new(map_future_error_rust(JsFuture::from(promise)))
+}
+
+/// Step through the records of a cursor request, calling `visit` on each
+/// until it returns false or the records run out. Cursors must be advanced
+/// within their success event, so this cannot be a chain of futures.
+fn for_each_record<F>(request: &IdbRequest, visit: F) -> Box<dyn Future<Item = (), Error = Error>>
+        where F: FnMut(&IdbCursorWithValue) -> Result<bool, JsValue> + 'static,
+{
+    let mut visit = Some(visit);
+    let promise = Promise::new(&mut |resolve, reject| {
+        let mut visit = match visit.take() {
+            Some(visit) => visit,
+            None => return,
+        };
+        let cursor_request = request.clone();
+        let failed = reject.clone();
+        let on_success = Closure::wrap(Box::new(move || {
+            let cursor = match cursor_request.result().map(JsCast::dyn_into::<IdbCursorWithValue>) {
+                Ok(Ok(cursor)) => cursor,
+                // A `null` result means there are no more records.
+                _ => {
+                    let _ = resolve.call0(&JsValue::NULL);
+                    return;
+                },
+            };
+            let continued = match visit(&cursor) {
+                Ok(true) => cursor.continue_(),
+                Ok(false) => resolve.call0(&JsValue::NULL).map(|_| ()),
+                Err(error) => Err(error),
+            };
+            if let Err(error) = continued {
+                let _ = reject.call1(&JsValue::NULL, &error);
+            }
+        }) as Box<dyn FnMut()>);
+        let on_error = Closure::once_into_js(move || {
+            let _ = failed.call1(&JsValue::NULL, &js_sys::Error::new("IndexedDB cursor failed"));
+        });
+        request.set_onsuccess(Some(on_success.into_js_value().unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+
+    Box::new(map_future_error_rust(JsFuture::from(promise)).map(|_| ()))
+}
+
+/// Store a record in place of the one under its key, resolving to the
+/// `size_bytes` of the replaced record, or 0 if there was none. The record
+/// is put from the success event of reading the old one, so that both are
+/// in one transaction and no other put of the key comes between them.
+fn replace_record(store: &IdbObjectStore, record: &JsValue) -> Box<dyn Future<Item = f64, Error = Error>> {
+    let get_request = match store.get(&record_field(record, "key")) {
+        Ok(get_request) => get_request,
+        Err(error) => return Box::new(future::err(convert_jsvalue_error(error))),
+    };
+
+    let promise = Promise::new(&mut |resolve, reject| {
+        let store = store.clone();
+        let record = record.clone();
+        let read = get_request.clone();
+        let failed = reject.clone();
+        let on_success = Closure::once_into_js(move || {
+            let replaced = read.result()
+                .map(|old| record_field(&old, "size_bytes").as_f64().unwrap_or(0.0))
+                .unwrap_or(0.0);
+            let put_request = match store.put(&record) {
+                Ok(put_request) => put_request,
+                Err(error) => {
+                    let _ = reject.call1(&JsValue::NULL, &error);
+                    return;
+                },
+            };
+            let on_put = Closure::once_into_js(move || {
+                let _ = resolve.call1(&JsValue::NULL, &JsValue::from_f64(replaced));
+            });
+            let on_put_error = Closure::once_into_js(move || {
+                let _ = reject.call1(&JsValue::NULL, &js_sys::Error::new("IndexedDB request failed"));
+            });
+            put_request.set_onsuccess(Some(on_put.unchecked_ref()));
+            put_request.set_onerror(Some(on_put_error.unchecked_ref()));
+        });
+        let on_error = Closure::once_into_js(move || {
+            let _ = failed.call1(&JsValue::NULL, &js_sys::Error::new("IndexedDB request failed"));
+        });
+        get_request.set_onsuccess(Some(on_success.unchecked_ref()));
+        get_request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+
+    Box::new(map_future_error_rust(JsFuture::from(promise))
+        .map(|replaced| replaced.as_f64().unwrap_or(0.0)))
+}
+
+fn record_field(record: &JsValue, name: &str) -> JsValue {
+    js_sys::Reflect::get(record, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED)
+}
+
+
+struct PersistentCacheState {
+    db: IdbDatabase,
+    capacity_bytes: f64,
+    /// Total `size_bytes` of the records, counted when the database is
+    /// opened and kept up to date by this instance.
+    bytes_used: Cell<f64>,
+}
+
+/// A byte-budgeted block cache in an IndexedDB database, which can be shared
+/// by the `PersistentReader`s of several datasets.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct PersistentCache(Rc<PersistentCacheState>);
+
+#[wasm_bindgen]
+impl PersistentCache {
+    /// Open (or create) the IndexedDB database `name` and resolve to a cache
+    /// keeping at most `capacity_bytes` of block data in it.
+    pub fn open(name: &str, capacity_bytes: f64) -> Promise {
+        let open_request = match open_database(name) {
+            Ok(open_request) => open_request,
+            Err(error) => return errors::reject(error),
+        };
+
+        let to_return = request_result(&open_request)
+            .and_then(|db| db.dyn_into::<IdbDatabase>().map_err(convert_jsvalue_error))
+            .and_then(move |db| {
+                let cache = PersistentCache(Rc::new(PersistentCacheState {
+                    db,
+                    capacity_bytes,
+                    bytes_used: Cell::new(0.0),
+                }));
+                let counted = cache.clone();
+                let request = match cache.store(IdbTransactionMode::Readonly)
+                        .and_then(|store| store.open_cursor().map_err(convert_jsvalue_error)) {
+                    Ok(request) => request,
+                    Err(error) => return future::Either::B(future::err(error)),
+                };
+
+                future::Either::A(for_each_record(&request, move |cursor| {
+                    let size_bytes = record_field(&cursor.value()?, "size_bytes").as_f64().unwrap_or(0.0);
+                    counted.0.bytes_used.set(counted.0.bytes_used.get() + size_bytes);
+                    Ok(true)
+                }).map(move |()| JsValue::from(cache)))
+            });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// Remove all blocks from the cache.
+    pub fn clear(&self) -> Promise {
+        let cache = self.clone();
+        let to_return = future::result(self.store(IdbTransactionMode::Readwrite)
+                .and_then(|store| store.clear().map_err(convert_jsvalue_error)))
+            .and_then(|request| request_result(&request))
+            .map(move |_| {
+                cache.0.bytes_used.set(0.0);
+                JsValue::UNDEFINED
+            });
+
+        future_to_promise(map_future_error_wasm(to_return))
+    }
+
+    /// The total size of the cached block data. Blocks written by other
+    /// instances (e.g. other tabs) are only counted once the database is
+    /// reopened.
+    pub fn size_bytes(&self) -> f64 {
+        self.0.bytes_used.get()
+    }
+}
+
+fn open_database(name: &str) -> Result<IdbOpenDbRequest, Error> {
+    let factory = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("indexedDB")).ok()
+        .and_then(|factory| factory.dyn_into::<IdbFactory>().ok())
+        .ok_or_else(|| errors::new(errors::ErrorKind::Other, "IndexedDB is not available"))?;
+    let open_request = factory.open_with_u32(name, DB_VERSION).map_err(convert_jsvalue_error)?;
+
+    let upgraded = open_request.clone();
+    let on_upgrade_needed = Closure::once_into_js(move || {
+        let created = upgraded.result()
+            .and_then(|db| db.dyn_into::<IdbDatabase>().map_err(JsValue::from))
+            .and_then(|db| {
+                let mut parameters = IdbObjectStoreParameters::new();
+                parameters.key_path(Some(&JsValue::from_str("key")));
+                db.create_object_store_with_optional_parameters(STORE_NAME, &parameters)
+            })
+            .and_then(|store| store.create_index_with_str(LAST_USED_INDEX, "last_used"));
+        // A failed upgrade aborts the open request, which then fails.
+        if created.is_err() {
+            if let Some(transaction) = upgraded.transaction() {
+                let _ = transaction.abort();
+            }
+        }
+    });
+    open_request.set_onupgradeneeded(Some(on_upgrade_needed.unchecked_ref()));
+
+    Ok(open_request)
+}
+
+impl PersistentCache {
+    fn store(&self, mode: IdbTransactionMode) -> Result<IdbObjectStore, Error> {
+        self.0.db.transaction_with_str_and_mode(STORE_NAME, mode)
+            .and_then(|transaction| transaction.object_store(STORE_NAME))
+            .map_err(convert_jsvalue_error)
+    }
+
+    /// The record stored under `key`, if any, marking it as recently used.
+    fn get(&self, key: &str) -> Box<dyn Future<Item = Option<JsValue>, Error = Error>> {
+        let cache = self.clone();
+        let request = match self.store(IdbTransactionMode::Readonly)
+                .and_then(|store| store.get(&JsValue::from_str(key)).map_err(convert_jsvalue_error)) {
+            Ok(request) => request,
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        Box::new(request_result(&request).map(move |record| {
+            if record.is_undefined() {
+                return None;
+            }
+            // Updating the access time is not waited for; if it fails the
+            // record is merely evicted earlier.
+            let _ = js_sys::Reflect::set(&record, &JsValue::from_str("last_used"),
+                &JsValue::from_f64(js_sys::Date::now()));
+            let _ = cache.store(IdbTransactionMode::Readwrite).map(|store| store.put(&record));
+            Some(record)
+        }))
+    }
+
+    /// Store a record, replacing any record under its key, then evict the
+    /// least recently used records while the cache is over its capacity.
+    /// Records larger than the capacity are not stored.
+    fn put(&self, record: JsValue) -> Box<dyn Future<Item = (), Error = Error>> {
+        let size_bytes = record_field(&record, "size_bytes").as_f64().unwrap_or(0.0);
+        if size_bytes > self.0.capacity_bytes {
+            return Box::new(future::ok(()));
+        }
+        let store = match self.store(IdbTransactionMode::Readwrite) {
+            Ok(store) => store,
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        let cache = self.clone();
+        Box::new(replace_record(&store, &record).and_then(move |replaced| {
+            cache.0.bytes_used.set((cache.0.bytes_used.get() + size_bytes - replaced).max(0.0));
+            cache.evict()
+        }))
+    }
+
+    fn evict(&self) -> Box<dyn Future<Item = (), Error = Error>> {
+        if self.0.bytes_used.get() <= self.0.capacity_bytes {
+            return Box::new(future::ok(()));
+        }
+        let request = match self.store(IdbTransactionMode::Readwrite)
+                .and_then(|store| store.index(LAST_USED_INDEX).map_err(convert_jsvalue_error))
+                .and_then(|index| index.open_cursor().map_err(convert_jsvalue_error)) {
+            Ok(request) => request,
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        let cache = self.clone();
+        for_each_record(&request, move |cursor| {
+            let size_bytes = record_field(&cursor.value()?, "size_bytes").as_f64().unwrap_or(0.0);
+            cursor.delete()?;
+            cache.0.bytes_used.set((cache.0.bytes_used.get() - size_bytes).max(0.0));
+            Ok(cache.0.bytes_used.get() > cache.0.capacity_bytes)
+        })
+    }
+
+    /// Remove the record under `key` if its etag differs from `etag`.
+    fn remove_if_stale(&self, key: &str, etag: Option<String>) -> Box<dyn Future<Item = (), Error = Error>> {
+        let cache = self.clone();
+        let key = key.to_owned();
+
+        Box::new(self.get(&key).and_then(move |record| -> Box<dyn Future<Item = (), Error = Error>> {
+            let record = match record {
+                Some(record) if record_field(&record, "etag").as_string() != etag => record,
+                _ => return Box::new(future::ok(())),
+            };
+            let size_bytes = record_field(&record, "size_bytes").as_f64().unwrap_or(0.0);
+            let request = match cache.store(IdbTransactionMode::Readwrite)
+                    .and_then(|store| store.delete(&JsValue::from_str(&key)).map_err(convert_jsvalue_error)) {
+                Ok(request) => request,
+                Err(error) => return Box::new(future::err(error)),
+            };
+
+            Box::new(request_result(&request).map(move |_| {
+                cache.0.bytes_used.set((cache.0.bytes_used.get() - size_bytes).max(0.0));
+            }))
+        }))
+    }
+}
+
+fn to_js<S: serde::Serialize + ?Sized>(value: &S) -> Result<JsValue, Error> {
+    JsValue::from_serde(value).map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string()))
+}
+
+/// The record of a block, with its data as little-endian bytes.
+fn block_record<T>(key: &str, block: &VecDataBlock<T>, etag: &Option<String>, data_type: &serde_json::Value)
+        -> Result<JsValue, Error>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let bytes = raw::encode_le(block.get_data());
+    Ok(js_object(&[
+        ("key", JsValue::from_str(key)),
+        ("etag", etag.as_ref().map_or(JsValue::NULL, |etag| JsValue::from_str(etag))),
+        ("data_type", to_js(data_type)?),
+        ("size", to_js(block.get_size())?),
+        ("grid_position", to_js(block.get_grid_position())?),
+        ("size_bytes", JsValue::from_f64(bytes.len() as f64)),
+        ("bytes", JsValue::from(js_sys::Uint8Array::from(&bytes[..]))),
+        ("last_used", JsValue::from_f64(js_sys::Date::now())),
+    ]))
+}
+
+/// The block of a record, or `None` if it was stored for another data type
+/// or is malformed, which is treated like a cache miss.
+fn record_block<T>(record: &JsValue, data_type: &serde_json::Value) -> Option<(VecDataBlock<T>, Option<String>)>
+        where VecDataBlock<T>: DataBlock<T>,
+            T: ReflectedType + RawElement,
+{
+    let stored_type: serde_json::Value = record_field(record, "data_type").into_serde().ok()?;
+    if stored_type != *data_type {
+        return None;
+    }
+    let size: Vec<u32> = record_field(record, "size").into_serde().ok()?;
+    let grid_position: Vec<u64> = record_field(record, "grid_position").into_serde().ok()?;
+    let bytes = record_field(record, "bytes").dyn_into::<js_sys::Uint8Array>().ok()?.to_vec();
+    let data = raw::decode_le::<T>(&bytes).ok()?;
+
+    Some((VecDataBlock::new(size.into(), grid_position.into(), data), record_field(record, "etag").as_string()))
+}
+
+
+/// A reader that keeps decoded blocks from an inner reader in a
+/// `PersistentCache`, checking it before making any request.
+///
+/// As with `CachedReader`, a call to `block_etag` returning an etag
+/// different from the cached one evicts the stale block. If IndexedDB fails,
+/// blocks are read from the inner reader.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct PersistentReader {
+    inner: NgPreHTTPFetch,
+    cache: PersistentCache,
+}
+
+#[wasm_bindgen]
+impl PersistentReader {
+    pub fn new(inner: &NgPreHTTPFetch, cache: &PersistentCache) -> PersistentReader {
+        PersistentReader {
+            inner: inner.clone(),
+            cache: cache.clone(),
+        }
+    }
+}
+
+reader_delegations!(PersistentReader);
+
+impl PersistentReader {
+    fn key(&self, path_name: &str, data_attrs: &DatasetAttributes, grid_position: &[i64]) -> Result<String, Error> {
+        let block_size = scale_block_size(data_attrs, path_name);
+        serde_json::to_string(&(self.inner.base_path(), path_name, block_size, grid_position))
+            .map_err(|e| errors::new(errors::ErrorKind::InvalidArgument, e.to_string()))
+    }
+}
+
+impl AbortableReader for PersistentReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        PersistentReader {
+            inner: self.inner.with_signal(signal),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl NgPreAsyncReader for PersistentReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
+                self, path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, _etag)| block)))
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for PersistentReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::attributes_etag(&self.inner, path_name)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let key = match self.key(path_name, data_attrs, &grid_position) {
+            Ok(key) => key,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let cache = self.cache.clone();
+
+        Box::new(NgPreAsyncEtagReader::block_etag(
+                &self.inner, path_name, data_attrs, grid_position)
+            .and_then(move |etag| cache.remove_if_stale(&key, etag.clone())
+                // Failing to remove a stale record does not affect the etag.
+                .then(move |_| Ok(etag))))
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        if self.inner.is_disposed() {
+            return Box::new(future::err(http_fetch::disposed_error()));
+        }
+        let key = match self.key(path_name, data_attrs, &grid_position) {
+            Ok(key) => key,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let data_type = serde_json::to_value(data_attrs.get_data_type()).unwrap_or(serde_json::Value::Null);
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        Box::new(self.cache.get(&key).then(move |result| {
+            // A failing cache is bypassed rather than failing the read.
+            let hit = result.ok()
+                .and_then(|maybe_record| maybe_record)
+                .and_then(|record| record_block::<T>(&record, &data_type));
+            if let Some(hit) = hit {
+                inner.report_cache_hit(&path_name, &data_attrs, &grid_position);
+                return future::Either::A(future::ok(Some(hit)));
+            }
+
+            future::Either::B(NgPreAsyncEtagReader::read_block_with_etag::<T>(
+                    &inner, &path_name, &data_attrs, grid_position)
+                .and_then(move |maybe_block| {
+                    let record = maybe_block.as_ref()
+                        .map(|(block, etag)| block_record(&key, block, etag, &data_type));
+                    match record {
+                        // Failing to store the block does not affect the read.
+                        Some(Ok(record)) => future::Either::A(cache.put(record).then(move |_| Ok(maybe_block))),
+                        _ => future::Either::B(future::ok(maybe_block)),
+                    }
+                }))
+        }))
+    }
+}
@@ -0,0 +1,242 @@
+//! Dispatching queued block requests by priority.
+
+use super::*;
+use crate::bounded::{
+    with_priority_permit,
+    Semaphore,
+};
+use crate::http_fetch::NgPreHTTPFetch;
+
+
+/// A reader that allows at most `max_concurrent` block reads of an inner
+/// reader to be in flight at once, like `BoundedReader`, but dispatches
+/// queued reads by priority. A read submitted with a higher priority is sent
+/// before all queued reads of lower priority, reads of equal priority are sent
+/// in submission order. Reads already in flight are never cancelled.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct PriorityReader {
+    inner: NgPreHTTPFetch,
+    semaphore: Semaphore,
+    priority: i32,
+}
+
+#[wasm_bindgen]
+impl PriorityReader {
+    pub fn new(inner: &NgPreHTTPFetch, max_concurrent: usize) -> PriorityReader {
+        PriorityReader {
+            inner: inner.clone(),
+            semaphore: Semaphore::new(std::cmp::max(max_concurrent, 1)),
+            priority: 0,
+        }
+    }
+
+    /// A reader sharing this reader's queue whose requests have `priority`
+    /// (by default 0), e.g. for batch reads of blocks in the center of view.
+    pub fn with_priority(&self, priority: i32) -> PriorityReader {
+        PriorityReader {
+            inner: self.inner.clone(),
+            semaphore: self.semaphore.clone(),
+            priority,
+        }
+    }
+}
+
+reader_delegations!(PriorityReader without read_block);
+
+#[wasm_bindgen]
+impl PriorityReader {
+    /// Read a block, queued with `priority` if given and with the reader's
+    /// priority otherwise.
+    pub fn read_block(
+        &self,
+        path_name: &str,
+        data_attrs: &wrapped::DatasetAttributes,
+        grid_position: Vec<i64>,
+        signal: Option<AbortSignal>,
+        order: Option<String>,
+        priority: Option<i32>,
+        chunk_size_index: Option<usize>,
+    ) -> Promise {
+        let reader = self.with_priority(priority.unwrap_or(self.priority));
+        read_block_in_order(
+            &reader.with_signal(signal), path_name, data_attrs, grid_position, order, chunk_size_index)
+    }
+}
+
+impl AbortableReader for PriorityReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        PriorityReader {
+            inner: self.inner.with_signal(signal),
+            semaphore: self.semaphore.clone(),
+            priority: self.priority,
+        }
+    }
+}
+
+impl NgPreAsyncReader for PriorityReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncReader::read_block::<T>(
+                &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncReader::read_block_subregion::<T>(
+                &inner, &path_name, &data_attrs, grid_position, min, max))
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncReader::block_exists(
+                &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for PriorityReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncEtagReader::attributes_etag(&inner, &path_name))
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncEtagReader::block_etag(
+                &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncEtagReader::read_block_with_etag::<T>(
+                &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncEtagReader::read_block_with_encoded_size::<T>(
+                &inner, &path_name, &data_attrs, grid_position))
+    }
+
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let inner = self.inner.clone();
+        let path_name = path_name.to_owned();
+        let data_attrs = data_attrs.clone();
+        let known_etag = known_etag.to_owned();
+
+        with_priority_permit(&self.semaphore, self.priority, move ||
+            NgPreAsyncEtagReader::read_block_if_changed::<T>(
+                &inner, &path_name, &data_attrs, grid_position, &known_etag))
+    }
+}
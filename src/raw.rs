@@ -0,0 +1,485 @@
+//! Little-endian byte layout of block elements, as used by the `raw`
+//! precomputed encoding, and helpers for rearranging block data.
+
+use super::*;
+
+
+/// Block element types with a fixed-size little-endian byte representation.
+pub trait RawElement: Copy + Default + PartialEq {
+    const SIZE: usize;
+
+    /// Whether the type is an integer type, whose values are rounded when
+    /// converting from `f64`.
+    const INTEGER: bool;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    fn write_le(self, out: &mut [u8]);
+
+    /// Convert from an integer label, as decoded from segmentation encodings.
+    fn from_u64(value: u64) -> Self;
+
+    /// Convert from a JS number, saturating at the bounds of integer types.
+    fn from_f64(value: f64) -> Self;
+
+    fn to_f64(self) -> f64;
+
+    /// Whether the value is NaN, which only values of float types can be.
+    fn is_nan(self) -> bool;
+
+    /// Normalize by the maximum of integer types, into `[0, 1]` for
+    /// unsigned and `[-1, 1]` for signed types, passing floats through.
+    fn to_normalized_f32(self) -> f32;
+
+    /// Convert to a JS number, or a `BigInt` for 64 bit integers to keep
+    /// their precision.
+    fn to_js(self) -> JsValue;
+}
+
+macro_rules! raw_element {
+    ($d_type:ty, $integer:expr, $max:expr) => {
+        impl RawElement for $d_type {
+            const SIZE: usize = std::mem::size_of::<$d_type>();
+
+            const INTEGER: bool = $integer;
+
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                let mut le = [0u8; std::mem::size_of::<$d_type>()];
+                le.copy_from_slice(&bytes[..Self::SIZE]);
+                <$d_type>::from_le_bytes(le)
+            }
+
+            fn write_le(self, out: &mut [u8]) {
+                out[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn from_u64(value: u64) -> Self {
+                value as $d_type
+            }
+
+            fn from_f64(value: f64) -> Self {
+                value as $d_type
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn is_nan(self) -> bool {
+                (self as f64).is_nan()
+            }
+
+            fn to_normalized_f32(self) -> f32 {
+                let max: Option<f64> = $max;
+                match max {
+                    Some(max) => (self as f64 / max).max(-1.0) as f32,
+                    None => self as f32,
+                }
+            }
+
+            fn to_js(self) -> JsValue {
+                if Self::INTEGER && Self::SIZE == 8 {
+                    js_sys::BigInt::new(&JsValue::from_str(&self.to_string())).into()
+                } else {
+                    JsValue::from_f64(self as f64)
+                }
+            }
+        }
+    }
+}
+
+raw_element!(u8, true, Some(u8::max_value() as f64));
+raw_element!(u16, true, Some(u16::max_value() as f64));
+raw_element!(u32, true, Some(u32::max_value() as f64));
+raw_element!(u64, true, Some(u64::max_value() as f64));
+raw_element!(i8, true, Some(i8::max_value() as f64));
+raw_element!(i16, true, Some(i16::max_value() as f64));
+raw_element!(i32, true, Some(i32::max_value() as f64));
+raw_element!(i64, true, Some(i64::max_value() as f64));
+raw_element!(f32, false, None);
+raw_element!(f64, false, None);
+
+/// Decode little-endian bytes into elements.
+pub(crate) fn decode_le<T: RawElement>(bytes: &[u8]) -> Result<Vec<T>, Error> {
+    if bytes.len() % T::SIZE != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} is not a multiple of the element size {}", bytes.len(), T::SIZE)));
+    }
+
+    Ok(bytes.chunks_exact(T::SIZE).map(T::from_le_slice).collect())
+}
+
+/// Encode elements as little-endian bytes.
+pub(crate) fn encode_le<T: RawElement>(data: &[T]) -> Vec<u8> {
+    let mut bytes = vec![0u8; data.len() * T::SIZE];
+    for (&value, out) in data.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+        value.write_le(out);
+    }
+
+    bytes
+}
+
+
+/// Unpack `count` bits, least significant bit of each byte first, into
+/// bytes of 0 or 1.
+pub(crate) fn unpack_bits(bytes: &[u8], count: usize) -> Result<Vec<u8>, Error> {
+    if bytes.len() != (count + 7) / 8 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Bitpacked data of {} bytes does not hold {} values", bytes.len(), count)));
+    }
+
+    Ok((0..count).map(|i| (bytes[i / 8] >> (i % 8)) & 1).collect())
+}
+
+/// Pack elements into one bit each, set for non-zero elements, least
+/// significant bit of each byte first.
+pub(crate) fn pack_bits<T: RawElement>(data: &[T]) -> Vec<u8> {
+    let mut bytes = vec![0u8; (data.len() + 7) / 8];
+    for (i, &value) in data.iter().enumerate() {
+        if value != T::default() {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    bytes
+}
+
+/// Convert the bits of a half-precision float to `f32`, which represents
+/// every half-precision value exactly.
+pub(crate) fn f16_to_f32(half: u16) -> f32 {
+    let sign = u32::from(half >> 15) << 31;
+    let exponent = u32::from(half >> 10) & 0x1f;
+    let mantissa = u32::from(half) & 0x3ff;
+
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        // Subnormal halves are normal floats: shift the leading one of the
+        // mantissa out into the implicit bit.
+        0 => {
+            let shift = mantissa.leading_zeros() - 21;
+            sign | (113 - shift) << 23 | ((mantissa << shift) & 0x3ff) << 13
+        },
+        // Infinities and NaNs.
+        0x1f => sign | 0xff << 23 | mantissa << 13,
+        _ => sign | (exponent + 127 - 15) << 23 | mantissa << 13,
+    };
+
+    f32::from_bits(bits)
+}
+
+/// Widen `float16` data in `byte_order` to little-endian `float32` bytes.
+pub(crate) fn widen_f16(bytes: &[u8], byte_order: ByteOrder) -> Result<Vec<u8>, Error> {
+    if bytes.len() % 2 != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Raw float16 data of {} bytes is not a whole number of elements", bytes.len())));
+    }
+
+    let mut widened = vec![0u8; bytes.len() * 2];
+    for (half, out) in bytes.chunks_exact(2).zip(widened.chunks_exact_mut(4)) {
+        let half = match byte_order {
+            ByteOrder::Little => u16::from_le_bytes([half[0], half[1]]),
+            ByteOrder::Big => u16::from_be_bytes([half[0], half[1]]),
+        };
+        f16_to_f32(half).write_le(out);
+    }
+
+    Ok(widened)
+}
+
+/// Byte order of raw chunks. Precomputed chunks are little-endian, but some
+/// stores write big-endian chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl std::str::FromStr for ByteOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "little" => Ok(ByteOrder::Little),
+            "big" => Ok(ByteOrder::Big),
+            _ => Err(errors::new(errors::ErrorKind::InvalidArgument,
+                format!("Unknown endianness: {}", s))),
+        }
+    }
+}
+
+/// Reverse the bytes of each element of `element_size` bytes, converting
+/// between big- and little-endian.
+pub(crate) fn swap_byte_order(bytes: &mut [u8], element_size: usize) {
+    if element_size > 1 {
+        for element in bytes.chunks_exact_mut(element_size) {
+            element.reverse();
+        }
+    }
+}
+
+/// Decode bytes in `byte_order` into elements.
+pub(crate) fn decode_ordered<T: RawElement>(bytes: &[u8], byte_order: ByteOrder) -> Result<Vec<T>, Error> {
+    match byte_order {
+        ByteOrder::Little => decode_le(bytes),
+        ByteOrder::Big => {
+            let mut swapped = bytes.to_vec();
+            swap_byte_order(&mut swapped, T::SIZE);
+            decode_le(&swapped)
+        },
+    }
+}
+
+
+/// Extent of the block at `grid_position`, which is clipped to the dataset
+/// dimensions for blocks at the upper boundary.
+pub(crate) fn block_extent(grid_position: &[u64], block_size: &[u32], dimensions: &[u64]) -> Vec<u32> {
+    grid_position.iter()
+        .zip(block_size.iter().zip(dimensions))
+        .map(|(&coord, (&size, &dim))| {
+            let begin = coord * size as u64;
+            std::cmp::min(size as u64, dim.saturating_sub(begin)) as u32
+        })
+        .collect()
+}
+
+/// Check that `[min, max)` is a non-empty box within `size`.
+pub(crate) fn check_box(size: &[u32], min: &[u32], max: &[u32]) -> Result<(), Error> {
+    let valid = min.len() == size.len() && max.len() == size.len() &&
+        min.iter().zip(max).zip(size).all(|((&lo, &hi), &s)| lo < hi && hi <= s);
+    if !valid {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Box {:?}-{:?} is not within block size {:?}", min, max, size)));
+    }
+
+    Ok(())
+}
+
+/// Copy the box `[min, max)` out of Fortran-ordered data of the given spatial
+/// `size`. Any trailing channel planes (data beyond the product of `size`) are
+/// cropped likewise.
+pub(crate) fn crop<T: Clone>(data: &[T], size: &[u32], min: &[u32], max: &[u32]) -> Result<Vec<T>, Error> {
+    check_box(size, min, max)?;
+
+    let plane_len: usize = size.iter().map(|&s| s as usize).product();
+    if plane_len == 0 || data.len() % plane_len != 0 {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} does not match block size {:?}", data.len(), size)));
+    }
+    let num_planes = data.len() / plane_len;
+
+    let out_size: Vec<usize> = min.iter().zip(max).map(|(&lo, &hi)| (hi - lo) as usize).collect();
+    let row_len = out_size[0];
+    let num_rows: usize = out_size[1..].iter().product();
+
+    let strides = fortran_strides(size);
+
+    let mut cropped = Vec::with_capacity(row_len * num_rows * num_planes);
+    for plane in 0..num_planes {
+        let plane_data = &data[plane * plane_len..(plane + 1) * plane_len];
+        // Odometer over all axes but the first, which is copied row-wise.
+        let mut coord = vec![0usize; size.len()];
+        for _ in 0..num_rows {
+            let start: usize = coord.iter().zip(min).zip(&strides)
+                .map(|((&c, &lo), &stride)| (c + lo as usize) * stride)
+                .sum();
+            cropped.extend_from_slice(&plane_data[start..start + row_len]);
+
+            for d in 1..coord.len() {
+                coord[d] += 1;
+                if coord[d] < out_size[d] {
+                    break;
+                }
+                coord[d] = 0;
+            }
+        }
+    }
+
+    Ok(cropped)
+}
+
+/// Copy the box of the given `extent` at `src_min` in Fortran-ordered data
+/// `src` of size `src_size` to `dst_min` in `dst` of size `dst_size`. The
+/// boxes must be within the sizes.
+pub(crate) fn copy_box<T: Copy>(
+    src: &[T], src_size: &[u32], src_min: &[u32],
+    dst: &mut [T], dst_size: &[u32], dst_min: &[u32],
+    extent: &[u32],
+) {
+    let src_strides = fortran_strides(src_size);
+    let dst_strides = fortran_strides(dst_size);
+    let row_len = extent[0] as usize;
+    let num_rows: usize = extent[1..].iter().map(|&e| e as usize).product();
+
+    // Odometer over all axes but the first, which is copied row-wise.
+    let mut coord = vec![0usize; extent.len()];
+    for _ in 0..num_rows {
+        let src_start = box_offset(&coord, src_min, &src_strides);
+        let dst_start = box_offset(&coord, dst_min, &dst_strides);
+        dst[dst_start..dst_start + row_len].copy_from_slice(&src[src_start..src_start + row_len]);
+
+        for d in 1..coord.len() {
+            coord[d] += 1;
+            if coord[d] < extent[d] as usize {
+                break;
+            }
+            coord[d] = 0;
+        }
+    }
+}
+
+fn box_offset(coord: &[usize], min: &[u32], strides: &[usize]) -> usize {
+    coord.iter().zip(min).zip(strides)
+        .map(|((&c, &lo), &stride)| (c + lo as usize) * stride)
+        .sum()
+}
+
+/// Reorder Fortran-ordered data of the given `size` so that axis `order[i]`
+/// of the input is axis `i` of the output, which is again fastest-first.
+pub(crate) fn transpose<T: Copy>(data: &[T], size: &[u32], order: &[usize]) -> Vec<T> {
+    let strides = fortran_strides(size);
+    let out_size: Vec<usize> = order.iter().map(|&axis| size[axis] as usize).collect();
+    let out_strides: Vec<usize> = order.iter().map(|&axis| strides[axis]).collect();
+
+    // Odometer over the output axes, tracking the input offset.
+    let mut transposed = Vec::with_capacity(data.len());
+    let mut coord = vec![0usize; order.len()];
+    let mut offset = 0;
+    for _ in 0..data.len() {
+        transposed.push(data[offset]);
+        for d in 0..coord.len() {
+            coord[d] += 1;
+            offset += out_strides[d];
+            if coord[d] < out_size[d] {
+                break;
+            }
+            offset -= coord[d] * out_strides[d];
+            coord[d] = 0;
+        }
+    }
+
+    transposed
+}
+
+/// The element range of a box within Fortran-ordered data, if the box is
+/// contiguous there: all axes before some axis `k` are complete and all
+/// axes after it have a single slice.
+pub(crate) fn contiguous_range(size: &[u32], min: &[u32], max: &[u32]) -> Option<(usize, usize)> {
+    let partial = (0..size.len()).find(|&d| min[d] != 0 || max[d] != size[d]);
+    if let Some(k) = partial {
+        if ((k + 1)..size.len()).any(|d| max[d] - min[d] != 1) {
+            return None;
+        }
+    }
+
+    let strides = fortran_strides(size);
+    let start: usize = min.iter().zip(&strides)
+        .map(|(&lo, &stride)| lo as usize * stride)
+        .sum();
+    let last: usize = max.iter().zip(&strides)
+        .map(|(&hi, &stride)| (hi as usize - 1) * stride)
+        .sum();
+
+    Some((start, last + 1))
+}
+
+/// Element strides of each axis for Fortran-ordered data of the given size.
+pub(crate) fn fortran_strides(size: &[u32]) -> Vec<usize> {
+    let mut strides = Vec::with_capacity(size.len());
+    let mut stride = 1;
+    for &s in size {
+        strides.push(stride);
+        stride *= s as usize;
+    }
+
+    strides
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode as the write path does for big-endian stores.
+    fn encode_be<T: RawElement>(data: &[T]) -> Vec<u8> {
+        let mut bytes = encode_le(data);
+        swap_byte_order(&mut bytes, T::SIZE);
+        bytes
+    }
+
+    #[test]
+    fn round_trips_big_endian_u16() {
+        let data = [0u16, 1, 0x0102, u16::max_value()];
+        let bytes = encode_be(&data);
+
+        assert_eq!(&bytes[..4], &[0, 0, 0, 1]);
+        assert_eq!(decode_ordered::<u16>(&bytes, ByteOrder::Big).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn round_trips_big_endian_u32() {
+        let data = [0u32, 0x0102_0304, u32::max_value()];
+        let bytes = encode_be(&data);
+
+        assert_eq!(&bytes[4..8], &[1, 2, 3, 4]);
+        assert_eq!(decode_ordered::<u32>(&bytes, ByteOrder::Big).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn round_trips_big_endian_f32() {
+        let data = [0.0f32, 1.5, -2.25, std::f32::MAX];
+        let bytes = encode_be(&data);
+
+        assert_eq!(&bytes[4..8], &1.5f32.to_be_bytes());
+        assert_eq!(decode_ordered::<f32>(&bytes, ByteOrder::Big).unwrap(), data.to_vec());
+        assert_eq!(decode_ordered::<f32>(&encode_le(&data), ByteOrder::Little).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn unpacks_bits_least_significant_first() {
+        assert_eq!(unpack_bits(&[0b1000_0101, 0b0000_0010], 10).unwrap(), vec![1, 0, 1, 0, 0, 0, 0, 1, 0, 1]);
+        assert!(unpack_bits(&[0, 0], 8).is_err());
+    }
+
+    #[test]
+    fn round_trips_packed_bits() {
+        let mask: Vec<u8> = (0..21).map(|i| (i % 3 == 0) as u8).collect();
+        let packed = pack_bits(&mask);
+
+        assert_eq!(packed.len(), 3);
+        assert_eq!(unpack_bits(&packed, mask.len()).unwrap(), mask);
+    }
+
+    #[test]
+    fn packs_non_zero_elements_as_set_bits() {
+        assert_eq!(pack_bits(&[0u8, 7, 255, 0, 0, 0, 0, 0, 1]), vec![0b0000_0110, 0b0000_0001]);
+        assert_eq!(unpack_bits(&pack_bits(&[0u16, 300, 0]), 3).unwrap(), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn transposes_non_cubic_blocks() {
+        let size = [2, 3, 4];
+        // Fortran order: the value of voxel (x, y, z) is its offset.
+        let data: Vec<usize> = (0..24).collect();
+        let transposed = transpose(&data, &size, &[2, 1, 0]);
+
+        for z in 0..4 {
+            for y in 0..3 {
+                for x in 0..2 {
+                    assert_eq!(transposed[z + 4 * (y + 3 * x)], x + 2 * (y + 3 * z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transposes_with_the_identity_order_unchanged() {
+        let data: Vec<u8> = (0..24).collect();
+
+        assert_eq!(transpose(&data, &[2, 3, 4], &[0, 1, 2]), data);
+    }
+
+    #[test]
+    fn clips_edge_blocks_to_the_dimensions() {
+        assert_eq!(block_extent(&[1, 0, 1], &[64, 64, 8], &[100, 100, 10]), vec![36, 64, 2]);
+        assert_eq!(block_extent(&[0, 0, 0], &[64, 64, 8], &[100, 100, 10]), vec![64, 64, 8]);
+    }
+}
@@ -0,0 +1,610 @@
+//! Recording the HTTP exchanges of a reader and replaying them offline, for
+//! deterministic tests without a server.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use web_sys::Response;
+
+use super::*;
+use crate::http_fetch::NgPreHTTPFetch;
+
+
+/// Response headers kept in recordings. Encoding and length headers are
+/// dropped, since recorded bodies are already decoded.
+const RECORDED_HEADERS: &[&str] = &["Content-Range", "Content-Type", "ETag", "Last-Modified"];
+
+/// A request and its response, without the request headers other than
+/// `Range`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Exchange {
+    method: String,
+    url: String,
+    range: Option<String>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Not serialized, as it is exposed to JS as a `Uint8Array`.
+    #[serde(skip)]
+    body: Vec<u8>,
+}
+
+impl Exchange {
+    /// The exchange of a request missing from a recording, answered with a
+    /// 404 like a missing file on a server.
+    fn missing(method: &str, url: &str, range: Option<&str>) -> Self {
+        Exchange {
+            method: method.to_owned(),
+            url: url.to_owned(),
+            range: range.map(str::to_owned),
+            status: 404,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn key(&self) -> String {
+        exchange_key(&self.method, &self.url, self.range.as_ref().map(String::as_str))
+    }
+}
+
+fn exchange_key(method: &str, url: &str, range: Option<&str>) -> String {
+    match range {
+        Some(range) => format!("{} {} ({})", method, url, range),
+        None => format!("{} {}", method, url),
+    }
+}
+
+/// Where a reader's requests go.
+#[derive(Clone)]
+pub(crate) enum Transport {
+    Network,
+    /// Send requests over the network and record them.
+    Record(Rc<RefCell<Vec<Exchange>>>),
+    /// Answer requests from a recording, by the key of their exchange.
+    Replay(Rc<HashMap<String, Exchange>>),
+}
+
+/// Index the exchanges of a recording by their key, for replaying them.
+fn replay_index(exchanges: Vec<Exchange>) -> HashMap<String, Exchange> {
+    exchanges.into_iter()
+        .map(|exchange| (exchange.key(), exchange))
+        .collect()
+}
+
+/// The recorded exchange of a request, or a 404 if it was not recorded.
+fn replayed_exchange(exchanges: &HashMap<String, Exchange>, method: &str, url: &str, headers: &[(String, String)])
+        -> Exchange {
+    let range = range_header(headers);
+    match exchanges.get(&exchange_key(method, url, range)) {
+        Some(exchange) => exchange.clone(),
+        None => Exchange::missing(method, url, range),
+    }
+}
+
+impl Transport {
+    /// The recorded response to a request, if replaying. Requests that were
+    /// not recorded get a 404 response.
+    pub(crate) fn replay(&self, method: &str, url: &str, headers: &[(String, String)])
+            -> Option<Result<Response, Error>> {
+        match self {
+            Transport::Replay(exchanges) =>
+                Some(replayed_response(&replayed_exchange(exchanges, method, url, headers))),
+            _ => None,
+        }
+    }
+
+    /// Wrap the response to a request so that it is recorded, if recording.
+    /// The response resolves once its body has been recorded.
+    pub(crate) fn record<F>(&self, method: &str, url: &str, headers: &[(String, String)], response: F)
+            -> Box<dyn Future<Item = Response, Error = Error>>
+            where F: Future<Item = Response, Error = Error> + 'static {
+        let exchanges = match self {
+            Transport::Record(exchanges) => exchanges.clone(),
+            _ => return Box::new(response),
+        };
+        let method = method.to_owned();
+        let url = url.to_owned();
+        let range = range_header(headers).map(str::to_owned);
+
+        Box::new(response.and_then(move |resp| {
+            let copy = match resp.clone() {
+                Ok(copy) => copy,
+                Err(error) => return future::Either::B(future::err(errors::from_js_error(error))),
+            };
+            let array_buffer = match copy.array_buffer() {
+                Ok(array_buffer) => array_buffer,
+                Err(error) => return future::Either::B(future::err(errors::from_js_error(error))),
+            };
+            let recorded_headers = RECORDED_HEADERS.iter()
+                .filter_map(|&name| resp.headers().get(name).unwrap_or(None)
+                    .map(|value| (name.to_owned(), value)))
+                .collect();
+            let mut exchange = Exchange {
+                method,
+                url,
+                range,
+                status: resp.status(),
+                headers: recorded_headers,
+                body: Vec::new(),
+            };
+
+            future::Either::A(JsFuture::from(array_buffer)
+                .map_err(errors::from_js_error)
+                .map(move |array_buffer| {
+                    exchange.body = js_sys::Uint8Array::new(&array_buffer).to_vec();
+                    exchanges.borrow_mut().push(exchange);
+                    resp
+                }))
+        }))
+    }
+}
+
+fn range_header(headers: &[(String, String)]) -> Option<&str> {
+    headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Range"))
+        .map(|(_, value)| value.as_str())
+}
+
+fn replayed_response(exchange: &Exchange) -> Result<Response, Error> {
+    let headers = web_sys::Headers::new().map_err(errors::from_js_error)?;
+    for (name, value) in &exchange.headers {
+        headers.set(name, value).map_err(errors::from_js_error)?;
+    }
+    let mut init = web_sys::ResponseInit::new();
+    init.status(exchange.status);
+    init.headers(&headers);
+
+    // Responses with these statuses must not have a body.
+    let mut body = exchange.body.clone();
+    let body = match exchange.status {
+        204 | 304 => None,
+        _ => Some(&mut body[..]),
+    };
+    Response::new_with_opt_u8_array_and_init(body, &init).map_err(errors::from_js_error)
+}
+
+/// Convert exchanges to `{base_path, exchanges}`, with the body of each
+/// exchange as a `Uint8Array`.
+fn recording_to_js(base_path: &str, exchanges: &[Exchange]) -> JsValue {
+    let js_exchanges = exchanges.iter()
+        .map(|exchange| {
+            let js_exchange = JsValue::from_serde(exchange).unwrap();
+            // Setting a property on a fresh, extensible object cannot fail.
+            let _ = js_sys::Reflect::set(&js_exchange, &JsValue::from_str("body"),
+                &js_sys::Uint8Array::from(&exchange.body[..]));
+            js_exchange
+        })
+        .collect::<js_sys::Array>();
+
+    js_object(&[
+        ("base_path", JsValue::from_str(base_path)),
+        ("exchanges", js_exchanges.into()),
+    ])
+}
+
+fn recording_from_js(recording: &JsValue) -> Result<(String, Vec<Exchange>), Error> {
+    let invalid = |message: String| errors::new(errors::ErrorKind::InvalidArgument,
+        format!("Invalid recording: {}", message));
+
+    let base_path = js_sys::Reflect::get(recording, &JsValue::from_str("base_path"))
+        .ok()
+        .and_then(|base_path| base_path.as_string())
+        .ok_or_else(|| invalid("no base_path".to_owned()))?;
+    let js_exchanges = js_sys::Reflect::get(recording, &JsValue::from_str("exchanges"))
+        .ok()
+        .filter(js_sys::Array::is_array)
+        .map(|js_exchanges| js_sys::Array::from(&js_exchanges))
+        .ok_or_else(|| invalid("no exchanges".to_owned()))?;
+
+    let exchanges = js_exchanges.iter()
+        .map(|js_exchange| {
+            let mut exchange: Exchange = js_exchange.into_serde()
+                .map_err(|e| invalid(e.to_string()))?;
+            let body = js_sys::Reflect::get(&js_exchange, &JsValue::from_str("body"))
+                .map_err(errors::from_js_error)?;
+            if !body.is_undefined() && !body.is_null() {
+                exchange.body = js_sys::Uint8Array::new(&body).to_vec();
+            }
+            Ok(exchange)
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok((base_path, exchanges))
+}
+
+
+/// A reader that sends requests like its inner reader and records each
+/// exchange with its response body.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct RecordingReader {
+    inner: NgPreHTTPFetch,
+    exchanges: Rc<RefCell<Vec<Exchange>>>,
+}
+
+#[wasm_bindgen]
+impl RecordingReader {
+    pub fn new(inner: &NgPreHTTPFetch) -> RecordingReader {
+        let exchanges = Rc::new(RefCell::new(Vec::new()));
+
+        RecordingReader {
+            inner: inner.with_transport(Transport::Record(exchanges.clone())),
+            exchanges,
+        }
+    }
+
+    /// Take the exchanges recorded so far, as `{base_path, exchanges}` for
+    /// `ReplayReader.from_recording`. Requests still in flight are not
+    /// included.
+    pub fn take_recording(&self) -> JsValue {
+        let exchanges = std::mem::replace(&mut *self.exchanges.borrow_mut(), Vec::new());
+
+        recording_to_js(self.inner.base_path(), &exchanges)
+    }
+}
+
+/// A reader answering requests from a recording of a `RecordingReader`
+/// instead of the network. Requests that were not recorded get a 404
+/// response, so missing blocks read as `null` like over the network.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct ReplayReader {
+    inner: NgPreHTTPFetch,
+}
+
+#[wasm_bindgen]
+impl ReplayReader {
+    pub fn from_recording(recording: JsValue) -> Result<ReplayReader, JsValue> {
+        let (base_path, exchanges) = recording_from_js(&recording)
+            .map_err(|e| errors::to_js_error(&e))?;
+
+        Ok(ReplayReader {
+            inner: NgPreHTTPFetch::unopened(&base_path)
+                .with_transport(Transport::Replay(Rc::new(replay_index(exchanges)))),
+        })
+    }
+
+    /// Call `on_fetch_start({method, url})` whenever a replayed request
+    /// starts, like `NgPreHTTPFetchBuilder.on_fetch_start`.
+    pub fn on_fetch_start(self, on_fetch_start: js_sys::Function) -> ReplayReader {
+        ReplayReader {
+            inner: self.inner.with_on_fetch_start(on_fetch_start),
+        }
+    }
+
+    /// Call `on_fetch_end({method, url, duration_ms, bytes, status,
+    /// cache_hit, error})` whenever a replayed request ends, like
+    /// `NgPreHTTPFetchBuilder.on_fetch_end`.
+    pub fn on_fetch_end(self, on_fetch_end: js_sys::Function) -> ReplayReader {
+        ReplayReader {
+            inner: self.inner.with_on_fetch_end(on_fetch_end),
+        }
+    }
+}
+
+reader_delegations!(RecordingReader);
+
+impl AbortableReader for RecordingReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        RecordingReader {
+            inner: self.inner.with_signal(signal),
+            exchanges: self.exchanges.clone(),
+        }
+    }
+}
+
+impl NgPreAsyncReader for RecordingReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        NgPreAsyncReader::read_block::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        NgPreAsyncReader::read_block_subregion::<T>(&self.inner, path_name, data_attrs, grid_position, min, max)
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::block_exists(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for RecordingReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::attributes_etag(&self.inner, path_name)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::block_etag(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_with_etag::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_with_encoded_size::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_if_changed::<T>(&self.inner, path_name, data_attrs, grid_position, known_etag)
+    }
+}
+
+reader_delegations!(ReplayReader);
+
+impl AbortableReader for ReplayReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        ReplayReader {
+            inner: self.inner.with_signal(signal),
+        }
+    }
+}
+
+impl NgPreAsyncReader for ReplayReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        NgPreAsyncReader::read_block::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_subregion<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        min: Vec<u32>,
+        max: Vec<u32>,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        NgPreAsyncReader::read_block_subregion::<T>(&self.inner, path_name, data_attrs, grid_position, min, max)
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::block_exists(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for ReplayReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::attributes_etag(&self.inner, path_name)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::block_etag(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_with_etag::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_with_encoded_size<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>, Option<u32>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_with_encoded_size::<T>(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        NgPreAsyncEtagReader::read_block_if_changed::<T>(&self.inner, path_name, data_attrs, grid_position, known_etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(range: Option<&str>) -> Vec<(String, String)> {
+        range.map(|range| ("Range".to_owned(), range.to_owned())).into_iter().collect()
+    }
+
+    #[test]
+    fn replays_recorded_exchanges() {
+        let recorded = vec![
+            Exchange {
+                method: "GET".to_owned(),
+                url: "https://example.com/data/info".to_owned(),
+                range: None,
+                status: 200,
+                headers: vec![("ETag".to_owned(), "\"1\"".to_owned())],
+                body: b"{}".to_vec(),
+            },
+            Exchange {
+                method: "GET".to_owned(),
+                url: "https://example.com/data/8_8_8/0.shard".to_owned(),
+                range: Some("bytes=0-15".to_owned()),
+                status: 206,
+                headers: vec![("Content-Range".to_owned(), "bytes 0-15/100".to_owned())],
+                body: vec![7; 16],
+            },
+        ];
+        // Recordings pass through JSON, with bodies set separately.
+        let exchanges = recorded.iter()
+            .map(|exchange| {
+                let mut replayed: Exchange = serde_json::from_str(&serde_json::to_string(exchange).unwrap()).unwrap();
+                replayed.body = exchange.body.clone();
+                replayed
+            })
+            .collect();
+        let exchanges = replay_index(exchanges);
+
+        for exchange in &recorded {
+            let replayed = replayed_exchange(&exchanges, &exchange.method, &exchange.url,
+                &headers(exchange.range.as_ref().map(String::as_str)));
+            assert_eq!(replayed.status, exchange.status);
+            assert_eq!(replayed.headers, exchange.headers);
+            assert_eq!(replayed.body, exchange.body);
+        }
+    }
+
+    #[test]
+    fn replays_requests_not_recorded_as_missing() {
+        let exchanges = replay_index(vec![Exchange {
+            status: 200,
+            body: b"{}".to_vec(),
+            ..Exchange::missing("GET", "https://example.com/data/info", None)
+        }]);
+
+        let replayed = replayed_exchange(&exchanges, "GET", "https://example.com/data/8_8_8/0-8_0-8_0-8", &[]);
+        assert_eq!(replayed.status, 404);
+        assert!(replayed.body.is_empty());
+        // Another range of a recorded URL was not recorded either.
+        let replayed = replayed_exchange(&exchanges, "GET", "https://example.com/data/info", &headers(Some("bytes=0-1")));
+        assert_eq!(replayed.status, 404);
+        assert_eq!(replayed_exchange(&exchanges, "GET", "https://example.com/data/info", &[]).status, 200);
+    }
+}
@@ -0,0 +1,113 @@
+//! Rendering of block data for previews.
+
+use super::*;
+
+
+/// Extract the 2D slice at `index` along `axis` of the first channel of
+/// 3D Fortran-ordered data and map it to grayscale RGBA pixels, linearly
+/// windowing `[window_min, window_max]` to `[0, 255]`. Returns the pixels
+/// (rows along the second remaining axis) with their width and height.
+pub(crate) fn slice_to_rgba<T: RawElement>(
+    data: &[T],
+    size: &[u32],
+    axis: u32,
+    index: u32,
+    window_min: f64,
+    window_max: f64,
+) -> Result<(Vec<u8>, u32, u32), Error> {
+    if size.len() != 3 || axis >= 3 || index >= size[axis as usize] {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot slice a block of size {:?} at index {} along axis {}", size, index, axis)));
+    }
+    if data.len() < size.iter().map(|&s| s as usize).product() {
+        return Err(errors::new(errors::ErrorKind::Decode, format!(
+            "Data length {} does not match block size {:?}", data.len(), size)));
+    }
+
+    let axis = axis as usize;
+    let (u, v) = match axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+    let (width, height) = (size[u], size[v]);
+    let strides = raw::fortran_strides(size);
+    let range = window_max - window_min;
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let value = data[index as usize * strides[axis] + x * strides[u] + y * strides[v]].to_f64();
+            let gray = if range > 0.0 {
+                ((value - window_min) / range * 255.0).max(0.0).min(255.0).round() as u8
+            } else if value >= window_max {
+                255
+            } else {
+                0
+            };
+            pixels.extend_from_slice(&[gray, gray, gray, 255]);
+        }
+    }
+
+    Ok((pixels, width, height))
+}
+
+/// Count the values of `data` in `bins` equal bins spanning
+/// `[range_min, range_max]`, with values outside the range counted in the
+/// first or last bin. NaNs are not counted.
+pub(crate) fn histogram<T: RawElement>(
+    data: &[T],
+    bins: u32,
+    range_min: f64,
+    range_max: f64,
+) -> Result<Vec<u32>, Error> {
+    if bins == 0 || range_min >= range_max || !range_min.is_finite() || !range_max.is_finite() {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Cannot bin into {} bins over [{}, {}]", bins, range_min, range_max)));
+    }
+
+    let scale = bins as f64 / (range_max - range_min);
+    let mut counts = vec![0u32; bins as usize];
+    for value in data.iter().map(|v| v.to_f64()).filter(|v| !v.is_nan()) {
+        let bin = ((value - range_min) * scale).floor().max(0.0).min((bins - 1) as f64);
+        counts[bin as usize] += 1;
+    }
+
+    Ok(counts)
+}
+
+/// The smallest and largest values of `data`, ignoring NaNs, or `None` if
+/// there are no other values.
+pub(crate) fn min_max<T: RawElement + PartialOrd>(data: &[T]) -> Option<(T, T)> {
+    data.iter().cloned()
+        .filter(|value| !value.is_nan())
+        .fold(None, |range, value| match range {
+            None => Some((value, value)),
+            Some((min, max)) => Some((
+                if value < min { value } else { min },
+                if value > max { value } else { max },
+            )),
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_clamps_values_into_the_end_bins() {
+        let data = [-5.0f32, 0.0, 1.0, 2.5, 3.9, 4.0, 100.0, std::f32::NAN];
+
+        assert_eq!(histogram(&data, 4, 0.0, 4.0).unwrap(), vec![2, 1, 1, 3]);
+        assert!(histogram(&data, 4, 4.0, 4.0).is_err());
+        assert!(histogram(&data, 4, std::f64::NAN, 4.0).is_err());
+    }
+
+    #[test]
+    fn min_max_ignores_nan() {
+        assert_eq!(min_max(&[std::f32::NAN, 3.0, -1.0, std::f32::NAN]), Some((-1.0, 3.0)));
+        assert_eq!(min_max(&[std::f64::NAN]), None);
+        assert_eq!(min_max(&[7u64, 2, u64::max_value()]), Some((2, u64::max_value())));
+    }
+}
@@ -0,0 +1,569 @@
+//! Support for the precomputed sharded chunk layout
+//! (`neuroglancer_uint64_sharded_v1`).
+//!
+//! In this layout chunks are identified by a uint64 chunk ID (for volumes,
+//! the compressed Morton code of the grid position) and stored in `.shard`
+//! files. Each shard starts with a fixed size shard index that locates the
+//! minishard indices, which in turn list the byte ranges of their chunks.
+
+use std::cell::RefCell;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::io::Read;
+use std::rc::Rc;
+
+use flate2::read::GzDecoder;
+use futures::future::Shared;
+
+use super::*;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ShardingHash {
+    Identity,
+    #[serde(rename = "murmurhash3_x86_128")]
+    MurmurHash3X86_128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ShardingEncoding {
+    Raw,
+    Gzip,
+}
+
+impl Default for ShardingEncoding {
+    fn default() -> Self {
+        ShardingEncoding::Raw
+    }
+}
+
+impl ShardingEncoding {
+    pub(crate) fn decode(self, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match self {
+            ShardingEncoding::Raw => Ok(bytes),
+            ShardingEncoding::Gzip => gunzip(&bytes),
+        }
+    }
+}
+
+pub(crate) fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut decoded)
+        .map_err(|e| errors::new(errors::ErrorKind::Decode, format!("Invalid gzip data: {}", e)))?;
+
+    Ok(decoded)
+}
+
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct ShardingSpec {
+    #[serde(rename = "@type")]
+    pub type_: String,
+    pub preshift_bits: u32,
+    pub hash: ShardingHash,
+    pub minishard_bits: u32,
+    pub shard_bits: u32,
+    #[serde(default)]
+    pub minishard_index_encoding: ShardingEncoding,
+    #[serde(default)]
+    pub data_encoding: ShardingEncoding,
+}
+
+/// The shard and minishard a chunk ID is stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ChunkLocation {
+    pub shard: u64,
+    pub minishard: u64,
+}
+
+impl ShardingSpec {
+    /// The sharding spec of a scale, if the scale is sharded.
+    pub(crate) fn for_scale(data_attrs: &DatasetAttributes, zoom_level: usize) -> Result<Option<Self>, Error> {
+        Self::of_attributes(&scale_attributes(data_attrs, zoom_level)?)
+    }
+
+    /// The sharding spec in the `sharding` key of JSON attributes, such as
+    /// those of a scale or a mesh info, if there is one.
+    pub(crate) fn of_attributes(attrs: &serde_json::Value) -> Result<Option<Self>, Error> {
+        match attrs.get("sharding") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(spec) => {
+                let spec: ShardingSpec = serde_json::from_value(spec.clone())
+                    .map_err(|e| errors::new(errors::ErrorKind::BadAttrs,
+                        format!("Invalid sharding spec: {}", e)))?;
+                if spec.type_ != "neuroglancer_uint64_sharded_v1" {
+                    return Err(errors::new(errors::ErrorKind::BadAttrs,
+                        format!("Unsupported sharding type: {}", spec.type_)));
+                }
+                // Chunk IDs and the shard and minishard numbers are uint64.
+                if spec.preshift_bits >= 64 || spec.minishard_bits >= 64 || spec.shard_bits >= 64
+                        || spec.minishard_bits + spec.shard_bits > 64 {
+                    return Err(errors::new(errors::ErrorKind::BadAttrs, format!(
+                        "Invalid sharding spec: preshift_bits {}, minishard_bits {} and shard_bits {} \
+                        do not fit a uint64", spec.preshift_bits, spec.minishard_bits, spec.shard_bits)));
+                }
+                Ok(Some(spec))
+            },
+        }
+    }
+
+    fn hash(&self, value: u64) -> u64 {
+        match self.hash {
+            ShardingHash::Identity => value,
+            ShardingHash::MurmurHash3X86_128 => {
+                let (low, _high) = murmurhash3_x86_128(&value.to_le_bytes(), 0);
+                low
+            },
+        }
+    }
+
+    pub(crate) fn locate(&self, chunk_id: u64) -> ChunkLocation {
+        let hashed = self.hash(chunk_id >> self.preshift_bits);
+        let minishard = hashed & low_bit_mask(self.minishard_bits);
+        let shard = (hashed >> self.minishard_bits) & low_bit_mask(self.shard_bits);
+
+        ChunkLocation { shard, minishard }
+    }
+
+    /// Name of the shard file relative to the scale directory.
+    pub(crate) fn shard_file_name(&self, shard: u64) -> String {
+        let width = ((self.shard_bits + 3) / 4) as usize;
+        format!("{:0width$x}.shard", shard, width = width)
+    }
+
+    /// Size in bytes of the shard index at the start of each shard file.
+    pub(crate) fn shard_index_size(&self) -> u64 {
+        16 << self.minishard_bits
+    }
+
+    /// Byte range of a minishard's entry within the shard index.
+    pub(crate) fn shard_index_range(&self, minishard: u64) -> (u64, u64) {
+        (16 * minishard, 16 * (minishard + 1))
+    }
+
+    /// Parse a shard index entry into the absolute byte range of the
+    /// minishard index. Returns `None` for an empty minishard.
+    pub(crate) fn minishard_index_range(&self, entry: &[u8]) -> Result<Option<(u64, u64)>, Error> {
+        let values = read_u64s(entry, 2)?;
+        let (start, end) = (values[0], values[1]);
+        if start > end {
+            return Err(errors::new(errors::ErrorKind::Decode, "Invalid shard index entry"));
+        }
+        if start == end {
+            return Ok(None);
+        }
+        let offset = self.shard_index_size();
+
+        Ok(Some((offset + start, offset + end)))
+    }
+
+    /// Decode a minishard index into a list of chunk IDs and the absolute
+    /// byte ranges of their data within the shard.
+    pub(crate) fn decode_minishard_index(&self, bytes: Vec<u8>) -> Result<Vec<(u64, (u64, u64))>, Error> {
+        let bytes = self.minishard_index_encoding.decode(bytes)?;
+        if bytes.len() % 24 != 0 {
+            return Err(errors::new(errors::ErrorKind::Decode, "Invalid minishard index length"));
+        }
+        let n = bytes.len() / 24;
+        let values = read_u64s(&bytes, 3 * n)?;
+
+        let mut chunk_id = 0u64;
+        let mut offset = self.shard_index_size();
+        let mut entries = Vec::with_capacity(n);
+        for i in 0..n {
+            chunk_id = chunk_id.wrapping_add(values[i]);
+            let start = offset + values[n + i];
+            let end = start + values[2 * n + i];
+            entries.push((chunk_id, (start, end)));
+            offset = end;
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Size of the shard index cache of a reader if not specified.
+pub const DEFAULT_SHARD_INDEX_CACHE_BYTES: usize = 16 * 1024 * 1024;
+
+/// A shard or minishard index held by an `IndexCache`.
+pub(crate) enum CachedIndex {
+    /// The whole shard index, or `None` for a missing shard.
+    Shard(Option<Vec<u8>>),
+    /// The decoded minishard index, as chunk IDs and their byte ranges.
+    Minishard(Vec<(u64, (u64, u64))>),
+}
+
+impl CachedIndex {
+    fn size_bytes(&self) -> usize {
+        match self {
+            CachedIndex::Shard(index) => index.as_ref().map_or(0, Vec::len),
+            CachedIndex::Minishard(index) => index.len() * std::mem::size_of::<(u64, (u64, u64))>(),
+        }
+    }
+}
+
+pub(crate) type IndexFuture = Box<dyn Future<Item = Rc<CachedIndex>, Error = Error>>;
+
+struct IndexEntry {
+    index: Shared<IndexFuture>,
+    /// Zero until the index has been fetched.
+    size_bytes: usize,
+    last_used: u64,
+}
+
+/// Byte-budgeted LRU map from shard URLs (for shard indices) and shard URLs
+/// with a minishard number (for minishard indices) to the indices. Indices
+/// are shared while they are still being fetched, so that concurrent reads
+/// from one shard only fetch its index once.
+pub(crate) struct IndexCache {
+    capacity_bytes: usize,
+    bytes_used: usize,
+    clock: u64,
+    entries: HashMap<String, IndexEntry>,
+    /// Keys ordered by last use, oldest first.
+    lru: BTreeMap<u64, String>,
+}
+
+impl IndexCache {
+    pub(crate) fn new(capacity_bytes: usize) -> Self {
+        IndexCache {
+            capacity_bytes,
+            bytes_used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn capacity_bytes(&self) -> usize {
+        self.capacity_bytes
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes_used = 0;
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.bytes_used -= entry.size_bytes;
+        }
+    }
+
+    /// Record the size of a fetched index and evict the least recently used
+    /// indices while over capacity.
+    fn loaded(&mut self, key: &str, size_bytes: usize) {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.bytes_used -= entry.size_bytes;
+                entry.size_bytes = size_bytes;
+            },
+            None => return,
+        }
+        self.bytes_used += size_bytes;
+
+        while self.bytes_used > self.capacity_bytes {
+            let oldest = match self.lru.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let oldest_key = self.lru[&oldest].clone();
+            self.remove(&oldest_key);
+        }
+    }
+}
+
+/// The index under `key`, fetched with `fetch` unless it is cached or
+/// already being fetched. Failed fetches are not cached, but all reads
+/// waiting for one fail with it.
+pub(crate) fn cached_index<F>(cache: &Rc<RefCell<IndexCache>>, key: String, fetch: F) -> IndexFuture
+        where F: FnOnce() -> IndexFuture,
+{
+    let cached = {
+        let mut state = cache.borrow_mut();
+        let now = state.tick();
+        let cached = state.entries.get_mut(&key).map(|entry| {
+            let last_used = std::mem::replace(&mut entry.last_used, now);
+            (entry.index.clone(), last_used)
+        });
+        if let Some((_, last_used)) = &cached {
+            state.lru.remove(last_used);
+            state.lru.insert(now, key.clone());
+        }
+        cached.map(|(index, _)| index)
+    };
+    let index = match cached {
+        Some(index) => index,
+        None => {
+            // `fetch` may look up other indices, so the cache must not be
+            // borrowed while it runs.
+            let loaded_cache = cache.clone();
+            let loaded_key = key.clone();
+            let fetched: IndexFuture = Box::new(fetch().then(move |result| {
+                let mut state = loaded_cache.borrow_mut();
+                match &result {
+                    Ok(index) => state.loaded(&loaded_key, index.size_bytes()),
+                    Err(_) => state.remove(&loaded_key),
+                }
+                result
+            }));
+            let index = fetched.shared();
+
+            let mut state = cache.borrow_mut();
+            let now = state.tick();
+            state.remove(&key);
+            state.entries.insert(key.clone(), IndexEntry {
+                index: index.clone(),
+                size_bytes: 0,
+                last_used: now,
+            });
+            state.lru.insert(now, key);
+            index
+        },
+    };
+
+    Box::new(index
+        .map(|index| (*index).clone())
+        .map_err(|error| errors::new(errors::kind_of(&error), errors::message_of(&error))))
+}
+
+fn low_bit_mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        !0
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+fn read_u64s(bytes: &[u8], count: usize) -> Result<Vec<u64>, Error> {
+    if bytes.len() < count * 8 {
+        return Err(errors::new(errors::ErrorKind::Decode, "Truncated shard index"));
+    }
+
+    Ok(bytes.chunks_exact(8)
+        .take(count)
+        .map(|b| {
+            let mut le = [0u8; 8];
+            le.copy_from_slice(b);
+            u64::from_le_bytes(le)
+        })
+        .collect())
+}
+
+
+/// Number of bits needed to represent coordinates of each axis of a grid.
+fn grid_bits(grid_shape: &[u64]) -> Vec<u32> {
+    grid_shape.iter()
+        .map(|&s| 64 - s.saturating_sub(1).leading_zeros())
+        .collect()
+}
+
+/// Compressed Morton code of a grid position, interleaving only as many bits
+/// per axis as are needed for the grid shape.
+pub(crate) fn compressed_morton_code(grid_position: &[u64], grid_shape: &[u64]) -> u64 {
+    let bits = grid_bits(grid_shape);
+    let max_bits = bits.iter().cloned().max().unwrap_or(0);
+    let mut code = 0u64;
+    let mut j = 0;
+    for bit in 0..max_bits {
+        for (dim, &dim_bits) in bits.iter().enumerate() {
+            if bit < dim_bits {
+                code |= ((grid_position[dim] >> bit) & 1) << j;
+                j += 1;
+            }
+        }
+    }
+
+    code
+}
+
+/// The grid position with the given compressed Morton code, the inverse of
+/// `compressed_morton_code`.
+pub(crate) fn decode_morton_code(code: u64, grid_shape: &[u64]) -> Vec<u64> {
+    let bits = grid_bits(grid_shape);
+    let max_bits = bits.iter().cloned().max().unwrap_or(0);
+    let mut grid_position = vec![0u64; grid_shape.len()];
+    let mut j = 0;
+    for bit in 0..max_bits {
+        for (dim, &dim_bits) in bits.iter().enumerate() {
+            if bit < dim_bits {
+                grid_position[dim] |= ((code >> j) & 1) << bit;
+                j += 1;
+            }
+        }
+    }
+
+    grid_position
+}
+
+/// Check that a compressed Morton code of a grid of this shape fits in 64
+/// bits.
+pub(crate) fn check_grid_shape(grid_shape: &[u64]) -> Result<(), Error> {
+    let total_bits: u32 = grid_bits(grid_shape).iter().sum();
+    if total_bits > 64 {
+        return Err(errors::new(errors::ErrorKind::InvalidArgument, format!(
+            "Grid shape {:?} needs {} bits for a Morton code", grid_shape, total_bits)));
+    }
+
+    Ok(())
+}
+
+/// Number of chunks along each axis of a scale.
+pub(crate) fn grid_shape(data_attrs: &DatasetAttributes, zoom_level: usize) -> Vec<u64> {
+    data_attrs.get_dimensions(zoom_level).iter()
+        .zip(data_attrs.get_block_size(zoom_level))
+        .map(|(&d, &b)| (d + b as u64 - 1) / b as u64)
+        .collect()
+}
+
+
+fn fmix32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// MurmurHash3 x86 128-bit variant, returning the low and high 64 bits.
+fn murmurhash3_x86_128(data: &[u8], seed: u32) -> (u64, u64) {
+    const C1: u32 = 0x239b_961b;
+    const C2: u32 = 0xab0e_9789;
+    const C3: u32 = 0x38b3_4ae5;
+    const C4: u32 = 0xa1e3_8b93;
+
+    let mut h = [seed; 4];
+    let c = [C1, C2, C3, C4];
+    let rotations = [15, 16, 17, 18];
+    let mix_rotations = [19, 17, 15, 13];
+    let mix_constants = [0x561c_cd1b, 0x0bca_a747, 0x96cd_1c35, 0x32ac_3b17];
+
+    let mix_k = |i: usize, k: u32| -> u32 {
+        k.wrapping_mul(c[i]).rotate_left(rotations[i]).wrapping_mul(c[(i + 1) % 4])
+    };
+
+    let blocks = data.chunks_exact(16);
+    let tail = blocks.remainder();
+    for block in blocks {
+        for i in 0..4 {
+            let k = u32::from_le_bytes([
+                block[4 * i], block[4 * i + 1], block[4 * i + 2], block[4 * i + 3]]);
+            h[i] ^= mix_k(i, k);
+            h[i] = h[i].rotate_left(mix_rotations[i])
+                .wrapping_add(h[(i + 1) % 4])
+                .wrapping_mul(5)
+                .wrapping_add(mix_constants[i]);
+        }
+    }
+
+    for i in 0..4 {
+        let mut k = 0u32;
+        for (j, &byte) in tail.iter().enumerate().skip(4 * i).take(4) {
+            k ^= (byte as u32) << (8 * (j - 4 * i));
+        }
+        if tail.len() > 4 * i {
+            h[i] ^= mix_k(i, k);
+        }
+    }
+
+    let len = data.len() as u32;
+    for hi in h.iter_mut() {
+        *hi ^= len;
+    }
+    h[0] = h[0].wrapping_add(h[1]).wrapping_add(h[2]).wrapping_add(h[3]);
+    for i in 1..4 {
+        h[i] = h[i].wrapping_add(h[0]);
+    }
+    for hi in h.iter_mut() {
+        *hi = fmix32(*hi);
+    }
+    h[0] = h[0].wrapping_add(h[1]).wrapping_add(h[2]).wrapping_add(h[3]);
+    for i in 1..4 {
+        h[i] = h[i].wrapping_add(h[0]);
+    }
+
+    (
+        u64::from(h[0]) | (u64::from(h[1]) << 32),
+        u64::from(h[2]) | (u64::from(h[3]) << 32),
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(minishard_bits: u32, shard_bits: u32) -> serde_json::Value {
+        serde_json::json!({
+            "sharding": {
+                "@type": "neuroglancer_uint64_sharded_v1",
+                "preshift_bits": 0,
+                "hash": "identity",
+                "minishard_bits": minishard_bits,
+                "shard_bits": shard_bits,
+            },
+        })
+    }
+
+    #[test]
+    fn accepts_bits_that_fit_a_uint64() {
+        let spec = ShardingSpec::of_attributes(&spec(6, 58)).unwrap().unwrap();
+        assert_eq!(spec.locate(0x41), ChunkLocation { shard: 1, minishard: 1 });
+    }
+
+    #[test]
+    fn rejects_bits_that_do_not_fit_a_uint64() {
+        assert!(ShardingSpec::of_attributes(&spec(64, 0)).is_err());
+        assert!(ShardingSpec::of_attributes(&spec(0, 64)).is_err());
+        assert!(ShardingSpec::of_attributes(&spec(40, 40)).is_err());
+    }
+
+    #[test]
+    fn interleaves_only_the_bits_each_axis_needs() {
+        // The axes need 2, 3 and 1 bits, so after the first bit of each,
+        // only x and y bits follow, then only y bits.
+        let grid_shape = [3, 5, 2];
+
+        assert_eq!(compressed_morton_code(&[1, 2, 1], &grid_shape), 0b01_0101);
+        assert_eq!(compressed_morton_code(&[2, 4, 0], &grid_shape), 0b10_1000);
+        assert_eq!(decode_morton_code(0b01_0101, &grid_shape), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn round_trips_morton_codes_of_non_power_of_two_grids() {
+        let grid_shape = [3, 5, 7];
+        let mut codes = Vec::new();
+        for z in 0..7 {
+            for y in 0..5 {
+                for x in 0..3 {
+                    let code = compressed_morton_code(&[x, y, z], &grid_shape);
+                    assert!(code < 1 << 8);
+                    assert_eq!(decode_morton_code(code, &grid_shape), vec![x, y, z]);
+                    codes.push(code);
+                }
+            }
+        }
+        codes.sort();
+        codes.dedup();
+
+        assert_eq!(codes.len(), 3 * 5 * 7);
+    }
+
+    #[test]
+    fn rejects_grids_whose_codes_exceed_64_bits() {
+        assert!(check_grid_shape(&[1 << 21, 1 << 21, 1 << 22]).is_ok());
+        assert!(check_grid_shape(&[1 << 21, 1 << 21, 1 << 23]).is_err());
+    }
+}
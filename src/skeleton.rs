@@ -0,0 +1,166 @@
+//! Skeletons of precomputed segmentation datasets
+//! (`neuroglancer_skeletons`).
+
+use super::*;
+
+
+/// A per-vertex attribute declared in the skeleton info.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub(crate) struct VertexAttribute {
+    id: String,
+    data_type: String,
+    num_components: u32,
+}
+
+/// The vertex attributes declared in a skeleton info, which has none if the
+/// skeleton directory has no info.
+pub(crate) fn vertex_attributes(info: &serde_json::Value) -> Result<Vec<VertexAttribute>, Error> {
+    match info.get("vertex_attributes") {
+        None | Some(serde_json::Value::Null) => Ok(Vec::new()),
+        Some(attributes) => serde_json::from_value(attributes.clone())
+            .map_err(|e| errors::new(errors::ErrorKind::BadAttrs,
+                format!("Invalid skeleton vertex_attributes: {}", e))),
+    }
+}
+
+fn element_size(data_type: &str) -> Result<usize, Error> {
+    match data_type {
+        "uint8" | "int8" => Ok(1),
+        "uint16" | "int16" => Ok(2),
+        "float32" | "uint32" | "int32" => Ok(4),
+        _ => Err(errors::new(errors::ErrorKind::BadAttrs,
+            format!("Unsupported skeleton attribute type: {}", data_type))),
+    }
+}
+
+fn typed_array(data_type: &str, bytes: &[u8]) -> Result<JsValue, Error> {
+    Ok(match data_type {
+        "uint8" => js_sys::Uint8Array::from(bytes).into(),
+        "int8" => js_sys::Int8Array::from(&raw::decode_le::<i8>(bytes)?[..]).into(),
+        "uint16" => js_sys::Uint16Array::from(&raw::decode_le::<u16>(bytes)?[..]).into(),
+        "int16" => js_sys::Int16Array::from(&raw::decode_le::<i16>(bytes)?[..]).into(),
+        "uint32" => js_sys::Uint32Array::from(&raw::decode_le::<u32>(bytes)?[..]).into(),
+        "int32" => js_sys::Int32Array::from(&raw::decode_le::<i32>(bytes)?[..]).into(),
+        "float32" => js_sys::Float32Array::from(&raw::decode_le::<f32>(bytes)?[..]).into(),
+        _ => return Err(errors::new(errors::ErrorKind::BadAttrs,
+            format!("Unsupported skeleton attribute type: {}", data_type))),
+    })
+}
+
+fn take<'a>(bytes: &'a [u8], position: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+    let end = position.saturating_add(len);
+    if end > bytes.len() {
+        return Err(errors::new(errors::ErrorKind::Decode, "Truncated skeleton"));
+    }
+    let taken = &bytes[*position..end];
+    *position = end;
+
+    Ok(taken)
+}
+
+/// A skeleton with the little-endian data of each of its vertex attributes.
+struct Skeleton<'a> {
+    vertices: Vec<f32>,
+    edges: Vec<u32>,
+    attributes: Vec<(&'a VertexAttribute, &'a [u8])>,
+}
+
+/// Parse a skeleton, which is the vertex and edge counts followed by the
+/// vertex positions, the vertex index pairs of the edges and the data of each
+/// vertex attribute.
+fn parse_skeleton<'a>(bytes: &'a [u8], attributes: &'a [VertexAttribute]) -> Result<Skeleton<'a>, Error> {
+    let mut position = 0;
+    let counts: Vec<u32> = raw::decode_le(take(bytes, &mut position, 8)?)?;
+    let (num_vertices, num_edges) = (counts[0] as usize, counts[1] as usize);
+    let vertices: Vec<f32> = raw::decode_le(take(bytes, &mut position, num_vertices.saturating_mul(12))?)?;
+    let edges: Vec<u32> = raw::decode_le(take(bytes, &mut position, num_edges.saturating_mul(8))?)?;
+    if edges.iter().any(|&vertex| vertex as usize >= num_vertices) {
+        return Err(errors::new(errors::ErrorKind::Decode, "Skeleton has invalid edge vertices"));
+    }
+
+    let mut attribute_data = Vec::with_capacity(attributes.len());
+    for attribute in attributes {
+        let len = (element_size(&attribute.data_type)? * attribute.num_components as usize)
+            .saturating_mul(num_vertices);
+        attribute_data.push((attribute, take(bytes, &mut position, len)?));
+    }
+    if position != bytes.len() {
+        return Err(errors::new(errors::ErrorKind::Decode, "Skeleton has trailing data"));
+    }
+
+    Ok(Skeleton {
+        vertices,
+        edges,
+        attributes: attribute_data,
+    })
+}
+
+/// Decode a skeleton into `{vertices, edges, ...attributes}` with an array
+/// per attribute `id`.
+pub(crate) fn decode_skeleton(bytes: &[u8], attributes: &[VertexAttribute]) -> Result<JsValue, Error> {
+    let skeleton = parse_skeleton(bytes, attributes)?;
+
+    let mut entries: Vec<(&str, JsValue)> = vec![
+        ("vertices", js_sys::Float32Array::from(&skeleton.vertices[..]).into()),
+        ("edges", js_sys::Uint32Array::from(&skeleton.edges[..]).into()),
+    ];
+    for (attribute, data) in &skeleton.attributes {
+        entries.push((&attribute.id, typed_array(&attribute.data_type, data)?));
+    }
+
+    Ok(js_object(&entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes() -> Vec<VertexAttribute> {
+        vertex_attributes(&serde_json::json!({
+            "@type": "neuroglancer_skeletons",
+            "vertex_attributes": [
+                {"id": "radius", "data_type": "float32", "num_components": 1},
+                {"id": "color", "data_type": "uint8", "num_components": 3},
+            ],
+        })).unwrap()
+    }
+
+    /// A skeleton of a path through three vertices, with a radius and a
+    /// color per vertex.
+    fn path() -> Vec<u8> {
+        let mut bytes = raw::encode_le(&[3u32, 2]);
+        bytes.extend(raw::encode_le(&[0f32, 0., 0., 1., 0., 0., 1., 1., 0.]));
+        bytes.extend(raw::encode_le(&[0u32, 1, 1, 2]));
+        bytes.extend(raw::encode_le(&[0.5f32, 0.25, 0.125]));
+        bytes.extend(&[255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        bytes
+    }
+
+    #[test]
+    fn parses_skeletons_with_vertex_attributes() {
+        let attributes = attributes();
+        let bytes = path();
+        let skeleton = parse_skeleton(&bytes, &attributes).unwrap();
+
+        assert_eq!(skeleton.vertices, vec![0., 0., 0., 1., 0., 0., 1., 1., 0.]);
+        assert_eq!(skeleton.edges, vec![0, 1, 1, 2]);
+        assert_eq!(skeleton.attributes.len(), 2);
+        assert_eq!(skeleton.attributes[0].0.id, "radius");
+        assert_eq!(raw::decode_le::<f32>(skeleton.attributes[0].1).unwrap(), vec![0.5, 0.25, 0.125]);
+        assert_eq!(skeleton.attributes[1].0.id, "color");
+        assert_eq!(skeleton.attributes[1].1, &[255, 0, 0, 0, 255, 0, 0, 0, 255][..]);
+    }
+
+    #[test]
+    fn rejects_truncated_skeletons() {
+        let attributes = attributes();
+        let bytes = path();
+
+        for len in &[4, 20, 50, bytes.len() - 1] {
+            let error = parse_skeleton(&bytes[..*len], &attributes).err().unwrap();
+            assert_eq!(errors::kind_of(&error), errors::ErrorKind::Decode);
+        }
+        // Without the attributes the data is trailing.
+        assert!(parse_skeleton(&bytes, &[]).is_err());
+    }
+}
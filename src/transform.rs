@@ -0,0 +1,146 @@
+//! Affine transforms between voxel and physical coordinates.
+//!
+//! Coordinates are in the axis order of the info (`x, y, z` for volumes),
+//! and physical coordinates are in the unit of the scale resolutions, which
+//! is nanometers for precomputed datasets.
+
+use super::*;
+
+
+/// An affine transform as one row per axis, each with a coefficient per axis
+/// followed by the translation.
+pub(crate) type Affine = Vec<Vec<f64>>;
+
+pub(crate) fn identity(ndim: usize) -> Affine {
+    (0..ndim)
+        .map(|row| (0..=ndim).map(|col| if row == col { 1.0 } else { 0.0 }).collect())
+        .collect()
+}
+
+/// Parse a transform given as nested rows or a flat row-major list, either
+/// with only the `ndim` rows or with the homogeneous last row as well.
+pub(crate) fn parse_affine(value: &serde_json::Value, ndim: usize) -> Result<Affine, Error> {
+    let invalid = || errors::new(errors::ErrorKind::BadAttrs, format!(
+        "Invalid transform for {} dimensions: {}", ndim, value));
+
+    let numbers: Vec<f64> = match serde_json::from_value::<Vec<Vec<f64>>>(value.clone()) {
+        Ok(rows) => rows.into_iter().flatten().collect(),
+        Err(_) => serde_json::from_value(value.clone()).map_err(|_| invalid())?,
+    };
+    if numbers.len() != ndim * (ndim + 1) && numbers.len() != (ndim + 1) * (ndim + 1) {
+        return Err(invalid());
+    }
+
+    Ok(numbers.chunks_exact(ndim + 1).take(ndim).map(<[f64]>::to_vec).collect())
+}
+
+pub(crate) fn apply(affine: &Affine, point: &[f64]) -> Vec<f64> {
+    affine.iter()
+        .map(|row| row[..point.len()].iter().zip(point).map(|(&a, &p)| a * p).sum::<f64>() + row[point.len()])
+        .collect()
+}
+
+/// The inverse transform, by Gauss-Jordan elimination of the linear part.
+pub(crate) fn invert(affine: &Affine) -> Result<Affine, Error> {
+    let ndim = affine.len();
+    let mut linear: Vec<Vec<f64>> = affine.iter().map(|row| row[..ndim].to_vec()).collect();
+    let mut inverse: Vec<Vec<f64>> = identity(ndim).into_iter()
+        .map(|mut row| { row.truncate(ndim); row })
+        .collect();
+
+    for col in 0..ndim {
+        let pivot = (col..ndim)
+            .max_by(|&a, &b| linear[a][col].abs().partial_cmp(&linear[b][col].abs())
+                .unwrap_or(std::cmp::Ordering::Equal))
+            .filter(|&row| linear[row][col].abs() > std::f64::EPSILON)
+            .ok_or_else(|| errors::new(errors::ErrorKind::BadAttrs, "Transform is not invertible"))?;
+        linear.swap(col, pivot);
+        inverse.swap(col, pivot);
+
+        let scale = linear[col][col];
+        for k in 0..ndim {
+            linear[col][k] /= scale;
+            inverse[col][k] /= scale;
+        }
+        for row in 0..ndim {
+            if row != col {
+                let factor = linear[row][col];
+                for k in 0..ndim {
+                    linear[row][k] -= factor * linear[col][k];
+                    inverse[row][k] -= factor * inverse[col][k];
+                }
+            }
+        }
+    }
+
+    let translation: Vec<f64> = affine.iter().map(|row| row[ndim]).collect();
+    Ok(inverse.into_iter()
+        .map(|mut row| {
+            let offset = -row.iter().zip(&translation).map(|(&a, &t)| a * t).sum::<f64>();
+            row.push(offset);
+            row
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn parses_nested_flat_and_homogeneous_transforms() {
+        let expected = vec![
+            vec![2.0, 0.0, 0.0, 1.0],
+            vec![0.0, 3.0, 0.0, 2.0],
+            vec![0.0, 0.0, 4.0, 3.0],
+        ];
+
+        let nested = serde_json::json!([[2, 0, 0, 1], [0, 3, 0, 2], [0, 0, 4, 3]]);
+        assert_eq!(parse_affine(&nested, 3).unwrap(), expected);
+        let flat = serde_json::json!([2, 0, 0, 1, 0, 3, 0, 2, 0, 0, 4, 3]);
+        assert_eq!(parse_affine(&flat, 3).unwrap(), expected);
+        let homogeneous = serde_json::json!([[2, 0, 0, 1], [0, 3, 0, 2], [0, 0, 4, 3], [0, 0, 0, 1]]);
+        assert_eq!(parse_affine(&homogeneous, 3).unwrap(), expected);
+
+        let short = serde_json::json!([[2, 0, 0, 1], [0, 3, 0, 2]]);
+        assert_eq!(errors::kind_of(&parse_affine(&short, 3).unwrap_err()), errors::ErrorKind::BadAttrs);
+        assert!(parse_affine(&serde_json::json!("identity"), 3).is_err());
+    }
+
+    #[test]
+    fn inverts_transforms() {
+        // A rotation about z with anisotropic scaling, needing row swaps.
+        let affine = vec![
+            vec![0.0, -4.0, 0.0, 10.0],
+            vec![8.0, 0.0, 0.0, -5.0],
+            vec![0.0, 0.0, 40.0, 100.0],
+        ];
+        let inverse = invert(&affine).unwrap();
+
+        for point in &[[0.0, 0.0, 0.0], [1.0, 2.0, 3.0], [-7.5, 0.25, 12.0]] {
+            assert_close(&apply(&inverse, &apply(&affine, point)), point);
+            assert_close(&apply(&affine, &apply(&inverse, point)), point);
+        }
+        assert_close(&apply(&affine, &[1.0, 2.0, 3.0]), &[2.0, 3.0, 220.0]);
+    }
+
+    #[test]
+    fn rejects_singular_transforms() {
+        let affine = vec![
+            vec![1.0, 2.0, 0.0, 0.0],
+            vec![2.0, 4.0, 0.0, 1.0],
+            vec![0.0, 0.0, 1.0, 0.0],
+        ];
+
+        let error = invert(&affine).unwrap_err();
+        assert_eq!(errors::kind_of(&error), errors::ErrorKind::BadAttrs);
+        assert!(invert(&vec![vec![0.0, 0.0]]).is_err());
+    }
+}
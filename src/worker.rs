@@ -0,0 +1,457 @@
+//! Decoding chunks in web workers instead of on the main thread.
+//!
+//! A `WorkerDecodeReader` fetches chunk bytes on the calling thread and
+//! posts them to one of a pool of workers, which decode them with
+//! `decode_worker_message` of their own instance of this module:
+//!
+//! ```js
+//! import init, { decode_worker_message } from './ngpre_wasm.js';
+//! const ready = init();
+//! onmessage = async (event) => {
+//!   await ready;
+//!   const reply = decode_worker_message(event.data);
+//!   postMessage(reply, reply.bytes ? [reply.bytes.buffer] : []);
+//! };
+//! ```
+//!
+//! Decoded data is sent back as little-endian bytes, which only need to be
+//! copied into a block.
+
+use std::cell::{
+    Cell,
+    RefCell,
+};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::sync::oneshot;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    MessageEvent,
+    Worker,
+};
+
+use super::*;
+use crate::http_fetch::{
+    FetchedChunk,
+    NgPreHTTPFetch,
+};
+
+
+type Reply = Result<JsValue, Error>;
+
+/// Workers decoding chunks, chosen round-robin, and the replies they still
+/// owe by message ID.
+struct WorkerPool {
+    workers: Vec<Worker>,
+    next_worker: Cell<usize>,
+    next_id: Cell<u32>,
+    pending: Rc<RefCell<HashMap<u32, oneshot::Sender<Reply>>>>,
+    _handlers: Vec<(Closure<dyn FnMut(MessageEvent)>, Closure<dyn FnMut(JsValue)>)>,
+}
+
+impl WorkerPool {
+    fn new(worker_urls: &[String]) -> Result<Self, JsValue> {
+        let pending: Rc<RefCell<HashMap<u32, oneshot::Sender<Reply>>>> = Rc::new(RefCell::new(HashMap::new()));
+        let mut workers = Vec::with_capacity(worker_urls.len());
+        let mut handlers = Vec::with_capacity(worker_urls.len());
+
+        for url in worker_urls {
+            let worker = Worker::new(url)?;
+
+            let replies = pending.clone();
+            let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+                let reply = event.data();
+                let id = js_sys::Reflect::get(&reply, &JsValue::from_str("id")).ok()
+                    .and_then(|id| id.as_f64());
+                let sender = match id.and_then(|id| replies.borrow_mut().remove(&(id as u32))) {
+                    Some(sender) => sender,
+                    None => return,
+                };
+                let error = js_sys::Reflect::get(&reply, &JsValue::from_str("error"))
+                    .unwrap_or(JsValue::UNDEFINED);
+                let _ = sender.send(if error.is_undefined() {
+                    Ok(reply)
+                } else {
+                    // Replies from workers of older versions have no kind.
+                    let kind = js_sys::Reflect::get(&reply, &JsValue::from_str("kind")).ok()
+                        .and_then(|kind| kind.as_string())
+                        .and_then(|kind| errors::ErrorKind::from_name(&kind))
+                        .unwrap_or(errors::ErrorKind::Decode);
+                    Err(errors::new(kind, error.as_string()
+                        .unwrap_or_else(|| "Decoding in a worker failed".to_owned())))
+                });
+            }) as Box<dyn FnMut(MessageEvent)>);
+
+            // A failing worker script leaves no way to tell which requests
+            // it had, so all pending requests fail.
+            let replies = pending.clone();
+            let on_error = Closure::wrap(Box::new(move |_event: JsValue| {
+                for (_, sender) in replies.borrow_mut().drain() {
+                    let _ = sender.send(Err(errors::new(errors::ErrorKind::Other,
+                        "A decoding worker failed")));
+                }
+            }) as Box<dyn FnMut(JsValue)>);
+
+            worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+            workers.push(worker);
+            handlers.push((on_message, on_error));
+        }
+
+        Ok(WorkerPool {
+            workers,
+            next_worker: Cell::new(0),
+            next_id: Cell::new(0),
+            pending,
+            _handlers: handlers,
+        })
+    }
+
+    /// Post a chunk to the next worker, transferring its bytes, and resolve
+    /// to the worker's reply.
+    fn decode(&self, chunk: &FetchedChunk, data_attrs: &DatasetAttributes, byte_order: raw::ByteOrder)
+            -> Box<dyn Future<Item = JsValue, Error = Error>> {
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+        let worker = &self.workers[self.next_worker.get() % self.workers.len()];
+        self.next_worker.set(self.next_worker.get().wrapping_add(1));
+
+        let info = match JsValue::from_serde(data_attrs) {
+            Ok(info) => info,
+            Err(error) => return Box::new(future::err(errors::new(errors::ErrorKind::Other, error.to_string()))),
+        };
+        let bytes = js_sys::Uint8Array::from(&chunk.bytes[..]);
+        let message = js_object(&[
+            ("id", JsValue::from(id)),
+            ("info", info),
+            ("zoom_level", JsValue::from(chunk.zoom_level as u32)),
+            ("grid_position", JsValue::from_serde(&chunk.grid_position).unwrap()),
+            ("big_endian", JsValue::from(byte_order == raw::ByteOrder::Big)),
+            ("bytes", JsValue::from(bytes.clone())),
+        ]);
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.borrow_mut().insert(id, sender);
+        let transfer = std::iter::once(bytes.buffer()).collect::<js_sys::Array>();
+        if let Err(error) = worker.post_message_with_transfer(&message, &transfer) {
+            self.pending.borrow_mut().remove(&id);
+            return Box::new(future::err(errors::from_js_error(error)));
+        }
+
+        Box::new(receiver
+            .map_err(|_| errors::new(errors::ErrorKind::Other, "Decoding worker pool was dropped"))
+            .and_then(future::result))
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.terminate();
+        }
+    }
+}
+
+/// Decode a chunk posted by a `WorkerDecodeReader`, to be called in a
+/// worker. Resolves to `{id, bytes, size}` with the decoded data as
+/// little-endian bytes, or `{id, error, kind}` if decoding failed.
+#[wasm_bindgen]
+pub fn decode_worker_message(message: JsValue) -> JsValue {
+    let id = js_sys::Reflect::get(&message, &JsValue::from_str("id")).unwrap_or(JsValue::NULL);
+    let decoded = decode_message(&message);
+
+    match decoded {
+        Ok((bytes, size)) => js_object(&[
+            ("id", id),
+            ("bytes", JsValue::from(js_sys::Uint8Array::from(&bytes[..]))),
+            ("size", JsValue::from_serde(&size).unwrap()),
+        ]),
+        Err(error) => js_object(&[
+            ("id", id),
+            ("error", JsValue::from_str(&errors::message_of(&error))),
+            ("kind", JsValue::from_str(errors::kind_of(&error).as_str())),
+        ]),
+    }
+}
+
+fn decode_message(message: &JsValue) -> Result<(Vec<u8>, Vec<u32>), Error> {
+    let field = |name: &str| js_sys::Reflect::get(message, &JsValue::from_str(name))
+        .map_err(errors::from_js_error);
+    let invalid = |e: serde_json::Error| errors::new(errors::ErrorKind::InvalidArgument,
+        format!("Invalid decode message: {}", e));
+
+    let data_attrs = parse_dataset_attributes(field("info")?.into_serde().map_err(invalid)?)?;
+    let zoom_level = field("zoom_level")?.as_f64().unwrap_or(0.0) as usize;
+    let grid_position: GridCoord = field("grid_position")?.into_serde().map_err(invalid)?;
+    let byte_order = if field("big_endian")?.as_bool().unwrap_or(false) {
+        raw::ByteOrder::Big
+    } else {
+        raw::ByteOrder::Little
+    };
+    let bytes = js_sys::Uint8Array::from(field("bytes")?).to_vec();
+
+    data_type_match! {
+        data_attrs.get_data_type(),
+        decode::decode_block::<RsType>(&bytes, &data_attrs, zoom_level, grid_position, byte_order)
+            .map(|block| (raw::encode_le(block.get_data()), block.get_size().to_vec()))
+    }
+}
+
+
+/// A reader that decodes chunks of an inner reader in web workers, so that
+/// decoding large JPEG or compressed segmentation chunks does not block the
+/// main thread. Without workers chunks are decoded inline as usual.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct WorkerDecodeReader {
+    inner: NgPreHTTPFetch,
+    pool: Option<Rc<WorkerPool>>,
+}
+
+#[wasm_bindgen]
+impl WorkerDecodeReader {
+    /// Start a worker for each of `worker_urls`, scripts that load this
+    /// module and answer messages with `decode_worker_message`.
+    pub fn new(inner: &NgPreHTTPFetch, worker_urls: Vec<JsValue>) -> Result<WorkerDecodeReader, JsValue> {
+        let worker_urls = worker_urls.iter()
+            .map(|url| url.as_string().ok_or_else(|| errors::to_js_error(&errors::new(
+                errors::ErrorKind::InvalidArgument, format!("Worker URL {:?} is not a string", url)))))
+            .collect::<Result<Vec<String>, JsValue>>()?;
+        let pool = if worker_urls.is_empty() {
+            None
+        } else {
+            Some(Rc::new(WorkerPool::new(&worker_urls)?))
+        };
+
+        Ok(WorkerDecodeReader {
+            inner: inner.clone(),
+            pool,
+        })
+    }
+}
+
+reader_delegations!(WorkerDecodeReader);
+
+impl WorkerDecodeReader {
+    fn read_block_conditional<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: Option<&str>,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let pool = match &self.pool {
+            Some(pool) => pool.clone(),
+            None => return match known_etag {
+                Some(known_etag) => NgPreAsyncEtagReader::read_block_if_changed::<T>(
+                    &self.inner, path_name, data_attrs, grid_position, known_etag),
+                None => Box::new(NgPreAsyncEtagReader::read_block_with_etag::<T>(
+                        &self.inner, path_name, data_attrs, grid_position)
+                    .map(Conditional::Changed)),
+            },
+        };
+        if known_etag.is_none() {
+            // Share the decoded block with concurrent reads of the chunk.
+            let (path_name2, da2) = (path_name.to_owned(), data_attrs.clone());
+            let kind = format!("worker:{}", std::any::type_name::<T>());
+            return Box::new(self.inner.read_chunk_shared(path_name, data_attrs, grid_position.clone(), &kind,
+                    move |inner| Box::new(WorkerDecodeReader::decode_on(inner, pool, &path_name2, &da2, grid_position, None)
+                        .map(|conditional| match conditional {
+                            Conditional::Changed(maybe_block) => maybe_block,
+                            Conditional::Unchanged => None,
+                        })))
+                .map(Conditional::Changed));
+        }
+
+        WorkerDecodeReader::decode_on(&self.inner, pool, path_name, data_attrs, grid_position, known_etag)
+    }
+
+    /// Fetch a chunk with `inner`, unless it still has `known_etag`, and
+    /// decode it on a worker of `pool`. Like the inner reader, with
+    /// `refetch_on_decode_error` a chunk failing to decode is fetched once
+    /// more bypassing caches.
+    fn decode_on<T>(
+        inner: &NgPreHTTPFetch,
+        pool: Rc<WorkerPool>,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: Option<&str>,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let refetch_reader = if inner.refetch_on_decode_error() {
+            Some(inner.bypassing_caches())
+        } else {
+            None
+        };
+        let (path_name, data_attrs) = (path_name.to_owned(), data_attrs.clone());
+        let byte_order = inner.byte_order();
+
+        Box::new(inner.fetch_chunk_if_changed(&path_name, &data_attrs, grid_position.clone(), known_etag)
+            .and_then(move |conditional|
+                    -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>> {
+                let chunk = match conditional {
+                    Conditional::Changed(Some(chunk)) => chunk,
+                    Conditional::Changed(None) => return Box::new(future::ok(Conditional::Changed(None))),
+                    Conditional::Unchanged => return Box::new(future::ok(Conditional::Unchanged)),
+                };
+
+                Box::new(WorkerDecodeReader::decode_chunk_on::<T>(&pool, &chunk, &data_attrs, byte_order)
+                    .map(|block| Conditional::Changed(Some(block)))
+                    .or_else(move |error|
+                            -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>> {
+                        let refetch_reader = match refetch_reader {
+                            Some(reader) if errors::kind_of(&error) == errors::ErrorKind::Decode => reader,
+                            _ => return Box::new(future::err(error)),
+                        };
+                        Box::new(refetch_reader.fetch_chunk_if_changed(&path_name, &data_attrs, grid_position, None)
+                            .and_then(move |conditional| match conditional {
+                                Conditional::Changed(Some(chunk)) => future::Either::A(
+                                    WorkerDecodeReader::decode_chunk_on::<T>(&pool, &chunk, &data_attrs, byte_order)
+                                        .map(|block| Conditional::Changed(Some(block)))),
+                                // The chunk was removed in the meantime.
+                                _ => future::Either::B(future::ok(Conditional::Changed(None))),
+                            }))
+                    }))
+            }))
+    }
+
+    /// Decode a fetched chunk on a worker of `pool`, with its etag.
+    fn decode_chunk_on<T>(
+        pool: &WorkerPool,
+        chunk: &FetchedChunk,
+        data_attrs: &DatasetAttributes,
+        byte_order: raw::ByteOrder,
+    ) -> Box<dyn Future<Item = (VecDataBlock<T>, Option<String>), Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        let grid_position = chunk.grid_position.clone();
+        let etag = chunk.etag.clone();
+
+        Box::new(pool.decode(chunk, data_attrs, byte_order).and_then(move |reply| {
+            let field = |name: &str| js_sys::Reflect::get(&reply, &JsValue::from_str(name))
+                .map_err(errors::from_js_error);
+            let size: Vec<u32> = field("size")?.into_serde()
+                .map_err(|e| errors::new(errors::ErrorKind::Decode, e.to_string()))?;
+            let data = raw::decode_le::<T>(&js_sys::Uint8Array::from(field("bytes")?).to_vec())?;
+
+            Ok((VecDataBlock::new(size.into(), grid_position.into(), data), etag))
+        }))
+    }
+}
+
+impl AbortableReader for WorkerDecodeReader {
+    fn with_signal(&self, signal: Option<AbortSignal>) -> Self {
+        WorkerDecodeReader {
+            inner: self.inner.with_signal(signal),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl NgPreAsyncReader for WorkerDecodeReader {
+    fn get_version(&self) -> Box<dyn Future<Item = ngpre::Version, Error = Error>> {
+        NgPreAsyncReader::get_version(&self.inner)
+    }
+
+    fn get_dataset_attributes(&self, path_name: &str) ->
+            Box<dyn Future<Item = DatasetAttributes, Error = Error>> {
+        NgPreAsyncReader::get_dataset_attributes(&self.inner, path_name)
+    }
+
+    fn exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::exists(&self.inner, path_name)
+    }
+
+    fn dataset_exists(&self, path_name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::dataset_exists(&self.inner, path_name)
+    }
+
+    fn read_block<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<VecDataBlock<T>>, Error = Error>>
+        where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+            T: ReflectedType + RawElement,
+    {
+        Box::new(NgPreAsyncEtagReader::read_block_with_etag(
+                self, path_name, data_attrs, grid_position)
+            .map(|maybe_block| maybe_block.map(|(block, _etag)| block)))
+    }
+
+    fn block_exists(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        NgPreAsyncReader::block_exists(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn list(&self, path_name: &str) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        NgPreAsyncReader::list(&self.inner, path_name)
+    }
+
+    fn list_attributes(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = serde_json::Value, Error = Error>> {
+        NgPreAsyncReader::list_attributes(&self.inner, path_name)
+    }
+}
+
+impl NgPreAsyncEtagReader for WorkerDecodeReader {
+    fn attributes_etag(
+        &self,
+        path_name: &str,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::attributes_etag(&self.inner, path_name)
+    }
+
+    fn block_etag(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error>> {
+        NgPreAsyncEtagReader::block_etag(&self.inner, path_name, data_attrs, grid_position)
+    }
+
+    fn read_block_with_etag<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+    ) -> Box<dyn Future<Item = Option<(VecDataBlock<T>, Option<String>)>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        Box::new(self.read_block_conditional(path_name, data_attrs, grid_position, None)
+            .map(|conditional| match conditional {
+                Conditional::Changed(maybe_block) => maybe_block,
+                Conditional::Unchanged => None,
+            }))
+    }
+
+    fn read_block_if_changed<T>(
+        &self,
+        path_name: &str,
+        data_attrs: &DatasetAttributes,
+        grid_position: UnboundedGridCoord,
+        known_etag: &str,
+    ) -> Box<dyn Future<Item = Conditional<Option<(VecDataBlock<T>, Option<String>)>>, Error = Error>>
+            where VecDataBlock<T>: DataBlock<T> + ngpre::ReadableDataBlock,
+                T: ReflectedType + RawElement,
+    {
+        self.read_block_conditional(path_name, data_attrs, grid_position, Some(known_etag))
+    }
+}